@@ -0,0 +1,71 @@
+#ifndef AWFUL_AJ_H
+#define AWFUL_AJ_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A conversation opened with `aj_session_open`.
+//
+// Each question is asked with the template's messages followed by the earlier questions and
+// answers. The oldest exchanges are forgotten once the conversation no longer fits in the
+// context window.
+typedef struct AjSession AjSession;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Asks a one-shot question, like `aj ask`, and returns the answer. Nothing is printed.
+//
+// # Safety
+//
+// The arguments must be NUL-terminated strings. The returned string must be freed with
+// `aj_free_string`.
+char *aj_ask(const char *config_path, const char *template_name, const char *question);
+
+// Opens a conversation with the configuration at `config_path` and the template named
+// `template_name`.
+//
+// # Safety
+//
+// The arguments must be NUL-terminated strings. The returned session must be closed with
+// `aj_session_close`.
+AjSession *aj_session_open(const char *config_path, const char *template_name);
+
+// Asks a question in a conversation and returns the answer.
+//
+// # Safety
+//
+// `session` must come from `aj_session_open` and not be used from two threads at once.
+// `question` must be a NUL-terminated string. The returned string must be freed with
+// `aj_free_string`.
+char *aj_session_ask(AjSession *session, const char *question);
+
+// Closes a conversation. Passing null does nothing.
+//
+// # Safety
+//
+// `session` must come from `aj_session_open` and not be used afterwards.
+void aj_session_close(AjSession *session);
+
+// Frees a string returned by awful_aj. Passing null does nothing.
+//
+// # Safety
+//
+// `text` must come from awful_aj and not be used afterwards.
+void aj_free_string(char *text);
+
+// Returns a description of the last failure on this thread, or null if the last call succeeded.
+//
+// The string is owned by awful_aj and valid until the next call on this thread; do not free it.
+const char *aj_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* AWFUL_AJ_H */
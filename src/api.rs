@@ -1,52 +1,97 @@
 //! This module handles interactions with the OpenAI API for asking questions and receiving responses.
 //!
 //! It provides functions to create a client, prepare messages, and stream responses from the API.
-//! The responses from the OpenAI API are printed in bold blue text to the console.
+//! The responses from the OpenAI API are printed to the console in the theme's assistant color.
 //!
 //! # Example
 //!
 //! ```no_run
-//! use awful_aj::{AwfulJadeConfig, ChatTemplate, ask};
+//! use awful_aj::{api::ask, config::load_config, template::load_template};
 //!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
 //! // Load your configuration and template, and prepare your question
-//! let config = AwfulJadeConfig::new(/* ... */);
-//! let template = ChatTemplate::new(/* ... */);
-//! let question = "What is the meaning of life?".to_string();
+//! let config = load_config("config.yaml")?;
+//! let template = load_template("simple_question").await?;
+//! let question = "What is the meaning of life?";
 //!
 //! // Ask a question using the OpenAI API
-//! let _ = ask(&config, question, template);
+//! ask(&config, question, &template).await?;
+//! # Ok(())
+//! # }
 //! ```
+#[cfg(feature = "tty")]
+use crate::theme::Theme;
+#[cfg(all(feature = "rag", feature = "tty"))]
 use crate::{
-    brain::{Brain, Memory},
+    brain, consolidation, duplicates, feedback, followups,
+    memory_audit::{self, RetrievalExplanation},
+    pager, word_diff,
+};
+use crate::{
+    brain::{Brain, Memory, Source},
+    budget::ContextDropped,
     config::AwfulJadeConfig,
+    decompose,
+    grounding::{self, GroundingMode},
+    guardrails, language,
+    model_family::ModelDefaults,
+    plugins,
+    postprocess::{self, Postprocessor},
+    pretty, privacy, request_id,
     template::ChatTemplate,
+    timings::Timings,
+    tokens,
+};
+#[cfg(feature = "rag")]
+use crate::{
+    budget::{ContextBudget, Parts},
+    knowledge::Knowledge,
+    memory_kinds, paraphrase, retrieval,
     vector_store::VectorStore,
 };
 use async_openai::{
     config::OpenAIConfig,
-    types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role},
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestMessage, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+        Role, Usage,
+    },
     Client,
 };
+#[cfg(all(feature = "rag", feature = "tty"))]
+use crossterm::{cursor::MoveTo, style::Print};
+#[cfg(feature = "tty")]
 use crossterm::{
-    cursor::MoveTo,
-    style::{Attribute, Color, Print, SetAttribute, SetForegroundColor},
+    style::{Attribute, Color, SetAttribute, SetForegroundColor},
     ExecutableCommand,
 };
 use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+#[cfg(all(feature = "rag", feature = "tty"))]
+use std::thread;
 use std::{
     error::Error,
+    fmt,
+    fs::{self, File},
     io::{stdout, Write},
-    thread,
-    time::Duration,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
-use tiktoken_rs::async_openai::get_chat_completion_max_tokens;
-use tracing::{debug, error};
+#[cfg(all(feature = "rag", feature = "tty"))]
+use tracing::info;
+use tracing::{debug, error, info_span, Instrument};
+
+/// Stands in for `vector_store::VectorStore` when the `rag` feature is disabled. It has no values,
+/// so memory arguments are always `None`.
+#[cfg(not(feature = "rag"))]
+pub enum VectorStore {}
 
 /// Creates a new OpenAI client using the provided configuration.
 ///
 /// # Arguments
 ///
-/// * `config` - A reference to the configuration object containing the API key and base URL.
+/// * `config` - A reference to the configuration object containing the API key, base URL, and any
+///   extra headers to send with every request.
 ///
 /// # Returns
 ///
@@ -54,46 +99,150 @@ use tracing::{debug, error};
 ///
 /// # Errors
 ///
-/// Returns an Error if there is a problem creating the client.
-fn create_client(config: &AwfulJadeConfig) -> Result<Client<OpenAIConfig>, Box<dyn Error>> {
+/// Returns an Error if there is a problem creating the client, or an extra header is invalid.
+pub fn create_client(config: &AwfulJadeConfig) -> Result<Client<OpenAIConfig>, Box<dyn Error>> {
     let openai_config = OpenAIConfig::new()
         .with_api_key(config.api_key.clone())
         .with_api_base(config.api_base.clone());
     debug!("Client created with config: {:?}", openai_config);
-    Ok(Client::with_config(openai_config))
+    let client = Client::with_config(openai_config);
+    if config.extra_headers.is_empty() {
+        return Ok(client);
+    }
+
+    let headers = extra_headers(config)?;
+    debug!(
+        "Sending extra headers: {:?}",
+        headers.keys().map(HeaderName::as_str).collect::<Vec<_>>()
+    );
+    let http_client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+    Ok(client.with_http_client(http_client))
 }
 
-/// Streams the response from the OpenAI API and prints it to the console in bold blue text.
+/// Parses the configured `extra_headers` into a header map.
 ///
-/// This function also ensures that the assistant has a minimum number of tokens to generate a response
-/// by ejecting older messages if necessary. The system message is never ejected.
+/// Header values are marked as sensitive, since they usually carry keys, so they are redacted when
+/// the map is logged.
 ///
-/// # Arguments
+/// # Errors
 ///
-/// * `client` - A reference to the OpenAI client.
-/// * `model` - A string containing the model name.
-/// * `messages` - A mutable vector of messages for the chat completion request.
-///                This vector may be modified to ensure the assistant has enough tokens to generate a response.
-/// * `config` - A reference to the configuration containing various settings including token limits.
+/// Returns an Error naming the header when a name or value is not a valid HTTP header.
+pub fn extra_headers(config: &AwfulJadeConfig) -> Result<HeaderMap, Box<dyn Error>> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.extra_headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name in extra_headers: `{}`", name))?;
+        let mut header_value = HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid value for header `{}` in extra_headers", name))?;
+        header_value.set_sensitive(true);
+        headers.insert(header_name, header_value);
+    }
+    Ok(headers)
+}
+
+/// The number of messages at the start of a conversation that are never ejected: the system
+/// prompt, the brain, and the assistant's acknowledgment of the brain.
+const PREAMBLE_LEN: usize = 3;
+
+/// Removes the oldest exchange after the first `preamble_len` messages and returns it.
 ///
-/// # Returns
+/// A user message and the assistant reply that follows it are removed together. A message without
+/// its partner, e.g. a question whose request failed, is removed on its own. The last message is
+/// the question being asked and is never removed, so an empty result means nothing is left to
+/// eject.
+pub fn eject_oldest_exchange(
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    preamble_len: usize,
+) -> Vec<ChatCompletionRequestMessage> {
+    let ejectable = messages.len().saturating_sub(1);
+    if preamble_len >= ejectable {
+        return Vec::new();
+    }
+    let is_pair = preamble_len + 1 < ejectable
+        && messages[preamble_len].role == Role::User
+        && messages[preamble_len + 1].role == Role::Assistant;
+    let count = if is_pair { 2 } else { 1 };
+    messages.drain(preamble_len..preamble_len + count).collect()
+}
+
+/// Ejects the oldest exchanges from `messages` until the assistant has at least
+/// `assistant_minimum_context_tokens` left to respond in, and archives them in the vector store,
+/// when one is provided, so they can later be recalled as memories. The preamble and the last
+/// message, the question being asked, are never ejected.
 ///
-/// A Result containing a new chat completion request message to add to the conversation if successful,
-/// otherwise returns an Error.
+/// # Arguments
 ///
-/// # Errors
+/// * `messages` - The messages for the chat completion request.
+/// * `model` - The model whose tokenizer counts the messages.
+/// * `config` - A reference to the configuration containing various settings including token limits.
+/// * `vector_store` - The vector store ejected messages are archived in, if any.
+#[cfg(all(feature = "rag", any(test, feature = "test_support")))]
+pub(crate) async fn eject_to_fit(
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    model: &str,
+    config: &AwfulJadeConfig,
+    vector_store: Option<&mut VectorStore>,
+) -> Result<(), Box<dyn Error>> {
+    let ejected = eject_memories_to_fit(messages, model, config)?;
+    archive(config, vector_store, ejected).await
+}
+
+/// Ejects the oldest exchanges from `messages` like `eject_to_fit`, but returns them as memories
+/// instead of archiving them, so they can be embedded while the request is underway.
 ///
-/// Returns an Error if there is a problem streaming the response or handling the output.
-async fn stream_response<'a>(
-    client: &Client<OpenAIConfig>,
-    model: String,
-    mut messages: Vec<ChatCompletionRequestMessage>,
+/// With `strict_context`, `messages` are left as they are, and a `ContextDropped` error describing
+/// the exchanges that would have been ejected is returned instead.
+fn eject_memories_to_fit(
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    model: &str,
     config: &AwfulJadeConfig,
-    mut vector_store: Option<&mut VectorStore>,
-    _brain: Option<&mut Brain<'a>>,
-) -> Result<ChatCompletionRequestMessage, Box<dyn Error>> {
-    let mut max_tokens = get_chat_completion_max_tokens("gpt-4", &messages)? as u16;
-    debug!("Max tokens: {}", max_tokens);
+) -> Result<Vec<Memory>, ContextDropped> {
+    let untouched = config.strict_context.then(|| messages.clone());
+    let ejected = eject_exchanges_to_fit(messages, model, config);
+    if let Some(untouched) = untouched {
+        if !ejected.is_empty() {
+            *messages = untouched;
+            let answer_tokens = config
+                .assistant_minimum_context_tokens
+                .min(config.context_max_tokens);
+            return Err(ContextDropped {
+                dropped: format!(
+                    "{} of the oldest messages of the conversation ({} tokens)",
+                    ejected.len(),
+                    tokens::count_messages(&ejected, model)
+                ),
+                limit: format!(
+                    "the {} tokens the context window leaves after the {} kept for the answer",
+                    config.context_max_tokens - answer_tokens,
+                    answer_tokens
+                ),
+            });
+        }
+    }
+
+    // Archive each message under the role that actually sent it, so that it is recalled as what it
+    // was.
+    Ok(ejected
+        .into_iter()
+        .filter_map(|ejected_message| {
+            let content = ejected_message.content?;
+            Some(Memory::new(ejected_message.role, content))
+        })
+        .collect())
+}
+
+/// Ejects the oldest exchanges from `messages` until the assistant has at least
+/// `assistant_minimum_context_tokens` left to respond in, and returns them.
+fn eject_exchanges_to_fit(
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    model: &str,
+    config: &AwfulJadeConfig,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut ejected_messages = Vec::new();
+    let mut remaining_tokens = tokens::remaining(messages, model, config.context_max_tokens) as u16;
+    debug!("Remaining tokens: {}", remaining_tokens);
     let assistant_minimum_context_tokens = std::cmp::min(
         config.assistant_minimum_context_tokens,
         config.context_max_tokens,
@@ -103,128 +252,1070 @@ async fn stream_response<'a>(
         assistant_minimum_context_tokens
     );
 
-    while max_tokens < assistant_minimum_context_tokens {
-        // First message should be the system prompt, second should be the brain, third should be a fake assistant acknowledgement.
-        if messages.len() > 3 {
-            let ejected_user_message = messages.remove(3);
-            let ejected_assistant_message = messages.remove(3);
-
-            if let Some(the_vector_store) = vector_store.as_deref_mut() {
-                if let Some(content) = ejected_user_message.content {
-                    let vector = the_vector_store.embed_text_to_vector(&content)?;
-                    let memory = Memory::new(Role::User, content.clone());
-                    the_vector_store.add_vector_with_content(vector, memory)?;
-                }
-                if let Some(content) = ejected_assistant_message.content {
-                    let vector = the_vector_store.embed_text_to_vector(&content)?;
-                    let memory = Memory::new(Role::Assistant, content.clone());
-                    the_vector_store.add_vector_with_content(vector, memory)?;
-                }
+    while remaining_tokens < assistant_minimum_context_tokens {
+        let ejected = eject_oldest_exchange(messages, PREAMBLE_LEN);
+        if ejected.is_empty() {
+            break;
+        }
+        ejected_messages.extend(ejected);
 
-                the_vector_store.build()?;
-            }
+        remaining_tokens = tokens::remaining(messages, model, config.context_max_tokens) as u16;
+    }
+
+    ejected_messages
+}
+
+/// Returns the limit for the brain of a question asked in `conversation`, the messages sent after
+/// the preamble, ending with the question. The memories get the room the conversation and the
+/// answer leave; see `ContextBudget`.
+#[cfg(feature = "rag")]
+fn brain_tokens(
+    config: &AwfulJadeConfig,
+    model: &str,
+    system_prompt: &str,
+    conversation: &[ChatCompletionRequestMessage],
+) -> u16 {
+    let system_tokens = tokens::count(system_prompt, model);
+    let budget = ContextBudget::plan(
+        config,
+        system_tokens + tokens::count_messages(conversation, model),
+        Parts {
+            memories: true,
+            context: false,
+        },
+    );
+    // The brain counts the system prompt and the question along with its memories
+    let question = &conversation[conversation.len().saturating_sub(1)..];
+    budget.brain_tokens(system_tokens + tokens::count_messages(question, model))
+}
+
+/// Scores how similar `memories` are to the system prompt of `template`, for a brain evicting by
+/// `EvictionPolicy::Importance`; with any other policy, this does nothing. Memories that could not
+/// be scored are evicted first.
+#[cfg(feature = "rag")]
+async fn score_importance(
+    brain: &mut Brain<'_>,
+    vector_store: &VectorStore,
+    template: &ChatTemplate,
+    config: &AwfulJadeConfig,
+    memories: &[(Memory, Option<(usize, f32)>)],
+) {
+    if template.eviction_policy(config) != crate::brain::EvictionPolicy::Importance {
+        return;
+    }
+    let memories: Vec<Memory> = memories.iter().map(|(memory, _)| memory.clone()).collect();
+    match retrieval::importance(vector_store, &template.system_prompt, &memories).await {
+        Ok(importance) => brain.add_importance(importance),
+        Err(e) => error!("Failed to score memories: {}", e),
+    }
+}
 
-            max_tokens = get_chat_completion_max_tokens("gpt-4", &messages)? as u16;
+/// Embeds `memories` and adds them to the vector store, when one is provided, then rebuilds its
+/// index so they can be recalled. With `store_verbatim: false`, user messages are restated first;
+/// see the `paraphrase` module. With `memory_kinds.classify`, they are then classified; see the
+/// `memory_kinds` module.
+#[cfg_attr(not(feature = "rag"), allow(unused_variables))]
+async fn archive(
+    config: &AwfulJadeConfig,
+    vector_store: Option<&mut VectorStore>,
+    memories: Vec<Memory>,
+) -> Result<(), Box<dyn Error>> {
+    #[cfg(feature = "rag")]
+    if let Some(the_vector_store) = vector_store {
+        if memories.is_empty() {
+            return Ok(());
+        }
+        let memories = if config.store_verbatim() {
+            memories
         } else {
-            break;
+            paraphrase::paraphrase_memories(config, memories).await
+        };
+        let classify = config
+            .memory_kinds
+            .as_ref()
+            .is_some_and(|kinds| kinds.classify);
+        let memories = if classify {
+            memory_kinds::classify_memories(config, memories).await
+        } else {
+            memories
+        };
+        for memory in memories {
+            the_vector_store.add_memory(memory).await?;
         }
+        the_vector_store.build()?;
     }
+    Ok(())
+}
 
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(max_tokens)
+/// Builds a chat completion request, ejecting older messages if necessary so the assistant has
+/// a minimum number of tokens to generate a response. The system message is never ejected; see
+/// `eject_to_fit`.
+///
+/// # Arguments
+///
+/// * `model` - A string containing the model name.
+/// * `messages` - The messages for the chat completion request.
+/// * `stop_words` - The sequences that end the response.
+/// * `config` - A reference to the configuration containing various settings including token limits.
+///
+/// # Returns
+///
+/// A Result containing the request and the ejected messages, which the caller archives with
+/// `archive`, if successful, otherwise returns an Error.
+pub(crate) fn prepare_request(
+    model: String,
+    mut messages: Vec<ChatCompletionRequestMessage>,
+    stop_words: Vec<String>,
+    config: &AwfulJadeConfig,
+) -> Result<(CreateChatCompletionRequest, Vec<Memory>), Box<dyn Error>> {
+    let ejected = eject_memories_to_fit(&mut messages, &model, config)?;
+    let request = build_request(model, messages, stop_words, config)?;
+    Ok((request, ejected))
+}
+
+/// Builds a chat completion request from messages that already leave room for the response, as
+/// `prepare_request` does once it has ejected what did not fit.
+fn build_request(
+    model: String,
+    messages: Vec<ChatCompletionRequestMessage>,
+    stop_words: Vec<String>,
+    config: &AwfulJadeConfig,
+) -> Result<CreateChatCompletionRequest, Box<dyn Error>> {
+    let mut request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(tokens::response_limit(&messages, &model, config))
         .model(model)
-        .stop(config.stop_words.clone())
-        .messages(messages)
+        .stop(stop_words)
+        .messages(ModelDefaults::from_config(config).adapt_messages(messages))
         .build()?;
+    request.temperature = config.temperature;
 
     debug!("Sending request: {:?}", request);
 
+    Ok(request)
+}
+
+/// Streams the response from the OpenAI API and prints it to the console in bold, in the theme's
+/// assistant color.
+///
+/// The request comes from `prepare_request`, whose caller archives the ejected messages once,
+/// whichever way the request ends up being sent. Tokens are soft-wrapped to the terminal's width
+/// as they arrive, see `pretty::WrapWriter`.
+///
+/// When `max_response_seconds` passes, the stream is closed and the answer so far is returned.
+/// Responses cut short by a time or token limit are marked as truncated on stderr. Streamed
+/// responses carry a finish reason but, from most backends, no token usage.
+///
+/// # Arguments
+///
+/// * `client` - A reference to the OpenAI client.
+/// * `request` - The request built by `prepare_request`.
+/// * `config` - A reference to the configuration containing various settings including token limits.
+/// * `output` - Where the tokens go besides stdout; see `StreamOutput`.
+///
+/// # Returns
+///
+/// A Result containing the completion, whose message is added to the conversation, and the first
+/// error the stream reported, if any, so callers can tell why a streamed answer came back empty.
+///
+/// # Errors
+///
+/// Returns an Error if there is a problem streaming the response or handling the output.
+async fn stream_response<'a>(
+    client: &Client<OpenAIConfig>,
+    request: CreateChatCompletionRequest,
+    config: &AwfulJadeConfig,
+    _brain: Option<&mut Brain<'a>>,
+    output: StreamOutput<'_>,
+) -> Result<(Completion, Option<OpenAIError>), Box<dyn Error>> {
+    let model = request.model.clone();
     let mut response_string = String::new();
+    let mut stream_error = None;
+
+    let mut finish_reason = None;
+    let mut timed_out = false;
+
+    let started = Instant::now();
+    let mut time_to_first_token = None;
+    let deadline = config
+        .max_response_seconds
+        .map(|seconds| tokio::time::Instant::now() + Duration::from_secs(seconds));
+    let mut stream = client.chat().create_stream(request).await?;
+    let mut tee = output.tee.map(File::create).transpose()?;
+    let mut writer = match output.on_delta {
+        Some(_) => None,
+        None => {
+            let writer = pretty::WrapWriter::new(stdout().lock(), pretty::terminal_width());
+            Some(match tee.take() {
+                Some(tee) => writer.with_tee(tee),
+                None => writer,
+            })
+        }
+    };
+    let mut stdout = std::io::stdout();
+    if writer.is_some() {
+        set_assistant_style(config)?;
+    }
+
+    loop {
+        let next = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    // Dropping the stream closes the connection, which stops the generation.
+                    timed_out = true;
+                    break;
+                }
+            },
+            None => stream.next().await,
+        };
+        let Some(result) = next else {
+            break;
+        };
+        match result {
+            Ok(response) => {
+                debug!("Received response: {:?}", response);
+                for chat_choice in &response.choices {
+                    if let Some(ref content) = chat_choice.delta.content {
+                        if time_to_first_token.is_none() && !content.is_empty() {
+                            time_to_first_token = Some(started.elapsed());
+                        }
+                        response_string.push_str(content);
+                        match (&mut writer, output.on_delta) {
+                            (Some(writer), _) => writer.write_str(content)?,
+                            (None, Some(on_delta)) => {
+                                if let Some(tee) = &mut tee {
+                                    tee.write_all(content.as_bytes())?;
+                                    tee.flush()?;
+                                }
+                                on_delta(content)
+                            }
+                            (None, None) => {}
+                        }
+                    }
+                    if chat_choice.finish_reason.is_some() {
+                        finish_reason = chat_choice.finish_reason.clone();
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Received error: {}", err);
+                if let Some(writer) = &mut writer {
+                    writer.write_str(&format!("error: {}\n", err))?;
+                }
+                stream_error.get_or_insert(err);
+            }
+        }
+        stdout.flush()?;
+    }
+    if let Some(mut writer) = writer {
+        writer.finish()?;
+        reset_style()?;
+    }
+
+    let timings = Timings {
+        time_to_first_token,
+        total: started.elapsed(),
+        tokens: tokens::count(&response_string, &model),
+    };
+    let completion = Completion {
+        message: ChatCompletionRequestMessage {
+            role: Role::Assistant,
+            content: Some(response_string),
+            name: None,
+            function_call: None,
+        },
+        finish_reason,
+        usage: None,
+        timed_out,
+        timings,
+        sources: Vec::new(),
+        request_id: None,
+    };
+    completion.warn_if_truncated(config);
+    completion.report_timings(config);
+    Ok((completion, stream_error))
+}
+
+/// Whether `error`, reported by a stream, shows that the backend doesn't stream at all, rather than
+/// that this one request failed.
+///
+/// A backend without server-sent events answers a streaming request like any other, with a JSON
+/// body, which the event source refuses for its content type.
+#[cfg(all(feature = "rag", feature = "tty"))]
+fn streaming_unsupported(error: &OpenAIError) -> bool {
+    matches!(error, OpenAIError::StreamError(message) if message.starts_with("Invalid header value"))
+}
+
+/// Fetches the complete response from the OpenAI API in a single request and prints it to the
+/// console in bold, in the theme's assistant color.
+///
+/// This is the counterpart of `stream_response` for backends that do not support server-sent
+/// events, and sends the same prepared request. Since the whole response is known up front, tables
+/// and math are laid out by the `pretty` module.
+///
+/// # Arguments
+///
+/// * `client` - A reference to the OpenAI client.
+/// * `request` - The request built by `prepare_request`.
+/// * `config` - A reference to the configuration containing various settings including token limits.
+/// * `quiet` - Whether to return the response without printing it.
+///
+/// # Returns
+///
+/// A Result containing the completion, whose message is added to the conversation, if successful,
+/// otherwise returns an Error.
+async fn fetch_response(
+    client: &Client<OpenAIConfig>,
+    request: CreateChatCompletionRequest,
+    config: &AwfulJadeConfig,
+    quiet: bool,
+) -> Result<Completion, Box<dyn Error>> {
+    let model = request.model.clone();
+    let started = Instant::now();
+    let response = match config.max_response_seconds {
+        Some(seconds) => {
+            tokio::time::timeout(Duration::from_secs(seconds), client.chat().create(request))
+                .await
+                .map_err(|_| format!("No response within {}s", seconds))??
+        }
+        None => client.chat().create(request).await?,
+    };
+    debug!("Received response: {:?}", response);
+
+    let (response_string, finish_reason) = response
+        .choices
+        .into_iter()
+        .next()
+        .map(|chat_choice| {
+            (
+                chat_choice.message.content.unwrap_or_default(),
+                chat_choice.finish_reason,
+            )
+        })
+        .unwrap_or_default();
+    let timings = Timings {
+        time_to_first_token: None,
+        total: started.elapsed(),
+        tokens: response.usage.as_ref().map_or_else(
+            || tokens::count(&response_string, &model),
+            |usage| usage.completion_tokens as usize,
+        ),
+    };
+
+    if !quiet {
+        print_answer(config, &response_string)?;
+    }
+
+    let completion = Completion {
+        message: ChatCompletionRequestMessage {
+            role: Role::Assistant,
+            content: Some(response_string),
+            name: None,
+            function_call: None,
+        },
+        finish_reason,
+        usage: response.usage,
+        timed_out: false,
+        timings,
+        sources: Vec::new(),
+        request_id: None,
+    };
+    completion.warn_if_truncated(config);
+    if !quiet {
+        completion.report_timings(config);
+    }
+    Ok(completion)
+}
+
+/// A response from the model, with why it ended and what it cost.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Completion {
+    /// The assistant's message.
+    pub message: ChatCompletionRequestMessage,
+
+    /// Why the model stopped, as reported by the backend: `stop`, `length`, ... `None` when the
+    /// backend did not say, or the response was stopped by `max_response_seconds`.
+    pub finish_reason: Option<String>,
+
+    /// The tokens the request used, when the backend reports them. Streamed responses usually
+    /// carry no usage.
+    pub usage: Option<Usage>,
+
+    /// Whether the response was stopped by `max_response_seconds`.
+    pub timed_out: bool,
+
+    /// How long the response took to arrive; see the `timings` module.
+    pub timings: Timings,
+
+    /// The memories recalled into the preamble for the question, when memory was used.
+    pub sources: Vec<Source>,
+
+    /// The id of the question the completion answers, when it was asked with
+    /// `ask_for_completion`; see the `request_id` module.
+    pub request_id: Option<String>,
+}
+
+impl Completion {
+    /// Returns the message's content, or an empty string.
+    #[must_use]
+    pub fn content(&self) -> &str {
+        self.message.content.as_deref().unwrap_or_default()
+    }
+
+    /// Returns whether the response was cut short by a time or token limit.
+    #[must_use]
+    pub fn is_truncated(&self) -> bool {
+        self.truncation().is_some()
+    }
+
+    fn truncation(&self) -> Option<Truncation> {
+        if self.timed_out {
+            Some(Truncation::TimeLimit)
+        } else if self.finish_reason.as_deref() == Some("length") {
+            Some(Truncation::TokenLimit)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the response as truncated on stderr, naming the limit that was reached.
+    fn warn_if_truncated(&self, config: &AwfulJadeConfig) {
+        if let Some(truncation) = self.truncation() {
+            eprintln!("\n[truncated] {}", truncation.describe(config));
+        }
+    }
+
+    /// Prints how long the response took on stderr, when `show_timings` is set.
+    fn report_timings(&self, config: &AwfulJadeConfig) {
+        if config.show_timings {
+            eprintln!("\n[timings] {}", self.timings);
+        }
+    }
+}
+
+/// Why a response was cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Truncation {
+    /// `max_response_seconds` passed before the response was complete.
+    TimeLimit,
+
+    /// The response reached the token limit of the request.
+    TokenLimit,
+}
+
+impl Truncation {
+    /// Explains the truncation, naming the limit that was reached.
+    fn describe(self, config: &AwfulJadeConfig) -> String {
+        match self {
+            Self::TimeLimit => format!(
+                "Stopped after {}s; the answer so far was kept.",
+                config.max_response_seconds.unwrap_or_default()
+            ),
+            Self::TokenLimit => match config.max_response_tokens {
+                Some(tokens) => format!("Stopped at the {} token response limit.", tokens),
+                None => "Stopped at the end of the context window.".to_string(),
+            },
+        }
+    }
+}
+
+/// Prints a complete answer to stdout, rendered for the terminal in the assistant's style.
+pub fn print_answer(config: &AwfulJadeConfig, answer: &str) -> Result<(), Box<dyn Error>> {
+    let mut stdout = std::io::stdout();
+    set_assistant_style(config)?;
+    write!(
+        stdout,
+        "{}",
+        pretty::render(answer, pretty::terminal_width())
+    )?;
+    reset_style()?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Styles the following output as the assistant's: bold, in the theme's assistant color.
+#[cfg(feature = "tty")]
+fn set_assistant_style(config: &AwfulJadeConfig) -> Result<(), Box<dyn Error>> {
+    let theme = Theme::from_config(config)?;
+    let mut stdout = std::io::stdout();
+    stdout.execute(SetForegroundColor(theme.assistant))?;
+    stdout.execute(SetAttribute(Attribute::Bold))?;
+    Ok(())
+}
+
+/// Output is unstyled without the `tty` feature.
+#[cfg(not(feature = "tty"))]
+fn set_assistant_style(_config: &AwfulJadeConfig) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Resets the style set by `set_assistant_style`.
+#[cfg(feature = "tty")]
+fn reset_style() -> Result<(), Box<dyn Error>> {
+    let mut stdout = std::io::stdout();
+    stdout.execute(SetAttribute(Attribute::Reset))?;
+    stdout.execute(SetForegroundColor(Color::Reset))?;
+    Ok(())
+}
+
+/// Output is unstyled without the `tty` feature.
+#[cfg(not(feature = "tty"))]
+fn reset_style() -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// The error returned when a question and its preamble do not fit in the context window.
+///
+/// It is detected before the API is contacted, so callers can downcast the error and react to it,
+/// e.g. by retrying with a smaller repository map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextOverflow {
+    /// The tokens taken by the system prompt, memories, context, and template messages.
+    pub preamble_tokens: usize,
+
+    /// The tokens taken by the question.
+    pub question_tokens: usize,
+
+    /// The configured context window.
+    pub context_max_tokens: u16,
+}
+
+impl ContextOverflow {
+    /// The total number of tokens in the prompt.
+    #[must_use]
+    pub fn prompt_tokens(&self) -> usize {
+        self.preamble_tokens + self.question_tokens
+    }
+
+    /// Checks that `messages`, whose last message is the question, leave room for a response.
+    pub(crate) fn check(
+        messages: &[ChatCompletionRequestMessage],
+        model: &str,
+        context_max_tokens: u16,
+    ) -> Result<(), Self> {
+        let prompt_tokens = tokens::count_messages(messages, model);
+        if prompt_tokens < context_max_tokens as usize {
+            return Ok(());
+        }
+        let preamble_tokens = tokens::count_messages(&messages[..messages.len() - 1], model);
+        Err(Self {
+            preamble_tokens,
+            question_tokens: prompt_tokens - preamble_tokens,
+            context_max_tokens,
+        })
+    }
+}
+
+impl fmt::Display for ContextOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "The prompt needs {} tokens ({} for the preamble, {} for the question), but the context window is {} tokens.",
+            self.prompt_tokens(),
+            self.preamble_tokens,
+            self.question_tokens,
+            self.context_max_tokens
+        )?;
+        writeln!(f, "Try one of:")?;
+        writeln!(
+            f,
+            "  - raise `context_max_tokens` in config.yaml, if the model supports a larger context"
+        )?;
+        writeln!(
+            f,
+            "  - leave out `--repo`, or narrow the repository map with `--include`/`--exclude`"
+        )?;
+        writeln!(
+            f,
+            "  - shorten the template's system prompt and example messages"
+        )?;
+        write!(f, "  - shorten the question")
+    }
+}
+
+impl Error for ContextOverflow {}
+
+/// Per-call options for `ask_with_options`.
+///
+/// The default options describe a one-shot question: the configured model and streaming mode are
+/// used, and no memories are recalled or archived.
+#[derive(Default)]
+pub struct AskOptions<'a> {
+    /// Overrides `config.model` for this call.
+    pub model: Option<String>,
+
+    /// Overrides `config.should_stream` for this call.
+    pub stream: Option<bool>,
+
+    /// A vector store to use as memory. When set, memories relevant to the question are recalled
+    /// into the preamble, and messages ejected to make room for the response are archived in it.
+    pub memory: Option<&'a mut VectorStore>,
+
+    /// Checks the answer against the memories and context it was given, and annotates or
+    /// regenerates it when it makes unsupported claims.
+    pub verify_grounding: Option<GroundingMode>,
+
+    /// Stop sequences for this call, added to the template's and the configuration's.
+    pub stop: Vec<String>,
+
+    /// Prints nothing to stdout, for scripts and scheduled jobs; the answer is only returned.
+    /// Responses are fetched rather than streamed, since nothing is shown as it arrives.
+    /// Truncation and other warnings are still reported on stderr.
+    pub quiet: bool,
+
+    /// Sends the question even if it fails the `guardrails` checks.
+    pub force: bool,
+
+    /// Receives each piece of the answer as it is streamed, in place of stdout, e.g. to relay it
+    /// to a web page; see the `sse` module. The answer is streamed whatever `stream` says.
+    pub on_delta: Option<&'a (dyn Fn(&str) + Send + Sync)>,
+
+    /// A file the answer is also written to as it is streamed, so a long answer is kept if the
+    /// terminal goes away. The answer is streamed whatever `stream` says, unless `quiet` is set.
+    pub tee: Option<PathBuf>,
+
+    /// Splits a question that asks several things into its distinct questions, answers each with
+    /// its own memories, and merges the answers with a section for each; see the `decompose`
+    /// module.
+    pub decompose: bool,
+}
+
+/// Where a streamed answer goes besides the conversation.
+#[derive(Clone, Copy, Default)]
+struct StreamOutput<'a> {
+    /// Receives the tokens instead of stdout, when set.
+    on_delta: Option<&'a (dyn Fn(&str) + Send + Sync)>,
+
+    /// A file the tokens are also written to as they arrive, whether they go to stdout or to
+    /// `on_delta`.
+    tee: Option<&'a Path>,
+}
+
+/// Asks a question using the OpenAI API and prints the response.
+///
+/// This function handles the entire process of asking a question via the OpenAI API, including creating the client,
+/// preparing messages, streaming the response, and handling errors.
+///
+/// # Parameters
+///
+/// - `config`: The configuration containing the API key, base URL, and model name.
+/// - `question`: The question to be asked, as a `&str` or `String`.
+/// - `template`: The chat template containing the system prompt and initial messages.
+///
+/// # Returns
+///
+/// A result indicating the success or failure of the operation.
+pub async fn ask(
+    config: &AwfulJadeConfig,
+    question: impl Into<String>,
+    template: &ChatTemplate,
+) -> Result<(), Box<dyn Error>> {
+    ask_with_options(config, question, template, AskOptions::default()).await?;
+
+    Ok(())
+}
+
+/// Asks a question using the OpenAI API with per-call options, prints the response, and returns it.
+///
+/// Library callers can use `options` to change the model, streaming mode, or memory for a single
+/// call without mutating the shared configuration.
+///
+/// # Parameters
+///
+/// - `config`: The configuration containing the API key, base URL, and model name.
+/// - `question`: The question to be asked, as a `&str` or `String`.
+/// - `template`: The chat template containing the system prompt and initial messages.
+/// - `options`: Per-call overrides, including the memory to use; `AskOptions::default()` asks a
+///   one-shot question.
+///
+/// # Returns
+///
+/// A result containing the assistant's answer. A `ContextOverflow` error is returned, before the
+/// API is contacted, when the question and its preamble do not fit in the context window.
+pub async fn ask_with_options(
+    config: &AwfulJadeConfig,
+    question: impl Into<String>,
+    template: &ChatTemplate,
+    options: AskOptions<'_>,
+) -> Result<String, Box<dyn Error>> {
+    let completion = ask_for_completion(config, question, template, options).await?;
+    Ok(completion.message.content.unwrap_or_default())
+}
+
+/// Asks a question like `ask_with_options`, and returns the answer together with its finish
+/// reason and token usage, so callers can tell a truncated answer from a complete one.
+///
+/// The completion describes the final answer: after a retry or a regeneration, it is that
+/// response's. Its content has the template's output filters and any grounding annotation
+/// applied. The question gets a new request id, which everything logged while it is answered
+/// carries.
+pub async fn ask_for_completion(
+    config: &AwfulJadeConfig,
+    question: impl Into<String>,
+    template: &ChatTemplate,
+    options: AskOptions<'_>,
+) -> Result<Completion, Box<dyn Error>> {
+    privacy::check(config, template)?;
+    let request_id = request_id::generate();
+    let span = info_span!("ask", request_id = %request_id);
+    let model = options
+        .model
+        .clone()
+        .unwrap_or_else(|| config.model.clone());
+    let mut question = question.into();
+    if let Some(hooks) = &config.hooks {
+        question = plugins::pre_ask(hooks, &request_id, &model, question)
+            .instrument(span.clone())
+            .await?;
+    }
+    let mut completion = if options.decompose {
+        complete_decomposed(config, question.clone(), template, options)
+            .instrument(span.clone())
+            .await?
+    } else {
+        complete(config, question.clone(), template, options)
+            .instrument(span.clone())
+            .await?
+    };
+    if let Some(hooks) = &config.hooks {
+        let envelope = plugins::Envelope::PostAnswer {
+            request_id: &request_id,
+            model: &model,
+            question: &question,
+            answer: completion.content(),
+            finish_reason: completion.finish_reason.as_deref(),
+        };
+        plugins::post_answer(hooks, &envelope)
+            .instrument(span)
+            .await;
+    }
+    completion.request_id = Some(request_id);
+    Ok(completion)
+}
+
+/// Answers a question for `ask_for_completion` with `AskOptions::decompose`: the distinct
+/// questions it holds are answered one after another, each under its heading, and the answers are
+/// merged. A question that holds only one question, or that could not be split, is answered whole.
+async fn complete_decomposed(
+    config: &AwfulJadeConfig,
+    question: String,
+    template: &ChatTemplate,
+    options: AskOptions<'_>,
+) -> Result<Completion, Box<dyn Error>> {
+    let sub_questions = match decompose::decompose(config, &question).await {
+        Ok(sub_questions) => sub_questions,
+        Err(e) => {
+            error!("Failed to split the question: {}", e);
+            Vec::new()
+        }
+    };
+    if sub_questions.len() < 2 {
+        return complete(config, question, template, options).await;
+    }
+    debug!("Split the question into: {:?}", sub_questions);
+
+    let mut options = options;
+    let mut sections = Vec::with_capacity(sub_questions.len());
+    for sub_question in sub_questions {
+        let separator = if sections.is_empty() { "" } else { "\n\n" };
+        let heading = format!("{}{}", separator, decompose::section_heading(&sub_question));
+        match options.on_delta {
+            Some(on_delta) => on_delta(&heading),
+            None if !options.quiet => print!("\n{}", heading),
+            None => {}
+        }
+        // Each sub-question recalls its own memories; the tee gets the merged answer
+        let sub_options = AskOptions {
+            model: options.model.clone(),
+            stream: options.stream,
+            memory: options.memory.as_deref_mut(),
+            verify_grounding: options.verify_grounding,
+            stop: options.stop.clone(),
+            quiet: options.quiet,
+            force: options.force,
+            on_delta: options.on_delta,
+            tee: None,
+            decompose: false,
+        };
+        let completion = complete(config, sub_question.clone(), template, sub_options).await?;
+        sections.push((sub_question, completion));
+    }
+
+    let merged = decompose::merge(sections);
+    if let Some(path) = &options.tee {
+        fs::write(path, merged.content())?;
+    }
+    Ok(merged)
+}
+
+/// Answers a question for `ask_for_completion`.
+async fn complete(
+    config: &AwfulJadeConfig,
+    question: String,
+    template: &ChatTemplate,
+    options: AskOptions<'_>,
+) -> Result<Completion, Box<dyn Error>> {
+    let client = create_client(config)?;
+    let model = options.model.unwrap_or_else(|| config.model.clone());
+    if let Some(guardrails) = config.guardrails.as_ref().filter(|_| !options.force) {
+        guardrails::check(guardrails, &question, &model)?;
+    }
+    let stream_output = StreamOutput {
+        on_delta: options.on_delta,
+        tee: options.tee.as_deref(),
+    };
+    let stream = stream_output.on_delta.is_some()
+        || (!options.quiet
+            && (options.tee.is_some() || options.stream.unwrap_or_else(|| config.should_stream())));
+    let quiet = options.quiet;
+    let mut vector_store = options.memory;
+    if vector_store.is_some() && !template.uses_memory() {
+        eprintln!(
+            "The template sets `use_memory: false`, so no memories are recalled or archived."
+        );
+        vector_store = None;
+    }
+    let filters = template.output_filters()?;
+    let acknowledgment = template.acknowledgment().to_string();
+    let stop_words = template.stop_words(config, &options.stop);
+
+    let question_message = ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(question.to_string()),
+        name: None,
+        function_call: None,
+    };
+
+    let system_prompt = language::instruct_system_prompt(
+        &template.system_prompt,
+        template.respond_in(config),
+        &question,
+    );
+
+    #[cfg_attr(not(feature = "rag"), allow(unused_mut))]
+    let mut sources = Vec::new();
+
+    // Excerpts of the template's knowledge files are appended to the system prompt
+    #[cfg(feature = "rag")]
+    let system_prompt =
+        match Knowledge::for_template(config, template, vector_store.as_deref()).await? {
+            Some(knowledge) => {
+                let conversation_tokens = tokens::count(&system_prompt, &model)
+                    + tokens::count_messages(&template.messages, &model)
+                    + tokens::count(&question, &model);
+                let (system_prompt, excerpts) = knowledge
+                    .augment(
+                        config,
+                        &question,
+                        &system_prompt,
+                        conversation_tokens,
+                        vector_store.is_some(),
+                    )
+                    .await?;
+                sources.extend(excerpts);
+                system_prompt
+            }
+            None => system_prompt,
+        };
+
+    let template_messages =
+        fit_template_messages(config, template, &model, &system_prompt, &question_message);
+
+    let mut messages = match vector_store.as_deref_mut() {
+        #[cfg(feature = "rag")]
+        Some(the_vector_store) => {
+            let mut conversation = template_messages.clone();
+            conversation.push(question_message.clone());
+            let mut brain = Brain::new(
+                brain_tokens(config, &model, &system_prompt, &conversation),
+                template,
+            );
+            let query = retrieval::retrieval_query(config, &question, &[]).await;
+            let neighbors =
+                retrieval::retrieve_within_budget(config, the_vector_store, &query, &conversation)
+                    .await?;
+            let memories: Vec<(Memory, Option<_>)> =
+                memory_kinds::always_included(config, the_vector_store)
+                    .into_iter()
+                    .map(|memory| (memory, None))
+                    .chain(neighbors.into_iter().filter_map(|neighbor| {
+                        let memory = the_vector_store.get_content_by_id(neighbor.0)?;
+                        Some((memory.clone(), Some(neighbor)))
+                    }))
+                    .collect();
+            score_importance(&mut brain, the_vector_store, template, config, &memories).await;
+            brain.add_memories(memories, &question_message, config);
+            brain.check_strict(config)?;
+            sources.extend(brain.sources(&model));
+            let mut messages = brain.build_preamble()?;
+            messages.extend(template_messages);
+            messages
+        }
+        #[cfg(not(feature = "rag"))]
+        Some(never) => match *never {},
+        None => {
+            let mut messages = vec![system_message(&system_prompt)];
+            messages.extend(template_messages);
+            messages
+        }
+    };
+    add_question(&mut messages, system_prompt, question_message);
+    ContextOverflow::check(&messages, &model, config.context_max_tokens)?;
 
-    let mut stream = client.chat().create_stream(request).await?;
-    let mut lock = stdout().lock();
-    let mut stdout = std::io::stdout();
-    stdout.execute(SetForegroundColor(Color::Blue))?;
-    stdout.execute(SetAttribute(Attribute::Bold))?;
+    // Messages are ejected and archived once, while the first answer is generated; a retry or a
+    // regeneration only sends the completion request again
+    let ejected = eject_memories_to_fit(&mut messages, &model, config)?;
+    let request = build_request(model.clone(), messages.clone(), stop_words.clone(), config)?;
+    let (answer, archived) = tokio::join!(
+        respond(&client, request, config, stream, quiet, stream_output),
+        archive(config, vector_store, ejected)
+    );
+    archived?;
+    let mut answer = answer?;
 
-    while let Some(result) = stream.next().await {
-        match result {
-            Ok(response) => {
-                debug!("Received response: {:?}", response);
-                response.choices.iter().for_each(|chat_choice| {
-                    if let Some(ref content) = chat_choice.delta.content {
-                        response_string.push_str(content);
-                        write!(lock, "{}", content).unwrap();
-                    }
-                });
+    if let Some(postprocess_config) = &config.postprocess {
+        let flags =
+            Postprocessor::from_config(postprocess_config).check(&question, answer.content());
+        for flag in &flags {
+            eprintln!("\n[{}] {}", flag.kind, flag.message);
+        }
+        let retry = postprocess_config
+            .retry
+            .then(|| postprocess::retry_question(&question, &flags))
+            .flatten();
+        if let Some(adjusted_question) = retry {
+            eprintln!("Retrying with an adjusted prompt.");
+            let mut retry_messages = messages.clone();
+            if let Some(question_message) = retry_messages.last_mut() {
+                question_message.content = Some(adjusted_question);
             }
-            Err(err) => {
-                error!("Received error: {}", err);
-                writeln!(lock, "error: {}", err).unwrap();
+            let retry_request =
+                build_request(model.clone(), retry_messages, stop_words.clone(), config)?;
+            answer = respond(&client, retry_request, config, stream, quiet, stream_output).await?;
+        }
+    }
+
+    let mut annotation = String::new();
+    if let Some(mode) = options.verify_grounding {
+        let sources = grounding::sources(&messages, &acknowledgment);
+        if sources.is_empty() {
+            eprintln!("\nNo memories or context were provided, so the answer cannot be verified.");
+        } else {
+            let unsupported =
+                grounding::unsupported_claims(config, &model, &sources, answer.content()).await?;
+            if unsupported.is_empty() {
+                eprintln!("\nEvery claim is supported by the provided context.");
+            } else if mode == GroundingMode::Annotate {
+                annotation = grounding::annotation(&unsupported);
+                if !quiet {
+                    println!("{}", annotation);
+                }
+            } else {
+                eprintln!(
+                    "\nRegenerating: {} claim(s) were not supported by the provided context.",
+                    unsupported.len()
+                );
+                if let Some(question_message) = messages.last_mut() {
+                    question_message.content =
+                        Some(grounding::regeneration_question(&question, &unsupported));
+                }
+                let request = build_request(model.clone(), messages, stop_words, config)?;
+                answer = respond(&client, request, config, stream, quiet, stream_output).await?;
             }
         }
-        stdout.flush()?;
     }
 
-    stdout.execute(SetAttribute(Attribute::Reset))?;
-    stdout.execute(SetForegroundColor(Color::Reset))?;
+    answer.message.content =
+        Some(postprocess::apply_filters(&filters, answer.content()) + &annotation);
+    answer.sources = sources;
+    Ok(answer)
+}
 
-    Ok(ChatCompletionRequestMessage {
-        role: Role::Assistant,
-        content: Some(response_string.clone()),
-        name: None,
-        function_call: None,
-    })
+/// Sends a prepared request, streaming or fetching the response, and returns the completion.
+/// Fetched responses are not printed when `quiet` is set, and streamed ones go to `output`.
+async fn respond(
+    client: &Client<OpenAIConfig>,
+    request: CreateChatCompletionRequest,
+    config: &AwfulJadeConfig,
+    stream: bool,
+    quiet: bool,
+    output: StreamOutput<'_>,
+) -> Result<Completion, Box<dyn Error>> {
+    if stream {
+        let (completion, _) = stream_response(client, request, config, None, output).await?;
+        Ok(completion)
+    } else {
+        fetch_response(client, request, config, quiet).await
+    }
 }
 
-/// Asks a question using the OpenAI API and prints the response.
+/// Builds the initial message list for a request from a chat template.
 ///
-/// This function handles the entire process of asking a question via the OpenAI API, including creating the client,
-/// preparing messages, streaming the response, and handling errors.
+/// The template's system prompt becomes the first message, followed by the template's own messages.
+/// It stays a system message here, so that ejection can rely on the preamble's layout; backends
+/// without system message support get it merged into the first user message when the request is
+/// sent, see `ModelDefaults::adapt_messages` and the `system_role_strategy` setting.
 ///
 /// # Parameters
 ///
-/// - `config`: The configuration containing the API key, base URL, and model name.
-/// - `question`: The question to be asked.
 /// - `template`: The chat template containing the system prompt and initial messages.
 ///
 /// # Returns
 ///
-/// A result indicating the success or failure of the operation.
-pub async fn ask(
-    config: &AwfulJadeConfig,
-    question: String,
-    template: ChatTemplate,
-) -> Result<(), Box<dyn Error>> {
-    let client = create_client(config)?;
-    let mut messages = prepare_messages(template)?;
-    messages.push(ChatCompletionRequestMessage {
-        role: Role::User,
-        content: Some(question.to_string()),
-        name: None,
-        function_call: None,
-    });
-    let _response =
-        stream_response(&client, config.model.clone(), messages, &config, None, None).await?;
+/// A result containing the prepared messages.
+pub fn prepare_messages(
+    template: &ChatTemplate,
+) -> Result<Vec<ChatCompletionRequestMessage>, Box<dyn Error>> {
+    let mut messages = vec![system_message(&template.system_prompt)];
 
-    Ok(())
+    messages.extend(template.messages.iter().cloned());
+
+    Ok(messages)
 }
 
-fn prepare_messages(
-    template: ChatTemplate,
-) -> Result<Vec<ChatCompletionRequestMessage>, Box<dyn Error>> {
-    let mut messages = vec![ChatCompletionRequestMessage {
+/// Returns a system message with `content`.
+pub fn system_message(content: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage {
         role: Role::System,
-        content: Some(template.system_prompt.clone()),
+        content: Some(content.to_string()),
         name: None,
         function_call: None,
-    }];
-
-    for message in template.messages {
-        messages.push(message);
     }
+}
 
-    Ok(messages)
+/// Returns the template's messages for a question, leaving out its optional messages, such as
+/// few-shot examples, as needed for `system_prompt`, the messages, and the question to leave the
+/// answer its `assistant_minimum_context_tokens`; see `ChatTemplate::messages_within`. Memories
+/// and supplementary context get the room that is left.
+pub fn fit_template_messages(
+    config: &AwfulJadeConfig,
+    template: &ChatTemplate,
+    model: &str,
+    system_prompt: &str,
+    question_message: &ChatCompletionRequestMessage,
+) -> Vec<ChatCompletionRequestMessage> {
+    let answer_tokens = config
+        .assistant_minimum_context_tokens
+        .min(config.context_max_tokens) as usize;
+    let surrounding = [system_message(system_prompt), question_message.clone()];
+    let max_tokens = (config.context_max_tokens as usize)
+        .saturating_sub(answer_tokens)
+        .saturating_sub(tokens::count_messages(&surrounding, model));
+    template.messages_within(max_tokens, model)
+}
+
+/// Finishes the messages of a question: `system_prompt`, the template's system prompt adjusted for
+/// this question (see `language::instruct_system_prompt`), replaces the content of the first
+/// message, and the question is added at the end.
+pub(crate) fn add_question(
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    system_prompt: String,
+    question_message: ChatCompletionRequestMessage,
+) {
+    if let Some(system_message) = messages.first_mut() {
+        system_message.content = Some(system_prompt);
+    }
+    messages.push(question_message);
 }
 
 /// Handles the interactive mode where the user can continuously ask questions and receive responses.
@@ -237,23 +1328,61 @@ fn prepare_messages(
 /// - `config`: The configuration containing the API key, base URL, and model name.
 /// - `conversation_name`: The name of the conversation.
 /// - `vector_store`: The vector store for managing and storing vectors.
+/// - `template_name`: The name of `template`, recorded with the ratings given with `/rate`.
 ///
 /// # Returns
 ///
 /// A result indicating the success or failure of the operation.
+#[cfg(all(feature = "rag", feature = "tty"))]
 pub async fn interactive_mode<'a>(
     config: &AwfulJadeConfig,
     conversation_name: String,
     mut vector_store: VectorStore,
     mut brain: Brain<'a>,
-    _template: &ChatTemplate,
+    template_name: &str,
+    template: &ChatTemplate,
 ) -> Result<(), Box<dyn Error>> {
+    let theme = Theme::from_config(config)?;
+    privacy::check(config, template)?;
+
+    // The template of the current persona and its name, replaced by `/persona`
+    let mut template = template.clone();
+    let mut template_name = template_name.to_string();
+
+    // The embedded knowledge files of the current persona's template, if it lists any
+    let mut knowledge = Knowledge::for_template(config, &template, Some(&vector_store)).await?;
+
     // Display existing conversation history, or start a new conversation
-    println!("Conversation: {}", conversation_name);
+    println!(
+        "{}",
+        theme.system(format!("Conversation: {}", conversation_name))
+    );
 
     // Prepare messages for API request
     let mut messages = brain.build_preamble().expect("Failed to build preamble");
 
+    // Filters the template applies to each response before it is kept in the conversation
+    let mut filters = template.output_filters()?;
+
+    // Whether responses are streamed; switched off for the rest of the session once the backend
+    // shows it doesn't stream
+    let mut streaming = config.should_stream();
+
+    // The most recent answer, shown again by `/last`
+    let mut last_answer = String::new();
+
+    // Every answer in this conversation, oldest first, compared by `/diff`
+    let mut answers: Vec<String> = Vec::new();
+
+    // The stop words for every request in this conversation
+    let mut stop_words = template.stop_words(config, &[]);
+
+    // The size of the memory store after it was last consolidated
+    let mut consolidated_len = vector_store.len();
+
+    // The follow-up questions suggested after the last answer, asked by typing their number
+    let mut suggestions: Vec<String> = Vec::new();
+
     loop {
         // Save the current cursor position
         let mut stdout = stdout();
@@ -270,7 +1399,7 @@ pub async fn interactive_mode<'a>(
         let new_x = x + " ".len() as u16; // Calculate the new x position
         stdout.execute(MoveTo(new_x, y))?; // Move the cursor to the new position
 
-        stdout.execute(SetForegroundColor(Color::Green))?;
+        stdout.execute(SetForegroundColor(theme.user))?;
 
         stdout.flush()?;
         let mut input = String::new();
@@ -279,13 +1408,200 @@ pub async fn interactive_mode<'a>(
 
         stdout.execute(SetForegroundColor(Color::Reset))?;
 
+        // Ask a suggested follow-up question by typing its number
+        if let Some(suggestion) = followups::pick(&suggestions, &input) {
+            println!("{}", theme.hint(suggestion));
+            input = suggestion.to_string();
+        }
+
         // Exit the loop if the user types "exit"
         if input.to_lowercase() == "exit" {
             break;
         }
 
-        // Embed the user's input
-        let vector = vector_store.embed_text_to_vector(&input)?;
+        // Show the last answer again, in the pager if it is long
+        if input == "/last" {
+            let rendered = pretty::render(&last_answer, pretty::terminal_width());
+            if !pager::page_if_long(config, &rendered)? {
+                println!("{}", rendered);
+            }
+            continue;
+        }
+
+        // Compare two answers word by word: the last two, or `/diff 2 5` for the 2nd and 5th
+        if input == "/diff" || input.starts_with("/diff ") {
+            match diff_answers(&answers, &input["/diff".len()..]) {
+                Ok(changes) if word_diff::has_changes(&changes) => {
+                    println!("{}", word_diff::render_colored(&changes))
+                }
+                Ok(_) => println!("{}", theme.system("The answers are identical.")),
+                Err(e) => println!("{}", theme.system(e)),
+            }
+            continue;
+        }
+
+        // Show the recalled memories the brain held for the last question
+        if input == "/sources" {
+            print!("{}", brain::format_sources(&brain.sources(&config.model)));
+            continue;
+        }
+
+        // Rate the last answer, e.g. `/rate 4 clear, but too long`; see the `feedback` module
+        if input == "/rate" || input.starts_with("/rate ") {
+            let (rating, comment) = match feedback::parse_rate_args(&input["/rate".len()..]) {
+                Ok(args) => args,
+                Err(e) => {
+                    println!("{}", theme.system(e));
+                    continue;
+                }
+            };
+            if answers.is_empty() {
+                println!("{}", theme.system("There is no answer to rate yet."));
+                continue;
+            }
+            let answer = feedback::RatedAnswer {
+                model: config.model.clone(),
+                template: template_name.clone(),
+                conversation: Some(conversation_name.clone()),
+                answer: Some(answers.len()),
+                ..Default::default()
+            };
+            match feedback::record(&feedback::Rating::new(answer, rating, comment)) {
+                Ok(()) => println!("{}", theme.system("Rated the last answer.")),
+                Err(e) => eprintln!("Failed to record the rating: {}", e),
+            }
+            continue;
+        }
+
+        // Merge similar memories now, rather than waiting for `memory_consolidation.every`
+        if input == "/consolidate" {
+            let consolidation_config = config.memory_consolidation.clone().unwrap_or_default();
+            match consolidation::consolidate(config, &consolidation_config, &mut vector_store).await
+            {
+                Ok(report) => println!("{}", theme.system(report.to_string())),
+                Err(e) => eprintln!("Failed to consolidate memories: {}", e),
+            }
+            consolidated_len = vector_store.len();
+            continue;
+        }
+
+        // Switch to another template's persona, keeping the conversation and its memories
+        if input == "/persona" || input.starts_with("/persona ") {
+            let name = input["/persona".len()..].trim();
+            if name.is_empty() {
+                println!("{}", theme.system("Usage: /persona <template>"));
+                continue;
+            }
+            let persona = match crate::template::load_template(name).await {
+                Ok(persona) => persona,
+                Err(e) => {
+                    println!(
+                        "{}",
+                        theme.system(format!("Failed to load template `{}`: {}", name, e))
+                    );
+                    continue;
+                }
+            };
+            let persona_filters = match persona.output_filters() {
+                Ok(persona_filters) => persona_filters,
+                Err(e) => {
+                    println!(
+                        "{}",
+                        theme.system(format!("Invalid template `{}`: {}", name, e))
+                    );
+                    continue;
+                }
+            };
+            if let Err(e) = privacy::check(config, &persona) {
+                println!("{}", theme.system(e.to_string()));
+                continue;
+            }
+            let persona_knowledge =
+                match Knowledge::for_template(config, &persona, Some(&vector_store)).await {
+                    Ok(persona_knowledge) => persona_knowledge,
+                    Err(e) => {
+                        println!(
+                            "{}",
+                            theme.system(format!(
+                                "Failed to load the knowledge of template `{}`: {}",
+                                name, e
+                            ))
+                        );
+                        continue;
+                    }
+                };
+            info!(
+                "Conversation {} switched to the persona of template {}",
+                conversation_name, name
+            );
+            filters = persona_filters;
+            knowledge = persona_knowledge;
+            stop_words = persona.stop_words(config, &[]);
+            brain.set_template(persona.clone());
+            template = persona;
+            template_name = name.to_string();
+            // The preamble carries the system prompt and the memory framing of the new template
+            let preamble = brain.build_preamble()?;
+            let preamble_len = PREAMBLE_LEN.min(messages.len());
+            messages.splice(..preamble_len, preamble);
+            println!(
+                "{}",
+                theme.system(format!("Switched to the `{}` persona.", name))
+            );
+            continue;
+        }
+
+        // Refuse questions that fail the guardrails, unless they start with `/force `
+        if let Some(forced) = input.strip_prefix("/force ") {
+            input = forced.trim().to_string();
+        } else if let Some(guardrails) = &config.guardrails {
+            if let Err(e) = guardrails::check(guardrails, &input, &config.model) {
+                println!(
+                    "{}",
+                    theme.system(e.to_string().replace("--force", "/force"))
+                );
+                continue;
+            }
+        }
+
+        // Offer the answer to an earlier, nearly identical question instead of asking again
+        match duplicates::find_previous_answer(
+            config,
+            &vector_store,
+            &input,
+            &messages[PREAMBLE_LEN.min(messages.len())..],
+        )
+        .await
+        {
+            Ok(Some(previous)) => {
+                println!(
+                    "{}",
+                    theme.system(format!(
+                        "You asked this before ({:.0}% similar): {}",
+                        previous.similarity * 100.0,
+                        previous.question
+                    ))
+                );
+                println!(
+                    "{}",
+                    pretty::render(&previous.answer, pretty::terminal_width())
+                );
+                print!("{}", theme.system("Ask again? [y/N] "));
+                stdout.flush()?;
+                let mut reply = String::new();
+                std::io::stdin().read_line(&mut reply)?;
+                if !reply.trim().eq_ignore_ascii_case("y") {
+                    last_answer = previous.answer;
+                    continue;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to look for an earlier answer: {}", e),
+        }
+
+        // Retrieve memories for the user's input, or the search query it was rewritten into
+        let query =
+            retrieval::retrieval_query(config, &input, retrieval::recent_turns(&messages, 3)).await;
 
         // Create the user prompt
         let user_request = ChatCompletionRequestMessage {
@@ -297,43 +1613,254 @@ pub async fn interactive_mode<'a>(
 
         messages.push(user_request.clone());
 
+        // Instruct the assistant which language to respond in for this question
+        let mut system_prompt = language::instruct_system_prompt(
+            &template.system_prompt,
+            template.respond_in(config),
+            &input,
+        );
+
+        // Append the excerpts of the template's knowledge files closest to the question
+        if let Some(knowledge) = &knowledge {
+            let conversation_tokens = tokens::count(&system_prompt, &config.model)
+                + tokens::count_messages(
+                    &messages[PREAMBLE_LEN.min(messages.len())..],
+                    &config.model,
+                );
+            match knowledge
+                .augment(
+                    config,
+                    &input,
+                    &system_prompt,
+                    conversation_tokens,
+                    template.uses_memory(),
+                )
+                .await
+            {
+                Ok((augmented, _)) => system_prompt = augmented,
+                Err(e) => error!("Failed to recall knowledge: {}", e),
+            }
+        }
+
+        // Give the memories the room this turn's conversation leaves
+        let max_tokens = brain_tokens(
+            config,
+            &config.model,
+            &system_prompt,
+            &messages[PREAMBLE_LEN.min(messages.len())..],
+        );
+        brain.set_max_tokens(max_tokens, &user_request, config);
+        messages[0].content = Some(system_prompt);
+
         // Query the VectorStore to get relevant content based on user's input
-        let neighbors = vector_store.search(&vector, 3)?; // Adjust the number of neighbors as needed
-        for neighbor_id in neighbors {
-            // Here, retrieve the actual content corresponding to neighbor_id and add it to Brain's memory
-            // This requires a mechanism to map IDs to actual content, which needs to be implemented in the VectorStore or another appropriate place
-            if let Some(neighbor_content) = vector_store.get_content_by_id(neighbor_id) {
-                brain.add_memory((*neighbor_content).clone(), &user_request, config);
+        let top_k = retrieval::candidate_count(config);
+        let neighbors = if template.uses_memory() {
+            retrieval::retrieve_within_budget(config, &vector_store, &query, &messages).await?
+        } else {
+            Vec::new()
+        };
+        let always_included = if template.uses_memory() {
+            memory_kinds::always_included(config, &vector_store)
+        } else {
+            Vec::new()
+        };
+        let memories: Vec<(Memory, Option<_>)> = always_included
+            .into_iter()
+            .map(|memory| (memory, None))
+            .chain(neighbors.iter().filter_map(|&neighbor| {
+                let memory = vector_store.get_content_by_id(neighbor.0)?;
+                Some((memory.clone(), Some(neighbor)))
+            }))
+            .collect();
+        score_importance(&mut brain, &vector_store, &template, config, &memories).await;
+        brain.add_memories(memories, &user_request, config);
+
+        // With `strict_context`, refuse the question rather than send it without what did not fit.
+        // The check runs before sending, so that it does not pass for a streaming failure.
+        let strict = brain.check_strict(config).and_then(|()| {
+            eject_memories_to_fit(&mut messages.clone(), &config.model, config).map(|_| ())
+        });
+        if let Err(e) = strict {
+            println!("{}", theme.system(e.to_string()));
+            messages.pop();
+            continue;
+        }
+
+        if config.explain_memory {
+            // Without `store_verbatim`, the question is kept out of the audit file too
+            let audited_query = if config.store_verbatim() {
+                query.as_str()
+            } else {
+                "(not recorded: store_verbatim is false)"
+            };
+            let explanation = RetrievalExplanation::new(
+                &conversation_name,
+                audited_query,
+                top_k,
+                &neighbors,
+                &vector_store,
+                &brain,
+            );
+            if let Err(e) = memory_audit::record(&explanation) {
+                error!("Failed to record memory explanation: {}", e);
             }
         }
 
         // Get the AI's response using the OpenAI API
-        let response = match stream_response(
-            &create_client(config)?,
+        let client = create_client(config)?;
+        let (request, ejected) = match prepare_request(
             config.model.clone(),
             messages.clone(),
-            &config,
-            Some(&mut vector_store),
-            Some(&mut brain),
-        )
-        .await
-        {
-            Ok(response) => response,
+            stop_words.clone(),
+            config,
+        ) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        let response = async {
+            if streaming {
+                match stream_response(
+                    &client,
+                    request.clone(),
+                    config,
+                    Some(&mut brain),
+                    StreamOutput::default(),
+                )
+                .await
+                {
+                    // An empty streamed answer means every chunk failed; the response is fetched
+                    // again without streaming.
+                    Ok((completion, _)) if !completion.content().is_empty() => {
+                        return Ok(completion)
+                    }
+                    Ok((_, Some(e))) if streaming_unsupported(&e) => {
+                        eprintln!(
+                            "{}",
+                            theme.system(
+                                "The backend doesn't stream, switching to non-streaming responses for the rest of this session."
+                            )
+                        );
+                        streaming = false;
+                    }
+                    Ok(_) => eprintln!(
+                        "{}",
+                        theme.system("Streaming failed, fetching this response without streaming.")
+                    ),
+                    Err(e) => {
+                        error!("Streaming failed: {}", e);
+                        eprintln!(
+                            "{}",
+                            theme.system(
+                                "Streaming failed, fetching this response without streaming."
+                            )
+                        );
+                    }
+                }
+            }
+            fetch_response(&client, request, config, false).await
+        };
+        // The ejected messages are archived once while the response is generated, even when
+        // streaming fails and the request is sent again
+        let (response, archived) = tokio::join!(
+            response,
+            archive(
+                config,
+                template.uses_memory().then_some(&mut vector_store),
+                ejected
+            )
+        );
+        if let Err(e) = archived {
+            error!("Failed to archive the ejected messages: {}", e);
+        }
+        let mut response = match response {
+            Ok(completion) => completion.message,
             Err(e) => {
                 eprintln!("Error: {}", e);
                 continue; // This will skip the current iteration of the loop and proceed to the next one
             }
         };
 
+        if !filters.is_empty() {
+            response.content = response
+                .content
+                .map(|content| postprocess::apply_filters(&filters, &content));
+        }
+        last_answer = response.content.clone().unwrap_or_default();
+        answers.push(last_answer.clone());
         messages.push(response);
+
+        if config.suggest_followups {
+            suggestions = match followups::suggest(config, &input, &last_answer).await {
+                Ok(suggestions) => suggestions,
+                Err(e) => {
+                    error!("Failed to suggest follow-up questions: {}", e);
+                    Vec::new()
+                }
+            };
+            if !suggestions.is_empty() {
+                print!(
+                    "\n{}",
+                    theme.hint(followups::format_suggestions(&suggestions))
+                );
+            }
+        }
+
+        // Merge similar memories once enough new ones were archived
+        if let Some(consolidation_config) = &config.memory_consolidation {
+            if vector_store.len() >= consolidated_len + consolidation_config.every {
+                match consolidation::consolidate(config, consolidation_config, &mut vector_store)
+                    .await
+                {
+                    Ok(report) => debug!("{}", report),
+                    Err(e) => error!("Failed to consolidate memories: {}", e),
+                }
+                consolidated_len = vector_store.len();
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Compares two of `answers` word by word for `/diff`.
+///
+/// `args` is empty to compare the last two answers, or holds the numbers of the two answers to
+/// compare, counting from 1 in the order they were given.
+#[cfg(all(feature = "rag", feature = "tty"))]
+fn diff_answers(answers: &[String], args: &str) -> Result<Vec<word_diff::WordChange>, String> {
+    let numbers = args
+        .split_whitespace()
+        .map(|arg| arg.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "Usage: /diff [FIRST SECOND], e.g. /diff 1 3".to_string())?;
+    let (first, second) = match numbers[..] {
+        [] if answers.len() >= 2 => (answers.len() - 1, answers.len()),
+        [] => return Err("There are not two answers to compare yet.".to_string()),
+        [first, second] => (first, second),
+        _ => return Err("Usage: /diff [FIRST SECOND], e.g. /diff 1 3".to_string()),
+    };
+    let answer = |number: usize| {
+        number
+            .checked_sub(1)
+            .and_then(|index| answers.get(index))
+            .ok_or_else(|| {
+                format!(
+                    "There is no answer {}; there are {}.",
+                    number,
+                    answers.len()
+                )
+            })
+    };
+    Ok(word_diff::diff(answer(first)?, answer(second)?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{message, mock_config};
     use async_openai::types::Role;
     use httpmock::prelude::*;
     use serde_json::json;
@@ -343,17 +1870,6 @@ mod tests {
         let _ = tracing_subscriber::fmt::try_init();
     }
 
-    // Mock configuration for testing
-    fn mock_config() -> AwfulJadeConfig {
-        AwfulJadeConfig {
-            api_key: "mock_api_key".to_string(),
-            api_base: "http://mock.api.base".to_string(),
-            model: "mock_model".to_string(),
-            context_max_tokens: 8192,
-            assistant_minimum_context_tokens: 2048,
-        }
-    }
-
     // Mock template for testing
     fn mock_template() -> ChatTemplate {
         ChatTemplate {
@@ -364,6 +1880,7 @@ mod tests {
                 name: None,
                 function_call: None,
             }],
+            ..Default::default()
         }
     }
 
@@ -377,7 +1894,7 @@ mod tests {
     #[tokio::test]
     async fn test_prepare_messages() {
         let template = mock_template();
-        let messages = prepare_messages(template).await;
+        let messages = prepare_messages(&template);
         assert!(messages.is_ok(), "Failed to prepare messages");
         let messages = messages.unwrap();
         assert_eq!(messages.len(), 2, "Unexpected number of messages");
@@ -442,14 +1959,392 @@ mod tests {
             model: "mock_model".to_string(),
             context_max_tokens: 8192,
             assistant_minimum_context_tokens: 2048,
+            ..Default::default()
         };
         let question = "How do I write tests in Rust?".to_string();
         let template = mock_template();
 
         // Note: This test will fail unless you have a mock or actual API set up to handle the request
-        let result = ask(&config, question, template).await;
+        let result = ask(&config, question, &template).await;
+        assert!(result.is_ok(), "Failed to ask question: {:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn test_ask_without_streaming() {
+        setup();
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/chat/completions")
+                .header("authorization", "Bearer mock_api_key");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "choices": [
+                        {
+                            "message": {
+                                "role": "assistant",
+                                "content": "Use the `#[test]` attribute.",
+                            },
+                            "finish_reason": "stop",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            should_stream: Some(false),
+            ..mock_config()
+        };
+        let question = "How do I write tests in Rust?".to_string();
+
+        let result = ask(&config, question, &mock_template()).await;
+        assert!(result.is_ok(), "Failed to ask question: {:?}", result.err());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_ask_for_completion_tees_what_goes_to_on_delta() {
+        setup();
+        let server = MockServer::start();
+        let chunk = |content: &str| {
+            json!({
+                "id": "chatcmpl-1234567890",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "mock_model",
+                "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]
+            })
+        };
+        let body = format!(
+            "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+            chunk("Use the "),
+            chunk("`#[test]` attribute.")
+        );
+        server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "text/event-stream")
+                .body(body);
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            ..mock_config()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let tee = dir.path().join("answer.md");
+        let deltas = std::sync::Mutex::new(String::new());
+        let on_delta = |content: &str| deltas.lock().unwrap().push_str(content);
+        let options = AskOptions {
+            on_delta: Some(&on_delta),
+            tee: Some(tee.clone()),
+            ..Default::default()
+        };
+        let completion =
+            ask_for_completion(&config, "How do I write tests?", &mock_template(), options)
+                .await
+                .unwrap();
+
+        assert_eq!(completion.content(), "Use the `#[test]` attribute.");
+        assert_eq!(*deltas.lock().unwrap(), completion.content());
+        assert_eq!(fs::read_to_string(&tee).unwrap(), completion.content());
+    }
+
+    #[tokio::test]
+    async fn test_ask_for_completion_reports_truncation() {
+        setup();
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "usage": { "prompt_tokens": 40, "completion_tokens": 16, "total_tokens": 56 },
+                    "choices": [
+                        {
+                            "message": {"role": "assistant", "content": "Use the `#[test]`"},
+                            "finish_reason": "length",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            max_response_tokens: Some(16),
+            ..mock_config()
+        };
+        let options = AskOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let completion = ask_for_completion(
+            &config,
+            "How do I write tests in Rust?",
+            &mock_template(),
+            options,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(completion.content(), "Use the `#[test]`");
+        assert_eq!(completion.finish_reason.as_deref(), Some("length"));
+        assert_eq!(
+            completion
+                .usage
+                .as_ref()
+                .map(|usage| usage.completion_tokens),
+            Some(16)
+        );
+        assert!(completion.is_truncated());
+    }
+
+    #[tokio::test]
+    async fn test_ask_stops_at_max_response_seconds() {
+        setup();
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200).delay(Duration::from_secs(5));
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            should_stream: Some(false),
+            max_response_seconds: Some(1),
+            ..mock_config()
+        };
+        let err = ask(&config, "Hi", &mock_template()).await.unwrap_err();
+        assert_eq!(err.to_string(), "No response within 1s");
+    }
+
+    #[tokio::test]
+    async fn test_ask_sends_extra_headers() {
+        setup();
+        let server = MockServer::start();
+
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/chat/completions")
+                .header("authorization", "Bearer mock_api_key")
+                .header("openai-organization", "org-123")
+                .header("x-portkey-api-key", "secret");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "choices": [
+                        {
+                            "message": {
+                                "role": "assistant",
+                                "content": "Hello.",
+                            },
+                            "finish_reason": "stop",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            should_stream: Some(false),
+            extra_headers: [
+                ("OpenAI-Organization", "org-123"),
+                ("X-Portkey-Api-Key", "secret"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+            ..mock_config()
+        };
+
+        let result = ask(&config, "Hi", &mock_template()).await;
         assert!(result.is_ok(), "Failed to ask question: {:?}", result.err());
+        mock.assert();
+    }
+
+    #[test]
+    fn test_extra_headers_are_validated_and_redacted() {
+        let mut config = mock_config();
+        config
+            .extra_headers
+            .insert("X-Api-Key".to_string(), "secret".to_string());
+        let headers = extra_headers(&config).unwrap();
+        assert!(!format!("{:?}", headers).contains("secret"));
+
+        config
+            .extra_headers
+            .insert("Bad Header".to_string(), "value".to_string());
+        let err = extra_headers(&config).unwrap_err();
+        assert!(err.to_string().contains("Bad Header"));
+    }
+
+    #[tokio::test]
+    async fn test_ask_reports_context_overflow() {
+        setup();
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(500);
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            context_max_tokens: 16,
+            ..mock_config()
+        };
+        let question = "How do I write tests in Rust?".to_string();
+
+        let error = ask_with_options(&config, question, &mock_template(), Default::default())
+            .await
+            .unwrap_err();
+        let overflow = error
+            .downcast_ref::<ContextOverflow>()
+            .expect("Expected a context overflow");
+        assert_eq!(overflow.context_max_tokens, 16);
+        assert!(overflow.preamble_tokens > 0 && overflow.question_tokens > 0);
+        assert!(overflow.prompt_tokens() >= 16);
+        mock.assert_hits(0);
     }
 
     // Add more specific test cases to handle different scenarios and edge cases
+
+    fn preamble() -> Vec<ChatCompletionRequestMessage> {
+        vec![
+            message(Role::System, "system"),
+            message(Role::User, "brain"),
+            message(Role::Assistant, "Ok."),
+        ]
+    }
+
+    fn contents(messages: &[ChatCompletionRequestMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter_map(|message| message.content.as_deref())
+            .collect()
+    }
+
+    #[cfg(all(feature = "rag", feature = "tty"))]
+    #[test]
+    fn test_diff_answers() {
+        let answers: Vec<String> = ["a b", "a c", "a b c"]
+            .iter()
+            .map(|answer| answer.to_string())
+            .collect();
+        let render = |args: &str| diff_answers(&answers, args).map(|c| word_diff::render_plain(&c));
+        assert_eq!(render(""), Ok("a {+b +}c".to_string()));
+        assert_eq!(render(" 1 2"), Ok("a [-b-]{+c+}".to_string()));
+        assert!(render(" 1 4").unwrap_err().contains("no answer 4"));
+        assert!(render(" one").unwrap_err().starts_with("Usage"));
+        assert!(diff_answers(&answers[..1], "").is_err());
+    }
+
+    #[test]
+    fn test_eject_oldest_exchange_removes_pairs() {
+        let mut messages = preamble();
+        messages.push(message(Role::User, "q1"));
+        messages.push(message(Role::Assistant, "a1"));
+        messages.push(message(Role::User, "q2"));
+
+        let ejected = eject_oldest_exchange(&mut messages, PREAMBLE_LEN);
+        assert_eq!(contents(&ejected), vec!["q1", "a1"]);
+        assert_eq!(contents(&messages), vec!["system", "brain", "Ok.", "q2"]);
+
+        // Only the question is left, which is never ejected.
+        assert!(eject_oldest_exchange(&mut messages, PREAMBLE_LEN).is_empty());
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn test_eject_oldest_exchange_handles_odd_histories() {
+        // A question whose request failed is left without an answer.
+        let mut messages = preamble();
+        messages.push(message(Role::User, "failed"));
+        messages.push(message(Role::User, "q1"));
+        messages.push(message(Role::Assistant, "a1"));
+        messages.push(message(Role::User, "q2"));
+
+        let ejected = eject_oldest_exchange(&mut messages, PREAMBLE_LEN);
+        assert_eq!(contents(&ejected), vec!["failed"]);
+        let ejected = eject_oldest_exchange(&mut messages, PREAMBLE_LEN);
+        assert_eq!(contents(&ejected), vec!["q1", "a1"]);
+
+        // A lone user message just before the question is ejected on its own.
+        let mut messages = preamble();
+        messages.push(message(Role::User, "failed"));
+        messages.push(message(Role::User, "q1"));
+        let ejected = eject_oldest_exchange(&mut messages, PREAMBLE_LEN);
+        assert_eq!(contents(&ejected), vec!["failed"]);
+        assert_eq!(contents(&messages), vec!["system", "brain", "Ok.", "q1"]);
+
+        // Short conversations never panic.
+        let mut messages = vec![message(Role::System, "system")];
+        assert!(eject_oldest_exchange(&mut messages, PREAMBLE_LEN).is_empty());
+        assert!(eject_oldest_exchange(&mut Vec::new(), PREAMBLE_LEN).is_empty());
+    }
+
+    #[cfg(all(feature = "rag", feature = "tty"))]
+    #[tokio::test]
+    async fn test_only_non_streaming_backends_stop_streaming() {
+        setup();
+        let server = MockServer::start();
+        let mut json = server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({ "choices": [] }));
+        });
+        let config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            model: "mock_model".to_string(),
+            ..Default::default()
+        };
+        let client = create_client(&config).unwrap();
+        let request = || {
+            build_request(
+                config.model.clone(),
+                vec![message(Role::User, "Hello")],
+                Vec::new(),
+                &config,
+            )
+            .unwrap()
+        };
+
+        let (completion, error) =
+            stream_response(&client, request(), &config, None, StreamOutput::default())
+                .await
+                .unwrap();
+        assert!(completion.content().is_empty());
+        assert!(streaming_unsupported(&error.unwrap()));
+
+        json.delete();
+        server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(503);
+        });
+        let (completion, error) =
+            stream_response(&client, request(), &config, None, StreamOutput::default())
+                .await
+                .unwrap();
+        assert!(completion.content().is_empty());
+        assert!(!streaming_unsupported(&error.unwrap()));
+    }
 }
@@ -0,0 +1,290 @@
+//! This module splits the context window between the parts of a request.
+//!
+//! A request holds the conversation (the system prompt, the template's messages, and the question),
+//! and, on some turns, recalled memories and supplementary context such as a repository map; the
+//! rest of the window is left for the answer. Rather than giving memories and context a fixed
+//! quarter of the window each, `ContextBudget::plan` splits the room the conversation and the
+//! answer leave between whichever of them is present this turn: a question asked without a
+//! repository map gets more memories, and one asked without memory gets a larger map.
+//!
+//! With `rag_budget_share` set, memories keep that share of the window, as before, and context gets
+//! what they leave.
+//!
+//! Parts that do not fit are normally left out: the oldest memories are evicted from the brain, and
+//! the oldest exchanges of a conversation are ejected and archived. With `strict_context`, or
+//! `--strict-context`, a `ContextDropped` error describing what did not fit is returned instead,
+//! and nothing is sent.
+//!
+//! # Example
+//!
+//! ```
+//! use awful_aj::{budget::{ContextBudget, Parts}, config::AwfulJadeConfig};
+//!
+//! let config = AwfulJadeConfig {
+//!     context_max_tokens: 8192,
+//!     assistant_minimum_context_tokens: 2048,
+//!     ..Default::default()
+//! };
+//! let alone = ContextBudget::plan(&config, 1024, Parts { memories: true, context: false });
+//! let shared = ContextBudget::plan(&config, 1024, Parts { memories: true, context: true });
+//! assert_eq!(alone.memories, 5120);
+//! assert_eq!(shared.memories + shared.context, 5120);
+//! ```
+
+use crate::config::AwfulJadeConfig;
+use std::{error::Error, fmt};
+
+/// Which parts besides the conversation are sent with a question.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Parts {
+    /// Memories recalled from the vector store.
+    pub memories: bool,
+
+    /// Supplementary context, such as a repository map.
+    pub context: bool,
+}
+
+/// How many tokens each part of a request may use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextBudget {
+    /// The conversation, as counted when the budget was planned.
+    pub conversation: usize,
+
+    /// The room kept for the answer, `assistant_minimum_context_tokens`.
+    pub answer: usize,
+
+    /// The recalled memories, including the handshake that frames them.
+    pub memories: usize,
+
+    /// The supplementary context, including the handshake that frames it.
+    pub context: usize,
+}
+
+impl ContextBudget {
+    /// Plans a request whose conversation takes `conversation_tokens`, sent with the `parts`
+    /// present this turn.
+    ///
+    /// The room left after the conversation and the answer goes to the memories or the context
+    /// when only one is present, and is halved when both are. A conversation that already fills
+    /// the window leaves nothing for either.
+    pub fn plan(config: &AwfulJadeConfig, conversation_tokens: usize, parts: Parts) -> Self {
+        let window = config.context_max_tokens as usize;
+        let answer = (config.assistant_minimum_context_tokens as usize).min(window);
+        let free = window
+            .saturating_sub(answer)
+            .saturating_sub(conversation_tokens);
+
+        let memories = match (parts.memories, config.rag_budget_share) {
+            (false, _) => 0,
+            (true, Some(share)) => ((share * window as f32) as usize)
+                .saturating_sub(conversation_tokens)
+                .min(free),
+            (true, None) if parts.context => free / 2,
+            (true, None) => free,
+        };
+        let context = if parts.context { free - memories } else { 0 };
+
+        Self {
+            conversation: conversation_tokens,
+            answer,
+            memories,
+            context,
+        }
+    }
+
+    /// Returns the limit for a `Brain` holding the memories. A brain counts its preamble along with
+    /// the question, so `overhead` is the tokens of the system prompt and the question, which are
+    /// part of the conversation rather than of the memories.
+    pub fn brain_tokens(&self, overhead: usize) -> u16 {
+        (self.memories + overhead).min(u16::MAX as usize) as u16
+    }
+}
+
+/// How a request divides the context window, part by part, as `aj budget simulate` reports it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Breakdown {
+    /// The size of the context window, `context_max_tokens`.
+    pub window: usize,
+
+    /// The system prompt, adjusted for the question.
+    pub system_prompt: usize,
+
+    /// The template's messages that fit, without the optional ones left out.
+    pub template: usize,
+
+    /// The question.
+    pub question: usize,
+
+    /// The room for recalled memories, which take up to this much, when they are sent.
+    pub memories: usize,
+
+    /// The supplementary context, including the handshake that frames it.
+    pub context: usize,
+
+    /// The most the supplementary context may take.
+    pub context_budget: usize,
+
+    /// The room kept for the answer.
+    pub answer: usize,
+}
+
+impl Breakdown {
+    /// Divides the window for a request whose system prompt, template messages, and question take
+    /// the given tokens, sent with `parts`. The context takes nothing until it is known; it gets
+    /// up to `context_budget`.
+    pub fn plan(
+        config: &AwfulJadeConfig,
+        system_prompt: usize,
+        template: usize,
+        question: usize,
+        parts: Parts,
+    ) -> Self {
+        let budget = ContextBudget::plan(config, system_prompt + template + question, parts);
+        Self {
+            window: config.context_max_tokens as usize,
+            system_prompt,
+            template,
+            question,
+            memories: budget.memories,
+            context: 0,
+            context_budget: budget.context,
+            answer: budget.answer,
+        }
+    }
+
+    /// Returns the room no part uses.
+    pub fn unused(&self) -> usize {
+        self.window.saturating_sub(
+            self.system_prompt
+                + self.template
+                + self.question
+                + self.memories
+                + self.context
+                + self.answer,
+        )
+    }
+}
+
+impl fmt::Display for Breakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Context window: {} tokens", self.window)?;
+        let context = format!("context (of {})", self.context_budget);
+        let rows = [
+            ("system prompt", self.system_prompt),
+            ("template messages", self.template),
+            ("memories (up to)", self.memories),
+            (context.as_str(), self.context),
+            ("question", self.question),
+            ("answer (reserved)", self.answer),
+            ("unused", self.unused()),
+        ];
+        for (part, tokens) in rows {
+            let share = if self.window == 0 {
+                0.0
+            } else {
+                tokens as f64 * 100.0 / self.window as f64
+            };
+            writeln!(f, "  {:<20}{:>8}{:>7.1}%", part, tokens, share)?;
+        }
+        Ok(())
+    }
+}
+
+/// The error returned with `strict_context` instead of leaving part of a request out to make it fit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextDropped {
+    /// What would have been left out, e.g. `2 memories (310 tokens)`.
+    pub dropped: String,
+
+    /// The limit it did not fit in, e.g. `the brain's 1500 tokens`.
+    pub limit: String,
+}
+
+impl fmt::Display for ContextDropped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} did not fit in {}, and `strict_context` keeps them from being left out.",
+            self.dropped, self.limit
+        )?;
+        writeln!(f, "Try one of:")?;
+        writeln!(
+            f,
+            "  - raise `context_max_tokens` in config.yaml, if the model supports a larger context"
+        )?;
+        writeln!(f, "  - start a new conversation, or shorten the question")?;
+        write!(f, "  - leave out `--strict-context` to let aj make room")
+    }
+}
+
+impl Error for ContextDropped {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn config() -> AwfulJadeConfig {
+        test_support::config_with_budget(4096, 1024)
+    }
+
+    #[test]
+    fn test_plan_splits_the_free_room() {
+        let both = Parts {
+            memories: true,
+            context: true,
+        };
+        let budget = ContextBudget::plan(&config(), 1000, both);
+        assert_eq!((budget.memories, budget.context), (1036, 1036));
+
+        let memories_only = Parts {
+            memories: true,
+            context: false,
+        };
+        let budget = ContextBudget::plan(&config(), 1000, memories_only);
+        assert_eq!((budget.memories, budget.context), (2072, 0));
+
+        let budget = ContextBudget::plan(&config(), 5000, both);
+        assert_eq!((budget.memories, budget.context), (0, 0));
+        assert_eq!(budget.answer, 1024);
+    }
+
+    #[test]
+    fn test_plan_keeps_the_configured_share() {
+        let config = AwfulJadeConfig {
+            rag_budget_share: Some(0.5),
+            ..config()
+        };
+        let parts = Parts {
+            memories: true,
+            context: true,
+        };
+        let budget = ContextBudget::plan(&config, 1000, parts);
+        assert_eq!((budget.memories, budget.context), (1048, 1024));
+        assert_eq!(budget.brain_tokens(100), 1148);
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let parts = Parts {
+            memories: false,
+            context: true,
+        };
+        let mut breakdown = Breakdown::plan(&config(), 100, 300, 50, parts);
+        assert_eq!(breakdown.context_budget, 2622);
+        breakdown.context = 1000;
+        assert_eq!(breakdown.unused(), 1622);
+        assert_eq!(
+            breakdown.to_string(),
+            "Context window: 4096 tokens
+  system prompt            100    2.4%
+  template messages        300    7.3%
+  memories (up to)           0    0.0%
+  context (of 2622)       1000   24.4%
+  question                  50    1.2%
+  answer (reserved)       1024   25.0%
+  unused                  1622   39.6%
+"
+        );
+    }
+}
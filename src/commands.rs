@@ -10,19 +10,29 @@
 //!
 //! ```no_run
 //! use awful_aj::commands::{Cli, Commands};
+//! use clap::Parser;
 //!
 //! let cli = Cli::parse();
 //! match cli.command {
-//!     Commands::Ask { question } => {
+//!     Commands::Ask { question, .. } => {
 //!         // Handle the 'ask' subcommand
 //!     }
-//!     Commands::Init => {
+//!     Commands::Init { .. } => {
 //!         // Handle the 'init' subcommand
 //!     }
+//!     _ => {}
 //! }
 //! ```
 
+use crate::{
+    config::{parse_duration, parse_size, AwfulJadeConfig},
+    feedback::parse_rating,
+    grounding::GroundingMode,
+    template::parse_var,
+    theme::BuiltinTheme,
+};
 use clap::{Parser, Subcommand};
+use std::{path::PathBuf, time::Duration};
 
 /// Represents the parsed command-line arguments.
 ///
@@ -31,11 +41,107 @@ use clap::{Parser, Subcommand};
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, propagate_version = true, color = clap::ColorChoice::Always)]
 pub struct Cli {
+    /// The built-in theme for terminal output, overriding the one named in the config.
+    #[arg(long, global = true, value_enum)]
+    pub theme: Option<BuiltinTheme>,
+
+    /// Show answers taller than the terminal in `$PAGER` once they are complete.
+    #[arg(long, global = true, conflicts_with = "no_pager")]
+    pub pager: bool,
+
+    /// Print long answers directly instead of showing them in `$PAGER`.
+    #[arg(long, global = true)]
+    pub no_pager: bool,
+
+    /// Stop generating a response after this many seconds, keeping what was produced so far.
+    #[arg(long, global = true)]
+    pub max_seconds: Option<u64>,
+
+    /// Stop generating a response after this many tokens, keeping what was produced so far.
+    #[arg(long, global = true)]
+    pub max_output_tokens: Option<u16>,
+
+    /// Print the time to the first token, the tokens per second, and the total time after each
+    /// response.
+    #[arg(long, global = true)]
+    pub timings: bool,
+
+    /// The model to ask, overriding `model` in the config.
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+
+    /// The model's context window in tokens, overriding `context_max_tokens` in the config.
+    #[arg(long, global = true)]
+    pub max_context: Option<u16>,
+
+    /// The sampling temperature, from 0 to 2, overriding `temperature` in the config.
+    #[arg(long, global = true)]
+    pub temperature: Option<f32>,
+
+    /// Fetch each response whole instead of streaming it, overriding `should_stream`.
+    #[arg(long, global = true)]
+    pub no_stream: bool,
+
+    /// Fail with an error describing what did not fit instead of evicting memories or ejecting
+    /// earlier exchanges, overriding `strict_context`.
+    #[arg(long, global = true)]
+    pub strict_context: bool,
+
+    /// Send questions asked with templates marked `local_only` to a backend that is not on
+    /// `local_hosts`, overriding `allow_remote`.
+    #[arg(long, global = true)]
+    pub allow_remote: bool,
+
     /// The parsed subcommand and its options.
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    /// Applies the global flags to `config`, overriding its values for this invocation only.
+    ///
+    /// This is the one place flags are turned into configuration, so every command sees the same
+    /// overrides.
+    pub fn apply_overrides(&self, config: &mut AwfulJadeConfig) {
+        if let Some(theme) = self.theme {
+            config.theme.get_or_insert_with(Default::default).name = Some(theme);
+        }
+        if self.pager {
+            config.pager = Some(true);
+        }
+        if self.no_pager {
+            config.pager = Some(false);
+        }
+        if self.max_seconds.is_some() {
+            config.max_response_seconds = self.max_seconds;
+        }
+        if self.max_output_tokens.is_some() {
+            config.max_response_tokens = self.max_output_tokens;
+        }
+        if self.timings {
+            config.show_timings = true;
+        }
+        if let Some(model) = &self.model {
+            config.model = model.clone();
+        }
+        if let Some(max_context) = self.max_context {
+            config.context_max_tokens = max_context;
+        }
+        if self.temperature.is_some() {
+            config.temperature = self.temperature;
+        }
+        if self.no_stream {
+            config.should_stream = Some(false);
+        }
+        if self.strict_context {
+            config.strict_context = true;
+        }
+        if self.allow_remote {
+            config.allow_remote = true;
+        }
+    }
+}
+
 /// Represents the available subcommands and their options.
 ///
 /// Each variant of this enum corresponds to a subcommand that the user can invoke
@@ -50,6 +156,81 @@ pub enum Commands {
     Ask {
         /// The question to be asked. If not provided, a default question is used.
         question: Option<String>,
+
+        /// The template to ask the question with.
+        #[arg(short, long, default_value = "simple_question")]
+        template: String,
+
+        /// Set a variable of the template, written `{{name}}` in it, e.g. `--var language=Rust`.
+        /// Variables the template prompts for are asked for when they are not set. May be
+        /// repeated.
+        #[arg(long = "var", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+
+        /// Path to a repository whose map should be included with the question.
+        #[arg(long)]
+        repo: Option<PathBuf>,
+
+        /// Only map repository files matching this glob. May be repeated.
+        #[arg(long, requires = "repo")]
+        include: Vec<String>,
+
+        /// Leave repository files matching this glob out of the map. May be repeated.
+        #[arg(long, requires = "repo")]
+        exclude: Vec<String>,
+
+        /// Check the answer against the provided context, then annotate (the default) or
+        /// regenerate it if it makes unsupported claims.
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "annotate")]
+        verify_grounding: Option<GroundingMode>,
+
+        /// Stop generating at this sequence, in addition to the template's and the config's stop
+        /// words. May be repeated.
+        #[arg(long)]
+        stop: Vec<String>,
+
+        /// Announce the answer when it took longer than `notify_after`, with `notify_command` or
+        /// a desktop notification.
+        #[arg(long)]
+        notify: bool,
+
+        /// After the answer, print the memories and context that were sent with the question,
+        /// with their distances and token counts, and the question's request ID.
+        #[arg(long)]
+        show_sources: bool,
+
+        /// Print only the code blocks of the answer, without their fences, for piping generated
+        /// code into other commands. Fails when the answer has no code block.
+        #[arg(long, conflicts_with = "show_sources")]
+        code_only: bool,
+
+        /// Send the question even if it fails the `guardrails` checks in the config.
+        #[arg(long)]
+        force: bool,
+
+        /// Split a question that asks several things into its distinct questions, answer each
+        /// with its own memories, and merge the answers with a section for each.
+        #[arg(long)]
+        decompose: bool,
+
+        /// Instead of printing the answer, wait for one HTTP client on this port of localhost and
+        /// stream the answer to it as server-sent events.
+        #[arg(long, conflicts_with_all = ["show_sources", "code_only"])]
+        sse_port: Option<u16>,
+
+        /// Also write the answer to this file as it is streamed, flushing it as tokens arrive, so
+        /// a long answer is kept if the terminal goes away.
+        #[arg(long, conflicts_with_all = ["sse_port", "code_only"])]
+        tee: Option<PathBuf>,
+
+        /// Ask the model to give the files it creates or changes in blocks naming them, and write
+        /// them, inside the current directory, once confirmed; see the `fs_tools` module.
+        #[arg(long, conflicts_with_all = ["sse_port", "code_only"])]
+        write_files: bool,
+
+        /// Write the files proposed with `--write-files` without asking for confirmation.
+        #[arg(long, requires = "write_files")]
+        yes: bool,
     },
 
     /// The 'interactive' subcommand, which can have an optional name for the conversation.
@@ -61,9 +242,277 @@ pub enum Commands {
         name: Option<String>,
     },
 
-    /// The 'init' subcommand, which takes no arguments and is used for initialization.
+    /// The 'digest' subcommand, which summarizes the documents in a directory that changed
+    /// recently. It prints nothing but the digest, so it can be run from cron.
+    Digest {
+        /// Include documents changed within this long (e.g. `24h`, `7d`).
+        #[arg(long, default_value = "24h")]
+        since: String,
+
+        /// The directory of documents to summarize.
+        #[arg(long)]
+        rag: PathBuf,
+
+        /// The template to write the digest with. Defaults to the `digest` template, or a
+        /// built-in prompt when there is none.
+        #[arg(long)]
+        template: Option<String>,
+
+        /// Append the digest to this file, with a header recording its sources, instead of
+        /// printing it.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// The 'init' subcommand, which sets aj up.
     ///
-    /// When invoked, this subcommand performs setup and initialization tasks, such
-    /// as creating necessary directories and files.
-    Init,
+    /// When invoked, this subcommand asks for a backend, a model, and a few defaults, detecting
+    /// Ollama and LM Studio on localhost, then creates the configuration and templates and checks
+    /// that the backend answers.
+    Init {
+        /// Write the default configuration and templates without asking anything.
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// The 'lsp-ish' subcommand, which runs a long-lived editor integration over stdin/stdout.
+    ///
+    /// Requests and responses are newline-delimited JSON-RPC 2.0 messages; streamed answers are
+    /// delivered as notifications.
+    #[clap(name = "lsp-ish")]
+    LspIsh {
+        /// Speak JSON-RPC over stdin/stdout. This is currently the only supported transport.
+        #[arg(long)]
+        jsonrpc: bool,
+    },
+
+    /// The 'config' subcommand, which groups commands that inspect the configuration.
+    Config {
+        /// The config subcommand to run.
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// The 'rate' subcommand, which rates the last answer of `aj ask` from 1 to 5; see the
+    /// `feedback` module.
+    Rate {
+        /// The rating of the last answer, from 1 (useless) to 5 (excellent).
+        #[arg(long, value_parser = parse_rating)]
+        last: u8,
+
+        /// What was good or bad about the answer.
+        comment: Option<String>,
+    },
+
+    /// The 'stats' subcommand, which summarizes the ratings of answers for each model and
+    /// template.
+    Stats,
+
+    /// The 'models' subcommand, which lists the models the configured backend serves and checks
+    /// that `model` is one of them; see the `setup` module.
+    Models,
+
+    /// The 'memory' subcommand, which groups commands that inspect conversation memories.
+    Memory {
+        /// The memory subcommand to run.
+        #[command(subcommand)]
+        command: MemoryCommands,
+    },
+
+    /// The 'rag' subcommand, which groups commands that search documents without asking the model.
+    Rag {
+        /// The rag subcommand to run.
+        #[command(subcommand)]
+        command: RagCommands,
+    },
+
+    /// The 'repo' subcommand, which groups commands that operate on source repositories.
+    Repo {
+        /// The repository subcommand to run.
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+
+    /// The 'template' subcommand, which groups commands that manage chat templates.
+    Template {
+        /// The template subcommand to run.
+        #[command(subcommand)]
+        command: TemplateCommands,
+    },
+
+    /// The 'budget' subcommand, which groups commands that plan how the context window is used.
+    Budget {
+        /// The budget subcommand to run.
+        #[command(subcommand)]
+        command: BudgetCommands,
+    },
+
+    /// The 'debug' subcommand, which groups commands that show what aj would send without sending
+    /// it.
+    Debug {
+        /// The debug subcommand to run.
+        #[command(subcommand)]
+        command: DebugCommands,
+    },
+
+    /// Any other subcommand runs the plugin `aj-<name>` found on `PATH` with the remaining
+    /// arguments; see the `plugins` module.
+    #[command(external_subcommand)]
+    External(Vec<String>),
+}
+
+/// Represents the subcommands of the 'config' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// Checks `config.yaml` for nonsensical values and prints every error and warning, with a
+    /// suggested fix for each.
+    Validate,
+}
+
+/// Represents the subcommands of the 'debug' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum DebugCommands {
+    /// Prints the request that `aj ask` would send for a question: the assembled messages and the
+    /// request parameters.
+    Prompt {
+        /// The question to assemble. If not provided, the default question of `aj ask` is used.
+        question: Option<String>,
+
+        /// The template to assemble the question with.
+        #[arg(long, default_value = "simple_question")]
+        template: String,
+
+        /// Print the request as JSON, in the stable form of `prompt::PromptSnapshot`.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Represents the subcommands of the 'budget' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum BudgetCommands {
+    /// Prints how the context window would be divided between the system prompt, the template's
+    /// messages, memories, documents, the question, and the answer, without asking the model.
+    Simulate {
+        /// The question to plan for. If neither it nor `--question-file` is given, the default
+        /// question of `aj ask` is used.
+        #[arg(conflicts_with = "question_file")]
+        question: Option<String>,
+
+        /// Read the question from this file.
+        #[arg(long)]
+        question_file: Option<PathBuf>,
+
+        /// The template to plan with.
+        #[arg(short, long, default_value = "simple_question")]
+        template: String,
+
+        /// A directory of documents whose chunks closest to the question fill the room left for
+        /// context, as `aj rag query` finds them.
+        #[arg(long)]
+        rag: Option<PathBuf>,
+
+        /// Plan for recalled memories too, as in interactive mode.
+        #[arg(long)]
+        memories: bool,
+    },
+}
+
+/// Represents the subcommands of the 'memory' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum MemoryCommands {
+    /// Shows which memories were retrieved for recent questions and why they were or were not used.
+    ///
+    /// Requires `explain_memory: true` in the configuration.
+    Explain {
+        /// How many of the most recent questions to explain.
+        #[arg(long, default_value_t = 1)]
+        last: usize,
+    },
+}
+
+/// Represents the subcommands of the 'rag' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum RagCommands {
+    /// Prints the chunks of the documents in a directory that are closest to a question, with
+    /// their distances, without asking the model.
+    Query {
+        /// What to search for.
+        question: String,
+
+        /// The directory of documents to search.
+        #[arg(long)]
+        rag: PathBuf,
+
+        /// How many chunks to print.
+        #[arg(long, default_value_t = 8)]
+        top_k: usize,
+
+        /// Print the chunks as a JSON array of `{path, distance, text}` objects.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Represents the subcommands of the 'repo' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum RepoCommands {
+    /// Builds (or refreshes) the cached map of a repository and prints it.
+    Map {
+        /// The repository to map. Defaults to the current directory.
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Only map files matching this glob. May be repeated.
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Leave files matching this glob out of the map. May be repeated.
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+
+    /// Manages the cache of repository maps.
+    Cache {
+        /// The cache subcommand to run.
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+/// Represents the subcommands of the 'repo cache' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// Lists the cached repository maps, oldest first.
+    Ls,
+
+    /// Deletes every cached repository map.
+    Clear,
+
+    /// Deletes cached repository maps by age and/or total cache size.
+    Prune {
+        /// Delete maps that were last written longer ago than this (e.g. `30d`, `12h`).
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Delete the oldest maps until the cache is no larger than this (e.g. `2G`, `500M`).
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+}
+
+/// Represents the subcommands of the 'template' subcommand.
+#[derive(Subcommand, Debug)]
+pub enum TemplateCommands {
+    /// Downloads a template into the templates directory and records it in `templates.lock`.
+    ///
+    /// Sources are `http(s)://` URLs or `github:owner/repo/path.yaml`, optionally pinned with `@ref`.
+    Install {
+        /// Where to download the template from.
+        source: String,
+
+        /// The name to install the template as. Defaults to the source's file name.
+        #[arg(long)]
+        name: Option<String>,
+    },
 }
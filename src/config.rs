@@ -8,22 +8,28 @@
 //! Loading the configuration from a file:
 //!
 //! ```no_run
-//! use awful_jade::config::{AwfulJadeConfig, load_config};
+//! use awful_aj::config::{AwfulJadeConfig, load_config};
 //!
 //! let config_file_path = "/path/to/config.yaml";
 //! let config: AwfulJadeConfig = load_config(config_file_path).unwrap();
 //! println!("{:?}", config);
 //! ```
 
+#[cfg(feature = "tty")]
+use crate::theme::BuiltinTheme;
+use crate::{
+    brain::{EvictionPolicy, MemoryKind},
+    validation,
+};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs};
+use std::{collections::BTreeMap, error::Error, fs, time::Duration};
 
 /// Represents the application's configuration.
 ///
 /// This struct holds the configuration parameters needed to run the application,
 /// such as API key, API base URL, and model name. It can be constructed by loading
 /// a YAML configuration file using the `load_config` function.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct AwfulJadeConfig {
     /// The API key used to authenticate requests to the API.
     pub api_key: String,
@@ -42,12 +48,459 @@ pub struct AwfulJadeConfig {
 
     // Stop words
     pub stop_words: Vec<String>,
+
+    /// Extra HTTP headers sent with every request, e.g. `OpenAI-Organization` or gateway keys.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_headers: BTreeMap<String, String>,
+
+    /// The most tokens to request for a response. Responses are always limited to what is left
+    /// of the context window after the prompt; this caps them further.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_tokens: Option<u16>,
+
+    /// Stop generating a response after this many seconds, keeping what was produced so far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_seconds: Option<u64>,
+
+    /// The sampling temperature of answers, from 0 to 2. Defaults to the backend's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+
+    /// Print the time to the first token, the tokens per second, and the total time after each
+    /// response; see the `timings` module.
+    #[serde(default)]
+    pub show_timings: bool,
+
+    /// Fail with a `budget::ContextDropped` error instead of evicting memories or ejecting earlier
+    /// exchanges to make a request fit in the context window.
+    #[serde(default)]
+    pub strict_context: bool,
+
+    /// The hosts of the backends that templates marked `local_only` may send questions to.
+    /// Defaults to `localhost`, `127.0.0.1`, and `::1`; see the `privacy` module.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub local_hosts: Vec<String>,
+
+    /// Send questions asked with templates marked `local_only` to any backend.
+    #[serde(default)]
+    pub allow_remote: bool,
+
+    /// Whether responses are streamed as they are generated. Defaults to `true`; disable for backends without server-sent events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub should_stream: Option<bool>,
+
+    /// The language responses are written in: `auto` to answer in the language of the question,
+    /// or a language code or name such as `es` or `Spanish`. Templates can override this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub respond_in: Option<String>,
+
+    /// Record which memories were retrieved for each question, and why they were or were not used.
+    #[serde(default)]
+    pub explain_memory: bool,
+
+    /// Suggest a few follow-up questions after each answer; see the `followups` module.
+    #[serde(default)]
+    pub suggest_followups: bool,
+
+    /// Cached repository maps older than this (e.g. `30d`, `12h`) are pruned on startup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_max_age: Option<String>,
+
+    /// The oldest cached repository maps are pruned on startup until the cache is smaller than this (e.g. `2G`, `500M`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_max_size: Option<String>,
+
+    /// Rewrite each question, with the recent turns, into a standalone search query before
+    /// retrieving memories for it.
+    #[serde(default)]
+    pub rag_query_rewrite: bool,
+
+    /// Search for several paraphrases of each query and fuse the results, to improve recall for
+    /// ambiguous questions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rag_multi_query: Option<MultiQueryConfig>,
+
+    /// Instead of recalling a fixed number of memories, keep adding the next-closest memory until
+    /// the conversation and memories fill this share of the context window (e.g. `0.3`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rag_budget_share: Option<f32>,
+
+    /// Where memories and documents are embedded: `local` (the default) runs a sentence embeddings
+    /// model on this machine, `api` calls the backend's `/v1/embeddings` endpoint.
+    #[serde(default)]
+    pub embedding_provider: EmbeddingProvider,
+
+    /// The model the `api` embedding provider asks for. Defaults to `text-embedding-ada-002`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+
+    /// How memories are embedded: `first_sentence` (the default) embeds only each memory's first
+    /// sentence, `chunks` embeds all of it in chunks of up to `memory_chunk_tokens` tokens, so the
+    /// tail of a long answer can be recalled too.
+    #[serde(default)]
+    pub memory_embedding: MemoryEmbedding,
+
+    /// The most tokens in each chunk of a memory when `memory_embedding` is `chunks`. Defaults to
+    /// 256; embedding models truncate longer texts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_chunk_tokens: Option<usize>,
+
+    /// In interactive mode, offer the earlier answer instead of asking again when a question is at
+    /// least this similar (e.g. `0.95`) to one asked before; see the `duplicates` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_question_threshold: Option<f32>,
+
+    /// Periodically merges clusters of similar memories in interactive mode; see the
+    /// `consolidation` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_consolidation: Option<ConsolidationConfig>,
+
+    /// Classifies memories as facts, preferences, or tasks, and recalls and budgets them by kind;
+    /// see the `memory_kinds` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_kinds: Option<MemoryKindsConfig>,
+
+    /// Which memories the brain evicts first when they no longer fit: `fifo` (the default), `lru`,
+    /// or `importance`. Templates can override it; see `brain::EvictionPolicy`.
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+
+    /// Checks answers for refusals, hallucination-risk phrasing, and empty or short answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub postprocess: Option<PostprocessConfig>,
+
+    /// Refuses questions that are too long, contain credentials, or match banned patterns,
+    /// before they are sent; see the `guardrails` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guardrails: Option<GuardrailsConfig>,
+
+    /// Shell commands run before each question is sent and after it is answered; see the
+    /// `plugins` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<HooksConfig>,
+
+    /// A shell command run when an answer took longer than `notify_after`; see the `notify` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify_command: Option<String>,
+
+    /// How long an answer must take before it is announced (e.g. `30s`, `2m`). Defaults to `10s`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify_after: Option<String>,
+
+    /// Whether user messages are stored in memory as they were written. With `false`, only the
+    /// model's restatements of them are stored; see the `paraphrase` module. Defaults to `true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_verbatim: Option<bool>,
+
+    /// Whether answers taller than the terminal are shown in `$PAGER`. Defaults to `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pager: Option<bool>,
+
+    /// Colors and spinner for terminal output; see the `theme` module.
+    #[cfg(feature = "tty")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<ThemeConfig>,
+
+    /// Overrides the defaults picked for the model's family; see the `model_family` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_family: Option<ModelFamilyConfig>,
+
+    /// How system messages are sent: `native` sends them as they are, `prepend_to_first_user`
+    /// puts their content in front of the first user message, for backends that ignore or reject
+    /// system messages. Defaults to what the model's family supports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_role_strategy: Option<SystemRoleStrategy>,
+}
+
+/// Configures multi-query retrieval; see the `retrieval` module.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultiQueryConfig {
+    /// How many paraphrases to search for in addition to the query itself.
+    pub count: usize,
+
+    /// How the paraphrases are generated.
+    #[serde(default)]
+    pub method: ParaphraseMethod,
+}
+
+/// How paraphrases of a query are generated for multi-query retrieval.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParaphraseMethod {
+    /// Keyword and per-sentence variants of the query; free and instant.
+    #[default]
+    Rules,
+
+    /// Paraphrases written by the model, at the cost of an extra request.
+    Llm,
+}
+
+/// Where text is embedded for vector stores; see the `embeddings` module.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingProvider {
+    /// A sentence embeddings model run locally; downloaded on first use.
+    #[default]
+    Local,
+
+    /// The backend's `/v1/embeddings` endpoint, with `embedding_model`.
+    Api,
+
+    /// Deterministic vectors hashed from the words of the text, for tests and CI; no model or
+    /// network is needed, but similarity only reflects shared words.
+    Fake,
+}
+
+/// How much of each memory is embedded; see `AwfulJadeConfig::memory_embedding`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryEmbedding {
+    /// Only the first sentence, which is fast but misses the rest of long memories.
+    #[default]
+    FirstSentence,
+
+    /// All of the memory, split into chunks that are each embedded and searched.
+    Chunks,
+}
+
+/// How system messages are sent to the backend; see `AwfulJadeConfig::system_role_strategy`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemRoleStrategy {
+    /// System messages are sent with the system role.
+    Native,
+
+    /// System messages are merged into the first user message.
+    PrependToFirstUser,
+}
+
+/// Configures memory kinds; see the `memory_kinds` module.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MemoryKindsConfig {
+    /// Classify memories as they are archived, in one extra request for each batch of ejected
+    /// messages. Unclassified memories are of the `conversation` kind.
+    #[serde(default)]
+    pub classify: bool,
+
+    /// Only recall memories of these kinds. Empty recalls every kind.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recall: Vec<MemoryKind>,
+
+    /// Memories of these kinds are recalled with every question, however distant, and are the
+    /// last to be evicted when the memories don't fit.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub always_include: Vec<MemoryKind>,
+
+    /// The most tokens the recalled memories of each kind may use, e.g. `fact: 500`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub max_tokens: BTreeMap<MemoryKind, usize>,
+}
+
+/// Configures memory consolidation; see the `consolidation` module.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConsolidationConfig {
+    /// Consolidate after this many memories were archived since the last consolidation.
+    #[serde(default = "default_consolidate_every")]
+    pub every: usize,
+
+    /// Memories at least this similar (by cosine similarity) to a cluster's first memory are
+    /// merged into it.
+    #[serde(default = "default_consolidation_similarity")]
+    pub similarity: f32,
+}
+
+/// Configures the checks run over questions before they are sent; see the `guardrails` module.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GuardrailsConfig {
+    /// Questions with more tokens than this are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_question_tokens: Option<usize>,
+
+    /// Refuse questions containing credentials, such as AWS keys, API tokens, or private keys.
+    #[serde(default)]
+    pub block_secrets: bool,
+
+    /// Regular expressions; questions matching any of them are refused.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub banned_patterns: Vec<String>,
+}
+
+/// Configures the hooks run around each question; see the `plugins` module.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HooksConfig {
+    /// Commands run, in order, before a question is sent. They may rewrite or refuse it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_ask: Vec<String>,
+
+    /// Commands run after a question is answered.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_answer: Vec<String>,
+}
+
+/// Configures the checks run over answers; see the `postprocess` module.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PostprocessConfig {
+    /// Ask again once, with an adjusted prompt, when an answer is flagged as a refusal or too short.
+    #[serde(default)]
+    pub retry: bool,
+
+    /// Answers with fewer characters than this are flagged as too short.
+    #[serde(default = "default_min_answer_chars")]
+    pub min_answer_chars: usize,
+}
+
+/// Configures the terminal theme; see the `theme` module.
+#[cfg(feature = "tty")]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ThemeConfig {
+    /// The built-in theme the overrides below apply to. Defaults to `dark`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<BuiltinTheme>,
+
+    /// The color of the user's input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// The color of the assistant's responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assistant: Option<String>,
+
+    /// The color of messages from aj itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+
+    /// The frames of the spinner shown while waiting on slow tasks, e.g. `⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spinner: Option<String>,
+}
+
+/// Overrides the defaults of the model's family; see the `model_family` module.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModelFamilyConfig {
+    /// The family to use instead of detecting one from the model name, e.g. `chatml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// The stop words added for the family, replacing the built-in ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_words: Option<Vec<String>>,
+
+    /// Whether the model understands system messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supports_system_role: Option<bool>,
+
+    /// Whether, without system role support, system messages are merged into the first user
+    /// message rather than sent as user messages of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge_system_into_user: Option<bool>,
+}
+
+impl Default for PostprocessConfig {
+    fn default() -> Self {
+        Self {
+            retry: false,
+            min_answer_chars: default_min_answer_chars(),
+        }
+    }
+}
+
+fn default_min_answer_chars() -> usize {
+    1
+}
+
+impl Default for ConsolidationConfig {
+    fn default() -> Self {
+        Self {
+            every: default_consolidate_every(),
+            similarity: default_consolidation_similarity(),
+        }
+    }
+}
+
+fn default_consolidate_every() -> usize {
+    32
+}
+
+fn default_consolidation_similarity() -> f32 {
+    0.85
+}
+
+impl AwfulJadeConfig {
+    /// Returns whether responses should be streamed, defaulting to `true`.
+    pub fn should_stream(&self) -> bool {
+        self.should_stream.unwrap_or(true)
+    }
+
+    /// Returns whether long answers should be shown in a pager, defaulting to `false`.
+    pub fn use_pager(&self) -> bool {
+        self.pager.unwrap_or(false)
+    }
+
+    /// Returns whether user messages are stored in memory verbatim, defaulting to `true`.
+    pub fn store_verbatim(&self) -> bool {
+        self.store_verbatim.unwrap_or(true)
+    }
+}
+
+/// Parses a human-readable duration such as `30d`, `12h`, `15m`, or `90s`.
+///
+/// A bare number is interpreted as seconds.
+///
+/// # Returns
+///
+/// - `Ok(Duration)`: The parsed duration.
+/// - `Err(String)`: A description of why the value could not be parsed.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('d') => (&value[..value.len() - 1], 24 * 60 * 60),
+        Some('h') => (&value[..value.len() - 1], 60 * 60),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('s') => (&value[..value.len() - 1], 1),
+        _ => (value, 1),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| {
+        format!(
+            "Invalid duration `{}`, expected e.g. `30d`, `12h`, or `15m`",
+            value
+        )
+    })?;
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+/// Parses a human-readable size such as `2G`, `500M`, `64K`, or `1024`.
+///
+/// Units are binary (`1K` is 1024 bytes) and an optional trailing `B` is accepted.
+///
+/// # Returns
+///
+/// - `Ok(u64)`: The parsed size in bytes.
+/// - `Err(String)`: A description of why the value could not be parsed.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let upper = value.to_uppercase();
+    let upper = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, multiplier) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1u64 << 10),
+        Some('M') => (&upper[..upper.len() - 1], 1u64 << 20),
+        Some('G') => (&upper[..upper.len() - 1], 1u64 << 30),
+        Some('T') => (&upper[..upper.len() - 1], 1u64 << 40),
+        _ => (upper, 1),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| {
+        format!(
+            "Invalid size `{}`, expected e.g. `2G`, `500M`, or `64K`",
+            value
+        )
+    })?;
+    Ok(number * multiplier)
 }
 
 /// Loads the application's configuration from a YAML file.
 ///
 /// This function reads the file at the given path, parses it as YAML, and
-/// constructs an `AwfulJadeConfig` struct from it.
+/// constructs an `AwfulJadeConfig` struct from it. The configuration is then checked by
+/// `validation::check`: its warnings are printed to stderr, and its errors are returned.
 ///
 /// # Parameters
 ///
@@ -56,7 +509,8 @@ pub struct AwfulJadeConfig {
 /// # Returns
 ///
 /// - `Ok(AwfulJadeConfig)`: The loaded configuration.
-/// - `Err(Box<dyn Error>)`: An error occurred while reading the file or parsing the YAML.
+/// - `Err(Box<dyn Error>)`: An error occurred while reading the file or parsing the YAML, or the
+///   configuration is invalid (a `validation::InvalidConfig`).
 ///
 /// # Examples
 ///
@@ -70,6 +524,13 @@ pub struct AwfulJadeConfig {
 /// }
 /// ```
 pub fn load_config(file: &str) -> Result<AwfulJadeConfig, Box<dyn Error>> {
+    let config = parse_config(file)?;
+    validation::check(&config)?;
+    Ok(config)
+}
+
+/// Reads and parses the configuration in `file` like `load_config`, without validating it.
+pub fn parse_config(file: &str) -> Result<AwfulJadeConfig, Box<dyn Error>> {
     let content = fs::read_to_string(file)?;
     let config: AwfulJadeConfig = serde_yaml::from_str(&content)?;
     Ok(config)
@@ -132,4 +593,48 @@ stop_words: ["<|im_end|>", "\n"]
         // Assert that an error occurred due to the invalid format.
         assert!(config.is_err());
     }
+
+    #[test]
+    fn test_load_config_rejects_invalid_values() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            r#"
+api_key: "example_api_key"
+api_base: "http://example.com"
+model: "example_model"
+context_max_tokens: 0
+assistant_minimum_context_tokens: 2048
+stop_words: []
+"#
+        )
+        .unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        let error = load_config(path).unwrap_err();
+        let invalid = error
+            .downcast_ref::<validation::InvalidConfig>()
+            .expect("Expected validation errors");
+        assert_eq!(invalid.0[0].field, "context_max_tokens");
+        assert!(parse_config(path).is_ok());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("500mb"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert!(parse_size("big").is_err());
+    }
 }
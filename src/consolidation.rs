@@ -0,0 +1,334 @@
+//! This module consolidates memories: clusters of similar memories are merged into one.
+//!
+//! Long conversations archive the same facts over and over ("the project uses tokio", "we're on
+//! tokio 1.33", ...), which wastes the memory budget on repetition. With `memory_consolidation`
+//! configured, interactive mode periodically clusters memories that are at least `similarity`
+//! alike, asks the model to merge each cluster into a single memory, and rebuilds the store with
+//! the merged memories in place of the originals. `/consolidate` runs it on demand:
+//!
+//! ```yaml
+//! memory_consolidation:
+//!   every: 32
+//!   similarity: 0.85
+//! ```
+//!
+//! The merging prompt can be customized with a `memory_consolidation` template; without one, a
+//! built-in prompt is used.
+
+use crate::{
+    api,
+    brain::Memory,
+    config::{AwfulJadeConfig, ConsolidationConfig},
+    duplicates::cosine_similarity,
+    model_family::ModelDefaults,
+    template::{self, ChatTemplate},
+    vector_store::VectorStore,
+};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role};
+use std::{error::Error, fmt};
+use tracing::{debug, error};
+
+/// The name of the template used to merge memories.
+pub const CONSOLIDATION_TEMPLATE: &str = "memory_consolidation";
+
+/// The most memories merged into one.
+pub const MAX_CLUSTER_SIZE: usize = 8;
+
+/// The system prompt used when no `memory_consolidation` template exists.
+const DEFAULT_CONSOLIDATION_PROMPT: &str = "You merge overlapping memories of a conversation into a single memory. Keep every distinct fact, drop the repetition, and reply with only the merged memory.";
+
+/// Returns the built-in memory merging template.
+pub fn default_consolidation_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_CONSOLIDATION_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// What a consolidation did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsolidationReport {
+    /// How many memories the store held before.
+    pub before: usize,
+
+    /// How many memories the store holds now.
+    pub after: usize,
+
+    /// How many clusters were merged.
+    pub clusters: usize,
+}
+
+impl fmt::Display for ConsolidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Merged {} clusters of similar memories; {} memories remain of {}.",
+            self.clusters, self.after, self.before
+        )
+    }
+}
+
+/// Groups memories into clusters, each started by the oldest memory not yet in one.
+///
+/// A cluster takes the memories with the same role whose vectors are at least `threshold` similar
+/// to its first memory's, up to `MAX_CLUSTER_SIZE`. Memories that are like no other form clusters
+/// of their own.
+///
+/// # Parameters
+///
+/// - `memories`: The role and vector of each memory, oldest first.
+/// - `threshold`: The least cosine similarity for memories to be merged.
+///
+/// # Returns
+///
+/// The clusters, as indices into `memories`, ordered by their first memory.
+pub fn cluster(memories: &[(Role, Vec<f32>)], threshold: f32) -> Vec<Vec<usize>> {
+    let mut clustered = vec![false; memories.len()];
+    let mut clusters = Vec::new();
+    for (first, (role, vector)) in memories.iter().enumerate() {
+        if clustered[first] {
+            continue;
+        }
+        clustered[first] = true;
+        let mut members = vec![first];
+        for (other, (other_role, other_vector)) in memories.iter().enumerate().skip(first + 1) {
+            if members.len() == MAX_CLUSTER_SIZE {
+                break;
+            }
+            if !clustered[other]
+                && other_role == role
+                && cosine_similarity(vector, other_vector) >= threshold
+            {
+                clustered[other] = true;
+                members.push(other);
+            }
+        }
+        clusters.push(members);
+    }
+    clusters
+}
+
+/// Builds the messages asking the model to merge `memories` into one.
+pub fn merge_messages(
+    template: &ChatTemplate,
+    memories: &[&Memory],
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut prompt = String::from("Memories:\n");
+    for memory in memories {
+        prompt.push_str(&format!("- {}\n", memory.content()));
+    }
+    prompt.push_str("\nMerged memory:");
+
+    let mut messages = api::prepare_messages(template).unwrap_or_default();
+    messages.push(ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(prompt),
+        name: None,
+        function_call: None,
+    });
+    messages
+}
+
+/// Asks the model to merge `memories` into a single memory.
+pub async fn merge(
+    config: &AwfulJadeConfig,
+    template: &ChatTemplate,
+    memories: &[&Memory],
+) -> Result<String, Box<dyn Error>> {
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(config.model.clone())
+        .stop(template.stop_words(config, &[]))
+        .messages(
+            ModelDefaults::from_config(config).adapt_messages(merge_messages(template, memories)),
+        )
+        .build()?;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let merged = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(merged.trim().to_string())
+}
+
+/// Consolidates the memories in `vector_store`, replacing it with a rebuilt store.
+///
+/// Each cluster of similar memories (see `cluster`) is merged by the model into one memory, which
+/// takes the place of the cluster's first memory. When a cluster cannot be merged, its memories
+/// are kept as they were.
+///
+/// # Returns
+///
+/// - `Ok(ConsolidationReport)`: What was merged.
+/// - `Err(Box<dyn Error>)`: The memories could not be embedded, or the store rebuilt. The store
+///   is left unchanged.
+pub async fn consolidate(
+    config: &AwfulJadeConfig,
+    consolidation: &ConsolidationConfig,
+    vector_store: &mut VectorStore,
+) -> Result<ConsolidationReport, Box<dyn Error>> {
+    let memories = vector_store.memories();
+    let mut report = ConsolidationReport {
+        before: memories.len(),
+        after: memories.len(),
+        clusters: 0,
+    };
+    if memories.len() < 2 {
+        return Ok(report);
+    }
+
+    let mut embedded = Vec::with_capacity(memories.len());
+    for (_, memory) in &memories {
+        let vector = vector_store.embed_text_to_vector(memory.content()).await?;
+        embedded.push((memory.role().clone(), vector));
+    }
+
+    let template = template::load_template(CONSOLIDATION_TEMPLATE)
+        .await
+        .unwrap_or_else(|_| default_consolidation_template());
+    let mut rebuilt = VectorStore::with_embedder(vector_store.dimension(), vector_store.embedder())
+        .with_chunk_tokens(vector_store.chunk_tokens());
+    for members in cluster(&embedded, consolidation.similarity) {
+        if members.len() > 1 {
+            let cluster_memories: Vec<&Memory> = members.iter().map(|&i| memories[i].1).collect();
+            match merge(config, &template, &cluster_memories).await {
+                Ok(merged) if !merged.is_empty() => {
+                    debug!("Merged {} memories into: {}", members.len(), merged);
+                    // The merged memory keeps the role and kind of the cluster's first memory
+                    let first = memories[members[0]].1;
+                    let memory = Memory::new(first.role().clone(), merged).with_kind(first.kind());
+                    rebuilt.add_memory(memory).await?;
+                    report.clusters += 1;
+                    continue;
+                }
+                Ok(_) => error!("The model returned an empty merged memory"),
+                Err(e) => error!("Failed to merge memories: {}", e),
+            }
+        }
+        for &i in &members {
+            if rebuilt.chunk_tokens().is_some() {
+                rebuilt.add_memory(memories[i].1.clone()).await?;
+            } else {
+                rebuilt.add_vector_with_content(embedded[i].1.clone(), memories[i].1.clone())?;
+            }
+        }
+    }
+
+    rebuilt.build()?;
+    report.after = rebuilt.len();
+    *vector_store = rebuilt;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::Embedder;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    fn embedding(vector: [f32; 3]) -> serde_json::Value {
+        json!({
+            "object": "list",
+            "model": "mock_embedding_model",
+            "data": [{"object": "embedding", "index": 0, "embedding": vector}],
+            "usage": {"prompt_tokens": 1, "total_tokens": 1}
+        })
+    }
+
+    #[test]
+    fn test_cluster() {
+        let memories = vec![
+            (Role::User, vec![1.0, 0.0]),
+            (Role::User, vec![0.0, 1.0]),
+            (Role::User, vec![0.99, 0.1]),
+            (Role::Assistant, vec![1.0, 0.0]),
+        ];
+        assert_eq!(cluster(&memories, 0.9), vec![vec![0, 2], vec![1], vec![3]]);
+    }
+
+    #[tokio::test]
+    async fn test_consolidate() {
+        let server = MockServer::start();
+        for (text, vector) in [
+            ("The project uses tokio.", [1.0, 0.0, 0.0]),
+            ("We're on tokio version one.", [0.95, 0.1, 0.0]),
+            ("Cats sleep a lot.", [0.0, 1.0, 0.0]),
+            ("The project uses tokio version one.", [1.0, 0.05, 0.0]),
+        ] {
+            server.mock(|when, then| {
+                when.method(POST)
+                    .path("/embeddings")
+                    .json_body_partial(json!({ "input": [text] }).to_string());
+                then.status(200).json_body(embedding(vector));
+            });
+        }
+        let merge_mock = server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "choices": [
+                        {
+                            "message": {"role": "assistant", "content": "The project uses tokio version one."},
+                            "finish_reason": "stop",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            model: "mock_model".to_string(),
+            ..Default::default()
+        };
+        let embedder = Embedder::Api {
+            client: api::create_client(&config).unwrap(),
+            model: "mock_embedding_model".to_string(),
+        };
+        let mut vector_store = VectorStore::with_embedder(3, embedder);
+        for text in [
+            "The project uses tokio.",
+            "Cats sleep a lot.",
+            "We're on tokio version one.",
+        ] {
+            let vector = vector_store.embed_text_to_vector(text).await.unwrap();
+            let memory = Memory::new(Role::User, text.to_string());
+            vector_store
+                .add_vector_with_content(vector, memory)
+                .unwrap();
+        }
+        vector_store.build().unwrap();
+
+        let report = consolidate(&config, &ConsolidationConfig::default(), &mut vector_store)
+            .await
+            .unwrap();
+        merge_mock.assert();
+        assert_eq!(
+            report,
+            ConsolidationReport {
+                before: 3,
+                after: 2,
+                clusters: 1
+            }
+        );
+        let contents: Vec<&str> = vector_store
+            .memories()
+            .into_iter()
+            .map(|(_, memory)| memory.content())
+            .collect();
+        assert_eq!(
+            contents,
+            vec!["The project uses tokio version one.", "Cats sleep a lot."]
+        );
+    }
+}
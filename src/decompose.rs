@@ -0,0 +1,236 @@
+//! This module splits a question made of several distinct questions into its parts.
+//!
+//! With `aj ask --decompose`, the model is first asked, in a small request, which separate
+//! questions the question holds. Each sub-question is then asked on its own, with the memories
+//! recalled for it alone, and the answers are merged into one answer with a section for each:
+//!
+//! ```text
+//! ## How do I rotate the API key?
+//!
+//! ...
+//!
+//! ## Why does the staging deploy time out?
+//!
+//! ...
+//! ```
+//!
+//! A question the model finds only one question in is answered as it is. The splitting prompt can
+//! be customized with a `decompose_question` template; without one, a built-in prompt is used.
+
+use crate::{
+    api::{self, Completion},
+    config::AwfulJadeConfig,
+    model_family::ModelDefaults,
+    template::{self, ChatTemplate},
+};
+use async_openai::types::{
+    ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role, Usage,
+};
+use std::error::Error;
+
+/// The name of the template used to split questions.
+pub const DECOMPOSE_TEMPLATE: &str = "decompose_question";
+
+/// The most sub-questions a question is split into.
+pub const MAX_SUB_QUESTIONS: usize = 5;
+
+/// The most tokens the model may spend on the sub-questions.
+const MAX_DECOMPOSE_TOKENS: u16 = 256;
+
+/// The system prompt used when no `decompose_question` template exists.
+const DEFAULT_DECOMPOSE_PROMPT: &str = "You split messages into the distinct questions they ask. Reply with each question on its own line, rewritten to make sense on its own, and nothing else. If the message asks only one thing, reply with it unchanged.";
+
+/// Returns the built-in question splitting template.
+pub fn default_decompose_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_DECOMPOSE_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Builds the messages asking the model for the questions `question` holds.
+pub fn decompose_messages(
+    template: &ChatTemplate,
+    question: &str,
+) -> Vec<ChatCompletionRequestMessage> {
+    let prompt = format!("Message:\n{}\n\nQuestions:", question);
+
+    let mut messages = api::prepare_messages(template).unwrap_or_default();
+    messages.push(ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(prompt),
+        name: None,
+        function_call: None,
+    });
+    messages
+}
+
+/// Parses the model's reply into at most `MAX_SUB_QUESTIONS` questions, without their numbering.
+pub fn parse_sub_questions(reply: &str) -> Vec<String> {
+    reply
+        .lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*')
+                })
+                .trim()
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .take(MAX_SUB_QUESTIONS)
+        .collect()
+}
+
+/// Asks the model for the distinct questions `question` holds.
+pub async fn decompose(
+    config: &AwfulJadeConfig,
+    question: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let template = template::load_template(DECOMPOSE_TEMPLATE)
+        .await
+        .unwrap_or_else(|_| default_decompose_template());
+    let mut request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(MAX_DECOMPOSE_TOKENS)
+        .model(config.model.clone())
+        .stop(template.stop_words(config, &[]))
+        .messages(
+            ModelDefaults::from_config(config)
+                .adapt_messages(decompose_messages(&template, question)),
+        )
+        .build()?;
+    request.temperature = config.temperature;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let reply = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(parse_sub_questions(&reply))
+}
+
+/// Returns the heading of the section answering `sub_question`.
+pub fn section_heading(sub_question: &str) -> String {
+    format!("## {}\n\n", sub_question)
+}
+
+/// Merges the answers to the sub-questions, in order, into one completion whose content has a
+/// section for each.
+///
+/// The merged completion is truncated if any answer was, and reports that answer's finish reason.
+/// Usage is the sum of the answers' when every answer reports it, and timings add up; the first
+/// token is the first answer's.
+pub fn merge(sections: Vec<(String, Completion)>) -> Completion {
+    let content: Vec<String> = sections
+        .iter()
+        .map(|(sub_question, completion)| {
+            format!(
+                "{}{}",
+                section_heading(sub_question),
+                completion.content().trim()
+            )
+        })
+        .collect();
+    let finish_reason = sections
+        .iter()
+        .find(|(_, completion)| completion.is_truncated())
+        .or_else(|| sections.last())
+        .and_then(|(_, completion)| completion.finish_reason.clone());
+    let usage = sections
+        .iter()
+        .map(|(_, completion)| completion.usage.clone())
+        .try_fold(
+            Usage {
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+            },
+            |sum, usage| {
+                let usage = usage?;
+                Some(Usage {
+                    prompt_tokens: sum.prompt_tokens + usage.prompt_tokens,
+                    completion_tokens: sum.completion_tokens + usage.completion_tokens,
+                    total_tokens: sum.total_tokens + usage.total_tokens,
+                })
+            },
+        );
+
+    let mut merged = match sections.first() {
+        Some((_, first)) => first.clone(),
+        None => return Completion::default(),
+    };
+    merged.message.content = Some(content.join("\n\n"));
+    merged.finish_reason = finish_reason;
+    merged.usage = usage;
+    merged.timed_out = sections.iter().any(|(_, completion)| completion.timed_out);
+    merged.timings.total = sections
+        .iter()
+        .map(|(_, completion)| completion.timings.total)
+        .sum();
+    merged.timings.tokens = sections
+        .iter()
+        .map(|(_, completion)| completion.timings.tokens)
+        .sum();
+    merged.sources = sections
+        .into_iter()
+        .flat_map(|(_, completion)| completion.sources)
+        .collect();
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timings::Timings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_sub_questions() {
+        let reply = "1. How do I rotate the API key?\n\n2) Why does staging time out?\n";
+        assert_eq!(
+            parse_sub_questions(reply),
+            vec!["How do I rotate the API key?", "Why does staging time out?"]
+        );
+    }
+
+    fn completion(content: &str, finish_reason: &str, seconds: u64) -> Completion {
+        Completion {
+            message: ChatCompletionRequestMessage {
+                role: Role::Assistant,
+                content: Some(content.to_string()),
+                ..Default::default()
+            },
+            finish_reason: Some(finish_reason.to_string()),
+            timings: Timings {
+                total: Duration::from_secs(seconds),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let merged = merge(vec![
+            (
+                "How do I rotate the key?".to_string(),
+                completion("Run `aj key rotate`.\n", "stop", 2),
+            ),
+            (
+                "Why does staging time out?".to_string(),
+                completion("The health check", "length", 3),
+            ),
+        ]);
+        assert_eq!(
+            merged.content(),
+            "## How do I rotate the key?\n\nRun `aj key rotate`.\n\n## Why does staging time out?\n\nThe health check"
+        );
+        assert_eq!(merged.finish_reason.as_deref(), Some("length"));
+        assert!(merged.is_truncated());
+        assert_eq!(merged.usage, None);
+        assert_eq!(merged.timings.total, Duration::from_secs(5));
+    }
+}
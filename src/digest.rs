@@ -0,0 +1,514 @@
+//! This module writes digests of recently changed documents, for scheduled jobs.
+//!
+//! `aj digest` gathers the documents in a directory that changed within a time window, hands as
+//! many of them as fit in the context window to the model (newest first), and appends the answer
+//! to a file with a small YAML header recording when it was written, from which documents, and
+//! why the model stopped (`length` means the digest was cut off):
+//!
+//! ```text
+//! 0 7 * * * aj digest --since 24h --rag ~/news --template daily_summary --out ~/digest.md
+//! ```
+//!
+//! Nothing is printed to stdout when `--out` is given, and failures exit with a nonzero status,
+//! so cron only sends mail when something went wrong.
+//!
+//! Documents are read in bounded windows, and reading stops as soon as a document is too large for
+//! what is left of the context window, so a directory holding multi-gigabyte logs or dumps is
+//! digested with flat memory use.
+
+use crate::{
+    api::{self, AskOptions, Completion},
+    config::AwfulJadeConfig,
+    template::ChatTemplate,
+    tokens,
+};
+use ignore::WalkBuilder;
+use serde::Serialize;
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+/// The template used when `--template` is not given, if it exists.
+pub const DIGEST_TEMPLATE: &str = "digest";
+
+/// The question asked about the documents.
+pub const DIGEST_QUESTION: &str =
+    "Write a digest of the documents above: the main points, grouped by topic, with the file each point comes from.";
+
+/// The system prompt used when no `digest` template exists.
+const DEFAULT_DIGEST_PROMPT: &str = "You summarize collections of documents into concise digests written in Markdown. Only report what the documents say.";
+
+/// Returns the built-in digest template.
+pub fn default_digest_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_DIGEST_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// The bytes of a document read at a time.
+const READ_WINDOW: usize = 64 * 1024;
+
+/// A file that changed within the digest's time window, before it is read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentFile {
+    /// Where the file is.
+    pub path: PathBuf,
+
+    /// The file's path, relative to the directory it was found in.
+    pub name: String,
+
+    /// When the file was last modified.
+    pub modified: SystemTime,
+}
+
+/// A text document that changed within the digest's time window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    /// The document's path, relative to the directory it was found in.
+    pub path: String,
+
+    /// When the document was last modified.
+    pub modified: SystemTime,
+
+    /// The document's text.
+    pub content: String,
+}
+
+/// Returns the files under `dir` modified less than `since` ago, newest first, without reading
+/// them.
+///
+/// Hidden and ignored files are skipped.
+pub fn recent_files(dir: &Path, since: Duration) -> Result<Vec<RecentFile>, Box<dyn Error>> {
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()).into());
+    }
+    let cutoff = SystemTime::now().checked_sub(since).unwrap_or(UNIX_EPOCH);
+
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(dir).require_git(false).build() {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if modified < cutoff {
+            continue;
+        }
+        let name = entry
+            .path()
+            .strip_prefix(dir)
+            .unwrap_or(entry.path())
+            .to_string_lossy()
+            .into_owned();
+        files.push(RecentFile {
+            path: entry.into_path(),
+            name,
+            modified,
+        });
+    }
+    files.sort_by(|a, b| b.modified.cmp(&a.modified).then(a.name.cmp(&b.name)));
+    Ok(files)
+}
+
+/// Returns the text documents under `dir` modified less than `since` ago, newest first.
+///
+/// Hidden and ignored files are skipped, as are files that are not valid UTF-8. Every document is
+/// read whole; `digest` reads only what fits in the context window, see `read_within_tokens`.
+pub fn recent_documents(dir: &Path, since: Duration) -> Result<Vec<Document>, Box<dyn Error>> {
+    let mut documents = Vec::new();
+    for file in recent_files(dir, since)? {
+        let Ok(content) = fs::read_to_string(&file.path) else {
+            debug!("Skipping non-text document {}", file.path.display());
+            continue;
+        };
+        documents.push(Document {
+            path: file.name,
+            modified: file.modified,
+            content,
+        });
+    }
+    Ok(documents)
+}
+
+/// Reads the text file at `path` if it has at most `max_tokens` tokens.
+///
+/// The file is read `READ_WINDOW` bytes at a time, and each window's tokens are counted up to its
+/// last whitespace, with the rest carried over so no word is split between windows and the counts
+/// add up to the whole file's. Reading stops
+/// as soon as the count passes `max_tokens`, so memory use is bounded by the budget, not by the
+/// size of the file.
+///
+/// # Returns
+///
+/// - `Ok(Some(String))`: The file's text.
+/// - `Ok(None)`: The file has more than `max_tokens` tokens.
+/// - `Err(io::Error)`: The file could not be read, or is not valid UTF-8.
+pub fn read_within_tokens(
+    path: &Path,
+    max_tokens: usize,
+    model: &str,
+) -> io::Result<Option<String>> {
+    let mut reader = BufReader::with_capacity(READ_WINDOW, File::open(path)?);
+    let mut content = String::new();
+    let mut counted = 0;
+    // Bytes read but not counted yet: the last, possibly partial, word of the previous window.
+    let mut pending: Vec<u8> = Vec::new();
+
+    loop {
+        let window = reader.fill_buf()?;
+        let done = window.is_empty();
+        pending.extend_from_slice(window);
+        let read = window.len();
+        reader.consume(read);
+
+        let end = match pending.iter().rposition(u8::is_ascii_whitespace) {
+            _ if done => pending.len(),
+            // Whitespace starts the next window, where the tokenizer attaches it to the next word.
+            Some(last_space) if last_space > 0 => last_space,
+            // Without whitespace, wait for a few more windows before splitting a word.
+            _ if pending.len() < 4 * READ_WINDOW => continue,
+            _ => match std::str::from_utf8(&pending) {
+                Ok(_) => pending.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            },
+        };
+        let text = std::str::from_utf8(&pending[..end])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        counted += tokens::count(text, model);
+        if counted > max_tokens {
+            return Ok(None);
+        }
+        content.push_str(text);
+        pending.drain(..end);
+
+        if done {
+            return Ok(Some(content));
+        }
+    }
+}
+
+/// Takes documents in order while they fit in `budget` tokens, and renders them for the prompt.
+///
+/// # Returns
+///
+/// The rendered documents and the paths of the documents that were included.
+pub fn render_within_budget(
+    documents: &[Document],
+    budget: usize,
+    model: &str,
+) -> (String, Vec<String>) {
+    let mut rendered = String::new();
+    let mut used = 0;
+    let mut included = Vec::new();
+    for document in documents {
+        let section = format!("=== {} ===\n{}\n\n", document.path, document.content.trim());
+        let tokens = tokens::count(&section, model);
+        if used + tokens > budget {
+            debug!("Leaving {} out of the digest", document.path);
+            continue;
+        }
+        used += tokens;
+        rendered.push_str(&section);
+        included.push(document.path.clone());
+    }
+    (rendered, included)
+}
+
+/// The header written above each digest.
+#[derive(Debug, Serialize)]
+struct DigestHeader<'a> {
+    generated_at: String,
+    since: &'a str,
+    sources: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<&'a str>,
+}
+
+/// Formats a digest as an entry to append to the output file: a YAML header between `---` lines,
+/// followed by the digest.
+///
+/// # Parameters
+///
+/// - `generated_at`: When the digest was written.
+/// - `since`: The time window, as given on the command line (e.g. `24h`).
+/// - `sources`: The paths of the documents the digest was written from.
+/// - `completion`: The model's answer.
+pub fn format_entry(
+    generated_at: SystemTime,
+    since: &str,
+    sources: &[String],
+    completion: &Completion,
+) -> Result<String, Box<dyn Error>> {
+    let header = serde_yaml::to_string(&DigestHeader {
+        generated_at: utc_timestamp(generated_at),
+        since,
+        sources,
+        finish_reason: completion.finish_reason.as_deref(),
+    })?;
+    Ok(format!(
+        "---\n{}---\n\n{}\n\n",
+        header,
+        completion.content().trim()
+    ))
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp, e.g. `2024-03-01T07:00:00Z`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Converts days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Appends `entry` to the file at `path`, creating it if needed.
+pub fn append_entry(path: &Path, entry: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(entry.as_bytes())?;
+    Ok(())
+}
+
+/// Options for `digest`.
+pub struct DigestOptions {
+    /// The directory whose documents are summarized.
+    pub dir: PathBuf,
+
+    /// How recently a document must have changed to be included.
+    pub since: Duration,
+
+    /// The time window as given by the user, recorded in the entry's header.
+    pub since_label: String,
+}
+
+/// Writes a digest of the documents in `options.dir` that changed within `options.since`.
+///
+/// The documents are handed to the model as context, newest first, for as long as they leave
+/// `assistant_minimum_context_tokens` free for the answer. Nothing is printed.
+///
+/// # Returns
+///
+/// - `Ok(Some(String))`: The entry to append, see `format_entry`.
+/// - `Ok(None)`: No document changed within the window, so there is nothing to summarize.
+/// - `Err(Box<dyn Error>)`: The documents could not be read, none fit in the context window, or
+///   the request failed.
+pub async fn digest(
+    config: &AwfulJadeConfig,
+    mut template: ChatTemplate,
+    options: &DigestOptions,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let files = recent_files(&options.dir, options.since)?;
+    if files.is_empty() {
+        debug!(
+            "No documents in {} changed in the last {}",
+            options.dir.display(),
+            options.since_label
+        );
+        return Ok(None);
+    }
+
+    let mut prompt = api::prepare_messages(&template)?;
+    prompt.extend(template.handshake(&template.context_framing(), ""));
+    let used = tokens::count_messages(&prompt, &config.model)
+        + tokens::count(DIGEST_QUESTION, &config.model);
+    let budget = (config.context_max_tokens as usize)
+        .saturating_sub(config.assistant_minimum_context_tokens as usize)
+        .saturating_sub(used);
+
+    // Only documents that can still fit are read, and no further than the budget left.
+    let mut documents = Vec::new();
+    let mut remaining = budget;
+    for file in &files {
+        let header = tokens::count(&format!("=== {} ===\n", file.name), &config.model);
+        let Some(max_tokens) = remaining.checked_sub(header).filter(|&max| max > 0) else {
+            continue;
+        };
+        match read_within_tokens(&file.path, max_tokens, &config.model) {
+            Ok(Some(content)) => {
+                remaining =
+                    remaining.saturating_sub(header + tokens::count(&content, &config.model));
+                documents.push(Document {
+                    path: file.name.clone(),
+                    modified: file.modified,
+                    content,
+                });
+            }
+            Ok(None) => debug!("Leaving {} out of the digest", file.name),
+            Err(e) => debug!("Skipping unreadable document {}: {}", file.name, e),
+        }
+    }
+
+    let (rendered, sources) = render_within_budget(&documents, budget, &config.model);
+    if sources.is_empty() {
+        return Err(format!(
+            "None of the {} changed documents fit in the context window",
+            files.len()
+        )
+        .into());
+    }
+    debug!("Digesting {} of {} documents", sources.len(), files.len());
+
+    let handshake = template.handshake(&template.context_framing(), &rendered);
+    template.prepend_messages(handshake);
+    let quiet = AskOptions {
+        quiet: true,
+        ..Default::default()
+    };
+    let completion = api::ask_for_completion(config, DIGEST_QUESTION, &template, quiet).await?;
+    if completion.content().trim().is_empty() {
+        return Err("The model returned an empty digest".into());
+    }
+
+    Ok(Some(format_entry(
+        SystemTime::now(),
+        &options.since_label,
+        &sources,
+        &completion,
+    )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_218_800);
+        assert_eq!(utc_timestamp(leap_day), "2024-02-29T15:00:00Z");
+    }
+
+    #[test]
+    fn test_render_within_budget() {
+        let document = |path: &str, content: &str| Document {
+            path: path.to_string(),
+            modified: UNIX_EPOCH,
+            content: content.to_string(),
+        };
+        let documents = vec![
+            document("short.md", "Rust 1.80 was released."),
+            document("long.md", &"word ".repeat(500)),
+            document("also_short.md", "Tokio 2.0 was announced."),
+        ];
+        let (rendered, included) = render_within_budget(&documents, 60, "gpt-4");
+        assert_eq!(included, vec!["short.md", "also_short.md"]);
+        assert!(rendered.contains("=== short.md ===\nRust 1.80 was released."));
+    }
+
+    #[test]
+    fn test_read_within_tokens() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("large.log");
+        // Larger than a read window, so words span windows.
+        let content = "lorem ipsum dolor ".repeat(8_000);
+        fs::write(&path, &content).unwrap();
+        let all = tokens::count(&content, "gpt-4");
+
+        assert_eq!(
+            read_within_tokens(&path, all, "gpt-4").unwrap(),
+            Some(content)
+        );
+        assert_eq!(read_within_tokens(&path, 1_000, "gpt-4").unwrap(), None);
+
+        let binary = dir.path().join("image.png");
+        fs::write(&binary, [0x89, b'P', b'N', b'G', 0xff, 0xfe]).unwrap();
+        assert!(read_within_tokens(&binary, all, "gpt-4").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_digest_appends_entry() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/chat/completions")
+                .body_contains("Rust 1.80 was released.");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "choices": [
+                        {
+                            "message": {"role": "assistant", "content": "- Rust 1.80 is out (rust.md)"},
+                            "finish_reason": "stop",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("rust.md"), "Rust 1.80 was released.").unwrap();
+        let config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            model: "mock_model".to_string(),
+            context_max_tokens: 8192,
+            assistant_minimum_context_tokens: 2048,
+            ..Default::default()
+        };
+        let options = DigestOptions {
+            dir: dir.path().to_path_buf(),
+            since: Duration::from_secs(3_600),
+            since_label: "1h".to_string(),
+        };
+
+        let entry = digest(&config, default_digest_template(), &options)
+            .await
+            .unwrap()
+            .expect("the document changed within the window");
+        mock.assert();
+        assert!(entry.starts_with("---\ngenerated_at: "));
+        assert!(entry.contains(
+            "since: 1h\nsources:\n- rust.md\nfinish_reason: stop\n---\n\n- Rust 1.80 is out"
+        ));
+
+        let out = dir.path().join("digest.md");
+        append_entry(&out, &entry).unwrap();
+        append_entry(&out, &entry).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), entry.repeat(2));
+
+        let options = DigestOptions {
+            since: Duration::ZERO,
+            ..options
+        };
+        assert_eq!(
+            digest(&config, default_digest_template(), &options)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
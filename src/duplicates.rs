@@ -0,0 +1,198 @@
+//! This module notices when a question was already asked in the conversation.
+//!
+//! With `duplicate_question_threshold` set, interactive mode looks for an earlier question that
+//! is nearly identical to the new one, among the recent turns and the archived memories. If one
+//! is at least that similar (by cosine similarity of their embeddings, e.g. `0.95`), its answer is
+//! shown and the user is asked whether to ask again, saving the time and tokens of a new answer.
+
+use crate::{config::AwfulJadeConfig, vector_store::VectorStore};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use std::error::Error;
+
+/// How many archived memories are considered as earlier questions.
+const ARCHIVED_CANDIDATES: usize = 5;
+
+/// An earlier question similar to the new one, and the answer it got.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviousAnswer {
+    /// The earlier question.
+    pub question: String,
+
+    /// The answer to the earlier question.
+    pub answer: String,
+
+    /// The cosine similarity of the two questions.
+    pub similarity: f32,
+}
+
+/// Returns the cosine similarity of two vectors, or 0 if either is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// Returns the questions in `conversation` that were answered, with their answers.
+fn answered_questions(conversation: &[ChatCompletionRequestMessage]) -> Vec<(String, String)> {
+    conversation
+        .windows(2)
+        .filter(|pair| pair[0].role == Role::User && pair[1].role == Role::Assistant)
+        .filter_map(|pair| Some((pair[0].content.clone()?, pair[1].content.clone()?)))
+        .collect()
+}
+
+/// Finds the earlier question most similar to `question`, if it is at least
+/// `duplicate_question_threshold` similar.
+///
+/// Earlier questions are the answered questions in `conversation` (which should not include the
+/// preamble) and the user memories in `vector_store` that were archived together with their
+/// answer.
+///
+/// # Returns
+///
+/// - `Ok(Some(PreviousAnswer))`: A similar enough question was found.
+/// - `Ok(None)`: None was, or `duplicate_question_threshold` is not set.
+/// - `Err(Box<dyn Error>)`: The questions could not be embedded or searched.
+pub async fn find_previous_answer(
+    config: &AwfulJadeConfig,
+    vector_store: &VectorStore,
+    question: &str,
+    conversation: &[ChatCompletionRequestMessage],
+) -> Result<Option<PreviousAnswer>, Box<dyn Error>> {
+    let Some(threshold) = config.duplicate_question_threshold else {
+        return Ok(None);
+    };
+
+    let embedder = vector_store.embedder();
+    let Some(query) = embedder.embed(&[question.to_string()]).await?.pop() else {
+        return Ok(None);
+    };
+
+    let mut candidates = answered_questions(conversation);
+    if !vector_store.is_empty() {
+        for (id, _distance) in vector_store.search_with_scores(&query, ARCHIVED_CANDIDATES)? {
+            let (Some(asked), Some(answered)) = (
+                vector_store.get_content_by_id(id),
+                vector_store
+                    .next_memory_id(id)
+                    .and_then(|next| vector_store.get_content_by_id(next)),
+            ) else {
+                continue;
+            };
+            // Archived exchanges are stored in order, so an answer directly follows its question.
+            if *asked.role() == Role::User && *answered.role() == Role::Assistant {
+                candidates.push((asked.content().to_string(), answered.content().to_string()));
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let questions: Vec<String> = candidates.iter().map(|(q, _)| q.clone()).collect();
+    let vectors = embedder.embed(&questions).await?;
+    let best = candidates
+        .into_iter()
+        .zip(vectors)
+        .map(|((question, answer), vector)| PreviousAnswer {
+            similarity: cosine_similarity(&query, &vector),
+            question,
+            answer,
+        })
+        .max_by(|a, b| a.similarity.total_cmp(&b.similarity));
+
+    Ok(best.filter(|previous| previous.similarity >= threshold))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::Embedder;
+    use crate::test_support::message;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    fn embeddings(vectors: &[[f32; 3]]) -> serde_json::Value {
+        json!({
+            "object": "list",
+            "model": "mock_embedding_model",
+            "data": vectors.iter().enumerate().map(|(index, vector)| json!({
+                "object": "embedding",
+                "index": index,
+                "embedding": vector,
+            })).collect::<Vec<_>>(),
+            "usage": {"prompt_tokens": 1, "total_tokens": 1}
+        })
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_find_previous_answer() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST)
+                .path("/embeddings")
+                .json_body_partial(r#"{"input": ["How can I read a file in Rust?"]}"#);
+            then.status(200).json_body(embeddings(&[[1.0, 0.0, 0.0]]));
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/embeddings").json_body_partial(
+                r#"{"input": ["How do I read a file in Rust?", "What is a trait?"]}"#,
+            );
+            then.status(200)
+                .json_body(embeddings(&[[0.99, 0.1, 0.0], [0.0, 1.0, 0.0]]));
+        });
+
+        let config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            duplicate_question_threshold: Some(0.95),
+            ..Default::default()
+        };
+        let embedder = Embedder::Api {
+            client: crate::api::create_client(&config).unwrap(),
+            model: "mock_embedding_model".to_string(),
+        };
+        let vector_store = VectorStore::with_embedder(3, embedder);
+        let conversation = vec![
+            message(Role::User, "How do I read a file in Rust?"),
+            message(Role::Assistant, "Use `fs::read_to_string`."),
+            message(Role::User, "What is a trait?"),
+            message(Role::Assistant, "A set of methods a type implements."),
+        ];
+
+        let previous = find_previous_answer(
+            &config,
+            &vector_store,
+            "How can I read a file in Rust?",
+            &conversation,
+        )
+        .await
+        .unwrap()
+        .expect("the first question should be found");
+        assert_eq!(previous.answer, "Use `fs::read_to_string`.");
+        assert!(previous.similarity > 0.99);
+
+        let config = AwfulJadeConfig {
+            duplicate_question_threshold: None,
+            ..config
+        };
+        assert_eq!(
+            find_previous_answer(&config, &vector_store, "Anything", &conversation)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! This module computes the embeddings vector stores index text by.
+//!
+//! An `Embedder` is chosen by the `embedding_provider` setting: `local` runs a sentence embeddings
+//! model on this machine, while `api` sends text to the backend's `/v1/embeddings` endpoint, which
+//! avoids downloading and running a model locally:
+//!
+//! ```yaml
+//! embedding_provider: api
+//! embedding_model: nomic-embed-text
+//! ```
+//!
+//! `fake` hashes the words of the text instead (see `hash_embedding`). Its vectors are meaningless
+//! beyond shared words, but deterministic and instant, so CI can run the memory and RAG pipelines
+//! without a model or a network.
+//!
+//! Vectors from different providers or models are not comparable, so a store must be searched
+//! with the same embedder it was filled with.
+
+#[cfg(feature = "embeddings")]
+use crate::progress::{ProgressEvent, Task};
+use crate::{
+    api,
+    config::{AwfulJadeConfig, EmbeddingProvider},
+    progress::ProgressSink,
+};
+use async_openai::{config::OpenAIConfig, types::CreateEmbeddingRequestArgs, Client};
+#[cfg(feature = "embeddings")]
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+};
+use std::error::Error;
+#[cfg(feature = "embeddings")]
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// The dimension of the vectors produced by the local model.
+pub const LOCAL_DIMENSION: usize = 384;
+
+/// The dimension of the vectors produced by the `fake` provider.
+pub const HASH_DIMENSION: usize = 256;
+
+/// The model the `api` provider asks for when `embedding_model` is not set.
+pub const DEFAULT_API_MODEL: &str = "text-embedding-ada-002";
+
+/// Turns text into embedding vectors.
+///
+/// Cloning an embedder is cheap: the local model is shared through an `Arc`, so a memory store and
+/// a RAG store don't each pay for loading it. The model is not `Sync`, so it sits behind a `Mutex`
+/// and embeds for one caller at a time, which keeps embedders `Send` and `Sync`.
+#[derive(Clone)]
+pub enum Embedder {
+    /// A sentence embeddings model run locally. Requires the `embeddings` feature.
+    #[cfg(feature = "embeddings")]
+    Local(Arc<Mutex<SentenceEmbeddingsModel>>),
+
+    /// The `/v1/embeddings` endpoint of an OpenAI compatible backend.
+    Api {
+        client: Client<OpenAIConfig>,
+        model: String,
+    },
+
+    /// Vectors hashed from the words of the text, see `hash_embedding`. For tests and CI.
+    Hash { dimension: usize },
+}
+
+impl Embedder {
+    /// Creates the embedder configured by `embedding_provider`, reporting the progress of loading
+    /// the local model to `progress`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Embedder)`: The configured embedder.
+    /// - `Err(Box<dyn Error>)`: The local model could not be loaded, or was not compiled in, or
+    ///   the API client could not be created.
+    pub async fn from_config(
+        config: &AwfulJadeConfig,
+        #[cfg_attr(not(feature = "embeddings"), allow(unused_variables))]
+        progress: &dyn ProgressSink,
+    ) -> Result<Self, Box<dyn Error>> {
+        match config.embedding_provider {
+            #[cfg(feature = "embeddings")]
+            EmbeddingProvider::Local => Ok(Self::Local(load_embedding_model(progress).await?)),
+            #[cfg(not(feature = "embeddings"))]
+            EmbeddingProvider::Local => Err(
+                "Local embeddings need the `embeddings` feature; set `embedding_provider: api`"
+                    .into(),
+            ),
+            EmbeddingProvider::Api => Ok(Self::Api {
+                client: api::create_client(config)?,
+                model: config
+                    .embedding_model
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_API_MODEL.to_string()),
+            }),
+            EmbeddingProvider::Fake => Ok(Self::Hash {
+                dimension: HASH_DIMENSION,
+            }),
+        }
+    }
+
+    /// Embeds each of `texts`, returning their vectors in the same order.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        match self {
+            #[cfg(feature = "embeddings")]
+            Self::Local(model) => {
+                let model = model
+                    .lock()
+                    .map_err(|_| "The local embeddings model panicked while embedding")?;
+                Ok(model.encode(texts)?)
+            }
+            Self::Api { client, model } => {
+                if texts.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let request = CreateEmbeddingRequestArgs::default()
+                    .model(model.clone())
+                    .input(texts.to_vec())
+                    .build()?;
+                debug!("Embedding {} texts with {}", texts.len(), model);
+
+                let mut data = client.embeddings().create(request).await?.data;
+                if data.len() != texts.len() {
+                    return Err(format!(
+                        "Expected {} embeddings from the API, got {}",
+                        texts.len(),
+                        data.len()
+                    )
+                    .into());
+                }
+                data.sort_by_key(|embedding| embedding.index);
+                Ok(data
+                    .into_iter()
+                    .map(|embedding| embedding.embedding)
+                    .collect())
+            }
+            Self::Hash { dimension } => Ok(texts
+                .iter()
+                .map(|text| hash_embedding(text, *dimension))
+                .collect()),
+        }
+    }
+
+    /// Returns a name for the vectors this embedder produces, e.g. `api:text-embedding-ada-002`.
+    /// Vectors saved under one name can only be compared with vectors of the same name.
+    pub fn id(&self) -> String {
+        match self {
+            #[cfg(feature = "embeddings")]
+            Self::Local(_) => "local".to_string(),
+            Self::Api { model, .. } => format!("api:{}", model),
+            Self::Hash { dimension } => format!("hash:{}", dimension),
+        }
+    }
+
+    /// Returns the dimension of the vectors this embedder produces.
+    ///
+    /// The dimension of an API model is not known up front, so it is measured by embedding a
+    /// short probe text.
+    pub async fn dimension(&self) -> Result<usize, Box<dyn Error>> {
+        match self {
+            #[cfg(feature = "embeddings")]
+            Self::Local(_) => Ok(LOCAL_DIMENSION),
+            Self::Api { .. } => {
+                let vectors = self.embed(&["dimension".to_string()]).await?;
+                match vectors.first() {
+                    Some(vector) if !vector.is_empty() => Ok(vector.len()),
+                    _ => Err("The embeddings API returned an empty vector".into()),
+                }
+            }
+            Self::Hash { dimension } => Ok(*dimension),
+        }
+    }
+}
+
+/// Embeds `text` without a model, by hashing each of its words to one of `dimension` components.
+///
+/// The vectors are deterministic and normalized, and texts that share words are close, which is
+/// enough to exercise retrieval in tests. They carry no meaning beyond the words themselves.
+pub fn hash_embedding(text: &str, dimension: usize) -> Vec<f32> {
+    let mut vector = vec![0.0; dimension];
+    if dimension == 0 {
+        return vector;
+    }
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty());
+    for word in words {
+        // FNV-1a, which, unlike the standard library's hasher, is stable across Rust versions.
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            });
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % dimension as u64) as usize] += sign;
+    }
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+/// Loads the sentence embeddings model used by the `local` provider, reporting progress to
+/// `progress`.
+///
+/// The model is downloaded on first use. Wrap the result in `Embedder::Local` to share it between
+/// stores.
+#[cfg(feature = "embeddings")]
+pub async fn load_embedding_model(
+    progress: &dyn ProgressSink,
+) -> Result<Arc<Mutex<SentenceEmbeddingsModel>>, Box<dyn Error>> {
+    let task = Task::LoadEmbeddingModel;
+    progress.on_event(ProgressEvent::Started { task, total: None });
+    let model = tokio::task::spawn_blocking(|| {
+        SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
+            .create_model()
+    })
+    .await;
+    progress.on_event(ProgressEvent::Finished { task });
+    Ok(Arc::new(Mutex::new(model??)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_api_embedder() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/embeddings")
+                .json_body_partial(r#"{"model": "nomic-embed-text"}"#);
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "object": "list",
+                    "model": "nomic-embed-text",
+                    "data": [
+                        {"object": "embedding", "index": 1, "embedding": [0.0, 1.0, 0.0]},
+                        {"object": "embedding", "index": 0, "embedding": [1.0, 0.0, 0.0]}
+                    ],
+                    "usage": {"prompt_tokens": 4, "total_tokens": 4}
+                }));
+        });
+
+        let config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            embedding_provider: EmbeddingProvider::Api,
+            embedding_model: Some("nomic-embed-text".to_string()),
+            ..Default::default()
+        };
+        let embedder = Embedder::from_config(&config, &NoProgress).await.unwrap();
+        let vectors = embedder
+            .embed(&["first".to_string(), "second".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(vectors, vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]]);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_fake_embedder() {
+        let config = AwfulJadeConfig {
+            embedding_provider: EmbeddingProvider::Fake,
+            ..Default::default()
+        };
+        let embedder = Embedder::from_config(&config, &NoProgress).await.unwrap();
+        assert_eq!(embedder.dimension().await.unwrap(), HASH_DIMENSION);
+
+        let texts = [
+            "The cat sat on the mat.".to_string(),
+            "the CAT sat on the mat".to_string(),
+            "Stock prices fell sharply.".to_string(),
+        ];
+        let vectors = embedder.embed(&texts).await.unwrap();
+        assert_eq!(vectors, embedder.embed(&texts).await.unwrap());
+        assert_eq!(vectors[0], vectors[1]);
+        assert_ne!(vectors[0], vectors[2]);
+        let norm: f32 = vectors[2].iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+        assert!(hash_embedding("", 8).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_embedder_is_send_and_sync() {
+        // Stores are shared across tasks, so their embedder must be, whichever features are on
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Embedder>();
+    }
+}
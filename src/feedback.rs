@@ -0,0 +1,252 @@
+//! This module records how good answers were, to compare models and templates over time.
+//!
+//! Answers are rated from 1 to 5, with an optional comment: in interactive mode with
+//! `/rate 4 clear, but too long`, and after `aj ask` with `aj rate --last 4 "clear, but too long"`.
+//! Each rating is appended as one JSON line to `feedback.jsonl` in the config directory, with the
+//! model and template that gave the answer and what identifies it: the request ID of an `aj ask`
+//! answer, or the conversation and number of an interactive one, as counted by `/diff`.
+//!
+//! `aj stats` summarizes the ratings for each model and template:
+//!
+//! ```text
+//! llama3:8b  simple_question  12 ratings  mean 3.92
+//! qwen2.5:7b  simple_question  9 ratings  mean 4.33
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the ratings file in the config directory.
+const FEEDBACK_FILENAME: &str = "feedback.jsonl";
+
+/// The name of the file, in the config directory, describing the last answer of `aj ask`.
+const LAST_ANSWER_FILENAME: &str = "last_answer.json";
+
+/// The lowest and highest ratings.
+pub const RATINGS: std::ops::RangeInclusive<u8> = 1..=5;
+
+/// An answer that can be rated.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct RatedAnswer {
+    /// The model that gave the answer.
+    pub model: String,
+
+    /// The name of the template the question was asked with.
+    pub template: String,
+
+    /// The request ID of an answer from `aj ask`; see the `request_id` module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+
+    /// The conversation of an answer from interactive mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<String>,
+
+    /// The number of an answer in its conversation, counting from 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<usize>,
+}
+
+/// A rating of an answer.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Rating {
+    /// When the answer was rated, in seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// The rating, from 1 to 5.
+    pub rating: u8,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    #[serde(flatten)]
+    pub answer: RatedAnswer,
+}
+
+/// The ratings of the answers of a model and template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RatingSummary {
+    pub model: String,
+    pub template: String,
+    pub count: usize,
+    pub mean: f64,
+}
+
+impl fmt::Display for RatingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{} ratings\tmean {:.2}",
+            self.model, self.template, self.count, self.mean
+        )
+    }
+}
+
+impl Rating {
+    /// Rates `answer` now.
+    pub fn new(answer: RatedAnswer, rating: u8, comment: Option<String>) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            rating,
+            comment,
+            answer,
+        }
+    }
+}
+
+/// Parses a rating from 1 to 5, e.g. the value of `aj rate --last`.
+pub fn parse_rating(arg: &str) -> Result<u8, String> {
+    match arg.trim().parse::<u8>() {
+        Ok(rating) if RATINGS.contains(&rating) => Ok(rating),
+        _ => Err(format!(
+            "`{}` is not a rating from {} to {}",
+            arg,
+            RATINGS.start(),
+            RATINGS.end()
+        )),
+    }
+}
+
+/// Parses the arguments of `/rate`: a rating from 1 to 5, optionally followed by a comment.
+pub fn parse_rate_args(args: &str) -> Result<(u8, Option<String>), String> {
+    let args = args.trim();
+    let (rating, comment) = args.split_once(' ').unwrap_or((args, ""));
+    let rating = parse_rating(rating)
+        .map_err(|_| "Usage: /rate 1-5 [comment], e.g. /rate 4 clear, but too long".to_string())?;
+    let comment = comment.trim();
+    Ok((rating, (!comment.is_empty()).then(|| comment.to_string())))
+}
+
+/// Returns the path of the ratings file.
+pub fn feedback_path() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(crate::config_dir()?.join(FEEDBACK_FILENAME))
+}
+
+/// Appends a rating to the ratings file.
+pub fn record(rating: &Rating) -> Result<(), Box<dyn Error>> {
+    record_to(&feedback_path()?, rating)
+}
+
+/// Reads every rating from the ratings file, oldest first.
+pub fn read_all() -> Result<Vec<Rating>, Box<dyn Error>> {
+    read_all_from(&feedback_path()?)
+}
+
+/// Remembers the last answer of `aj ask`, for `aj rate --last`.
+pub fn remember_last_answer(answer: &RatedAnswer) -> Result<(), Box<dyn Error>> {
+    let path = crate::config_dir()?.join(LAST_ANSWER_FILENAME);
+    fs::write(path, serde_json::to_string(answer)?)?;
+    Ok(())
+}
+
+/// Returns the last answer of `aj ask`, if one was remembered.
+pub fn last_answer() -> Result<Option<RatedAnswer>, Box<dyn Error>> {
+    let path = crate::config_dir()?.join(LAST_ANSWER_FILENAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+}
+
+/// Summarizes `ratings` for each model and template, in the order of their names.
+pub fn summarize(ratings: &[Rating]) -> Vec<RatingSummary> {
+    let mut totals: BTreeMap<(&str, &str), (usize, u64)> = BTreeMap::new();
+    for rating in ratings {
+        let total = totals
+            .entry((
+                rating.answer.model.as_str(),
+                rating.answer.template.as_str(),
+            ))
+            .or_default();
+        total.0 += 1;
+        total.1 += u64::from(rating.rating);
+    }
+    totals
+        .into_iter()
+        .map(|((model, template), (count, sum))| RatingSummary {
+            model: model.to_string(),
+            template: template.to_string(),
+            count,
+            mean: sum as f64 / count as f64,
+        })
+        .collect()
+}
+
+fn record_to(path: &Path, rating: &Rating) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(rating)?)?;
+    Ok(())
+}
+
+fn read_all_from(path: &Path) -> Result<Vec<Rating>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| e.into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_rate_args() {
+        assert_eq!(parse_rate_args("4"), Ok((4, None)));
+        assert_eq!(
+            parse_rate_args(" 2 too long, and wrong "),
+            Ok((2, Some("too long, and wrong".to_string())))
+        );
+        assert!(parse_rate_args("6").is_err());
+        assert!(parse_rate_args("great").is_err());
+        assert!(parse_rate_args("").is_err());
+    }
+
+    #[test]
+    fn test_record_and_summarize() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(FEEDBACK_FILENAME);
+        let answer = |model: &str| RatedAnswer {
+            model: model.to_string(),
+            template: "simple_question".to_string(),
+            request_id: Some("3f2b8c1e".to_string()),
+            ..Default::default()
+        };
+        for (model, rating) in [("qwen2.5:7b", 5), ("llama3:8b", 3), ("qwen2.5:7b", 4)] {
+            record_to(&path, &Rating::new(answer(model), rating, None)).unwrap();
+        }
+
+        let ratings = read_all_from(&path).unwrap();
+        assert_eq!(ratings.len(), 3);
+        assert_eq!(ratings[0].answer, answer("qwen2.5:7b"));
+        let summaries = summarize(&ratings);
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|summary| summary.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "llama3:8b\tsimple_question\t1 ratings\tmean 3.00",
+                "qwen2.5:7b\tsimple_question\t2 ratings\tmean 4.50",
+            ]
+        );
+    }
+}
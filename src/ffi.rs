@@ -0,0 +1,381 @@
+//! This module exports a C ABI for using awful_aj from other languages, such as Swift or Go.
+//! It requires the `ffi` feature.
+//!
+//! Build a shared or static library with
+//! `cargo rustc --release --features ffi --crate-type cdylib` (or `staticlib`). The matching
+//! header is `include/awful_aj.h`; it is generated from this module with
+//! `cbindgen --config cbindgen.toml --output include/awful_aj.h`.
+//!
+//! Strings passed in are NUL-terminated UTF-8. Strings returned are owned by the caller and must
+//! be released with `aj_free_string`. Functions that fail return null, and `aj_last_error`
+//! describes the failure.
+//!
+//! ```c
+//! AjSession *session = aj_session_open("config.yaml", "default");
+//! char *answer = aj_session_ask(session, "What is a lifetime?");
+//! if (answer == NULL) {
+//!     fprintf(stderr, "%s\n", aj_last_error());
+//! }
+//! aj_free_string(answer);
+//! aj_session_close(session);
+//! ```
+
+use crate::{
+    api::{self, AskOptions},
+    config::{self, AwfulJadeConfig},
+    template::{self, ChatTemplate},
+    tokens,
+};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use std::{
+    cell::RefCell,
+    error::Error,
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+use tokio::runtime::Runtime;
+
+thread_local! {
+    /// The error from the last failed call on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A conversation opened with `aj_session_open`.
+///
+/// Each question is asked with the template's messages followed by the earlier questions and
+/// answers. The oldest exchanges are forgotten once the conversation no longer fits in the
+/// context window.
+pub struct AjSession {
+    runtime: Runtime,
+    config: AwfulJadeConfig,
+    template: ChatTemplate,
+
+    /// The number of the template's own messages, which are never forgotten.
+    template_len: usize,
+}
+
+impl AjSession {
+    fn open(config_path: &str, template_name: &str) -> Result<Self, Box<dyn Error>> {
+        let runtime = Runtime::new()?;
+        let config = config::load_config(config_path)?;
+        let template = runtime.block_on(template::load_template(template_name))?;
+        Ok(Self::with_template(runtime, config, template))
+    }
+
+    fn with_template(runtime: Runtime, config: AwfulJadeConfig, template: ChatTemplate) -> Self {
+        let template_len = template.messages.len();
+        Self {
+            runtime,
+            config,
+            template,
+            template_len,
+        }
+    }
+
+    fn ask(&mut self, question: &str) -> Result<String, Box<dyn Error>> {
+        self.forget_to_fit(question);
+
+        // The host application owns stdout; the answer is only returned
+        let options = AskOptions {
+            stream: Some(false),
+            quiet: true,
+            ..Default::default()
+        };
+        let answer = self.runtime.block_on(api::ask_with_options(
+            &self.config,
+            question,
+            &self.template,
+            options,
+        ))?;
+
+        for (role, content) in [(Role::User, question), (Role::Assistant, answer.as_str())] {
+            self.template.messages.push(ChatCompletionRequestMessage {
+                role,
+                content: Some(content.to_string()),
+                name: None,
+                function_call: None,
+            });
+        }
+        Ok(answer)
+    }
+
+    /// Forgets the oldest exchanges of the conversation until the system prompt, the rest of the
+    /// conversation and `question` leave the answer its `assistant_minimum_context_tokens`. The
+    /// template's own messages are kept.
+    fn forget_to_fit(&mut self, question: &str) {
+        let answer_tokens = self
+            .config
+            .assistant_minimum_context_tokens
+            .min(self.config.context_max_tokens) as usize;
+        let max_tokens = (self.config.context_max_tokens as usize).saturating_sub(answer_tokens);
+
+        let mut messages = vec![api::system_message(&self.template.system_prompt)];
+        messages.append(&mut self.template.messages);
+        messages.push(ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(question.to_string()),
+            name: None,
+            function_call: None,
+        });
+        while tokens::count_messages(&messages, &self.config.model) > max_tokens {
+            let ejected = api::eject_oldest_exchange(&mut messages, 1 + self.template_len);
+            if ejected.is_empty() {
+                break;
+            }
+        }
+        messages.pop();
+        self.template.messages = messages.split_off(1);
+    }
+}
+
+/// Runs `f`, recording its error or panic for `aj_last_error` and returning null on failure.
+/// Panics must not unwind into the caller's language.
+fn guard<T>(f: impl FnOnce() -> Result<*mut T, Box<dyn Error>>) -> *mut T {
+    let result = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => Err("awful_aj panicked".into()),
+    };
+    match result {
+        Ok(ptr) => {
+            LAST_ERROR.with(|last| *last.borrow_mut() = None);
+            ptr
+        }
+        Err(err) => {
+            let message =
+                CString::new(err.to_string().replace('\0', "")).expect("NUL bytes were removed");
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Reads the string argument `name` from `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL-terminated string that outlives the call.
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Box<dyn Error>> {
+    if ptr.is_null() {
+        return Err(format!("`{}` is null", name).into());
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| format!("`{}` is not valid UTF-8", name).into())
+}
+
+/// Hands `text` to the caller, who frees it with `aj_free_string`.
+fn into_c_string(text: String) -> Result<*mut c_char, Box<dyn Error>> {
+    Ok(CString::new(text)?.into_raw())
+}
+
+/// Asks a one-shot question, like `aj ask`, and returns the answer. Nothing is printed.
+///
+/// # Safety
+///
+/// The arguments must be NUL-terminated strings. The returned string must be freed with
+/// `aj_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn aj_ask(
+    config_path: *const c_char,
+    template_name: *const c_char,
+    question: *const c_char,
+) -> *mut c_char {
+    guard(|| {
+        let mut session = AjSession::open(
+            str_arg(config_path, "config_path")?,
+            str_arg(template_name, "template_name")?,
+        )?;
+        into_c_string(session.ask(str_arg(question, "question")?)?)
+    })
+}
+
+/// Opens a conversation with the configuration at `config_path` and the template named
+/// `template_name`.
+///
+/// # Safety
+///
+/// The arguments must be NUL-terminated strings. The returned session must be closed with
+/// `aj_session_close`.
+#[no_mangle]
+pub unsafe extern "C" fn aj_session_open(
+    config_path: *const c_char,
+    template_name: *const c_char,
+) -> *mut AjSession {
+    guard(|| {
+        let session = AjSession::open(
+            str_arg(config_path, "config_path")?,
+            str_arg(template_name, "template_name")?,
+        )?;
+        Ok(Box::into_raw(Box::new(session)))
+    })
+}
+
+/// Asks a question in a conversation and returns the answer.
+///
+/// # Safety
+///
+/// `session` must come from `aj_session_open` and not be used from two threads at once.
+/// `question` must be a NUL-terminated string. The returned string must be freed with
+/// `aj_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn aj_session_ask(
+    session: *mut AjSession,
+    question: *const c_char,
+) -> *mut c_char {
+    guard(|| {
+        let session = session.as_mut().ok_or("`session` is null")?;
+        into_c_string(session.ask(str_arg(question, "question")?)?)
+    })
+}
+
+/// Closes a conversation. Passing null does nothing.
+///
+/// # Safety
+///
+/// `session` must come from `aj_session_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn aj_session_close(session: *mut AjSession) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+/// Frees a string returned by awful_aj. Passing null does nothing.
+///
+/// # Safety
+///
+/// `text` must come from awful_aj and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn aj_free_string(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+/// Returns a description of the last failure on this thread, or null if the last call succeeded.
+///
+/// The string is owned by awful_aj and valid until the next call on this thread; do not free it.
+#[no_mangle]
+pub extern "C" fn aj_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::{prelude::*, Mock};
+    use serde_json::json;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(aj_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_errors_are_reported() {
+        let answer = unsafe { aj_ask(ptr::null(), ptr::null(), ptr::null()) };
+        assert!(answer.is_null());
+        assert!(last_error().contains("config_path"));
+
+        let question = CString::new("Hi").unwrap();
+        let answer = unsafe { aj_session_ask(ptr::null_mut(), question.as_ptr()) };
+        assert!(answer.is_null());
+        assert!(last_error().contains("session"));
+    }
+
+    /// Starts a session against a mock backend that answers every question with `answer`.
+    fn session_answering<'a>(
+        server: &'a MockServer,
+        answer: &str,
+        context_max_tokens: u16,
+        assistant_minimum_context_tokens: u16,
+    ) -> (Mock<'a>, *mut AjSession) {
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "choices": [
+                        {
+                            "message": {"role": "assistant", "content": answer},
+                            "finish_reason": "stop",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let mut config_file = NamedTempFile::new().unwrap();
+        writeln!(
+            config_file,
+            "api_key: mock_api_key\napi_base: {}\nmodel: mock_model\ncontext_max_tokens: {}\nassistant_minimum_context_tokens: {}\nstop_words: []",
+            server.url(""),
+            context_max_tokens,
+            assistant_minimum_context_tokens
+        )
+        .unwrap();
+        let config = config::load_config(config_file.path().to_str().unwrap()).unwrap();
+        let template =
+            template::parse_template("system_prompt: Be brief.\nmessages: []\n").unwrap();
+        let session = Box::into_raw(Box::new(AjSession::with_template(
+            Runtime::new().unwrap(),
+            config,
+            template,
+        )));
+        (mock, session)
+    }
+
+    /// Asks `question` in `session` and returns the answer.
+    fn session_ask(session: *mut AjSession, question: &str) -> String {
+        let question = CString::new(question).unwrap();
+        let answer = unsafe { aj_session_ask(session, question.as_ptr()) };
+        assert!(!answer.is_null(), "{}", last_error());
+        let text = unsafe { CStr::from_ptr(answer) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { aj_free_string(answer) };
+        text
+    }
+
+    #[test]
+    fn test_session_keeps_the_conversation() {
+        let server = MockServer::start();
+        let (mock, session) = session_answering(&server, "Hello.", 8192, 2048);
+
+        for _ in 0..2 {
+            assert_eq!(session_ask(session, "Hi"), "Hello.");
+        }
+        assert_eq!(unsafe { &*session }.template.messages.len(), 4);
+
+        unsafe { aj_session_close(session) };
+        mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_session_forgets_what_no_longer_fits() {
+        let server = MockServer::start();
+        let answer = "The borrow checker makes sure references never outlive their data.";
+        let (mock, session) = session_answering(&server, answer, 160, 64);
+
+        // Each exchange takes about 30 tokens, so the window fills up after a few questions
+        for turn in 0..12 {
+            let question = format!("Tell me about the borrow checker, part {}.", turn);
+            assert_eq!(session_ask(session, &question), answer);
+        }
+        let messages = &unsafe { &*session }.template.messages;
+        assert!(messages.len() < 24);
+        assert_eq!(messages.len() % 2, 0);
+        assert_eq!(messages.last().unwrap().content.as_deref(), Some(answer));
+
+        unsafe { aj_session_close(session) };
+        mock.assert_hits(12);
+    }
+}
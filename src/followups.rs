@@ -0,0 +1,161 @@
+//! This module suggests follow-up questions after an answer.
+//!
+//! With `suggest_followups: true` in the config, the model is asked, in a second and much smaller
+//! request, for a few questions the user might ask next. They are printed dimmed after the answer,
+//! numbered, and in interactive mode typing a suggestion's number asks it:
+//!
+//! ```text
+//! 1. How do I share state between tokio tasks?
+//! 2. When should I use spawn_blocking?
+//! 3. What is the difference between tokio and async-std?
+//! ```
+//!
+//! The prompt can be customized with a `suggest_followups` template; without one, a built-in
+//! prompt is used.
+
+use crate::{
+    api,
+    config::AwfulJadeConfig,
+    model_family::ModelDefaults,
+    template::{self, ChatTemplate},
+};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role};
+use std::error::Error;
+
+/// The name of the template used to suggest follow-up questions.
+pub const FOLLOWUPS_TEMPLATE: &str = "suggest_followups";
+
+/// The most follow-up questions suggested after an answer.
+pub const MAX_SUGGESTIONS: usize = 3;
+
+/// The most tokens the model may spend on the suggestions.
+const MAX_SUGGESTION_TOKENS: u16 = 128;
+
+/// The system prompt used when no `suggest_followups` template exists.
+const DEFAULT_FOLLOWUPS_PROMPT: &str = "You suggest follow-up questions. Given a question and its answer, reply with two or three short questions the asker might ask next, one per line, and nothing else.";
+
+/// Returns the built-in follow-up template.
+pub fn default_followups_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_FOLLOWUPS_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Builds the messages asking the model for questions to ask after `question` was answered with
+/// `answer`.
+pub fn followup_messages(
+    template: &ChatTemplate,
+    question: &str,
+    answer: &str,
+) -> Vec<ChatCompletionRequestMessage> {
+    let prompt = format!(
+        "Question:\n{}\n\nAnswer:\n{}\n\nFollow-up questions:",
+        question, answer
+    );
+
+    let mut messages = api::prepare_messages(template).unwrap_or_default();
+    messages.push(ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(prompt),
+        name: None,
+        function_call: None,
+    });
+    messages
+}
+
+/// Parses the model's reply into at most `MAX_SUGGESTIONS` questions, without their numbering.
+pub fn parse_suggestions(reply: &str) -> Vec<String> {
+    reply
+        .lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*')
+                })
+                .trim()
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .take(MAX_SUGGESTIONS)
+        .collect()
+}
+
+/// Asks the model for questions to ask after `question` was answered with `answer`.
+pub async fn suggest(
+    config: &AwfulJadeConfig,
+    question: &str,
+    answer: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let template = template::load_template(FOLLOWUPS_TEMPLATE)
+        .await
+        .unwrap_or_else(|_| default_followups_template());
+    let mut request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(MAX_SUGGESTION_TOKENS)
+        .model(config.model.clone())
+        .stop(template.stop_words(config, &[]))
+        .messages(
+            ModelDefaults::from_config(config)
+                .adapt_messages(followup_messages(&template, question, answer)),
+        )
+        .build()?;
+    request.temperature = config.temperature;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let reply = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(parse_suggestions(&reply))
+}
+
+/// Renders `suggestions` as a numbered list, one per line.
+pub fn format_suggestions(suggestions: &[String]) -> String {
+    suggestions
+        .iter()
+        .enumerate()
+        .map(|(i, suggestion)| format!("{}. {}\n", i + 1, suggestion))
+        .collect()
+}
+
+/// Returns the suggestion `input` picks by its number, if it is one.
+pub fn pick<'a>(suggestions: &'a [String], input: &str) -> Option<&'a str> {
+    let number: usize = input.trim().parse().ok()?;
+    suggestions.get(number.checked_sub(1)?).map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_suggestions() {
+        let reply = "1. How do I share state between tasks?\n\n2) When should I block?\n- Is tokio fast?\n4. One too many?";
+        let suggestions = parse_suggestions(reply);
+        assert_eq!(
+            suggestions,
+            vec![
+                "How do I share state between tasks?",
+                "When should I block?",
+                "Is tokio fast?"
+            ]
+        );
+        assert_eq!(
+            format_suggestions(&suggestions[..2]),
+            "1. How do I share state between tasks?\n2. When should I block?\n"
+        );
+    }
+
+    #[test]
+    fn test_pick() {
+        let suggestions = vec!["First?".to_string(), "Second?".to_string()];
+        assert_eq!(pick(&suggestions, "2"), Some("Second?"));
+        assert_eq!(pick(&suggestions, " 1 "), Some("First?"));
+        assert_eq!(pick(&suggestions, "0"), None);
+        assert_eq!(pick(&suggestions, "3"), None);
+        assert_eq!(pick(&suggestions, "what is 1?"), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! This module lets the model propose files to write, for code generation.
+//!
+//! With `aj ask --write-files`, the model is asked to give the whole content of each file it
+//! creates or changes in a fenced code block naming the file after the opening fence:
+//!
+//! ````text
+//! ```rust file=src/lib.rs
+//! pub fn add(a: i32, b: i32) -> i32 {
+//!     a + b
+//! }
+//! ```
+//! ````
+//!
+//! Once the answer is printed, the proposed files are listed, and written when the user confirms,
+//! or right away with `--yes`. Files are only written inside the current directory: absolute
+//! paths, paths through `..`, and paths through symbolic links that are broken or lead out of it
+//! are refused, and nothing is written when any path is.
+
+use crate::pretty;
+use std::{
+    error::Error,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// The instructions appended to the system prompt with `--write-files`.
+const WRITE_FILES_INSTRUCTIONS: &str = "To create or replace a file, give its whole content in a fenced code block with `file=` and the file's path, relative to the current directory, after the language, like ```rust file=src/lib.rs. Only the files in such blocks are written.";
+
+/// A file the model proposed to write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileWrite {
+    /// The path of the file, relative to the current directory, as the model gave it.
+    pub path: String,
+
+    /// The content of the file.
+    pub content: String,
+}
+
+/// Returns `system_prompt` with the instructions for proposing files to write.
+pub fn instruct_system_prompt(system_prompt: &str) -> String {
+    format!("{}\n\n{}", system_prompt, WRITE_FILES_INSTRUCTIONS)
+}
+
+/// Returns the files `answer` proposes to write, in order: its code blocks with a `file=` after
+/// the opening fence.
+pub fn proposed_writes(answer: &str) -> Vec<FileWrite> {
+    pretty::extract_code_blocks(answer)
+        .into_iter()
+        .filter_map(|block| {
+            let path = block
+                .info
+                .split_whitespace()
+                .find_map(|word| word.strip_prefix("file="))?
+                .trim_matches(|c| c == '"' || c == '\'' || c == '`');
+            (!path.is_empty()).then(|| FileWrite {
+                path: path.to_string(),
+                content: block.text,
+            })
+        })
+        .collect()
+}
+
+/// Returns where `path` is in `root`, or an error when it would lead out of `root`.
+pub fn resolve(root: &Path, path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let outside = || {
+        format!(
+            "Refusing to write {}, which is outside {}",
+            path,
+            root.display()
+        )
+    };
+    let relative = Path::new(path);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside().into());
+    }
+
+    // Every symbolic link on the way, the file itself included, must lead to an existing path in
+    // the root: a dangling link would be followed by the write wherever it points
+    let root = root.canonicalize()?;
+    let mut resolved = root.clone();
+    for component in relative.components() {
+        if let Component::Normal(name) = component {
+            resolved.push(name);
+        }
+        let is_link = fs::symlink_metadata(&resolved)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_link {
+            let target = resolved.canonicalize().map_err(|_| {
+                format!(
+                    "Refusing to write {}, which goes through the broken symbolic link {}",
+                    path,
+                    resolved.display()
+                )
+            })?;
+            if !target.starts_with(&root) {
+                return Err(outside().into());
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Describes `writes` in `root`, one per line, e.g. `create src/lib.rs (3 lines)`.
+///
+/// # Returns
+///
+/// - `Ok(String)`: The description.
+/// - `Err(Box<dyn Error>)`: A path leads out of `root`.
+pub fn describe(root: &Path, writes: &[FileWrite]) -> Result<String, Box<dyn Error>> {
+    let mut lines = Vec::new();
+    for write in writes {
+        let action = if resolve(root, &write.path)?.exists() {
+            "overwrite"
+        } else {
+            "create"
+        };
+        lines.push(format!(
+            "{} {} ({} lines)",
+            action,
+            write.path,
+            write.content.lines().count()
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Writes `writes` in `root`, creating the directories they need, and returns their paths.
+///
+/// Every path is checked before any file is written, so a path leading out of `root` leaves all
+/// the files untouched.
+pub fn apply(root: &Path, writes: &[FileWrite]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let paths = writes
+        .iter()
+        .map(|write| resolve(root, &write.path))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (path, write) in paths.iter().zip(writes) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, &write.content)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_proposed_writes() {
+        let answer = "Add the function:\n```rust file=src/lib.rs\npub fn add() {}\n```\n\
+                      Then run:\n```sh\ncargo test\n```\n";
+        assert_eq!(
+            proposed_writes(answer),
+            vec![FileWrite {
+                path: "src/lib.rs".to_string(),
+                content: "pub fn add() {}\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_apply_stays_in_root() {
+        let dir = tempdir().unwrap();
+        let write = |path: &str| FileWrite {
+            path: path.to_string(),
+            content: "fn main() {}\n".to_string(),
+        };
+
+        let writes = vec![write("src/main.rs")];
+        assert_eq!(
+            describe(dir.path(), &writes).unwrap(),
+            "create src/main.rs (1 lines)"
+        );
+        apply(dir.path(), &writes).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("src/main.rs")).unwrap(),
+            "fn main() {}\n"
+        );
+        assert_eq!(
+            describe(dir.path(), &writes).unwrap(),
+            "overwrite src/main.rs (1 lines)"
+        );
+
+        // Nothing is written when any path leads out of the root
+        for outside in ["../main.rs", "/tmp/main.rs"] {
+            let writes = vec![write("build.rs"), write(outside)];
+            assert!(apply(dir.path(), &writes).is_err());
+            assert!(!dir.path().join("build.rs").exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_refuses_symbolic_links_out_of_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempdir().unwrap();
+        let elsewhere = tempdir().unwrap();
+        let write = |path: &str| FileWrite {
+            path: path.to_string(),
+            content: "fn main() {}\n".to_string(),
+        };
+
+        // A dangling link, to a file that does not exist yet, and a link to a directory
+        let target = elsewhere.path().join("main.rs");
+        symlink(&target, dir.path().join("main.rs")).unwrap();
+        symlink(elsewhere.path(), dir.path().join("src")).unwrap();
+        for path in ["main.rs", "src/lib.rs"] {
+            assert!(apply(dir.path(), &[write(path)]).is_err());
+        }
+        assert!(!target.exists());
+        assert!(!elsewhere.path().join("lib.rs").exists());
+
+        // Links that stay in the root are followed
+        fs::create_dir(dir.path().join("crates")).unwrap();
+        symlink(dir.path().join("crates"), dir.path().join("packages")).unwrap();
+        apply(dir.path(), &[write("packages/main.rs")]).unwrap();
+        assert!(dir.path().join("crates/main.rs").exists());
+    }
+}
@@ -0,0 +1,205 @@
+//! This module checks that answers are grounded in the context they were given.
+//!
+//! After an answer is generated from recalled memories or supplementary context (e.g. a repository
+//! map), the model is asked, in a second request, to list the answer's claims that the context does
+//! not support. The answer is then either annotated with those claims or regenerated with an
+//! instruction to stick to the context.
+//!
+//! The verification prompt can be customized with a `verify_grounding` template; without one, a
+//! built-in prompt is used.
+
+use crate::{
+    api,
+    config::AwfulJadeConfig,
+    model_family::ModelDefaults,
+    template::{self, ChatTemplate},
+};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role};
+use clap::ValueEnum;
+use std::error::Error;
+
+/// The name of the template used to verify answers.
+pub const VERIFY_GROUNDING_TEMPLATE: &str = "verify_grounding";
+
+/// The reply the verifier gives when every claim is supported.
+const ALL_SUPPORTED: &str = "NONE";
+
+/// The system prompt used when no `verify_grounding` template exists.
+const DEFAULT_VERIFY_GROUNDING_PROMPT: &str = "You are a fact checker. Given numbered sources and an answer, list each claim in the answer that the sources do not support, one per line. If every claim is supported, reply with only NONE.";
+
+/// What to do with an answer that makes unsupported claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroundingMode {
+    /// Print the unsupported claims after the answer.
+    Annotate,
+
+    /// Ask again, telling the model which claims were unsupported.
+    Regenerate,
+}
+
+/// Returns the built-in verification template.
+pub fn default_verify_grounding_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_VERIFY_GROUNDING_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Collects the context an answer should be grounded in from the messages it was generated from.
+///
+/// The system prompt, the question (the last message), and the assistant's acknowledgments of
+/// handshakes are left out; everything else was context handed to the model.
+pub fn sources(messages: &[ChatCompletionRequestMessage], acknowledgment: &str) -> Vec<String> {
+    let Some((_question, preamble)) = messages.split_last() else {
+        return Vec::new();
+    };
+    preamble
+        .iter()
+        .filter(|message| message.role != Role::System)
+        .filter(|message| {
+            !(message.role == Role::Assistant && message.content.as_deref() == Some(acknowledgment))
+        })
+        .filter_map(|message| message.content.clone())
+        .filter(|content| !content.trim().is_empty())
+        .collect()
+}
+
+/// Builds the messages asking the model which claims in `answer` are not supported by `sources`.
+pub fn verification_messages(
+    template: &ChatTemplate,
+    sources: &[String],
+    answer: &str,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut prompt = String::from("Sources:\n");
+    for (i, source) in sources.iter().enumerate() {
+        prompt.push_str(&format!("[{}] {}\n", i + 1, source));
+    }
+    prompt.push_str(&format!("\nAnswer:\n{}\n\nUnsupported claims:", answer));
+
+    let mut messages = api::prepare_messages(template).unwrap_or_default();
+    messages.push(ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(prompt),
+        name: None,
+        function_call: None,
+    });
+    messages
+}
+
+/// Parses the verifier's reply into a list of unsupported claims.
+pub fn parse_unsupported_claims(reply: &str) -> Vec<String> {
+    if reply
+        .trim()
+        .trim_end_matches('.')
+        .eq_ignore_ascii_case(ALL_SUPPORTED)
+    {
+        return Vec::new();
+    }
+    reply
+        .lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*')
+                })
+                .trim()
+                .to_string()
+        })
+        .filter(|line| !line.is_empty() && !line.eq_ignore_ascii_case(ALL_SUPPORTED))
+        .collect()
+}
+
+/// Asks the model which claims in `answer` are not supported by `sources`.
+pub async fn unsupported_claims(
+    config: &AwfulJadeConfig,
+    model: &str,
+    sources: &[String],
+    answer: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let template = template::load_template(VERIFY_GROUNDING_TEMPLATE)
+        .await
+        .unwrap_or_else(|_| default_verify_grounding_template());
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model.to_string())
+        .stop(template.stop_words(config, &[]))
+        .messages(
+            ModelDefaults::from_config(config)
+                .adapt_messages(verification_messages(&template, sources, answer)),
+        )
+        .build()?;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let reply = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(parse_unsupported_claims(&reply))
+}
+
+/// Renders the note appended to an answer with unsupported claims.
+pub fn annotation(unsupported: &[String]) -> String {
+    let mut note = String::from("\n\nClaims not supported by the provided context:");
+    for claim in unsupported {
+        note.push_str(&format!("\n- {}", claim));
+    }
+    note
+}
+
+/// Adjusts a question so the regenerated answer avoids the unsupported claims.
+pub fn regeneration_question(question: &str, unsupported: &[String]) -> String {
+    let mut adjusted = format!(
+        "{}\n\nOnly make claims that the context above supports. A previous answer made these unsupported claims:",
+        question
+    );
+    for claim in unsupported {
+        adjusted.push_str(&format!("\n- {}", claim));
+    }
+    adjusted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::message;
+
+    #[test]
+    fn test_sources() {
+        let messages = vec![
+            message(Role::System, "Be helpful."),
+            message(Role::User, "Here is the repository map: src/main.rs"),
+            message(Role::Assistant, "Ok."),
+            message(Role::User, "Where is main?"),
+        ];
+        assert_eq!(
+            sources(&messages, "Ok."),
+            vec!["Here is the repository map: src/main.rs".to_string()]
+        );
+        assert!(sources(&messages[..1], "Ok.").is_empty());
+    }
+
+    #[test]
+    fn test_verification_messages() {
+        let messages = verification_messages(
+            &default_verify_grounding_template(),
+            &["The sky is blue.".to_string()],
+            "The sky is green.",
+        );
+        assert_eq!(
+            messages.last().unwrap().content.as_deref(),
+            Some("Sources:\n[1] The sky is blue.\n\nAnswer:\nThe sky is green.\n\nUnsupported claims:")
+        );
+    }
+
+    #[test]
+    fn test_parse_unsupported_claims() {
+        assert!(parse_unsupported_claims("NONE").is_empty());
+        assert!(parse_unsupported_claims(" none.\n").is_empty());
+        assert_eq!(
+            parse_unsupported_claims("1. The sky is green.\n- Water is dry.\n"),
+            vec!["The sky is green.".to_string(), "Water is dry.".to_string()]
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! This module implements a long-running JSON-RPC 2.0 mode over stdin/stdout for editor integrations.
+//!
+//! Each line on stdin is one JSON-RPC request. Responses and notifications are written to stdout,
+//! one JSON object per line, so an editor plugin can keep a single `aj` process alive instead of
+//! spawning one per question.
+//!
+//! ## Methods
+//!
+//! - `ask` — `{ "question": "...", "template": "simple_question" }`
+//! - `ask_selection` — `{ "question": "...", "selection": "...", "language": "rust", "path": "src/lib.rs" }`
+//! - `add_context` — `{ "text": "..." }` or `{ "path": "..." }`; included with every later ask.
+//! - `clear_context` — drops all context added so far.
+//! - `shutdown` — ends the session.
+//!
+//! While an answer is generated, `aj/delta` notifications carrying `{ "id": <request id>, "content": "..." }`
+//! are emitted for every streamed chunk. The final response's result holds the complete answer.
+//! When `postprocess` is configured, the result also carries a `flags` array describing refusals,
+//! hallucination-risk phrasing, or empty answers (see the `postprocess` module). The result's
+//! content has the template's output filters applied; the deltas are the raw model output. Its
+//! `finish_reason` says why the model stopped; `length` means the answer was cut off. Its
+//! `request_id` is the id the question's log lines carry; see the `request_id` module.
+//!
+//! Questions are answered like `aj ask` answers them (see `api::ask_for_completion`), so
+//! guardrails, local-only templates, hooks, and the context window and time limits all apply.
+//!
+//! ## Example
+//!
+//! ```text
+//! --> {"jsonrpc": "2.0", "id": 1, "method": "ask", "params": {"question": "What is a monad?"}}
+//! <-- {"jsonrpc": "2.0", "method": "aj/delta", "params": {"id": 1, "content": "A monad"}}
+//! <-- {"jsonrpc": "2.0", "method": "aj/delta", "params": {"id": 1, "content": " is..."}}
+//! <-- {"jsonrpc": "2.0", "id": 1, "result": {"content": "A monad is...", "finish_reason": "stop", "request_id": "3f2b8c1e-9d4a-4e7b-a1c2-5d6e7f8a9b0c"}}
+//! ```
+
+use crate::{
+    api::{self, AskOptions, Completion},
+    config::AwfulJadeConfig,
+    postprocess::Postprocessor,
+    template::{self, ChatTemplate},
+};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    sync::Mutex,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{debug, error, warn};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// The template used when a request does not name one.
+const DEFAULT_TEMPLATE: &str = "simple_question";
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<JsonValue>,
+    method: String,
+    #[serde(default)]
+    params: JsonValue,
+}
+
+#[derive(Debug, Deserialize)]
+struct AskParams {
+    question: String,
+    #[serde(default)]
+    template: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AskSelectionParams {
+    question: String,
+    selection: String,
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    template: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddContextParams {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    path: Option<String>,
+}
+
+/// An error that is reported back to the client as a JSON-RPC error object.
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for RpcError {
+    fn from(err: Box<dyn Error>) -> Self {
+        Self::new(SERVER_ERROR, err.to_string())
+    }
+}
+
+/// The state of a JSON-RPC session: the configuration, the accumulated editor context, and the
+/// writer that responses are sent to.
+pub struct Server<'a, W: Write> {
+    config: &'a AwfulJadeConfig,
+    context: Vec<String>,
+    out: W,
+}
+
+impl<'a, W: Write + Send> Server<'a, W> {
+    /// Creates a new server writing its responses to `out`.
+    pub fn new(config: &'a AwfulJadeConfig, out: W) -> Self {
+        Self {
+            config,
+            context: Vec::new(),
+            out,
+        }
+    }
+
+    /// Handles a single line of input.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(false)` once the client asked to shut down, `Ok(true)` otherwise. Errors are only
+    /// returned when writing to the output fails; request failures are reported to the client.
+    pub async fn handle_line(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        if line.trim().is_empty() {
+            return Ok(true);
+        }
+
+        let request: Request = match serde_json::from_str::<JsonValue>(line) {
+            Ok(value) => match serde_json::from_value(value) {
+                Ok(request) => request,
+                Err(err) => {
+                    self.send_error(
+                        JsonValue::Null,
+                        RpcError::new(INVALID_REQUEST, err.to_string()),
+                    )?;
+                    return Ok(true);
+                }
+            },
+            Err(err) => {
+                self.send_error(JsonValue::Null, RpcError::new(PARSE_ERROR, err.to_string()))?;
+                return Ok(true);
+            }
+        };
+
+        debug!("Received JSON-RPC request: {:?}", request);
+        let id = request.id.clone().unwrap_or(JsonValue::Null);
+
+        if request.method == "shutdown" {
+            self.send_result(id, JsonValue::Null)?;
+            return Ok(false);
+        }
+
+        match self.dispatch(&id, request).await {
+            Ok(result) => self.send_result(id, result)?,
+            Err(err) => {
+                error!("JSON-RPC request failed: {}", err.message);
+                self.send_error(id, err)?
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn dispatch(&mut self, id: &JsonValue, request: Request) -> Result<JsonValue, RpcError> {
+        match request.method.as_str() {
+            "ask" => {
+                let params: AskParams = parse_params(request.params)?;
+                let question = params.question.clone();
+                let completion = self.ask(id, params.question, params.template).await?;
+                Ok(self.answer_result(&question, completion))
+            }
+            "ask_selection" => {
+                let params: AskSelectionParams = parse_params(request.params)?;
+                let mut question = String::new();
+                if let Some(path) = params.path {
+                    question.push_str(&format!("From `{}`:\n", path));
+                }
+                question.push_str(&format!(
+                    "```{}\n{}\n```\n\n{}",
+                    params.language.unwrap_or_default(),
+                    params.selection,
+                    params.question
+                ));
+                let completion = self.ask(id, question, params.template).await?;
+                Ok(self.answer_result(&params.question, completion))
+            }
+            "add_context" => {
+                let params: AddContextParams = parse_params(request.params)?;
+                let text = match (params.text, params.path) {
+                    (Some(text), _) => text,
+                    (None, Some(path)) => {
+                        let content = fs::read_to_string(&path)
+                            .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+                        format!("Contents of `{}`:\n{}", path, content)
+                    }
+                    (None, None) => {
+                        return Err(RpcError::new(
+                            INVALID_PARAMS,
+                            "add_context requires either `text` or `path`",
+                        ))
+                    }
+                };
+                self.context.push(text);
+                Ok(json!({ "context_items": self.context.len() }))
+            }
+            "clear_context" => {
+                self.context.clear();
+                Ok(json!({ "context_items": 0 }))
+            }
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method: {}", method),
+            )),
+        }
+    }
+
+    async fn ask(
+        &mut self,
+        id: &JsonValue,
+        question: String,
+        template_name: Option<String>,
+    ) -> Result<Completion, RpcError> {
+        let template =
+            template::load_template(template_name.as_deref().unwrap_or(DEFAULT_TEMPLATE)).await?;
+        self.ask_with_template(id, question, template).await
+    }
+
+    /// Asks `question` with `template` through `api::ask_for_completion`, handing over the editor
+    /// context before the question and sending each streamed chunk as an `aj/delta` notification.
+    async fn ask_with_template(
+        &mut self,
+        id: &JsonValue,
+        question: String,
+        mut template: ChatTemplate,
+    ) -> Result<Completion, RpcError> {
+        if !self.context.is_empty() && !template.uses_rag() {
+            warn!("The template sets `use_rag: false`, so the editor context is not sent.");
+        } else if !self.context.is_empty() {
+            let context = format!("Context from my editor:\n{}", self.context.join("\n\n"));
+            let handshake = template.handshake(&template.context_framing(), &context);
+            template.messages.extend(handshake);
+        }
+
+        // The first failed write is reported once the answer is complete
+        let out = Mutex::new(&mut self.out);
+        let failed_write = Mutex::new(None);
+        let on_delta = |content: &str| {
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "aj/delta",
+                "params": { "id": id, "content": content },
+            });
+            let mut out = out.lock().expect("a delta writer panicked");
+            if let Err(err) = write_message(&mut **out, &notification) {
+                failed_write
+                    .lock()
+                    .expect("a delta writer panicked")
+                    .get_or_insert(err);
+            }
+        };
+        let options = AskOptions {
+            quiet: true,
+            on_delta: Some(&on_delta),
+            ..Default::default()
+        };
+        let completion = api::ask_for_completion(self.config, question, &template, options).await?;
+        match failed_write.into_inner().expect("a delta writer panicked") {
+            Some(err) => Err(RpcError::new(SERVER_ERROR, err.to_string())),
+            None => Ok(completion),
+        }
+    }
+
+    /// Wraps an answer, its finish reason, and its request id in a result, adding the
+    /// postprocessing flags when postprocessing is enabled.
+    fn answer_result(&self, question: &str, completion: Completion) -> JsonValue {
+        let flags = self.config.postprocess.as_ref().map(|postprocess_config| {
+            Postprocessor::from_config(postprocess_config).check(question, completion.content())
+        });
+        let mut result = json!({
+            "content": completion.content(),
+            "finish_reason": completion.finish_reason,
+            "request_id": completion.request_id,
+        });
+        if let Some(flags) = flags {
+            result["flags"] = json!(flags);
+        }
+        result
+    }
+
+    fn send_result(&mut self, id: JsonValue, result: JsonValue) -> Result<(), Box<dyn Error>> {
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+    }
+
+    fn send_error(&mut self, id: JsonValue, err: RpcError) -> Result<(), Box<dyn Error>> {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": err.code, "message": err.message },
+        }))
+    }
+
+    fn send(&mut self, message: JsonValue) -> Result<(), Box<dyn Error>> {
+        write_message(&mut self.out, &message)?;
+        Ok(())
+    }
+}
+
+/// Writes `message` as one line and flushes it, so the client sees it right away.
+fn write_message(out: &mut impl Write, message: &JsonValue) -> io::Result<()> {
+    writeln!(out, "{}", message)?;
+    out.flush()
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: JsonValue) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+/// Runs the JSON-RPC loop until stdin is closed or the client sends `shutdown`.
+///
+/// # Parameters
+///
+/// - `config`: The configuration containing the API key, base URL, and model name.
+///
+/// # Returns
+///
+/// A result indicating the success or failure of the operation.
+pub async fn serve(config: &AwfulJadeConfig) -> Result<(), Box<dyn Error>> {
+    let mut server = Server::new(config, std::io::stdout());
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if !server.handle_line(&line).await? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, mock_config};
+    use httpmock::prelude::*;
+
+    fn output_lines(out: &[u8]) -> Vec<JsonValue> {
+        String::from_utf8_lossy(out)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_parse_error() {
+        let config = mock_config();
+        let mut out = Vec::new();
+        let mut server = Server::new(&config, &mut out);
+        assert!(server.handle_line("{not json").await.unwrap());
+
+        let lines = output_lines(&out);
+        assert_eq!(lines[0]["error"]["code"], PARSE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let config = mock_config();
+        let mut out = Vec::new();
+        let mut server = Server::new(&config, &mut out);
+        server
+            .handle_line(r#"{"jsonrpc": "2.0", "id": 7, "method": "explode"}"#)
+            .await
+            .unwrap();
+
+        let lines = output_lines(&out);
+        assert_eq!(lines[0]["id"], 7);
+        assert_eq!(lines[0]["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_add_context_and_shutdown() {
+        let config = mock_config();
+        let mut out = Vec::new();
+        let mut server = Server::new(&config, &mut out);
+        server
+            .handle_line(r#"{"jsonrpc": "2.0", "id": 1, "method": "add_context", "params": {"text": "fn main() {}"}}"#)
+            .await
+            .unwrap();
+        server
+            .handle_line(r#"{"jsonrpc": "2.0", "id": 2, "method": "add_context", "params": {}}"#)
+            .await
+            .unwrap();
+        assert_eq!(server.context.len(), 1);
+        assert!(!server
+            .handle_line(r#"{"jsonrpc": "2.0", "id": 3, "method": "shutdown"}"#)
+            .await
+            .unwrap());
+
+        let lines = output_lines(&out);
+        assert_eq!(lines[0]["result"]["context_items"], 1);
+        assert_eq!(lines[1]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(lines[2]["id"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_ask_streams_deltas() {
+        let server = MockServer::start();
+        let chunk = |content: &str| {
+            json!({
+                "id": "chatcmpl-1234567890",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "mock_model",
+                "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]
+            })
+        };
+        let body = format!(
+            "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+            chunk("A monad"),
+            chunk(" is...")
+        );
+        server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "text/event-stream")
+                .body(body);
+        });
+
+        let config = AwfulJadeConfig {
+            api_base: server.url(""),
+            ..mock_config()
+        };
+        let mut out = Vec::new();
+        let mut rpc = Server::new(&config, &mut out);
+        let completion = rpc
+            .ask_with_template(
+                &json!(1),
+                "What is a monad?".to_string(),
+                test_support::template(),
+            )
+            .await
+            .unwrap();
+        let result = rpc.answer_result("What is a monad?", completion);
+
+        assert_eq!(result["content"], "A monad is...");
+        assert!(result["request_id"].is_string());
+        let lines = output_lines(&out);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["method"], "aj/delta");
+        assert_eq!(lines[0]["params"], json!({"id": 1, "content": "A monad"}));
+        assert_eq!(lines[1]["params"]["content"], " is...");
+    }
+
+    #[tokio::test]
+    async fn test_ask_refuses_local_only_templates_for_remote_backends() {
+        let config = mock_config();
+        let mut out = Vec::new();
+        let mut rpc = Server::new(&config, &mut out);
+        let template = ChatTemplate {
+            local_only: Some(true),
+            ..test_support::template()
+        };
+        let err = rpc
+            .ask_with_template(&json!(1), "What is in the contract?".to_string(), template)
+            .await
+            .unwrap_err();
+
+        assert!(err.message.contains("local-only"), "{}", err.message);
+        assert!(out.is_empty());
+    }
+}
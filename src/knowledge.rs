@@ -0,0 +1,353 @@
+//! This module gives templates their own reference documents.
+//!
+//! A template can list Markdown files from the `knowledge` directory next to `config.yaml`:
+//!
+//! ```yaml
+//! system_prompt: You review pull requests for the web team.
+//! knowledge:
+//!   - style_guide.md
+//!   - review_checklist.md
+//! ```
+//!
+//! The files are split into chunks and embedded the first time they are used, and the vectors are
+//! saved in `knowledge/.embeddings`, so a file is only embedded again once it changes. For every
+//! question asked with the template, the chunks closest to the question are appended to the
+//! system prompt, within the room the context window leaves for supplementary context; see the
+//! `budget` module.
+
+use crate::{
+    brain::Source,
+    budget::{ContextBudget, Parts},
+    config::AwfulJadeConfig,
+    progress::NoProgress,
+    rag::{Collection, Hit},
+    retrieval,
+    template::ChatTemplate,
+    tokens,
+    vector_store::{chunk_text, VectorStore, DEFAULT_CHUNK_TOKENS},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    error::Error,
+    fs,
+    hash::{Hash, Hasher},
+    path::{Component, Path, PathBuf},
+};
+use tracing::debug;
+
+/// The directory, in the configuration directory, that holds knowledge files.
+pub const KNOWLEDGE_DIR: &str = "knowledge";
+
+/// The directory, in the knowledge directory, that holds the saved vectors of knowledge files.
+const EMBEDDINGS_DIR: &str = ".embeddings";
+
+/// The most chunks appended to the system prompt for a question.
+pub const KNOWLEDGE_TOP_K: usize = 4;
+
+/// The text introducing the chunks in the system prompt.
+const KNOWLEDGE_FRAMING: &str =
+    "Below are excerpts from reference documents. Use them where they are relevant to the question:";
+
+/// The saved chunks and vectors of a knowledge file.
+#[derive(Serialize, Deserialize)]
+struct EmbeddedFile {
+    /// The embedder that produced the vectors; see `Embedder::id`.
+    embedder: String,
+
+    /// The digest of the file's content and chunk size when it was embedded.
+    digest: String,
+
+    chunks: Vec<EmbeddedChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EmbeddedChunk {
+    text: String,
+    vector: Vec<f32>,
+}
+
+/// The embedded knowledge files of a template.
+pub struct Knowledge {
+    collection: Collection,
+}
+
+/// Returns the directory that holds knowledge files.
+pub fn knowledge_dir() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(crate::config_dir()?.join(KNOWLEDGE_DIR))
+}
+
+impl Knowledge {
+    /// Loads the knowledge files of `template`, embedding them with the embedder of
+    /// `vector_store` when there is one, and with the configured embedder otherwise.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(Knowledge))`: The template's knowledge.
+    /// - `Ok(None)`: The template lists no knowledge files.
+    /// - `Err(Box<dyn Error>)`: A file could not be read or embedded.
+    pub async fn for_template(
+        config: &AwfulJadeConfig,
+        template: &ChatTemplate,
+        vector_store: Option<&VectorStore>,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        if template.knowledge.is_empty() {
+            return Ok(None);
+        }
+        let vector_store = match vector_store {
+            Some(vector_store) => {
+                VectorStore::with_embedder(vector_store.dimension(), vector_store.embedder())
+            }
+            None => VectorStore::from_config(config, &NoProgress).await?,
+        };
+        let knowledge = Self::load(vector_store, &knowledge_dir()?, &template.knowledge).await?;
+        Ok(Some(knowledge))
+    }
+
+    /// Loads the files `names` in `dir` into `vector_store`, an empty store, reusing the vectors
+    /// saved for files that have not changed and saving the vectors of those that have.
+    pub async fn load(
+        vector_store: VectorStore,
+        dir: &Path,
+        names: &[String],
+    ) -> Result<Self, Box<dyn Error>> {
+        let embedder = vector_store.embedder();
+        let mut chunks = Vec::new();
+        for name in names {
+            let relative = Path::new(name);
+            if !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(format!(
+                    "The knowledge file `{}` must be a path inside {}",
+                    name,
+                    dir.display()
+                )
+                .into());
+            }
+            let path = dir.join(relative);
+            let content = fs::read_to_string(&path).map_err(|e| {
+                format!(
+                    "Failed to read the knowledge file {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+
+            let digest = digest(&content);
+            let saved_path = dir.join(EMBEDDINGS_DIR).join(format!("{}.json", name));
+            let saved: Option<EmbeddedFile> = fs::read_to_string(&saved_path)
+                .ok()
+                .and_then(|saved| serde_json::from_str(&saved).ok());
+            let embedded = match saved {
+                Some(saved) if saved.embedder == embedder.id() && saved.digest == digest => saved,
+                _ => {
+                    debug!("Embedding the knowledge file {}", path.display());
+                    let texts: Vec<String> = chunk_text(&content, DEFAULT_CHUNK_TOKENS)
+                        .into_iter()
+                        .filter(|chunk| !chunk.is_empty())
+                        .collect();
+                    let vectors = embedder.embed(&texts).await?;
+                    let embedded = EmbeddedFile {
+                        embedder: embedder.id(),
+                        digest,
+                        chunks: texts
+                            .into_iter()
+                            .zip(vectors)
+                            .map(|(text, vector)| EmbeddedChunk { text, vector })
+                            .collect(),
+                    };
+                    if let Some(parent) = saved_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(&saved_path, serde_json::to_string(&embedded)?)?;
+                    embedded
+                }
+            };
+            chunks.extend(
+                embedded
+                    .chunks
+                    .into_iter()
+                    .map(|chunk| (name.clone(), chunk.text, chunk.vector)),
+            );
+        }
+
+        Ok(Self {
+            collection: Collection::from_embedded(vector_store, chunks, &NoProgress)?,
+        })
+    }
+
+    /// Returns the chunks closest to `question`, closest first, for as long as they fit in
+    /// `max_tokens` for `model`.
+    pub async fn excerpts(
+        &self,
+        question: &str,
+        max_tokens: usize,
+        model: &str,
+    ) -> Result<Vec<Hit>, Box<dyn Error>> {
+        let hits = self.collection.query(question, KNOWLEDGE_TOP_K).await?;
+        let candidates: Vec<(usize, f32)> = hits
+            .iter()
+            .enumerate()
+            .map(|(i, hit)| (i, hit.distance))
+            .collect();
+        let selected = retrieval::select_within_budget(&candidates, max_tokens, |i| {
+            Some(tokens::count(&format_excerpt(&hits[i]), model))
+        });
+        Ok(selected.into_iter().map(|(i, _)| hits[i].clone()).collect())
+    }
+
+    /// Appends the chunks closest to `question` to `system_prompt`, in the room left for context
+    /// by a conversation of `conversation_tokens`, sent with memories when `memories` is set.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok((String, Vec<Source>))`: The system prompt, and the chunks that were appended to it.
+    /// - `Err(Box<dyn Error>)`: The question could not be embedded.
+    pub async fn augment(
+        &self,
+        config: &AwfulJadeConfig,
+        question: &str,
+        system_prompt: &str,
+        conversation_tokens: usize,
+        memories: bool,
+    ) -> Result<(String, Vec<Source>), Box<dyn Error>> {
+        let budget = ContextBudget::plan(
+            config,
+            conversation_tokens + tokens::count(KNOWLEDGE_FRAMING, &config.model),
+            Parts {
+                memories,
+                context: true,
+            },
+        );
+        let excerpts = self
+            .excerpts(question, budget.context, &config.model)
+            .await?;
+        let sources = excerpts
+            .iter()
+            .map(|hit| Source {
+                label: format!("knowledge file {}", hit.path),
+                distance: Some(hit.distance),
+                tokens: tokens::count(&format_excerpt(hit), &config.model),
+                content: hit.text.clone(),
+            })
+            .collect();
+        Ok((with_excerpts(system_prompt, &excerpts), sources))
+    }
+}
+
+/// Returns `system_prompt` followed by `excerpts`, or `system_prompt` alone when there are none.
+pub fn with_excerpts(system_prompt: &str, excerpts: &[Hit]) -> String {
+    if excerpts.is_empty() {
+        return system_prompt.to_string();
+    }
+    let excerpts: Vec<String> = excerpts.iter().map(format_excerpt).collect();
+    format!(
+        "{}\n\n{}\n\n{}",
+        system_prompt,
+        KNOWLEDGE_FRAMING,
+        excerpts.join("\n\n")
+    )
+}
+
+/// Renders a chunk as it is appended to the system prompt, below the file it comes from.
+fn format_excerpt(hit: &Hit) -> String {
+    format!("[{}]\n{}", hit.path, hit.text)
+}
+
+/// Returns a digest of a knowledge file's `content` and the size of its chunks.
+fn digest(content: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    DEFAULT_CHUNK_TOKENS.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_load_saves_and_reuses_vectors() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("style_guide.md"),
+            "Indent with four spaces. Prefer early returns over nested conditions.",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("garden.md"),
+            "Tomatoes need full sun and regular watering.",
+        )
+        .unwrap();
+        let names = vec!["style_guide.md".to_string(), "garden.md".to_string()];
+
+        let knowledge = Knowledge::load(test_support::vector_store(), dir.path(), &names)
+            .await
+            .unwrap();
+        let saved = dir.path().join(EMBEDDINGS_DIR).join("style_guide.md.json");
+        let embedded: EmbeddedFile =
+            serde_json::from_str(&fs::read_to_string(&saved).unwrap()).unwrap();
+        assert_eq!(embedded.embedder, "hash:64");
+
+        let excerpts = knowledge
+            .excerpts("how should I indent", usize::MAX, "gpt-4")
+            .await
+            .unwrap();
+        assert_eq!(excerpts[0].path, "style_guide.md");
+        let excerpts = knowledge
+            .excerpts("how should I indent", 0, "gpt-4")
+            .await
+            .unwrap();
+        assert!(excerpts.is_empty());
+
+        // Saved chunks are used as they are while the file is unchanged
+        let tampered = EmbeddedFile {
+            chunks: vec![EmbeddedChunk {
+                text: "Indent with tabs.".to_string(),
+                vector: embedded.chunks[0].vector.clone(),
+            }],
+            ..embedded
+        };
+        fs::write(&saved, serde_json::to_string(&tampered).unwrap()).unwrap();
+        let knowledge = Knowledge::load(test_support::vector_store(), dir.path(), &names)
+            .await
+            .unwrap();
+        let excerpts = knowledge
+            .excerpts("how should I indent", usize::MAX, "gpt-4")
+            .await
+            .unwrap();
+        assert_eq!(excerpts[0].text, "Indent with tabs.");
+    }
+
+    #[tokio::test]
+    async fn test_load_refuses_paths_outside_the_directory() {
+        let dir = tempdir().unwrap();
+        let names = vec!["../config.yaml".to_string()];
+        assert!(
+            Knowledge::load(test_support::vector_store(), dir.path(), &names)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_with_excerpts() {
+        let hit = Hit {
+            path: "style_guide.md".to_string(),
+            distance: 0.1,
+            text: "Indent with four spaces.".to_string(),
+        };
+        assert_eq!(with_excerpts("You review code.", &[]), "You review code.");
+        assert_eq!(
+            with_excerpts("You review code.", &[hit]),
+            format!(
+                "You review code.\n\n{}\n\n[style_guide.md]\nIndent with four spaces.",
+                KNOWLEDGE_FRAMING
+            )
+        );
+    }
+}
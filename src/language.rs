@@ -0,0 +1,146 @@
+//! This module controls which language the assistant answers in.
+//!
+//! The `respond_in` option (in the configuration or a template) accepts `auto`, a language code
+//! such as `es` or `spa`, or a language name such as `Spanish`. With `auto`, the language of each
+//! question is detected and the assistant is instructed to answer in it; detection that is not
+//! reliable (e.g. very short questions) leaves the system prompt untouched.
+//!
+//! # Example
+//!
+//! ```
+//! use awful_aj::language::instruct_system_prompt;
+//!
+//! let prompt = instruct_system_prompt("You are a helpful assistant.", Some("es"), "Hi!");
+//! assert_eq!(prompt, "You are a helpful assistant.\n\nAlways respond in Spanish.");
+//! ```
+
+use whatlang::Lang;
+
+/// The `respond_in` value that enables language detection.
+pub const AUTO: &str = "auto";
+
+/// ISO 639-1 codes for the languages whatlang can detect that are most commonly configured.
+const ISO_639_1: &[(&str, Lang)] = &[
+    ("ar", Lang::Ara),
+    ("de", Lang::Deu),
+    ("en", Lang::Eng),
+    ("es", Lang::Spa),
+    ("fr", Lang::Fra),
+    ("he", Lang::Heb),
+    ("hi", Lang::Hin),
+    ("it", Lang::Ita),
+    ("ja", Lang::Jpn),
+    ("ko", Lang::Kor),
+    ("nl", Lang::Nld),
+    ("pl", Lang::Pol),
+    ("pt", Lang::Por),
+    ("ru", Lang::Rus),
+    ("sv", Lang::Swe),
+    ("tr", Lang::Tur),
+    ("uk", Lang::Ukr),
+    ("zh", Lang::Cmn),
+];
+
+/// Detects the language of `text`, returning `None` when the detection is not reliable.
+pub fn detect(text: &str) -> Option<Lang> {
+    whatlang::detect(text)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang())
+}
+
+/// Returns the English name of a configured language.
+///
+/// Accepts ISO 639-1 codes (`es`), ISO 639-3 codes (`spa`), or names, which are returned as-is.
+pub fn language_name(value: &str) -> String {
+    let code = value.trim().to_lowercase();
+    ISO_639_1
+        .iter()
+        .find(|(iso, _)| *iso == code)
+        .map(|(_, lang)| *lang)
+        .or_else(|| Lang::from_code(code))
+        .map(|lang| lang.eng_name().to_string())
+        .unwrap_or_else(|| value.trim().to_string())
+}
+
+/// Builds the instruction telling the assistant which language to respond in.
+///
+/// # Parameters
+///
+/// - `respond_in`: The configured `respond_in` value.
+/// - `question`: The question being asked, used when `respond_in` is `auto`.
+///
+/// # Returns
+///
+/// The instruction, or `None` when `auto` could not reliably detect the question's language.
+pub fn response_instruction(respond_in: &str, question: &str) -> Option<String> {
+    let language = if respond_in.trim().eq_ignore_ascii_case(AUTO) {
+        detect(question)?.eng_name().to_string()
+    } else {
+        language_name(respond_in)
+    };
+    Some(format!("Always respond in {}.", language))
+}
+
+/// Appends the response language instruction, if any, to a system prompt.
+///
+/// # Parameters
+///
+/// - `system_prompt`: The template's system prompt.
+/// - `respond_in`: The configured `respond_in` value, if any.
+/// - `question`: The question being asked.
+///
+/// # Returns
+///
+/// The system prompt to send with this question.
+pub fn instruct_system_prompt(
+    system_prompt: &str,
+    respond_in: Option<&str>,
+    question: &str,
+) -> String {
+    match respond_in.and_then(|respond_in| response_instruction(respond_in, question)) {
+        Some(instruction) => format!("{}\n\n{}", system_prompt, instruction),
+        None => system_prompt.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            detect(
+                "¿Cómo puedo leer un archivo muy grande línea por línea, sin tener que cargarlo \
+                 entero en la memoria del ordenador?"
+            ),
+            Some(Lang::Spa)
+        );
+        assert_eq!(
+            detect("How can I read a file in Rust without loading all of it into memory?"),
+            Some(Lang::Eng)
+        );
+    }
+
+    #[test]
+    fn test_language_name() {
+        assert_eq!(language_name("es"), "Spanish");
+        assert_eq!(language_name("deu"), "German");
+        assert_eq!(language_name("Klingon"), "Klingon");
+    }
+
+    #[test]
+    fn test_instruct_system_prompt() {
+        let prompt = instruct_system_prompt(
+            "Be helpful.",
+            Some("auto"),
+            "Wie kann ich in Rust eine Datei lesen, ohne sie komplett in den Speicher zu laden?",
+        );
+        assert_eq!(prompt, "Be helpful.\n\nAlways respond in German.");
+
+        assert_eq!(
+            instruct_system_prompt("Be helpful.", None, "Hola"),
+            "Be helpful."
+        );
+    }
+}
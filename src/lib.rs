@@ -0,0 +1,117 @@
+//! # Awful Jade
+//!
+//! A library for interacting with OpenAI compatible APIs, used by the `aj` command-line tool.
+//!
+//! It provides loading of configuration and chat templates, asking questions with or without
+//! streaming, a "brain" of conversation memories backed by a vector store, and helpers such as
+//! repository maps for codebase questions.
+//!
+//! # Features
+//!
+//! - `embeddings`: computes embeddings locally with rust-bert; implies `rag`.
+//! - `rag`: vector stores and memory retrieval (`vector_store`, `retrieval`, `embeddings`,
+//!   `memory_audit`, `memory_kinds`, `consolidation`, `paraphrase`, `rag`, `knowledge`, and the
+//!   memory options of `api`).
+//! - `tty`: colors, themes, the pager, and the command-line interface (`theme`, `pager`,
+//!   `commands`, and `api::interactive_mode` together with `rag`).
+//! - `notify`: desktop notifications for slow answers (`notify`).
+//! - `ffi`: a C ABI for other languages (`ffi`); off by default.
+//! - `test_support`: helpers for testing code built on the crate without a model or a backend
+//!   (`test_support`); off by default.
+//!
+//! All but `ffi` and `test_support` are enabled by default. To only ask questions, depend on the crate with
+//! `default-features = false`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use awful_aj::{api, config, template};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = config::load_config("config.yaml")?;
+//! let template = template::load_template("simple_question").await?;
+//! let answer = api::ask_with_options(&config, "What is a lifetime?", &template, Default::default())
+//!     .await?;
+//! println!("{}", answer);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod api;
+pub mod brain;
+pub mod budget;
+#[cfg(feature = "tty")]
+pub mod commands;
+pub mod config;
+#[cfg(feature = "rag")]
+pub mod consolidation;
+pub mod decompose;
+pub mod digest;
+#[cfg(feature = "rag")]
+pub mod duplicates;
+#[cfg(feature = "rag")]
+pub mod embeddings;
+pub mod feedback;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod followups;
+pub mod fs_tools;
+pub mod grounding;
+pub mod guardrails;
+pub mod jsonrpc;
+#[cfg(feature = "rag")]
+pub mod knowledge;
+pub mod language;
+#[cfg(feature = "rag")]
+pub mod memory_audit;
+#[cfg(feature = "rag")]
+pub mod memory_kinds;
+pub mod model_family;
+pub mod notify;
+#[cfg(feature = "tty")]
+pub mod pager;
+#[cfg(feature = "rag")]
+pub mod paraphrase;
+pub mod plugins;
+pub mod postprocess;
+pub mod pretty;
+pub mod privacy;
+pub mod progress;
+pub mod prompt;
+#[cfg(feature = "rag")]
+pub mod rag;
+pub mod repo;
+pub mod request_id;
+#[cfg(feature = "rag")]
+pub mod retrieval;
+pub mod setup;
+pub mod sse;
+pub mod template;
+pub mod template_install;
+#[cfg(any(test, feature = "test_support"))]
+pub mod test_support;
+#[cfg(feature = "tty")]
+pub mod theme;
+pub mod timings;
+pub mod tokens;
+pub mod validation;
+#[cfg(feature = "rag")]
+pub mod vector_store;
+pub mod word_diff;
+
+use directories::ProjectDirs;
+use std::error::Error;
+
+/// # Configuration Directory Retrieval
+///
+/// Uses the `directories` crate to fetch the appropriate configuration directory based on the
+/// operating system. This ensures compatibility and adherence to the OS's directory structure
+/// and conventions.
+///
+/// ## Returns
+/// - `Result<PathBuf, Box<dyn Error>>`: The path to the configuration directory or an error
+pub fn config_dir() -> Result<std::path::PathBuf, Box<dyn Error>> {
+    let proj_dirs = ProjectDirs::from("com", "awful-security", "aj")
+        .ok_or("Unable to determine config directory")?;
+    Ok(proj_dirs.config_dir().to_path_buf())
+}
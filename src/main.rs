@@ -4,20 +4,32 @@
 //! configuration loading, and command execution based on user input from the command line.
 
 // Importing necessary modules and libraries
-mod api;
-mod brain;
-mod commands;
-mod config;
-mod template;
-mod vector_store;
-
-use brain::{Brain, Memory};
+use awful_aj::{
+    api,
+    brain::{self, Brain},
+    budget::{self, ContextBudget, Parts},
+    commands, config, config_dir, digest, feedback, followups, fs_tools, jsonrpc, language,
+    memory_audit, notify, pager, plugins, pretty,
+    progress::{ProgressEvent, ProgressSink},
+    prompt, rag, repo, retrieval, setup, sse, template, template_install,
+    theme::{BuiltinTheme, Theme},
+    tokens, validation,
+    vector_store::VectorStore,
+};
 use clap::Parser;
-use directories::ProjectDirs;
+use indicatif::{ProgressBar, ProgressStyle};
 use once_cell::sync::OnceCell;
-use std::{env, error::Error, fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use tracing::{debug, info};
-use vector_store::VectorStore;
 
 // A static OnceCell to hold the tracing subscriber, ensuring it is only initialized once.
 static TRACING: OnceCell<()> = OnceCell::new();
@@ -32,7 +44,12 @@ static TRACING: OnceCell<()> = OnceCell::new();
 fn main() -> Result<(), Box<dyn Error>> {
     initialize_tracing();
     let runtime = tokio::runtime::Runtime::new().unwrap();
-    runtime.block_on(run()).unwrap();
+    if let Err(e) = runtime.block_on(run()) {
+        // Print the error's message rather than its debug representation, so that errors such as
+        // `api::ContextOverflow` can explain themselves.
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
     Ok(())
 }
 
@@ -57,45 +74,727 @@ fn initialize_tracing() {
 async fn run() -> Result<(), Box<dyn Error>> {
     let cli = commands::Cli::parse();
     let config_path = determine_config_path()?;
-    let jade_config = config::load_config(config_path.to_str().unwrap())?;
+    // Plugins read the configuration themselves, so it is neither loaded nor checked for them.
+    if let commands::Commands::External(args) = &cli.command {
+        let status = plugins::run_plugin(&args[0], &args[1..], &config_path).await?;
+        if !status.success() {
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        return Ok(());
+    }
+    // `init` writes the configuration, so there is none to load yet.
+    if let commands::Commands::Init { yes } = cli.command {
+        debug!("Initializing configuration");
+        return if yes {
+            init()
+        } else {
+            init_wizard(&config_path).await
+        };
+    }
+    // The command-line overrides are applied before validation, so that what is checked is the
+    // configuration this invocation actually uses.
+    let mut jade_config = config::parse_config(config_path.to_str().unwrap())?;
+    cli.apply_overrides(&mut jade_config);
+    if let commands::Commands::Config {
+        command: commands::ConfigCommands::Validate,
+    } = cli.command
+    {
+        return handle_config_validate_command(&config_path, &jade_config);
+    }
+    validation::check(&jade_config)?;
+    prune_repo_cache(&jade_config)?;
 
     match cli.command {
-        commands::Commands::Ask { question } => {
+        commands::Commands::Ask {
+            question,
+            template: template_name,
+            vars,
+            repo,
+            include,
+            exclude,
+            verify_grounding,
+            stop,
+            notify,
+            show_sources,
+            code_only,
+            force,
+            decompose,
+            sse_port,
+            tee,
+            write_files,
+            yes,
+        } => {
             debug!("Asking question: {:?}", question);
-            handle_ask_command(jade_config, question).await?;
+            let walk_options = repo::WalkOptions { include, exclude };
+            let options = api::AskOptions {
+                verify_grounding,
+                stop,
+                quiet: code_only,
+                force,
+                tee,
+                decompose,
+                ..Default::default()
+            };
+            let output = AskOutput {
+                notify,
+                show_sources,
+                code_only,
+                sse_port,
+                write_files: write_files.then_some(yes),
+            };
+            let template = load_rendered_template(&template_name, vars).await?;
+            handle_ask_command(
+                jade_config,
+                &template_name,
+                template,
+                question,
+                repo,
+                walk_options,
+                options,
+                output,
+            )
+            .await?;
         }
         commands::Commands::Interactive { name } => {
             debug!("Entering interactive mode");
             handle_interactive_command(jade_config, name).await?;
         }
-        commands::Commands::Init => {
-            debug!("Initializing configuration");
-            init()?;
+        commands::Commands::Digest {
+            since,
+            rag,
+            template,
+            out,
+        } => {
+            debug!("Writing a digest of {}", rag.display());
+            handle_digest_command(&jade_config, since, rag, template, out).await?;
+        }
+        commands::Commands::Init { .. } => unreachable!("handled before the config is loaded"),
+        commands::Commands::LspIsh { jsonrpc } => {
+            if !jsonrpc {
+                return Err("lsp-ish currently only supports the --jsonrpc transport".into());
+            }
+            debug!("Starting JSON-RPC session");
+            jsonrpc::serve(&jade_config).await?;
+        }
+        commands::Commands::Config { .. } => unreachable!("handled before the config is validated"),
+        commands::Commands::External(_) => unreachable!("handled before the config is loaded"),
+        commands::Commands::Rate { last, comment } => {
+            let answer = feedback::last_answer()?
+                .ok_or("There is no answer to rate yet; ask one with `aj ask`")?;
+            feedback::record(&feedback::Rating::new(answer, last, comment))?;
+        }
+        commands::Commands::Stats => {
+            let summaries = feedback::summarize(&feedback::read_all()?);
+            if summaries.is_empty() {
+                println!("No answers rated yet. Rate them with `/rate` or `aj rate --last`.");
+            }
+            for summary in summaries {
+                println!("{}", summary);
+            }
+        }
+        commands::Commands::Models => {
+            let models = setup::served_models(&jade_config).await?;
+            println!("{}", setup::format_models(&models, &jade_config.model));
+            setup::check_model(&jade_config, &models)?;
         }
+        commands::Commands::Memory { command } => match command {
+            commands::MemoryCommands::Explain { last } => {
+                let explanations = memory_audit::read_last(last)?;
+                if explanations.is_empty() {
+                    println!("No memory decisions recorded. Set `explain_memory: true` in config.yaml to enable them.");
+                }
+                for explanation in explanations {
+                    println!("{}", explanation);
+                }
+            }
+        },
+        commands::Commands::Rag { command } => match command {
+            commands::RagCommands::Query {
+                question,
+                rag,
+                top_k,
+                json,
+            } => {
+                debug!("Searching {} for: {}", rag.display(), question);
+                handle_rag_query_command(&jade_config, &rag, &question, top_k, json).await?;
+            }
+        },
+        commands::Commands::Repo { command } => match command {
+            commands::RepoCommands::Map {
+                path,
+                include,
+                exclude,
+            } => {
+                debug!("Mapping repository: {}", path.display());
+                let map = repo::load_or_build_with_progress(
+                    &path,
+                    &repo::WalkOptions { include, exclude },
+                    &TerminalProgress::new(&Theme::from_config(&jade_config)?),
+                )?;
+                print!("{}", map.render());
+            }
+            commands::RepoCommands::Cache { command } => handle_cache_command(command)?,
+        },
+        commands::Commands::Template { command } => match command {
+            commands::TemplateCommands::Install { source, name } => {
+                debug!("Installing template from: {}", source);
+                handle_template_install_command(&source, name).await?;
+            }
+        },
+        commands::Commands::Budget { command } => match command {
+            commands::BudgetCommands::Simulate {
+                question,
+                question_file,
+                template,
+                rag,
+                memories,
+            } => {
+                let question = match question_file {
+                    Some(path) => fs::read_to_string(path)?,
+                    None => question.unwrap_or_else(|| "What is the meaning of life?".to_string()),
+                };
+                handle_budget_simulate_command(&jade_config, &question, &template, rag, memories)
+                    .await?;
+            }
+        },
+        commands::Commands::Debug { command } => match command {
+            commands::DebugCommands::Prompt {
+                question,
+                template,
+                json,
+            } => {
+                debug!("Assembling the prompt for: {:?}", question);
+                handle_debug_prompt_command(&jade_config, question, &template, json).await?;
+            }
+        },
     }
 
     Ok(())
 }
 
+/// # Load Rendered Template
+///
+/// Loads the template `name` for the 'ask' command and renders it with `vars`. Variables the
+/// template prompts for that are missing from `vars` are asked for when stdin is a terminal.
+///
+/// ## Parameters
+/// - `name: &str`: The name of the template
+/// - `vars: Vec<(String, String)>`: The variables given with `--var`
+///
+/// ## Returns
+/// - `Result<template::ChatTemplate, Box<dyn Error>>`: The rendered template, or an error when it
+///   could not be loaded or a variable has no value
+async fn load_rendered_template(
+    name: &str,
+    vars: Vec<(String, String)>,
+) -> Result<template::ChatTemplate, Box<dyn Error>> {
+    let template = template::load_template(name).await?;
+    let mut vars: BTreeMap<String, String> = vars.into_iter().collect();
+    if io::stdin().is_terminal() {
+        let missing: Vec<template::TemplatePrompt> =
+            template.missing_vars(&vars).into_iter().cloned().collect();
+        for prompt in missing {
+            let value = ask(&prompt.question, "")?;
+            vars.insert(prompt.var, value);
+        }
+    }
+    template.render(&vars)
+}
+
 /// # Handle Ask Command
 ///
-/// Processes the 'ask' command. Loads a template and the user's question (or a default one)
-/// and forwards them to the API for processing. The result is then handled as per the application's
-/// design.
+/// Processes the 'ask' command. Sends the user's question (or a default one) with the rendered
+/// template to the API for processing. When the pager is active, the answer is streamed without
+/// being printed and shown once it is complete, in the pager if it is taller than the terminal.
+/// Suggested follow-up questions follow when `suggest_followups` is set.
 ///
 /// ## Parameters
 /// - `jade_config: config::AwfulJadeConfig`: The configuration for Awful Jade
+/// - `template_name: &str`: The name of the template, recorded for `aj rate --last`
+/// - `template: template::ChatTemplate`: The template to ask the question with
 /// - `question: Option<String>`: The question to be asked, or None to use a default question
+/// - `repo: Option<PathBuf>`: A repository whose map is added to the preamble, if any
+/// - `walk_options: repo::WalkOptions`: Glob overrides for the files included in the repository map
+/// - `options: api::AskOptions`: Per-question options, such as grounding verification
+/// - `output: AskOutput`: What is reported besides the answer, or instead of it
 ///
 /// ## Returns
 /// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+#[allow(clippy::too_many_arguments)]
 async fn handle_ask_command(
     jade_config: config::AwfulJadeConfig,
+    template_name: &str,
+    template: template::ChatTemplate,
+    question: Option<String>,
+    repo: Option<PathBuf>,
+    walk_options: repo::WalkOptions,
+    options: api::AskOptions<'_>,
+    output: AskOutput,
+) -> Result<(), Box<dyn Error>> {
+    let mut template = template;
+    if output.write_files.is_some() {
+        template.system_prompt = fs_tools::instruct_system_prompt(&template.system_prompt);
+    }
+    let question = question.unwrap_or_else(|| "What is the meaning of life?".to_string());
+    let mut sources = Vec::new();
+    let mut repo = repo;
+    if repo.is_some() && !template.uses_rag() {
+        eprintln!("The template sets `use_rag: false`, so --repo is ignored.");
+        repo = None;
+    }
+    if let Some(repo) = repo {
+        // The map gets the room the question leaves, less the handshake that frames it
+        let mut conversation = api::prepare_messages(&template)?;
+        conversation.extend(template.handshake(&template.context_framing(), ""));
+        let budget = ContextBudget::plan(
+            &jade_config,
+            tokens::count_messages(&conversation, &jade_config.model)
+                + tokens::count(&question, &jade_config.model),
+            Parts {
+                memories: options.memory.is_some(),
+                context: true,
+            },
+        );
+        let max_repo_map_tokens = budget.context;
+        let progress = TerminalProgress::new(&Theme::from_config(&jade_config)?);
+        let map = repo::load_or_build_with_progress(&repo, &walk_options, &progress)?;
+        let repo_map = map.render_compressed(max_repo_map_tokens);
+        let context = repo::describe(&repo_map);
+        let handshake = template.handshake(&template.context_framing(), &context);
+        template.prepend_messages(handshake);
+        sources.push(brain::Source {
+            label: format!("repository map of {}", repo.display()),
+            distance: None,
+            tokens: tokens::count(&context, &jade_config.model),
+            content: context,
+        });
+    }
+    let started = Instant::now();
+    if let Some(port) = output.sse_port {
+        let relay = sse::SseRelay::accept(port).await?;
+        let on_delta = |content: &str| relay.send(content);
+        let options = api::AskOptions {
+            on_delta: Some(&on_delta),
+            ..options
+        };
+        let completion =
+            api::ask_for_completion(&jade_config, question.as_str(), &template, options).await;
+        return match completion {
+            Ok(completion) => relay.finish(&completion).await,
+            Err(e) => {
+                relay.fail(e.as_ref()).await?;
+                Err(e)
+            }
+        };
+    }
+    // An answer that may be paged is still streamed, to `--tee` and against the time limit, but
+    // only shown once it is complete, or it would be shown twice
+    let paged = !options.quiet && pager::is_active(&jade_config);
+    let discard = |_: &str| {};
+    let options = match paged {
+        true => api::AskOptions {
+            on_delta: Some(&discard),
+            ..options
+        },
+        false => options,
+    };
+    let mut completion =
+        api::ask_for_completion(&jade_config, question.as_str(), &template, options).await?;
+    let rated_answer = feedback::RatedAnswer {
+        model: jade_config.model.clone(),
+        template: template_name.to_string(),
+        request_id: completion.request_id.clone(),
+        ..Default::default()
+    };
+    if let Err(e) = feedback::remember_last_answer(&rated_answer) {
+        eprintln!("Failed to remember the answer for `aj rate`: {}", e);
+    }
+    if let Err(e) = notify::after_answer(&jade_config, output.notify, &question, started.elapsed())
+    {
+        eprintln!("Failed to send notification: {}", e);
+    }
+    if output.code_only {
+        let blocks = pretty::extract_code_blocks(completion.content());
+        if blocks.is_empty() {
+            return Err("The answer has no code blocks".into());
+        }
+        let code: Vec<String> = blocks.into_iter().map(|block| block.text).collect();
+        print!("{}", code.join("\n"));
+        return Ok(());
+    }
+    if paged {
+        let rendered = pretty::render(completion.content(), pretty::terminal_width());
+        if !pager::page_if_long(&jade_config, &rendered)? {
+            api::print_answer(&jade_config, completion.content())?;
+        }
+    }
+    if let Some(yes) = output.write_files {
+        write_proposed_files(completion.content(), yes)?;
+    }
+    if output.show_sources {
+        sources.append(&mut completion.sources);
+        print!("\n{}", brain::format_sources(&sources));
+        if let Some(request_id) = &completion.request_id {
+            println!("Request ID: {}", request_id);
+        }
+    }
+    if jade_config.suggest_followups {
+        match followups::suggest(&jade_config, &question, completion.content()).await {
+            Ok(suggestions) if !suggestions.is_empty() => {
+                let theme = Theme::from_config(&jade_config)?;
+                print!(
+                    "\n{}",
+                    theme.hint(followups::format_suggestions(&suggestions))
+                );
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to suggest follow-up questions: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// # Write Proposed Files
+///
+/// Lists the files `answer` proposes to write, and writes them in the current directory once the
+/// user confirms, or right away when `yes` is set.
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: An error when a proposed path leads out of the current
+///   directory, when a file could not be written, or when confirmation is needed but stdin is not
+///   a terminal.
+fn write_proposed_files(answer: &str, yes: bool) -> Result<(), Box<dyn Error>> {
+    let writes = fs_tools::proposed_writes(answer);
+    if writes.is_empty() {
+        eprintln!("The answer proposes no files to write.");
+        return Ok(());
+    }
+    let root = std::env::current_dir()?;
+    eprintln!("\n{}", fs_tools::describe(&root, &writes)?);
+    if !yes {
+        if !io::stdin().is_terminal() {
+            return Err("Pass --yes to write the files without confirmation".into());
+        }
+        if !confirm("Write these files?", false)? {
+            return Ok(());
+        }
+    }
+    for path in fs_tools::apply(&root, &writes)? {
+        eprintln!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// # Handle Config Validate Command
+///
+/// Processes the 'config validate' command. Prints every error and warning `validation::validate`
+/// finds in the configuration, with the command-line overrides applied.
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: An error when the configuration has errors, so the process
+///   exits with a nonzero status
+fn handle_config_validate_command(
+    config_path: &std::path::Path,
+    config: &config::AwfulJadeConfig,
+) -> Result<(), Box<dyn Error>> {
+    let diagnostics = validation::validate(config);
+    if diagnostics.is_empty() {
+        println!("{} is valid.", config_path.display());
+        return Ok(());
+    }
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == validation::Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(format!("{} has {} error(s)", config_path.display(), errors).into());
+    }
+    Ok(())
+}
+
+/// What `aj ask` reports besides the answer.
+struct AskOutput {
+    /// Whether `--notify` was passed; slow answers are announced when it was, or when
+    /// `notify_command` is configured.
+    notify: bool,
+
+    /// Whether `--show-sources` was passed, to print the memories and context sent with the
+    /// question.
+    show_sources: bool,
+
+    /// Whether `--code-only` was passed, to print the answer's code blocks instead of the answer.
+    code_only: bool,
+
+    /// The port given with `--sse-port`, to stream the answer to a client instead of printing it.
+    sse_port: Option<u16>,
+
+    /// Whether `--write-files` was passed, to write the files the answer proposes, and if so
+    /// whether `--yes` was too, to write them without confirmation.
+    write_files: Option<bool>,
+}
+
+/// # Handle Digest Command
+///
+/// Processes the 'digest' command. Summarizes the documents in `rag` that changed within `since`,
+/// and appends the digest to `out`, or prints it when no file is given. When no document changed,
+/// nothing is written. Any failure is returned, so the process exits with a nonzero status.
+///
+/// ## Parameters
+/// - `jade_config: &config::AwfulJadeConfig`: The configuration for Awful Jade
+/// - `since: String`: The time window, e.g. `24h`
+/// - `rag: PathBuf`: The directory of documents to summarize
+/// - `template: Option<String>`: The template to use, or None for the `digest` template
+/// - `out: Option<PathBuf>`: The file to append the digest to, or None to print it
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+async fn handle_digest_command(
+    jade_config: &config::AwfulJadeConfig,
+    since: String,
+    rag: PathBuf,
+    template: Option<String>,
+    out: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let template = match template {
+        Some(name) => template::load_template(&name).await?,
+        None => template::load_template(digest::DIGEST_TEMPLATE)
+            .await
+            .unwrap_or_else(|_| digest::default_digest_template()),
+    };
+    let options = digest::DigestOptions {
+        dir: rag,
+        since: config::parse_duration(&since)?,
+        since_label: since,
+    };
+    let Some(entry) = digest::digest(jade_config, template, &options).await? else {
+        return Ok(());
+    };
+    match out {
+        Some(path) => digest::append_entry(&path, &entry)?,
+        None => print!("{}", entry),
+    }
+    Ok(())
+}
+
+/// # Handle Template Install Command
+///
+/// Downloads a template into the templates directory. When the template was already installed,
+/// the changes from the previous version are printed.
+///
+/// ## Parameters
+/// - `source: &str`: A URL or `github:owner/repo/path.yaml` source
+/// - `name: Option<String>`: The name to install the template as, or None to use the source's file name
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+async fn handle_template_install_command(
+    source: &str,
+    name: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let source = template_install::TemplateSource::parse(source)?;
+    let templates_dir = template::templates_dir()?;
+    let installed = template_install::install(&templates_dir, &source, name).await?;
+
+    match &installed.diff {
+        Some(diff) if installed.changed() => {
+            println!("Updated template `{}`:", installed.name);
+            for line in diff {
+                println!("{}", line);
+            }
+        }
+        Some(_) => println!("Template `{}` is already up to date.", installed.name),
+        None => println!(
+            "Installed template `{}` to {}",
+            installed.name,
+            installed.path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// # Handle Debug Prompt Command
+///
+/// Prints the request that `aj ask` would send for a question, without sending it. Memories and
+/// repository maps are not recalled, so the request is the one sent without them.
+///
+/// ## Parameters
+/// - `jade_config: &config::AwfulJadeConfig`: The configuration
+/// - `question: Option<String>`: The question, or None for the default question of `aj ask`
+/// - `template_name: &str`: The template to assemble the question with
+/// - `json: bool`: Whether to print the request as JSON
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+async fn handle_debug_prompt_command(
+    jade_config: &config::AwfulJadeConfig,
     question: Option<String>,
+    template_name: &str,
+    json: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let template = template::load_template("simple_question").await?;
+    let template = template::load_template(template_name).await?;
     let question = question.unwrap_or_else(|| "What is the meaning of life?".to_string());
-    api::ask(&jade_config, question, template).await
+    let snapshot = prompt::assemble(jade_config, &template, &question, None)?;
+    if json {
+        println!("{}", snapshot.to_json());
+        return Ok(());
+    }
+
+    println!(
+        "model: {}, stream: {}, max_tokens: {:?}, temperature: {:?}, stop: {:?}",
+        snapshot.model, snapshot.stream, snapshot.max_tokens, snapshot.temperature, snapshot.stop
+    );
+    for message in &snapshot.messages {
+        println!("\n[{}]\n{}", message.role, message.content);
+    }
+    Ok(())
+}
+
+/// # Handle Budget Simulate Command
+///
+/// Prints how the context window would be divided for a question asked with a template, without
+/// asking the model. With `rag`, the documents' chunks closest to the question are handed over as
+/// context for as long as they fit in the room left for it.
+///
+/// ## Parameters
+/// - `jade_config: &config::AwfulJadeConfig`: The configuration
+/// - `question: &str`: The question to plan for
+/// - `template_name: &str`: The template to plan with
+/// - `rag: Option<PathBuf>`: A directory of documents to fill the context with, if any
+/// - `memories: bool`: Whether room is planned for recalled memories
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+async fn handle_budget_simulate_command(
+    jade_config: &config::AwfulJadeConfig,
+    question: &str,
+    template_name: &str,
+    rag: Option<PathBuf>,
+    memories: bool,
+) -> Result<(), Box<dyn Error>> {
+    let template = template::load_template(template_name).await?;
+    let model = &jade_config.model;
+    let system_prompt = language::instruct_system_prompt(
+        &template.system_prompt,
+        template.respond_in(jade_config),
+        question,
+    );
+    let question_message = async_openai::types::ChatCompletionRequestMessage {
+        role: async_openai::types::Role::User,
+        content: Some(question.to_string()),
+        name: None,
+        function_call: None,
+    };
+    let template_messages = api::fit_template_messages(
+        jade_config,
+        &template,
+        model,
+        &system_prompt,
+        &question_message,
+    );
+
+    // Each part is counted as what it adds to the request, so the parts add up to the whole
+    let mut messages = vec![api::system_message(&system_prompt)];
+    let system_tokens = tokens::count_messages(&messages, model);
+    messages.extend(template_messages);
+    let template_tokens = tokens::count_messages(&messages, model) - system_tokens;
+    messages.push(question_message);
+    let question_tokens =
+        tokens::count_messages(&messages, model) - system_tokens - template_tokens;
+
+    let parts = Parts {
+        memories,
+        context: rag.is_some(),
+    };
+    let mut breakdown = budget::Breakdown::plan(
+        jade_config,
+        system_tokens,
+        template_tokens,
+        question_tokens,
+        parts,
+    );
+    let mut chunks = None;
+    if let Some(dir) = rag {
+        let framing = template.handshake(&template.context_framing(), "");
+        let room = breakdown
+            .context_budget
+            .saturating_sub(tokens::count_messages(&framing, model));
+        let progress = TerminalProgress::new(&Theme::from_config(jade_config)?);
+        let vector_store = VectorStore::from_config(jade_config, &progress).await?;
+        let collection = rag::Collection::load(
+            vector_store,
+            &dir,
+            jade_config.memory_chunk_tokens,
+            &progress,
+        )
+        .await?;
+        let hits = collection.query(question, collection.len()).await?;
+        let candidates: Vec<(usize, f32)> = hits
+            .iter()
+            .enumerate()
+            .map(|(i, hit)| (i, hit.distance))
+            .collect();
+        let selected = retrieval::select_within_budget(&candidates, room, |i| {
+            Some(tokens::count(&hits[i].text, model))
+        });
+        let context: Vec<&str> = selected
+            .iter()
+            .map(|(i, _)| hits[*i].text.as_str())
+            .collect();
+        let handshake = template.handshake(&template.context_framing(), &context.join("\n\n"));
+        breakdown.context = tokens::count_messages(&handshake, model);
+        chunks = Some((selected.len(), hits.len()));
+    }
+
+    print!("{}", breakdown);
+    if let Some((selected, total)) = chunks {
+        println!(
+            "{} of {} document chunks fit in the context.",
+            selected, total
+        );
+    }
+    Ok(())
+}
+
+/// # Handle Rag Query Command
+///
+/// Processes the 'rag query' command. Chunks and embeds the documents in `dir`, then prints the
+/// chunks closest to `question` with their distances. The model is not asked.
+///
+/// ## Parameters
+/// - `jade_config: &config::AwfulJadeConfig`: The configuration for Awful Jade
+/// - `dir: &Path`: The directory of documents to search
+/// - `question: &str`: What to search for
+/// - `top_k: usize`: How many chunks to print
+/// - `json: bool`: Whether to print the chunks as JSON
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+async fn handle_rag_query_command(
+    jade_config: &config::AwfulJadeConfig,
+    dir: &std::path::Path,
+    question: &str,
+    top_k: usize,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let progress = TerminalProgress::new(&Theme::from_config(jade_config)?);
+    let vector_store = VectorStore::from_config(jade_config, &progress).await?;
+    let collection = rag::Collection::load(
+        vector_store,
+        dir,
+        jade_config.memory_chunk_tokens,
+        &progress,
+    )
+    .await?;
+    let hits = collection.query(question, top_k).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+    } else {
+        print!("{}", rag::format_hits(&hits));
+    }
+    Ok(())
 }
 
 /// # Handle Interactive Command
@@ -115,22 +814,95 @@ async fn handle_interactive_command(
     name: Option<String>,
 ) -> Result<(), Box<dyn Error>> {
     let conversation_name = name.unwrap_or_else(|| "default".to_string());
-    let template = template::load_template("default").await?;
-    let vector_store = VectorStore::new(384).await?;
-    let max_brain_token_percentage = 0.25;
-    let max_brain_tokens =
-        (max_brain_token_percentage * jade_config.context_max_tokens as f32) as u16;
-    let brain = Brain::new(max_brain_tokens, &template);
+    let template_name = "default";
+    let template = template::load_template(template_name).await?;
+    let progress = TerminalProgress::new(&Theme::from_config(&jade_config)?);
+    let vector_store = VectorStore::from_config(&jade_config, &progress).await?;
+    let brain = Brain::new(brain::max_brain_tokens(&jade_config), &template);
     api::interactive_mode(
         &jade_config,
         conversation_name,
         vector_store,
         brain,
+        template_name,
         &template,
     )
     .await
 }
 
+/// # Handle Cache Command
+///
+/// Processes the 'repo cache' subcommands: listing, clearing, and pruning cached repository maps.
+///
+/// ## Parameters
+/// - `command: commands::CacheCommands`: The cache subcommand to run
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+fn handle_cache_command(command: commands::CacheCommands) -> Result<(), Box<dyn Error>> {
+    let removed = match command {
+        commands::CacheCommands::Ls => {
+            let entries = repo::list_cache()?;
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            for entry in &entries {
+                let age = entry.modified.elapsed().unwrap_or_default();
+                println!(
+                    "{}\t{} bytes\t{}h old\t{}",
+                    entry.path.display(),
+                    entry.size,
+                    age.as_secs() / 3600,
+                    entry
+                        .root
+                        .as_ref()
+                        .map(|root| root.display().to_string())
+                        .unwrap_or_else(|| "<unreadable>".to_string())
+                );
+            }
+            println!("{} cached maps, {} bytes", entries.len(), total);
+            return Ok(());
+        }
+        commands::CacheCommands::Clear => repo::clear_cache()?,
+        commands::CacheCommands::Prune {
+            older_than,
+            max_size,
+        } => repo::prune_cache(older_than, max_size)?,
+    };
+
+    let freed: u64 = removed.iter().map(|e| e.size).sum();
+    println!("Removed {} cached maps, {} bytes", removed.len(), freed);
+    Ok(())
+}
+
+/// # Prune Repo Cache
+///
+/// Applies the `cache_max_age` and `cache_max_size` limits from the configuration to the cache
+/// of repository maps. Runs on every startup so the cache cannot grow without bound.
+///
+/// ## Parameters
+/// - `jade_config: &config::AwfulJadeConfig`: The configuration for Awful Jade
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+fn prune_repo_cache(jade_config: &config::AwfulJadeConfig) -> Result<(), Box<dyn Error>> {
+    let older_than = jade_config
+        .cache_max_age
+        .as_deref()
+        .map(config::parse_duration)
+        .transpose()?;
+    let max_size = jade_config
+        .cache_max_size
+        .as_deref()
+        .map(config::parse_size)
+        .transpose()?;
+
+    if older_than.is_some() || max_size.is_some() {
+        let removed = repo::prune_cache(older_than, max_size)?;
+        debug!("Pruned {} cached repo maps", removed.len());
+    }
+
+    Ok(())
+}
+
 /// # Determine Config Path
 ///
 /// Decides the path for the configuration file. If the application is in a test environment,
@@ -149,7 +921,7 @@ fn determine_config_path() -> Result<PathBuf, Box<dyn Error>> {
 
 /// # Initialization Function
 ///
-/// Handles the 'init' command. It is responsible for creating the necessary directories and
+/// Handles the 'init --yes' command. It is responsible for creating the necessary directories and
 /// files, and writing the default configuration and templates into them. It ensures that the
 /// application is ready for use, with all required setups completed.
 ///
@@ -157,11 +929,221 @@ fn determine_config_path() -> Result<PathBuf, Box<dyn Error>> {
 /// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
 fn init() -> Result<(), Box<dyn Error>> {
     let config_dir = config_dir()?;
+    create_templates(&config_dir)?;
+
+    let config_path = config_dir.join("config.yaml");
+    info!("Creating config file: {}", config_path.display());
+    let config = config::AwfulJadeConfig {
+        api_base: "http://localhost:5001/v1".to_string(),
+        api_key: "CHANGEME".to_string(),
+        model: "mistrel-7b-openorca".to_string(),
+        context_max_tokens: 8192,
+        assistant_minimum_context_tokens: 2048,
+        stop_words: vec![
+            "<|im_end|>\\n<|im_start|>".to_string(),
+            "\n<|im_start|>".to_string(),
+        ],
+        ..Default::default()
+    };
+    let config_yaml = serde_yaml::to_string(&config)?;
+    fs::write(config_path, config_yaml)?;
+
+    Ok(())
+}
+
+/// # Initialization Wizard
+///
+/// Handles the 'init' command without `--yes`. It looks for Ollama and LM Studio on localhost,
+/// asks for the backend, model, context size, and output defaults, writes the configuration and
+/// templates, and checks that the backend answers. A conversation can be started right away.
+///
+/// ## Parameters
+/// - `config_path: &Path`: Where the configuration is written
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+async fn init_wizard(config_path: &Path) -> Result<(), Box<dyn Error>> {
+    if config_path.exists()
+        && !confirm(
+            &format!("{} already exists. Replace it?", config_path.display()),
+            false,
+        )?
+    {
+        return Ok(());
+    }
+
+    println!("Looking for local backends...");
+    let backends = setup::detect_backends().await;
+    let mut options: Vec<String> = backends
+        .iter()
+        .map(|backend| {
+            format!(
+                "{} at {} ({} models)",
+                backend.name,
+                backend.api_base,
+                backend.models.len()
+            )
+        })
+        .collect();
+    options.push("Another OpenAI-compatible API".to_string());
+    let choice = pick("Which backend should aj use?", &options, 0)?;
+    let (api_base, api_key, models) = match backends.get(choice) {
+        Some(backend) => (
+            backend.api_base.clone(),
+            "local".to_string(),
+            backend.models.clone(),
+        ),
+        None => {
+            let api_base = ask("API base URL", "http://localhost:5001/v1")?;
+            let api_key = ask("API key", "CHANGEME")?;
+            let models = setup::list_models(&api_base, &api_key, Duration::from_secs(5))
+                .await
+                .unwrap_or_else(|e| {
+                    debug!("Failed to list the models of {}: {}", api_base, e);
+                    Vec::new()
+                });
+            (api_base, api_key, models)
+        }
+    };
+
+    let model = if models.is_empty() {
+        let mut model = String::new();
+        while model.is_empty() {
+            model = ask("Model name", "")?;
+        }
+        model
+    } else {
+        models[pick("Which model should aj use?", &models, 0)?].clone()
+    };
+
+    let sizes = [4096, 8192, 16384, 32768];
+    let size_options: Vec<String> = sizes
+        .iter()
+        .map(|size| format!("{} tokens", size))
+        .collect();
+    let context_max_tokens =
+        sizes[pick("How large is the model's context window?", &size_options, 1)?];
+
+    let should_stream = confirm("Stream answers as they are generated?", true)?;
+    let pager = confirm("Show answers taller than the terminal in a pager?", false)?;
+    let themes = ["Dark".to_string(), "Light".to_string()];
+    let theme = match pick("Is your terminal's background dark or light?", &themes, 0)? {
+        0 => BuiltinTheme::Dark,
+        _ => BuiltinTheme::Light,
+    };
+    let conversation = ask(
+        "Name a first conversation to start now (leave empty to skip)",
+        "",
+    )?;
+
+    let config_dir = config_dir()?;
+    create_templates(&config_dir)?;
+    let jade_config = config::AwfulJadeConfig {
+        api_base,
+        api_key,
+        model,
+        context_max_tokens,
+        assistant_minimum_context_tokens: context_max_tokens / 4,
+        should_stream: Some(should_stream),
+        pager: Some(pager),
+        theme: Some(config::ThemeConfig {
+            name: Some(theme),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(config_path, serde_yaml::to_string(&jade_config)?)?;
+    println!("Wrote {}", config_path.display());
+
+    println!("Checking {}...", jade_config.api_base);
+    setup::check_connectivity(&jade_config).await.map_err(|e| {
+        format!(
+            "{}. Edit {} to fix it, or run `aj init` again.",
+            e,
+            config_path.display()
+        )
+    })?;
+    println!("aj is ready.");
+
+    if !conversation.is_empty() {
+        let jade_config = config::parse_config(config_path.to_str().unwrap())?;
+        handle_interactive_command(jade_config, Some(conversation)).await?;
+    }
+    Ok(())
+}
+
+/// # Ask
+///
+/// Asks `question` on stderr, so it stays out of piped output, and reads the answer from stdin,
+/// returning `default` when the answer is empty.
+fn ask(question: &str, default: &str) -> io::Result<String> {
+    if default.is_empty() {
+        eprint!("{}: ", question);
+    } else {
+        eprint!("{} [{}]: ", question, default);
+    }
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+/// # Confirm
+///
+/// Asks a yes or no `question`, returning `default` when the answer is empty.
+fn confirm(question: &str, default: bool) -> io::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        match ask(&format!("{} [{}]", question, hint), "")?
+            .to_lowercase()
+            .as_str()
+        {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n."),
+        }
+    }
+}
+
+/// # Pick
+///
+/// Lists `options`, numbered from 1, and asks for one of them until a valid number is given.
+/// Returns the index of the chosen option, or `default` when the answer is empty.
+fn pick(question: &str, options: &[String], default: usize) -> io::Result<usize> {
+    println!("{}", question);
+    for (i, option) in options.iter().enumerate() {
+        println!("  {}. {}", i + 1, option);
+    }
+    loop {
+        let answer = ask("Choice", &(default + 1).to_string())?;
+        match answer.parse::<usize>() {
+            Ok(number) if (1..=options.len()).contains(&number) => return Ok(number - 1),
+            _ => println!("Please pick a number from 1 to {}.", options.len()),
+        }
+    }
+}
+
+/// # Create Templates
+///
+/// Writes the starter templates, `simple_question` and `default`, into the templates directory
+/// of `config_dir`, creating it if needed.
+///
+/// ## Parameters
+/// - `config_dir: &Path`: The configuration directory
+///
+/// ## Returns
+/// - `Result<(), Box<dyn Error>>`: Result type indicating success or error
+fn create_templates(config_dir: &Path) -> Result<(), Box<dyn Error>> {
     let path = config_dir.join("templates");
     info!("Creating template config directory: {}", path.display());
     fs::create_dir_all(path.clone())?;
 
-    let template_path = config_dir.join("templates/simple_question.yml");
+    let template_path = config_dir.join("templates/simple_question.yaml");
     info!("Creating template file: {}", template_path.display());
     let template = template::ChatTemplate {
         system_prompt: "You are Awful Jade, a helpful AI assistant programmed by Awful Security."
@@ -195,28 +1177,12 @@ fn main() -> io::Result<()> {
                 function_call: None,
             },
         ],
+        ..Default::default()
     };
     let template_yaml = serde_yaml::to_string(&template)?;
     fs::write(template_path, template_yaml)?;
     // Create the default template
     create_default_template(&path)?;
-
-    let config_path = config_dir.join("config.yaml");
-    info!("Creating config file: {}", config_path.display());
-    let config = config::AwfulJadeConfig {
-        api_base: "http://localhost:5001/v1".to_string(),
-        api_key: "CHANGEME".to_string(),
-        model: "mistrel-7b-openorca".to_string(),
-        context_max_tokens: 8192,
-        assistant_minimum_context_tokens: 2048,
-        stop_words: vec![
-            "<|im_end|>\\n<|im_start|>".to_string(),
-            "\n<|im_start|>".to_string(),
-        ],
-    };
-    let config_yaml = serde_yaml::to_string(&config)?;
-    fs::write(config_path, config_yaml)?;
-
     Ok(())
 }
 
@@ -244,16 +1210,53 @@ messages: []
     Ok(())
 }
 
-/// # Configuration Directory Retrieval
+/// # Terminal Progress
 ///
-/// Uses the `directories` crate to fetch the appropriate configuration directory based on the
-/// operating system. This ensures compatibility and adherence to the OS's directory structure
-/// and conventions.
-///
-/// ## Returns
-/// - `Result<PathBuf, Box<dyn Error>>`: The path to the configuration directory or an error
-pub fn config_dir() -> Result<std::path::PathBuf, Box<dyn Error>> {
-    let proj_dirs = ProjectDirs::from("com", "awful-security", "aj")
-        .ok_or("Unable to determine config directory")?;
-    Ok(proj_dirs.config_dir().to_path_buf())
+/// Renders progress events from the library as a progress bar on stderr, or as a spinner when a
+/// task does not know how many steps it has.
+struct TerminalProgress {
+    bar: Mutex<Option<ProgressBar>>,
+    spinner: String,
+}
+
+impl TerminalProgress {
+    /// Creates a progress renderer using the theme's spinner.
+    fn new(theme: &Theme) -> Self {
+        Self {
+            bar: Mutex::new(None),
+            spinner: theme.spinner.clone(),
+        }
+    }
+}
+
+impl ProgressSink for TerminalProgress {
+    fn on_event(&self, event: ProgressEvent) {
+        let mut bar = self.bar.lock().unwrap();
+        match event {
+            ProgressEvent::Started { task, total } => {
+                let progress = match total {
+                    Some(total) => ProgressBar::new(total).with_style(
+                        ProgressStyle::with_template("{msg} [{bar:30}] {pos}/{len}")
+                            .unwrap()
+                            .progress_chars("=> "),
+                    ),
+                    None => ProgressBar::new_spinner()
+                        .with_style(ProgressStyle::default_spinner().tick_chars(&self.spinner)),
+                };
+                progress.set_message(task.to_string());
+                progress.enable_steady_tick(Duration::from_millis(100));
+                *bar = Some(progress);
+            }
+            ProgressEvent::Advanced { completed, .. } => {
+                if let Some(progress) = bar.as_ref() {
+                    progress.set_position(completed);
+                }
+            }
+            ProgressEvent::Finished { .. } => {
+                if let Some(progress) = bar.take() {
+                    progress.finish_and_clear();
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,240 @@
+//! This module records why memories were, or were not, handed to the model.
+//!
+//! When `explain_memory: true` is set in the configuration, every question asked in interactive
+//! mode appends one JSON line to `memory_audit.jsonl` in the config directory. Each line lists the
+//! memories retrieved from the vector store, their distances to the question, and whether they
+//! made it into the brain. `aj memory explain --last N` prints the most recent entries.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use awful_aj::memory_audit::read_last;
+//!
+//! for explanation in read_last(1).unwrap() {
+//!     println!("{}", explanation);
+//! }
+//! ```
+
+use crate::{brain::Brain, brain::Memory, vector_store::VectorStore};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the audit file in the config directory.
+const AUDIT_FILENAME: &str = "memory_audit.jsonl";
+
+/// The decision made about a single retrieved memory.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MemoryDecision {
+    /// The id of the memory in the vector store.
+    pub id: usize,
+
+    /// The distance between the memory and the question; smaller is more similar.
+    pub distance: f32,
+
+    /// The retrieved memory, if the vector store still holds its content.
+    pub memory: Option<Memory>,
+
+    /// Whether the memory is part of the brain sent with the question.
+    pub injected: bool,
+
+    /// Why the memory was rejected, if it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Everything retrieved for one question and what became of it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetrievalExplanation {
+    /// When the question was asked, in seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// The name of the conversation the question was asked in.
+    pub conversation: String,
+
+    /// The text that was embedded to search the vector store.
+    pub query: String,
+
+    /// How many neighbours were requested from the vector store.
+    pub top_k: usize,
+
+    /// The retrieved memories, closest first.
+    pub candidates: Vec<MemoryDecision>,
+}
+
+impl RetrievalExplanation {
+    /// Explains a retrieval by checking which of the `neighbors` survived in `brain`.
+    ///
+    /// # Parameters
+    ///
+    /// - `conversation`: The name of the conversation.
+    /// - `query`: The text that was embedded to search the vector store.
+    /// - `top_k`: How many neighbours were requested.
+    /// - `neighbors`: The `(id, distance)` pairs returned by the vector store.
+    /// - `vector_store`: The vector store the neighbours came from.
+    /// - `brain`: The brain after the neighbours were added to it.
+    pub fn new(
+        conversation: &str,
+        query: &str,
+        top_k: usize,
+        neighbors: &[(usize, f32)],
+        vector_store: &VectorStore,
+        brain: &Brain,
+    ) -> Self {
+        let candidates = neighbors
+            .iter()
+            .map(|&(id, distance)| {
+                let memory = vector_store.get_content_by_id(id).cloned();
+                let (injected, reason) = match &memory {
+                    None => (false, Some("no content is stored for this id".to_string())),
+                    Some(memory) if brain.contains(memory) => (true, None),
+                    Some(_) => (
+                        false,
+                        Some("evicted to keep the brain within its token budget".to_string()),
+                    ),
+                };
+                MemoryDecision {
+                    id,
+                    distance,
+                    memory,
+                    injected,
+                    reason,
+                }
+            })
+            .collect();
+
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            conversation: conversation.to_string(),
+            query: query.to_string(),
+            top_k,
+            candidates,
+        }
+    }
+}
+
+impl fmt::Display for RetrievalExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "[{}] {} (at {}, top_k = {})",
+            self.conversation, self.query, self.timestamp, self.top_k
+        )?;
+        if self.candidates.is_empty() {
+            writeln!(f, "  no memories were retrieved")?;
+        }
+        for candidate in &self.candidates {
+            let verdict = if candidate.injected {
+                "injected".to_string()
+            } else {
+                format!(
+                    "rejected: {}",
+                    candidate.reason.as_deref().unwrap_or("unknown")
+                )
+            };
+            let memory = candidate
+                .memory
+                .as_ref()
+                .map(|m| format!("{}: {:?}", m.role(), m.content()))
+                .unwrap_or_default();
+            writeln!(
+                f,
+                "  #{} distance {:.4} {} {}",
+                candidate.id, candidate.distance, verdict, memory
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the audit file.
+pub fn audit_path() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(crate::config_dir()?.join(AUDIT_FILENAME))
+}
+
+/// Appends an explanation to the audit file.
+pub fn record(explanation: &RetrievalExplanation) -> Result<(), Box<dyn Error>> {
+    record_to(&audit_path()?, explanation)
+}
+
+/// Reads the last `count` explanations from the audit file, oldest first.
+pub fn read_last(count: usize) -> Result<Vec<RetrievalExplanation>, Box<dyn Error>> {
+    read_last_from(&audit_path()?, count)
+}
+
+fn record_to(path: &Path, explanation: &RetrievalExplanation) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(explanation)?)?;
+    Ok(())
+}
+
+fn read_last_from(path: &Path, count: usize) -> Result<Vec<RetrievalExplanation>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    let lines: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+    lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(|line| serde_json::from_str(line).map_err(|e| e.into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::Role;
+    use tempfile::tempdir;
+
+    fn explanation(query: &str) -> RetrievalExplanation {
+        RetrievalExplanation {
+            timestamp: 0,
+            conversation: "default".to_string(),
+            query: query.to_string(),
+            top_k: 3,
+            candidates: vec![MemoryDecision {
+                id: 1,
+                distance: 0.25,
+                memory: Some(Memory::new(Role::Assistant, "Rust is neat.".to_string())),
+                injected: false,
+                reason: Some("evicted to keep the brain within its token budget".to_string()),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_record_and_read_last() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(AUDIT_FILENAME);
+
+        assert!(read_last_from(&path, 1).unwrap().is_empty());
+
+        record_to(&path, &explanation("first")).unwrap();
+        record_to(&path, &explanation("second")).unwrap();
+        record_to(&path, &explanation("third")).unwrap();
+
+        let last = read_last_from(&path, 2).unwrap();
+        let queries: Vec<_> = last.iter().map(|e| e.query.as_str()).collect();
+        assert_eq!(queries, vec!["second", "third"]);
+        assert!(!last[0].candidates[0].injected);
+    }
+
+    #[test]
+    fn test_display_lists_rejection_reason() {
+        let rendered = explanation("why?").to_string();
+        assert!(rendered.contains("#1 distance 0.2500 rejected: evicted"));
+        assert!(rendered.contains("assistant: \"Rust is neat.\""));
+    }
+}
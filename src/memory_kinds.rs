@@ -0,0 +1,223 @@
+//! This module sorts memories by what they hold: facts, preferences, tasks, or conversation.
+//!
+//! With `memory_kinds.classify` set, the messages archived in a turn are classified by the model,
+//! in one extra request, before they are stored; memories that are not classified, or that were
+//! stored before, are of the `conversation` kind. Kinds then steer recall:
+//!
+//! ```yaml
+//! memory_kinds:
+//!   classify: true
+//!   recall: [fact, preference, task]
+//!   always_include: [preference]
+//!   max_tokens:
+//!     fact: 500
+//! ```
+//!
+//! Only memories of the `recall` kinds are recalled (all kinds when it is empty). Memories of the
+//! `always_include` kinds are recalled with every question, however far they are from it, and are
+//! the last to be evicted from the brain. `max_tokens` caps the tokens the recalled memories of a
+//! kind may use.
+//!
+//! The prompt can be customized with a `classify_memories` template; without one, a built-in
+//! prompt is used.
+
+use crate::{
+    api,
+    brain::{Memory, MemoryKind},
+    config::AwfulJadeConfig,
+    model_family::ModelDefaults,
+    template::{self, ChatTemplate},
+    vector_store::VectorStore,
+};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role};
+use std::error::Error;
+use tracing::error;
+
+/// The name of the template used to classify memories.
+pub const MEMORY_KINDS_TEMPLATE: &str = "classify_memories";
+
+/// The most tokens the model may spend on each classified memory.
+const TOKENS_PER_KIND: u16 = 8;
+
+/// The system prompt used when no `classify_memories` template exists.
+const DEFAULT_MEMORY_KINDS_PROMPT: &str = "You sort messages before they are stored. For each numbered message, decide whether it states a fact about the user or their work (fact), a preference about how they like things done (preference), something they still need to do (task), or none of those (conversation). Reply with one line per message, like `1: fact`, and nothing else.";
+
+/// Returns the built-in classification template.
+pub fn default_memory_kinds_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_MEMORY_KINDS_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Builds the messages asking the model to classify `memories`, numbered from 1.
+pub fn classification_messages(
+    template: &ChatTemplate,
+    memories: &[Memory],
+) -> Vec<ChatCompletionRequestMessage> {
+    let numbered: Vec<String> = memories
+        .iter()
+        .enumerate()
+        .map(|(i, memory)| format!("{}. ({}) {}", i + 1, memory.role(), memory.content()))
+        .collect();
+    let prompt = format!("Messages:\n{}\n\nKinds:", numbered.join("\n"));
+
+    let mut messages = api::prepare_messages(template).unwrap_or_default();
+    messages.push(ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(prompt),
+        name: None,
+        function_call: None,
+    });
+    messages
+}
+
+/// Returns the kinds of `count` memories from the model's reply, a line like `2: task` for each.
+///
+/// Memories the reply leaves out, or gives a kind that is not known, are of the `conversation`
+/// kind.
+pub fn parse_kinds(reply: &str, count: usize) -> Vec<MemoryKind> {
+    let mut kinds = vec![MemoryKind::Conversation; count];
+    for line in reply.lines() {
+        let Some((number, kind)) = line.split_once([':', '.']) else {
+            continue;
+        };
+        let number = number.trim_matches(|c: char| !c.is_ascii_digit());
+        let kind = kind.trim_matches(|c: char| !c.is_alphabetic());
+        let Some(slot) = number
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| kinds.get_mut(number.checked_sub(1)?))
+        else {
+            continue;
+        };
+        if let Some(known) = MemoryKind::ALL
+            .into_iter()
+            .find(|known| known.name().eq_ignore_ascii_case(kind))
+        {
+            *slot = known;
+        }
+    }
+    kinds
+}
+
+/// Asks the model for the kind of each of `memories`.
+pub async fn classify(
+    config: &AwfulJadeConfig,
+    memories: &[Memory],
+) -> Result<Vec<MemoryKind>, Box<dyn Error>> {
+    let template = template::load_template(MEMORY_KINDS_TEMPLATE)
+        .await
+        .unwrap_or_else(|_| default_memory_kinds_template());
+    let max_tokens = (memories.len() as u16)
+        .saturating_add(1)
+        .saturating_mul(TOKENS_PER_KIND);
+    let mut request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(max_tokens)
+        .model(config.model.clone())
+        .stop(template.stop_words(config, &[]))
+        .messages(
+            ModelDefaults::from_config(config)
+                .adapt_messages(classification_messages(&template, memories)),
+        )
+        .build()?;
+    request.temperature = config.temperature;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let reply = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(parse_kinds(&reply, memories.len()))
+}
+
+/// Classifies `memories`. When classification fails, the failure is logged and the memories are
+/// returned as they are.
+pub async fn classify_memories(config: &AwfulJadeConfig, memories: Vec<Memory>) -> Vec<Memory> {
+    match classify(config, &memories).await {
+        Ok(kinds) => memories
+            .into_iter()
+            .zip(kinds)
+            .map(|(memory, kind)| memory.with_kind(kind))
+            .collect(),
+        Err(e) => {
+            error!("Failed to classify memories: {}", e);
+            memories
+        }
+    }
+}
+
+/// Returns whether `memory` may be recalled under `config`'s `memory_kinds`.
+pub fn is_recalled(config: &AwfulJadeConfig, memory: &Memory) -> bool {
+    match &config.memory_kinds {
+        Some(kinds) => {
+            kinds.recall.is_empty()
+                || kinds.recall.contains(&memory.kind())
+                || kinds.always_include.contains(&memory.kind())
+        }
+        None => true,
+    }
+}
+
+/// Returns the memories in `vector_store` of the `memory_kinds.always_include` kinds, oldest
+/// first.
+pub fn always_included(config: &AwfulJadeConfig, vector_store: &VectorStore) -> Vec<Memory> {
+    let Some(kinds) = &config.memory_kinds else {
+        return Vec::new();
+    };
+    if kinds.always_include.is_empty() {
+        return Vec::new();
+    }
+    vector_store
+        .memories()
+        .into_iter()
+        .filter(|(_, memory)| kinds.always_include.contains(&memory.kind()))
+        .map(|(_, memory)| memory.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MemoryKindsConfig;
+
+    #[test]
+    fn test_parse_kinds() {
+        let reply = "1: fact\n2. Preference\n- 4: `task`\n5: opinion\n9: fact";
+        assert_eq!(
+            parse_kinds(reply, 5),
+            vec![
+                MemoryKind::Fact,
+                MemoryKind::Preference,
+                MemoryKind::Conversation,
+                MemoryKind::Task,
+                MemoryKind::Conversation,
+            ]
+        );
+        assert_eq!(parse_kinds("", 1), vec![MemoryKind::Conversation]);
+    }
+
+    #[test]
+    fn test_is_recalled() {
+        let config = AwfulJadeConfig {
+            memory_kinds: Some(MemoryKindsConfig {
+                recall: vec![MemoryKind::Fact],
+                always_include: vec![MemoryKind::Preference],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let memory = |kind| Memory::new(Role::User, "I use tabs.".to_string()).with_kind(kind);
+        assert!(is_recalled(&config, &memory(MemoryKind::Fact)));
+        assert!(is_recalled(&config, &memory(MemoryKind::Preference)));
+        assert!(!is_recalled(&config, &memory(MemoryKind::Task)));
+        assert!(!is_recalled(&config, &memory(MemoryKind::Conversation)));
+        assert!(is_recalled(
+            &AwfulJadeConfig::default(),
+            &memory(MemoryKind::Task)
+        ));
+    }
+}
@@ -0,0 +1,320 @@
+//! This module picks prompt conventions for a model from its family.
+//!
+//! Local models are trained on different chat formats: they stop on different tokens, and some
+//! ignore or reject system messages. The family is detected from the model name (`qwen2-7b` is
+//! `chatml`, `mistral-7b-instruct` is `mistral`, ...) and supplies default stop words and system
+//! message handling. Any of them can be overridden in `config.yaml`:
+//!
+//! ```yaml
+//! model_family:
+//!   name: chatml
+//!   supports_system_role: false
+//! ```
+//!
+//! Models that match no family get no extra stop words and are sent system messages unchanged.
+
+use crate::config::{AwfulJadeConfig, SystemRoleStrategy};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use tracing::{debug, error};
+
+/// A family of models sharing a chat format.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ModelFamily {
+    /// The name the family is selected by in `model_family.name`.
+    pub name: &'static str,
+
+    /// Lowercase substrings of model names belonging to the family.
+    pub patterns: &'static [&'static str],
+
+    /// The tokens that end a response in the family's chat format.
+    pub stop_words: &'static [&'static str],
+
+    /// Whether the family's chat format has a system role.
+    pub supports_system_role: bool,
+
+    /// Whether, without a system role, system messages are merged into the first user message.
+    pub merge_system_into_user: bool,
+}
+
+/// The built-in families, in the order they are matched. Families whose patterns appear in other
+/// families' model names (`hermes` models are often fine-tuned from `mistral`) come first.
+pub const REGISTRY: &[ModelFamily] = &[
+    ModelFamily {
+        name: "chatml",
+        patterns: &["chatml", "qwen", "hermes", "dolphin", "openchat"],
+        stop_words: &["<|im_end|>", "<|im_start|>"],
+        supports_system_role: true,
+        merge_system_into_user: false,
+    },
+    ModelFamily {
+        name: "llama3",
+        patterns: &["llama-3", "llama3"],
+        stop_words: &["<|eot_id|>"],
+        supports_system_role: true,
+        merge_system_into_user: false,
+    },
+    ModelFamily {
+        name: "llama2",
+        patterns: &["llama-2", "llama2", "codellama"],
+        stop_words: &["</s>", "[INST]"],
+        supports_system_role: true,
+        merge_system_into_user: false,
+    },
+    ModelFamily {
+        name: "mistral",
+        patterns: &["mistral", "mixtral"],
+        stop_words: &["</s>", "[INST]"],
+        supports_system_role: false,
+        merge_system_into_user: true,
+    },
+    ModelFamily {
+        name: "gemma",
+        patterns: &["gemma"],
+        stop_words: &["<end_of_turn>"],
+        supports_system_role: false,
+        merge_system_into_user: true,
+    },
+    ModelFamily {
+        name: "phi3",
+        patterns: &["phi-3", "phi3"],
+        stop_words: &["<|end|>"],
+        supports_system_role: true,
+        merge_system_into_user: false,
+    },
+    ModelFamily {
+        name: "zephyr",
+        patterns: &["zephyr"],
+        stop_words: &["</s>", "<|user|>"],
+        supports_system_role: true,
+        merge_system_into_user: false,
+    },
+    ModelFamily {
+        name: "vicuna",
+        patterns: &["vicuna"],
+        stop_words: &["</s>", "USER:"],
+        supports_system_role: true,
+        merge_system_into_user: false,
+    },
+];
+
+/// Returns the family `model` belongs to, if its name matches one.
+pub fn detect(model: &str) -> Option<&'static ModelFamily> {
+    let model = model.to_lowercase();
+    REGISTRY.iter().find(|family| {
+        family
+            .patterns
+            .iter()
+            .any(|pattern| model.contains(pattern))
+    })
+}
+
+/// Returns the family with the given name.
+pub fn by_name(name: &str) -> Option<&'static ModelFamily> {
+    REGISTRY
+        .iter()
+        .find(|family| family.name.eq_ignore_ascii_case(name.trim()))
+}
+
+/// The prompt conventions used for the configured model: its family's, with the overrides from
+/// `model_family` applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelDefaults {
+    /// The name of the family the defaults come from, if any.
+    pub family: Option<&'static str>,
+
+    /// Stop words added to the template's and configuration's.
+    pub stop_words: Vec<String>,
+
+    /// Whether system messages are sent as they are.
+    pub supports_system_role: bool,
+
+    /// Whether, without system role support, system messages are merged into the first user message.
+    pub merge_system_into_user: bool,
+}
+
+impl ModelDefaults {
+    /// Resolves the defaults for `config.model`.
+    ///
+    /// The family named in `model_family.name` is used when it is set, otherwise the family is
+    /// detected from the model name. An unknown family name is logged and treated as no family.
+    /// `system_role_strategy`, when set, takes precedence over the family's system role handling.
+    pub fn from_config(config: &AwfulJadeConfig) -> Self {
+        let overrides = config.model_family.clone().unwrap_or_default();
+        let family = match &overrides.name {
+            Some(name) => {
+                let family = by_name(name);
+                if family.is_none() {
+                    error!("Unknown model family `{}`, using no family defaults", name);
+                }
+                family
+            }
+            None => detect(&config.model),
+        };
+        debug!(
+            "Model family for {}: {:?}",
+            config.model,
+            family.map(|family| family.name)
+        );
+
+        let mut defaults = Self {
+            family: family.map(|family| family.name),
+            stop_words: overrides.stop_words.unwrap_or_else(|| {
+                family
+                    .map(|family| family.stop_words.iter().map(|w| w.to_string()).collect())
+                    .unwrap_or_default()
+            }),
+            supports_system_role: overrides
+                .supports_system_role
+                .unwrap_or_else(|| family.is_none_or(|family| family.supports_system_role)),
+            merge_system_into_user: overrides
+                .merge_system_into_user
+                .unwrap_or_else(|| family.is_none_or(|family| family.merge_system_into_user)),
+        };
+        match config.system_role_strategy {
+            Some(SystemRoleStrategy::Native) => defaults.supports_system_role = true,
+            Some(SystemRoleStrategy::PrependToFirstUser) => {
+                defaults.supports_system_role = false;
+                defaults.merge_system_into_user = true;
+            }
+            None => {}
+        }
+        defaults
+    }
+
+    /// Rewrites the system messages in `messages` for models without a system role.
+    ///
+    /// With `merge_system_into_user`, the system messages are removed and their content is put in
+    /// front of the first user message; otherwise they are sent as user messages. Messages are
+    /// returned unchanged when the model supports the system role.
+    pub fn adapt_messages(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        if self.supports_system_role {
+            return messages;
+        }
+        if !self.merge_system_into_user {
+            return messages
+                .into_iter()
+                .map(|mut message| {
+                    if message.role == Role::System {
+                        message.role = Role::User;
+                    }
+                    message
+                })
+                .collect();
+        }
+
+        let (system, mut rest): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .partition(|message| message.role == Role::System);
+        let instructions: Vec<String> = system
+            .into_iter()
+            .filter_map(|message| message.content)
+            .filter(|content| !content.is_empty())
+            .collect();
+        if instructions.is_empty() {
+            return rest;
+        }
+        let instructions = instructions.join("\n\n");
+
+        match rest.iter_mut().find(|message| message.role == Role::User) {
+            Some(first_user) => {
+                let content = first_user.content.take().unwrap_or_default();
+                first_user.content = Some(format!("{}\n\n{}", instructions, content));
+            }
+            None => rest.insert(
+                0,
+                ChatCompletionRequestMessage {
+                    role: Role::User,
+                    content: Some(instructions),
+                    name: None,
+                    function_call: None,
+                },
+            ),
+        }
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelFamilyConfig;
+    use crate::test_support::message;
+
+    #[test]
+    fn test_detect() {
+        let family = |model: &str| detect(model).map(|family| family.name);
+        assert_eq!(family("Qwen2-7B-Instruct"), Some("chatml"));
+        assert_eq!(family("openhermes-2.5-mistral-7b"), Some("chatml"));
+        assert_eq!(family("mistral-7b-instruct-v0.2"), Some("mistral"));
+        assert_eq!(family("Meta-Llama-3-8B-Instruct"), Some("llama3"));
+        assert_eq!(family("gpt-4"), None);
+    }
+
+    #[test]
+    fn test_config_overrides_family() {
+        let config = AwfulJadeConfig {
+            model: "gemma-2b-it".to_string(),
+            model_family: Some(ModelFamilyConfig {
+                stop_words: Some(vec!["###".to_string()]),
+                merge_system_into_user: Some(false),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let defaults = ModelDefaults::from_config(&config);
+        assert_eq!(defaults.family, Some("gemma"));
+        assert_eq!(defaults.stop_words, vec!["###".to_string()]);
+        assert!(!defaults.supports_system_role);
+        assert!(!defaults.merge_system_into_user);
+    }
+
+    #[test]
+    fn test_adapt_messages() {
+        let messages = vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello."),
+        ];
+        let config = AwfulJadeConfig {
+            model: "mistral-7b-instruct".to_string(),
+            ..Default::default()
+        };
+        let adapted = ModelDefaults::from_config(&config).adapt_messages(messages.clone());
+        assert_eq!(adapted.len(), 2);
+        assert_eq!(adapted[0].role, Role::User);
+        assert_eq!(adapted[0].content.as_deref(), Some("Be brief.\n\nHi"));
+
+        let config = AwfulJadeConfig {
+            model: "gpt-4".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ModelDefaults::from_config(&config).adapt_messages(messages.clone()),
+            messages
+        );
+    }
+
+    #[test]
+    fn test_system_role_strategy_overrides_family() {
+        let config = AwfulJadeConfig {
+            model: "gpt-4".to_string(),
+            system_role_strategy: Some(SystemRoleStrategy::PrependToFirstUser),
+            ..Default::default()
+        };
+        let adapted = ModelDefaults::from_config(&config).adapt_messages(vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "Hi"),
+        ]);
+        assert_eq!(adapted, vec![message(Role::User, "Be brief.\n\nHi")]);
+
+        let config = AwfulJadeConfig {
+            model: "gemma-2b-it".to_string(),
+            system_role_strategy: Some(SystemRoleStrategy::Native),
+            ..Default::default()
+        };
+        assert!(ModelDefaults::from_config(&config).supports_system_role);
+    }
+}
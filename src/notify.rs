@@ -0,0 +1,147 @@
+//! This module announces that a slow answer is ready.
+//!
+//! Local models can take minutes to answer. When an `aj ask` takes longer than `notify_after`
+//! (10 seconds by default), aj runs `notify_command`, or, with `--notify` and no command
+//! configured, shows a desktop notification:
+//!
+//! ```yaml
+//! notify_command: 'terminal-notifier -title aj -message "$AJ_NOTIFICATION"'
+//! notify_after: 30s
+//! ```
+//!
+//! The command runs in the shell with `AJ_NOTIFICATION` set to a short summary and
+//! `AJ_ELAPSED_SECS` set to how long the answer took.
+
+use crate::config::{self, AwfulJadeConfig};
+use std::{error::Error, process::Command, time::Duration};
+use tracing::debug;
+
+/// How long an answer must take before it is announced, when `notify_after` is not set.
+pub const DEFAULT_NOTIFY_AFTER: Duration = Duration::from_secs(10);
+
+/// The most characters of the question quoted in a notification.
+const MAX_SUMMARY_QUESTION_CHARS: usize = 60;
+
+/// Returns how long an answer must take before it is announced.
+///
+/// # Returns
+///
+/// - `Ok(Duration)`: `notify_after`, or `DEFAULT_NOTIFY_AFTER` when it is not set.
+/// - `Err(String)`: `notify_after` could not be parsed.
+pub fn notify_after(config: &AwfulJadeConfig) -> Result<Duration, String> {
+    config
+        .notify_after
+        .as_deref()
+        .map_or(Ok(DEFAULT_NOTIFY_AFTER), config::parse_duration)
+}
+
+/// Returns the text of the notification for an answer to `question` that took `elapsed`.
+pub fn summary(question: &str, elapsed: Duration) -> String {
+    let mut quoted: String = question.chars().take(MAX_SUMMARY_QUESTION_CHARS).collect();
+    if question.chars().count() > MAX_SUMMARY_QUESTION_CHARS {
+        quoted.push('…');
+    }
+    format!("Answered in {}s: {}", elapsed.as_secs(), quoted)
+}
+
+/// Announces that the answer to `question` is ready, if notifications are enabled and it took
+/// longer than `notify_after`.
+///
+/// Notifications are enabled when `notify_command` is set or `requested` (`--notify`) is true.
+///
+/// # Returns
+///
+/// - `Ok(true)`: A notification was sent.
+/// - `Ok(false)`: Notifications are disabled, or the answer was quick.
+/// - `Err(Box<dyn Error>)`: `notify_after` is invalid, or the notification could not be sent.
+pub fn after_answer(
+    config: &AwfulJadeConfig,
+    requested: bool,
+    question: &str,
+    elapsed: Duration,
+) -> Result<bool, Box<dyn Error>> {
+    if !requested && config.notify_command.is_none() {
+        return Ok(false);
+    }
+    if elapsed < notify_after(config)? {
+        return Ok(false);
+    }
+
+    let summary = summary(question, elapsed);
+    match &config.notify_command {
+        Some(command) => run_command(command, &summary, elapsed)?,
+        None => show_desktop_notification(&summary)?,
+    }
+    Ok(true)
+}
+
+/// Runs `command` in the shell with the notification in its environment.
+fn run_command(command: &str, summary: &str, elapsed: Duration) -> Result<(), Box<dyn Error>> {
+    debug!("Running notify command: {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(command)
+        .env("AJ_NOTIFICATION", summary)
+        .env("AJ_ELAPSED_SECS", elapsed.as_secs().to_string())
+        .status()?;
+    if !status.success() {
+        return Err(format!("The notify command failed with {}", status).into());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "notify")]
+fn show_desktop_notification(summary: &str) -> Result<(), Box<dyn Error>> {
+    notify_rust::Notification::new()
+        .summary("aj")
+        .body(summary)
+        .show()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "notify"))]
+fn show_desktop_notification(_summary: &str) -> Result<(), Box<dyn Error>> {
+    Err("Desktop notifications need the `notify` feature; set `notify_command` instead".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_truncates_question() {
+        let question = "a".repeat(100);
+        let summary = summary(&question, Duration::from_secs(42));
+        assert!(summary.starts_with("Answered in 42s: aaa"));
+        assert!(summary.ends_with('…'));
+    }
+
+    #[test]
+    fn test_after_answer() {
+        let config = AwfulJadeConfig::default();
+        let slow = Duration::from_secs(60);
+        assert!(!after_answer(&config, false, "Hi", slow).unwrap());
+
+        let config = AwfulJadeConfig {
+            notify_command: Some("exit 0".to_string()),
+            notify_after: Some("2m".to_string()),
+            ..Default::default()
+        };
+        assert!(!after_answer(&config, false, "Hi", slow).unwrap());
+        assert!(after_answer(&config, false, "Hi", Duration::from_secs(180)).unwrap());
+
+        let config = AwfulJadeConfig {
+            notify_command: Some("exit 3".to_string()),
+            ..Default::default()
+        };
+        assert!(after_answer(&config, false, "Hi", slow).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! This module shows long answers in a pager.
+//!
+//! Answers taller than the terminal scroll out of view before they can be read. When paging is
+//! enabled (`pager: true`, or passing `--pager`), such answers are piped through `$PAGER`, or
+//! `less -R` if it is unset, so they can be read from the top. An answer that may be paged is
+//! still streamed, but only shown once it is complete, so it is only shown once.
+
+use crate::config::AwfulJadeConfig;
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// The pager used when `$PAGER` is not set.
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Returns the pager command and its arguments, from `$PAGER` or `DEFAULT_PAGER`.
+pub fn pager_command() -> Vec<String> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    pager.split_whitespace().map(str::to_string).collect()
+}
+
+/// Returns whether `text` is taller than a terminal `height` rows high.
+pub fn exceeds_height(text: &str, height: usize) -> bool {
+    text.lines().count() > height
+}
+
+/// Returns whether long answers go to the pager: paging is enabled and stdout is a terminal.
+pub fn is_active(config: &AwfulJadeConfig) -> bool {
+    config.use_pager() && io::stdout().is_terminal()
+}
+
+/// Shows `text` in the pager if paging is active (see `is_active`) and `text` is taller than the
+/// terminal.
+///
+/// # Returns
+///
+/// - `Ok(true)`: The text was shown in the pager.
+/// - `Ok(false)`: The text did not need paging, or the pager could not be started.
+/// - `Err(io::Error)`: Writing to the pager failed.
+pub fn page_if_long(config: &AwfulJadeConfig, text: &str) -> io::Result<bool> {
+    if !is_active(config) {
+        return Ok(false);
+    }
+    let Ok((_, height)) = crossterm::terminal::size() else {
+        return Ok(false);
+    };
+    if !exceeds_height(text, height as usize) {
+        return Ok(false);
+    }
+    page(text)
+}
+
+/// Pipes `text` through the pager and waits for it to exit.
+///
+/// # Returns
+///
+/// - `Ok(true)`: The text was shown in the pager.
+/// - `Ok(false)`: The pager could not be started.
+/// - `Err(io::Error)`: Writing to the pager failed.
+pub fn page(text: &str) -> io::Result<bool> {
+    let command = pager_command();
+    let Some((program, args)) = command.split_first() else {
+        return Ok(false);
+    };
+    let Ok(mut child) = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+    else {
+        return Ok(false);
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager closes its input when the user quits before reading everything.
+        match stdin.write_all(text.as_bytes()) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+            _ => {}
+        }
+    }
+    child.wait()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeds_height() {
+        assert!(!exceeds_height("one\ntwo\n", 2));
+        assert!(exceeds_height("one\ntwo\nthree", 2));
+    }
+
+    #[test]
+    fn test_page_if_long_respects_config() {
+        let config = AwfulJadeConfig {
+            pager: Some(false),
+            ..Default::default()
+        };
+        assert!(!page_if_long(&config, &"line\n".repeat(10_000)).unwrap());
+    }
+}
@@ -0,0 +1,264 @@
+//! This module extends aj with commands and hooks that live outside of it.
+//!
+//! ## Commands
+//!
+//! `aj <name> [args...]`, for a `<name>` that is not one of aj's own commands, runs the executable
+//! `aj-<name>` found on `PATH` with the remaining arguments, like git does. The plugin's exit
+//! status becomes aj's, and it finds the configuration through `AJ_CONFIG` (the path of
+//! `config.yaml`) and `AJ_CONFIG_DIR`.
+//!
+//! ## Hooks
+//!
+//! Shell commands listed under `hooks` run around every question asked with
+//! `api::ask_for_completion`, and get a JSON envelope describing it on stdin:
+//!
+//! ```yaml
+//! hooks:
+//!   pre_ask:
+//!     - ~/bin/redact-hostnames
+//!   post_answer:
+//!     - 'jq -c . >> ~/aj-answers.jsonl'
+//! ```
+//!
+//! ```text
+//! {"event":"pre_ask","request_id":"3f2b8c1e-...","model":"gpt-4","question":"..."}
+//! {"event":"post_answer","request_id":"3f2b8c1e-...","model":"gpt-4","question":"...","answer":"...","finish_reason":"stop"}
+//! ```
+//!
+//! A `pre_ask` hook that exits with a failure refuses the question, with its stderr as the reason.
+//! One that prints an envelope with a `question` replaces the question with it, so later hooks and
+//! the model see the new one; one that prints nothing leaves it as it is. `post_answer` hooks are
+//! told about the answer; their output is ignored and their failures are only reported.
+
+use crate::config::HooksConfig;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    error::Error,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::debug;
+
+/// The prefix of the names of plugin executables.
+pub const PLUGIN_PREFIX: &str = "aj-";
+
+/// What a hook is told about a question.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Envelope<'a> {
+    /// The question is about to be sent.
+    PreAsk {
+        request_id: &'a str,
+        model: &'a str,
+        question: &'a str,
+    },
+
+    /// The question was answered.
+    PostAnswer {
+        request_id: &'a str,
+        model: &'a str,
+        question: &'a str,
+        answer: &'a str,
+        finish_reason: Option<&'a str>,
+    },
+}
+
+/// What a `pre_ask` hook may print to change the question.
+#[derive(Debug, Deserialize)]
+struct PreAskReply {
+    question: String,
+}
+
+/// Returns the path of the plugin executable `aj-<name>` on `PATH`, if there is one.
+pub fn find_plugin(name: &str) -> Option<PathBuf> {
+    find_plugin_in(name, &env::var_os("PATH")?)
+}
+
+/// Returns the path of the plugin executable `aj-<name>` in the directories of `path`, a list
+/// like `PATH`, if there is one.
+fn find_plugin_in(name: &str, path: &OsStr) -> Option<PathBuf> {
+    let file_name = format!("{}{}{}", PLUGIN_PREFIX, name, env::consts::EXE_SUFFIX);
+    env::split_paths(path)
+        .map(|dir| dir.join(&file_name))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Runs the plugin `aj-<name>` with `args`, telling it where the configuration is.
+///
+/// # Returns
+///
+/// - `Ok(ExitStatus)`: How the plugin exited.
+/// - `Err(Box<dyn Error>)`: There is no such command or plugin, or it could not be started.
+pub async fn run_plugin(
+    name: &str,
+    args: &[String],
+    config_path: &Path,
+) -> Result<ExitStatus, Box<dyn Error>> {
+    let plugin = find_plugin(name).ok_or_else(|| {
+        format!(
+            "`{}` is not an aj command, and no `{}{}` plugin was found on PATH",
+            name, PLUGIN_PREFIX, name
+        )
+    })?;
+    debug!("Running plugin {}", plugin.display());
+    let mut command = Command::new(&plugin);
+    command.args(args).env("AJ_CONFIG", config_path);
+    if let Some(config_dir) = config_path.parent() {
+        command.env("AJ_CONFIG_DIR", config_dir);
+    }
+    Ok(command.status().await?)
+}
+
+/// Runs `command` in the shell with `input` on stdin, and returns what it printed.
+async fn run_hook(command: &str, input: &str) -> Result<String, Box<dyn Error>> {
+    debug!("Running hook: {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "The hook `{}` failed with {}: {}",
+            command,
+            output.status,
+            stderr.trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs the `pre_ask` hooks in order, each with the question the previous one left.
+///
+/// # Returns
+///
+/// - `Ok(String)`: The question to send.
+/// - `Err(Box<dyn Error>)`: A hook refused the question, or printed something that is not an
+///   envelope.
+pub async fn pre_ask(
+    hooks: &HooksConfig,
+    request_id: &str,
+    model: &str,
+    question: String,
+) -> Result<String, Box<dyn Error>> {
+    let mut question = question;
+    for command in &hooks.pre_ask {
+        let envelope = Envelope::PreAsk {
+            request_id,
+            model,
+            question: &question,
+        };
+        let output = run_hook(command, &serde_json::to_string(&envelope)?).await?;
+        if !output.trim().is_empty() {
+            let reply: PreAskReply = serde_json::from_str(&output).map_err(|e| {
+                format!(
+                    "The hook `{}` printed something other than an envelope: {}",
+                    command, e
+                )
+            })?;
+            question = reply.question;
+        }
+    }
+    Ok(question)
+}
+
+/// Runs the `post_answer` hooks, reporting their failures on stderr.
+pub async fn post_answer(hooks: &HooksConfig, envelope: &Envelope<'_>) {
+    let input = match serde_json::to_string(envelope) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Failed to describe the answer to hooks: {}", e);
+            return;
+        }
+    };
+    for command in &hooks.post_answer {
+        if let Err(e) = run_hook(command, &input).await {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn hooks(pre_ask: &[&str]) -> HooksConfig {
+        HooksConfig {
+            pre_ask: pre_ask.iter().map(|command| command.to_string()).collect(),
+            post_answer: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pre_ask_hooks() {
+        let question = "What is the status of db-7.internal?".to_string();
+
+        let hooks_config = hooks(&["cat > /dev/null"]);
+        let asked = pre_ask(&hooks_config, "1", "gpt-4", question.clone())
+            .await
+            .unwrap();
+        assert_eq!(asked, question);
+
+        // The second hook sees the first one's question
+        let hooks_config = hooks(&["sed 's/db-7/db-?/'", "sed 's/db-?.internal/HOST/'"]);
+        let asked = pre_ask(&hooks_config, "1", "gpt-4", question.clone())
+            .await
+            .unwrap();
+        assert_eq!(asked, "What is the status of HOST?");
+
+        let hooks_config = hooks(&["echo 'No hostnames, please.' >&2; exit 3"]);
+        let error = pre_ask(&hooks_config, "1", "gpt-4", question)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("No hostnames, please."));
+    }
+
+    #[test]
+    fn test_find_plugin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = dir.path().join("aj-hello");
+        std::fs::write(&plugin, "#!/bin/sh\necho hello\n").unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(dir.path().join("aj-readme"), "not executable").unwrap();
+
+        let path = env::join_paths([Path::new("/nonexistent"), dir.path()]).unwrap();
+        assert_eq!(find_plugin_in("hello", &path), Some(plugin));
+        assert_eq!(find_plugin_in("readme", &path), None);
+        assert_eq!(find_plugin_in("missing", &path), None);
+    }
+}
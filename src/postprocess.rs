@@ -0,0 +1,425 @@
+//! This module classifies answers after they are generated.
+//!
+//! A `Postprocessor` runs a list of `Rule`s over each answer. Rules flag answers that look like
+//! refusals, carry hallucination-risk markers ("as an AI language model", "as of my knowledge
+//! cutoff"), or are empty or suspiciously short. Flags can be shown to the user, returned in
+//! machine-readable output, or used to retry the question once with an adjusted prompt.
+//!
+//! Custom rules implement the `Rule` trait and are added with `Postprocessor::with_rule`.
+//!
+//! Templates can also declare `OutputFilter`s that rewrite the model's output before it is returned
+//! or kept in the conversation, e.g. `postprocess: [trim_code_fences]` to keep only the code.
+//!
+//! # Example
+//!
+//! ```
+//! use awful_aj::postprocess::{FlagKind, Postprocessor};
+//!
+//! let postprocessor = Postprocessor::with_default_rules(1);
+//! let flags = postprocessor.check("How do I pick a lock?", "I'm sorry, but I can't help with that.");
+//! assert_eq!(flags[0].kind, FlagKind::Refusal);
+//! ```
+
+use crate::config::PostprocessConfig;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt};
+
+static REFUSAL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)^\s*(i'm sorry|i am sorry|i apologi[sz]e|i cannot|i can't|i can not|i'm unable|i am unable|i won't|i will not|unfortunately, i)\b|\bi (cannot|can't|am unable to|won't) (help|assist|provide|comply|fulfill)",
+    )
+    .unwrap()
+});
+
+static CODE_FENCE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)```[^\n]*\n(.*?)\n?```").unwrap());
+
+static HALLUCINATION_RISK: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\bas an ai\b|\bas a(n ai)? language model\b|\bmy (knowledge|training) (cutoff|cut-off|data)\b|\bi (do not|don't) have (access to )?real-time\b|\bi (cannot|can't) browse\b",
+    )
+    .unwrap()
+});
+
+/// The kind of problem a rule found with an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagKind {
+    /// The model declined to answer.
+    Refusal,
+
+    /// The answer contains phrasing that often accompanies made-up or outdated content.
+    HallucinationRisk,
+
+    /// The answer is empty.
+    Empty,
+
+    /// The answer is shorter than the configured minimum.
+    TooShort,
+}
+
+impl fmt::Display for FlagKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FlagKind::Refusal => "refusal",
+            FlagKind::HallucinationRisk => "hallucination_risk",
+            FlagKind::Empty => "empty",
+            FlagKind::TooShort => "too_short",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A problem found with an answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Flag {
+    /// What kind of problem was found.
+    pub kind: FlagKind,
+
+    /// The name of the rule that raised the flag.
+    pub rule: String,
+
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+/// A check run over every answer.
+pub trait Rule: Send + Sync {
+    /// The name reported in the flags this rule raises.
+    fn name(&self) -> &str;
+
+    /// Inspects an answer, returning a flag if something is wrong with it.
+    fn check(&self, question: &str, answer: &str) -> Option<Flag>;
+}
+
+/// Flags answers that are empty or only whitespace.
+pub struct EmptyRule;
+
+impl Rule for EmptyRule {
+    fn name(&self) -> &str {
+        "empty"
+    }
+
+    fn check(&self, _question: &str, answer: &str) -> Option<Flag> {
+        answer.trim().is_empty().then(|| Flag {
+            kind: FlagKind::Empty,
+            rule: self.name().to_string(),
+            message: "The answer is empty.".to_string(),
+        })
+    }
+}
+
+/// Flags non-empty answers with fewer than `min_chars` characters.
+pub struct MinLengthRule {
+    /// The minimum number of characters, ignoring surrounding whitespace.
+    pub min_chars: usize,
+}
+
+impl Rule for MinLengthRule {
+    fn name(&self) -> &str {
+        "min_length"
+    }
+
+    fn check(&self, _question: &str, answer: &str) -> Option<Flag> {
+        let length = answer.trim().chars().count();
+        (length > 0 && length < self.min_chars).then(|| Flag {
+            kind: FlagKind::TooShort,
+            rule: self.name().to_string(),
+            message: format!(
+                "The answer has {} characters, fewer than the minimum of {}.",
+                length, self.min_chars
+            ),
+        })
+    }
+}
+
+/// Flags answers that decline to help.
+pub struct RefusalRule;
+
+impl Rule for RefusalRule {
+    fn name(&self) -> &str {
+        "refusal"
+    }
+
+    fn check(&self, _question: &str, answer: &str) -> Option<Flag> {
+        REFUSAL.find(answer).map(|found| Flag {
+            kind: FlagKind::Refusal,
+            rule: self.name().to_string(),
+            message: format!(
+                "The answer looks like a refusal: \"{}\".",
+                found.as_str().trim()
+            ),
+        })
+    }
+}
+
+/// Flags answers containing disclaimers that often accompany unreliable content.
+pub struct HallucinationRiskRule;
+
+impl Rule for HallucinationRiskRule {
+    fn name(&self) -> &str {
+        "hallucination_risk"
+    }
+
+    fn check(&self, _question: &str, answer: &str) -> Option<Flag> {
+        HALLUCINATION_RISK.find(answer).map(|found| Flag {
+            kind: FlagKind::HallucinationRisk,
+            rule: self.name().to_string(),
+            message: format!(
+                "The answer contains \"{}\"; double-check its claims.",
+                found.as_str()
+            ),
+        })
+    }
+}
+
+/// Runs a set of rules over answers.
+#[derive(Default)]
+pub struct Postprocessor {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Postprocessor {
+    /// Creates a postprocessor without any rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a postprocessor with the built-in rules.
+    ///
+    /// # Parameters
+    ///
+    /// - `min_answer_chars`: Answers shorter than this are flagged as too short.
+    pub fn with_default_rules(min_answer_chars: usize) -> Self {
+        Self::new()
+            .with_rule(EmptyRule)
+            .with_rule(MinLengthRule {
+                min_chars: min_answer_chars,
+            })
+            .with_rule(RefusalRule)
+            .with_rule(HallucinationRiskRule)
+    }
+
+    /// Creates a postprocessor with the built-in rules, configured from the configuration.
+    pub fn from_config(config: &PostprocessConfig) -> Self {
+        Self::with_default_rules(config.min_answer_chars)
+    }
+
+    /// Adds a rule.
+    pub fn with_rule(mut self, rule: impl Rule + 'static) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// Runs every rule over an answer and collects the flags they raise.
+    pub fn check(&self, question: &str, answer: &str) -> Vec<Flag> {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.check(question, answer))
+            .collect()
+    }
+}
+
+/// Adjusts a question so that asking it again is likely to avoid the flagged problems.
+///
+/// # Returns
+///
+/// The adjusted question, or `None` if none of the flags are worth retrying.
+pub fn retry_question(question: &str, flags: &[Flag]) -> Option<String> {
+    let mut adjustments = Vec::new();
+    if flags.iter().any(|f| f.kind == FlagKind::Refusal) {
+        adjustments.push("This is a legitimate request. Please answer it directly.");
+    }
+    if flags
+        .iter()
+        .any(|f| matches!(f.kind, FlagKind::Empty | FlagKind::TooShort))
+    {
+        adjustments.push("Please give a complete, detailed answer.");
+    }
+
+    if adjustments.is_empty() {
+        None
+    } else {
+        Some(format!("{}\n\n{}", question, adjustments.join(" ")))
+    }
+}
+
+/// A transformation applied to the model's output, declared in a template's `postprocess` list.
+#[derive(Debug, Clone)]
+pub enum OutputFilter {
+    /// Keeps only the contents of the first fenced code block, if there is one.
+    TrimCodeFences,
+
+    /// Keeps only the first JSON object or array, if there is one.
+    ExtractJson,
+
+    /// Keeps only the first match of the pattern, or its first capture group if it has one.
+    Regex(Regex),
+}
+
+impl OutputFilter {
+    /// Parses a filter: `trim_code_fences`, `extract_json`, or `regex:<pattern>`.
+    pub fn parse(filter: &str) -> Result<Self, Box<dyn Error>> {
+        match filter.trim() {
+            "trim_code_fences" => Ok(OutputFilter::TrimCodeFences),
+            "extract_json" => Ok(OutputFilter::ExtractJson),
+            other => match other.strip_prefix("regex:") {
+                Some(pattern) => {
+                    let pattern = pattern
+                        .strip_prefix('\'')
+                        .and_then(|p| p.strip_suffix('\''))
+                        .unwrap_or(pattern);
+                    Ok(OutputFilter::Regex(Regex::new(pattern)?))
+                }
+                None => Err(format!("Unknown postprocess filter `{}`", other).into()),
+            },
+        }
+    }
+
+    /// Applies the filter. Output the filter finds nothing in is returned unchanged.
+    pub fn apply(&self, output: &str) -> String {
+        let filtered = match self {
+            OutputFilter::TrimCodeFences => CODE_FENCE
+                .captures(output)
+                .and_then(|captures| captures.get(1))
+                .map(|code| code.as_str().to_string()),
+            OutputFilter::ExtractJson => extract_json(output),
+            OutputFilter::Regex(regex) => regex.captures(output).map(|captures| {
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|m| m.as_str().to_string())
+                    .unwrap_or_default()
+            }),
+        };
+        filtered.unwrap_or_else(|| output.to_string())
+    }
+}
+
+/// Applies each filter in turn.
+pub fn apply_filters(filters: &[OutputFilter], output: &str) -> String {
+    filters
+        .iter()
+        .fold(output.to_string(), |output, filter| filter.apply(&output))
+}
+
+/// Finds the first complete JSON object or array in `text`.
+fn extract_json(text: &str) -> Option<String> {
+    text.char_indices()
+        .filter(|(_, c)| *c == '{' || *c == '[')
+        .find_map(|(start, _)| {
+            let mut values =
+                serde_json::Deserializer::from_str(&text[start..]).into_iter::<serde_json::Value>();
+            match values.next() {
+                Some(Ok(_)) => Some(text[start..start + values.byte_offset()].to_string()),
+                _ => None,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(flags: &[Flag]) -> Vec<FlagKind> {
+        flags.iter().map(|f| f.kind).collect()
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let postprocessor = Postprocessor::with_default_rules(10);
+
+        assert_eq!(
+            kinds(&postprocessor.check("q", "   ")),
+            vec![FlagKind::Empty]
+        );
+        assert_eq!(
+            kinds(&postprocessor.check("q", "Yes.")),
+            vec![FlagKind::TooShort]
+        );
+        assert_eq!(
+            kinds(&postprocessor.check("q", "I'm sorry, but I can't help with that request.")),
+            vec![FlagKind::Refusal]
+        );
+        assert_eq!(
+            kinds(&postprocessor.check(
+                "q",
+                "As an AI language model, I believe the population is 3 million."
+            )),
+            vec![FlagKind::HallucinationRisk]
+        );
+        assert!(postprocessor
+            .check("q", "Use `std::fs::read_to_string` to read the whole file.")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_custom_rule() {
+        struct NoLorem;
+        impl Rule for NoLorem {
+            fn name(&self) -> &str {
+                "no_lorem"
+            }
+            fn check(&self, _question: &str, answer: &str) -> Option<Flag> {
+                answer.contains("lorem").then(|| Flag {
+                    kind: FlagKind::HallucinationRisk,
+                    rule: self.name().to_string(),
+                    message: "Placeholder text.".to_string(),
+                })
+            }
+        }
+
+        let flags = Postprocessor::new()
+            .with_rule(NoLorem)
+            .check("q", "lorem ipsum");
+        assert_eq!(flags[0].rule, "no_lorem");
+    }
+
+    #[test]
+    fn test_retry_question() {
+        let refusal = Flag {
+            kind: FlagKind::Refusal,
+            rule: "refusal".to_string(),
+            message: String::new(),
+        };
+        let risk = Flag {
+            kind: FlagKind::HallucinationRisk,
+            rule: "hallucination_risk".to_string(),
+            message: String::new(),
+        };
+
+        assert_eq!(
+            retry_question("Why?", &[refusal]).as_deref(),
+            Some("Why?\n\nThis is a legitimate request. Please answer it directly.")
+        );
+        assert_eq!(retry_question("Why?", &[risk]), None);
+    }
+
+    #[test]
+    fn test_output_filters() {
+        let output = "Here you go:\n```rust\nfn main() {}\n```\nEnjoy!";
+        let filter = OutputFilter::parse("trim_code_fences").unwrap();
+        assert_eq!(filter.apply(output), "fn main() {}");
+        assert_eq!(filter.apply("no code"), "no code");
+
+        let output = "Sure! {\"name\": \"aj\", \"tags\": [1, 2]} Anything else?";
+        let filter = OutputFilter::parse("extract_json").unwrap();
+        assert_eq!(filter.apply(output), "{\"name\": \"aj\", \"tags\": [1, 2]}");
+
+        let filter = OutputFilter::parse("regex:'Answer: (\\d+)'").unwrap();
+        assert_eq!(filter.apply("Thinking... Answer: 42."), "42");
+
+        assert!(OutputFilter::parse("shout").is_err());
+    }
+
+    #[test]
+    fn test_apply_filters_in_order() {
+        let filters = vec![
+            OutputFilter::parse("trim_code_fences").unwrap(),
+            OutputFilter::parse("extract_json").unwrap(),
+        ];
+        let output = "```json\n// result\n[1, 2, 3]\n```";
+        assert_eq!(apply_filters(&filters, output), "[1, 2, 3]");
+    }
+}
@@ -0,0 +1,907 @@
+//! This module formats Markdown answers for display in a terminal.
+//!
+//! Models often answer with Markdown tables and LaTeX math, which are hard to read as raw text.
+//! `render` lays tables out with box-drawing characters, wrapping cells so the table fits the
+//! terminal, and turns common LaTeX (`$x^2$`, `\frac{a}{b}`, `\alpha`) into Unicode. Code blocks
+//! are left untouched; `extract_code_blocks` returns them on their own, for `aj ask --code-only`.
+//!
+//! # Example
+//!
+//! ```
+//! use awful_aj::pretty;
+//!
+//! let answer = "| a | b |\n|---|---|\n| 1 | 2 |\n";
+//! assert!(pretty::render(answer, 80).starts_with("┌───┬───┐"));
+//! assert_eq!(pretty::render("$x^2 \\leq y$", 80), "x² ≤ y");
+//! ```
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use std::io::{self, Write};
+
+/// The width used when the terminal's width is unknown, e.g. when output is piped.
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// Columns are never shrunk below this width when a table has to be wrapped.
+const MIN_COLUMN_WIDTH: usize = 3;
+
+static MATH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"\$\$(?P<display>.+?)\$\$|\\\((?P<paren>.+?)\\\)|\\\[(?P<bracket>.+?)\\\]|\$(?P<inline>[^\s$\d](?:[^$\n]*?[^\s$\\])?)\$",
+    )
+    .unwrap()
+});
+static COMMAND: Lazy<Regex> = Lazy::new(|| Regex::new(r"\\([A-Za-z]+)").unwrap());
+static FRACTION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\\[dt]?frac\{([^{}]*)\}\{([^{}]*)\}").unwrap());
+static SQRT: Lazy<Regex> = Lazy::new(|| Regex::new(r"\\sqrt\{([^{}]*)\}").unwrap());
+static SCRIPT: Lazy<Regex> = Lazy::new(|| Regex::new(r"([\^_])(?:\{([^{}]*)\}|(\S))").unwrap());
+
+/// Returns the width of the terminal, or `DEFAULT_WIDTH` when it is unknown.
+#[cfg(feature = "tty")]
+pub fn terminal_width() -> usize {
+    crossterm::terminal::size()
+        .map(|(width, _)| width as usize)
+        .ok()
+        .filter(|width| *width > 0)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// Returns `DEFAULT_WIDTH`; the terminal's width is only queried with the `tty` feature.
+#[cfg(not(feature = "tty"))]
+pub fn terminal_width() -> usize {
+    DEFAULT_WIDTH
+}
+
+/// Formats a Markdown answer for a terminal `width` columns wide.
+///
+/// Tables are laid out with box drawing and math is prettified; code blocks and everything else
+/// are passed through unchanged.
+pub fn render(text: &str, width: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut output = Vec::with_capacity(lines.len());
+    let mut in_code = false;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            output.push(line.to_string());
+            i += 1;
+            continue;
+        }
+        if in_code {
+            output.push(line.to_string());
+            i += 1;
+            continue;
+        }
+
+        let table_len = table_len(&lines[i..]);
+        if table_len > 0 {
+            output.push(render_table(&lines[i..i + table_len], width));
+            i += table_len;
+            continue;
+        }
+
+        output.push(render_math(line));
+        i += 1;
+    }
+
+    let mut rendered = output.join("\n");
+    if text.ends_with('\n') {
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Returns whether `line` could be a row of a Markdown table.
+pub fn is_table_row(line: &str) -> bool {
+    line.trim_start().starts_with('|')
+}
+
+/// Returns how many of the leading `lines` form a Markdown table, or 0 if they don't start one.
+fn table_len(lines: &[&str]) -> usize {
+    if lines.len() < 2 || !is_table_row(lines[0]) || parse_alignments(lines[1]).is_none() {
+        return 0;
+    }
+    2 + lines[2..]
+        .iter()
+        .take_while(|line| is_table_row(line))
+        .count()
+}
+
+/// How a table column's cells are aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+/// Parses a table's separator row (`|---|:---:|--:|`), returning `None` if it isn't one.
+fn parse_alignments(line: &str) -> Option<Vec<Alignment>> {
+    let cells = split_row(line);
+    if cells.is_empty() {
+        return None;
+    }
+    cells
+        .iter()
+        .map(|cell| {
+            let dashes = cell.trim_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            Some(match (cell.starts_with(':'), cell.ends_with(':')) {
+                (true, true) => Alignment::Center,
+                (false, true) => Alignment::Right,
+                _ => Alignment::Left,
+            })
+        })
+        .collect()
+}
+
+/// Splits a table row into its trimmed cells, honoring escaped pipes.
+fn split_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(rest) if !rest.ends_with('\\') => rest,
+        _ => line,
+    };
+
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                cell.push('|');
+                chars.next();
+            }
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+/// Lays out a Markdown table (header, separator, and body rows) with box-drawing characters.
+fn render_table(lines: &[&str], width: usize) -> String {
+    let alignments = parse_alignments(lines[1]).unwrap_or_default();
+    let rows: Vec<Vec<String>> = std::iter::once(lines[0])
+        .chain(lines[2..].iter().copied())
+        .map(|line| {
+            split_row(line)
+                .iter()
+                .map(|cell| render_math(cell))
+                .collect()
+        })
+        .collect();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+
+    let mut widths = vec![1; columns];
+    for row in &rows {
+        for (column, cell) in row.iter().enumerate() {
+            widths[column] = widths[column].max(display_width(cell));
+        }
+    }
+    fit_columns(&mut widths, width);
+
+    let border = |left: &str, middle: &str, right: &str| {
+        let segments: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+        format!("{}{}{}", left, segments.join(middle), right)
+    };
+
+    let mut output = vec![border("┌", "┬", "┐")];
+    for (i, row) in rows.iter().enumerate() {
+        let wrapped: Vec<Vec<String>> = (0..columns)
+            .map(|column| wrap(row.get(column).map_or("", String::as_str), widths[column]))
+            .collect();
+        let height = wrapped.iter().map(Vec::len).max().unwrap_or(1);
+        for line in 0..height {
+            let cells: Vec<String> = wrapped
+                .iter()
+                .enumerate()
+                .map(|(column, cell_lines)| {
+                    let text = cell_lines.get(line).map_or("", String::as_str);
+                    let alignment = alignments.get(column).copied().unwrap_or(Alignment::Left);
+                    pad(text, widths[column], alignment)
+                })
+                .collect();
+            output.push(format!("│ {} │", cells.join(" │ ")));
+        }
+        if i == 0 {
+            output.push(border("├", "┼", "┤"));
+        }
+    }
+    output.push(border("└", "┴", "┘"));
+    output.join("\n")
+}
+
+/// Shrinks the widest columns until a table with these column widths fits in `width`.
+fn fit_columns(widths: &mut [usize], width: usize) {
+    // Each column takes its width plus a space of padding on each side and one border.
+    let overhead = widths.len() * 3 + 1;
+    let available = width.saturating_sub(overhead);
+    while widths.iter().sum::<usize>() > available {
+        let Some(widest) = widths.iter_mut().max() else {
+            return;
+        };
+        if *widest <= MIN_COLUMN_WIDTH {
+            return;
+        }
+        *widest -= 1;
+    }
+}
+
+/// Pads `text` to `width` columns with the given alignment.
+fn pad(text: &str, width: usize, alignment: Alignment) -> String {
+    let space = width.saturating_sub(display_width(text));
+    let (left, right) = match alignment {
+        Alignment::Left => (0, space),
+        Alignment::Right => (space, 0),
+        Alignment::Center => (space / 2, space - space / 2),
+    };
+    format!("{}{}{}", " ".repeat(left), text, " ".repeat(right))
+}
+
+/// Returns the number of columns `text` takes up in a terminal.
+pub fn display_width(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// Wraps `text` at word boundaries into lines at most `width` columns wide.
+///
+/// Words longer than `width` are split.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let mut word = word.to_string();
+        while display_width(&word) > width {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            let split = word
+                .char_indices()
+                .nth(width)
+                .map_or(word.len(), |(i, _)| i);
+            lines.push(word[..split].to_string());
+            word = word[split..].to_string();
+        }
+        if word.is_empty() {
+            continue;
+        }
+        if !line.is_empty() && display_width(&line) + 1 + display_width(&word) > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Replaces the math spans in `line` (`$...$`, `$$...$$`, `\(...\)`, `\[...\]`) with Unicode.
+pub fn render_math(line: &str) -> String {
+    MATH.replace_all(line, |caps: &Captures| {
+        let expression = ["display", "paren", "bracket", "inline"]
+            .iter()
+            .find_map(|name| caps.name(name))
+            .map_or("", |m| m.as_str());
+        prettify_math(expression.trim())
+    })
+    .into_owned()
+}
+
+/// Turns a LaTeX expression into its closest plain Unicode rendering.
+fn prettify_math(expression: &str) -> String {
+    let mut text = expression.replace("\\left", "").replace("\\right", "");
+    text = text
+        .replace("\\,", " ")
+        .replace("\\;", " ")
+        .replace("\\ ", " ");
+
+    // Innermost fractions and roots first, so nested ones unwrap on later passes.
+    loop {
+        let next = FRACTION.replace_all(&text, |caps: &Captures| {
+            format!("{}/{}", group(&caps[1]), group(&caps[2]))
+        });
+        let next = SQRT
+            .replace_all(&next, |caps: &Captures| format!("√{}", group(&caps[1])))
+            .into_owned();
+        if next == text {
+            break;
+        }
+        text = next;
+    }
+
+    let text = COMMAND.replace_all(&text, |caps: &Captures| {
+        symbol(&caps[1]).map_or_else(|| caps[1].to_string(), str::to_string)
+    });
+    let text = SCRIPT.replace_all(&text, |caps: &Captures| {
+        let script = caps
+            .get(2)
+            .or_else(|| caps.get(3))
+            .map_or("", |m| m.as_str());
+        let superscript = &caps[1] == "^";
+        let converted: Option<String> = script
+            .chars()
+            .map(|c| {
+                if superscript {
+                    superscript_char(c)
+                } else {
+                    subscript_char(c)
+                }
+            })
+            .collect();
+        converted.unwrap_or_else(|| format!("{}({})", &caps[1], script))
+    });
+
+    text.replace(['{', '}'], "")
+}
+
+/// Wraps `text` in parentheses unless it is a single term.
+fn group(text: &str) -> String {
+    let text = text.trim();
+    if text.chars().all(|c| c.is_alphanumeric() || c == '.') {
+        text.to_string()
+    } else {
+        format!("({})", text)
+    }
+}
+
+/// Returns the Unicode symbol for a LaTeX command such as `alpha` or `leq`.
+fn symbol(command: &str) -> Option<&'static str> {
+    Some(match command {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" | "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "rho" => "ρ",
+        "sigma" => "σ",
+        "tau" => "τ",
+        "phi" | "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        "sum" => "∑",
+        "prod" => "∏",
+        "int" => "∫",
+        "oint" => "∮",
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "times" => "×",
+        "cdot" => "·",
+        "div" => "÷",
+        "pm" => "±",
+        "mp" => "∓",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "propto" => "∝",
+        "to" | "rightarrow" => "→",
+        "leftarrow" => "←",
+        "Rightarrow" | "implies" => "⇒",
+        "Leftrightarrow" | "iff" => "⇔",
+        "in" => "∈",
+        "notin" => "∉",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "cup" => "∪",
+        "cap" => "∩",
+        "emptyset" => "∅",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "circ" => "∘",
+        "degree" => "°",
+        "mathbb" | "mathrm" | "mathbf" | "text" | "operatorname" => "",
+        _ => return None,
+    })
+}
+
+fn superscript_char(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '⁰',
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '4' => '⁴',
+        '5' => '⁵',
+        '6' => '⁶',
+        '7' => '⁷',
+        '8' => '⁸',
+        '9' => '⁹',
+        '+' => '⁺',
+        '-' => '⁻',
+        '=' => '⁼',
+        '(' => '⁽',
+        ')' => '⁾',
+        'n' => 'ⁿ',
+        'i' => 'ⁱ',
+        _ => return None,
+    })
+}
+
+fn subscript_char(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '₀',
+        '1' => '₁',
+        '2' => '₂',
+        '3' => '₃',
+        '4' => '₄',
+        '5' => '₅',
+        '6' => '₆',
+        '7' => '₇',
+        '8' => '₈',
+        '9' => '₉',
+        '+' => '₊',
+        '-' => '₋',
+        '=' => '₌',
+        '(' => '₍',
+        ')' => '₎',
+        'a' => 'ₐ',
+        'e' => 'ₑ',
+        'i' => 'ᵢ',
+        'j' => 'ⱼ',
+        'k' => 'ₖ',
+        'n' => 'ₙ',
+        'o' => 'ₒ',
+        'x' => 'ₓ',
+        _ => return None,
+    })
+}
+
+/// A fenced code block in an answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// The language named after the opening fence, e.g. `rust`, if any.
+    pub lang: Option<String>,
+
+    /// Everything after the opening fence, e.g. `rust file=src/lib.rs`.
+    pub info: String,
+
+    /// The code, without the fences.
+    pub text: String,
+}
+
+/// Returns the fenced code blocks in `text`, in order.
+///
+/// A block is closed by a fence at least as long as the one that opened it, so a block showing
+/// Markdown with its own ```` ``` ```` fences is kept whole. Code inside an indented fence, e.g. in
+/// a list item, is dedented by the fence's indentation. A block the answer ends in without closing,
+/// e.g. because it was cut off, is still returned.
+///
+/// # Example
+///
+/// ```
+/// use awful_aj::pretty::extract_code_blocks;
+///
+/// let blocks = extract_code_blocks("Run:\n```sh\ncargo test\n```\n");
+/// assert_eq!(blocks[0].lang.as_deref(), Some("sh"));
+/// assert_eq!(blocks[0].text, "cargo test\n");
+/// ```
+pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    // The open block's fence length, indentation, and contents so far.
+    let mut open: Option<(usize, usize, CodeBlock)> = None;
+
+    for line in text.lines() {
+        let token = line.trim_start();
+        let indent = line.len() - token.len();
+        let fence = token.chars().take_while(|&c| c == '`').count();
+
+        match &mut open {
+            None if fence >= 3 => {
+                let info = token[fence..].trim().to_string();
+                let block = CodeBlock {
+                    lang: info.split_whitespace().next().map(str::to_string),
+                    info,
+                    text: String::new(),
+                };
+                open = Some((fence, indent, block));
+            }
+            None => {}
+            Some((open_fence, _, _))
+                if fence >= *open_fence && token[fence..].trim().is_empty() =>
+            {
+                blocks.extend(open.take().map(|(_, _, block)| block));
+            }
+            Some((_, open_indent, block)) => {
+                let dedent = indent.min(*open_indent);
+                block.text.push_str(&line[dedent..]);
+                block.text.push('\n');
+            }
+        }
+    }
+    blocks.extend(open.map(|(_, _, block)| block));
+    blocks
+}
+
+/// What the line being streamed turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// Not classified yet; its first word is still being buffered.
+    Pending,
+
+    /// Prose, which is soft-wrapped.
+    Text,
+
+    /// A code block line or fence, which is written as is.
+    Raw,
+
+    /// A table row, which is buffered until the table ends.
+    Table,
+}
+
+/// Writes streamed text soft-wrapped to the terminal's width.
+///
+/// Text is wrapped at word boundaries, and continuation lines of list items keep the item's
+/// indentation. Code blocks are never wrapped, and tables are held back until they are complete so
+/// they can be laid out like `render` does.
+///
+/// # Example
+///
+/// ```
+/// use awful_aj::pretty::WrapWriter;
+///
+/// let mut output = Vec::new();
+/// let mut writer = WrapWriter::new(&mut output, 12);
+/// for token in ["- one", " two", " three\n"] {
+///     writer.write_str(token).unwrap();
+/// }
+/// writer.finish().unwrap();
+/// assert_eq!(String::from_utf8(output).unwrap(), "- one two\n  three\n");
+/// ```
+pub struct WrapWriter<W: Write> {
+    out: W,
+    width: usize,
+    column: usize,
+    indent: usize,
+    kind: LineKind,
+    in_code: bool,
+    head: String,
+    word: String,
+    pending_space: bool,
+    table: String,
+    tee: Option<Box<dyn Write>>,
+}
+
+impl<W: Write> WrapWriter<W> {
+    /// Creates a writer wrapping to `width` columns.
+    pub fn new(out: W, width: usize) -> Self {
+        Self {
+            out,
+            width: width.max(1),
+            column: 0,
+            indent: 0,
+            kind: LineKind::Pending,
+            in_code: false,
+            head: String::new(),
+            word: String::new(),
+            pending_space: false,
+            table: String::new(),
+            tee: None,
+        }
+    }
+
+    /// Also writes the text to `tee` as it is given, without wrapping, flushing it after every
+    /// chunk so that what was streamed so far survives if the process does not.
+    pub fn with_tee(mut self, tee: impl Write + 'static) -> Self {
+        self.tee = Some(Box::new(tee));
+        self
+    }
+
+    /// Writes the next chunk of streamed text.
+    ///
+    /// The last word of a chunk is held back until it is known to be complete.
+    pub fn write_str(&mut self, text: &str) -> io::Result<()> {
+        if let Some(tee) = &mut self.tee {
+            tee.write_all(text.as_bytes())?;
+            tee.flush()?;
+        }
+        for c in text.chars() {
+            self.write_char(c)?;
+        }
+        self.out.flush()
+    }
+
+    /// Writes out everything that is still held back, such as the last word or a table.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.kind {
+            LineKind::Pending if !self.head.is_empty() => {
+                self.classify()?;
+                self.emit_word()?;
+            }
+            LineKind::Text => self.emit_word()?,
+            _ => {}
+        }
+        self.flush_table()?;
+        self.out.flush()
+    }
+
+    fn write_char(&mut self, c: char) -> io::Result<()> {
+        if self.kind == LineKind::Pending {
+            let has_content = self.head.chars().any(|c| !c.is_whitespace());
+            if c != '\n' && !(c.is_whitespace() && has_content) {
+                self.head.push(c);
+                return Ok(());
+            }
+            self.classify()?;
+        }
+
+        match self.kind {
+            LineKind::Raw => write!(self.out, "{}", c)?,
+            LineKind::Table => self.table.push(c),
+            _ if c == '\n' => {
+                self.emit_word()?;
+                writeln!(self.out)?;
+            }
+            _ if c.is_whitespace() => {
+                self.emit_word()?;
+                self.pending_space = true;
+            }
+            _ => self.word.push(c),
+        }
+
+        if c == '\n' {
+            self.kind = LineKind::Pending;
+            self.column = 0;
+            self.pending_space = false;
+        }
+        Ok(())
+    }
+
+    /// Decides what kind of line the buffered start of a line belongs to, and writes it out.
+    fn classify(&mut self) -> io::Result<()> {
+        let head = std::mem::take(&mut self.head);
+        let token = head.trim_start();
+
+        if is_table_row(token) && !self.in_code {
+            self.kind = LineKind::Table;
+            self.table.push_str(&head);
+            return Ok(());
+        }
+        self.flush_table()?;
+
+        if token.starts_with("```") {
+            self.in_code = !self.in_code;
+            self.kind = LineKind::Raw;
+        } else if self.in_code {
+            self.kind = LineKind::Raw;
+        }
+        if self.kind == LineKind::Raw {
+            return write!(self.out, "{}", head);
+        }
+
+        self.kind = LineKind::Text;
+        let leading = &head[..head.len() - token.len()];
+        write!(self.out, "{}", leading)?;
+        self.column = display_width(leading);
+        if is_list_marker(token) {
+            write!(self.out, "{}", token)?;
+            self.column += display_width(token);
+            self.indent = self.column + 1;
+        } else {
+            self.indent = self.column;
+            self.word.push_str(token);
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered word, starting a new, indented line first if it would not fit.
+    fn emit_word(&mut self) -> io::Result<()> {
+        if self.word.is_empty() {
+            return Ok(());
+        }
+        let word = std::mem::take(&mut self.word);
+        let space = usize::from(self.pending_space);
+        if self.column > self.indent && self.column + space + display_width(&word) > self.width {
+            write!(self.out, "\n{}", " ".repeat(self.indent))?;
+            self.column = self.indent;
+        } else if self.pending_space {
+            write!(self.out, " ")?;
+            self.column += 1;
+        }
+        write!(self.out, "{}", word)?;
+        self.column += display_width(&word);
+        self.pending_space = false;
+        Ok(())
+    }
+
+    /// Lays out and writes the buffered table, if any.
+    fn flush_table(&mut self) -> io::Result<()> {
+        if self.table.is_empty() {
+            return Ok(());
+        }
+        let table = std::mem::take(&mut self.table);
+        write!(self.out, "{}", render(&table, self.width))
+    }
+}
+
+/// Returns whether `token` starts a list item, e.g. `-`, `*`, `1.`, or `2)`.
+fn is_list_marker(token: &str) -> bool {
+    match token {
+        "-" | "*" | "+" => true,
+        _ => token
+            .strip_suffix(['.', ')'])
+            .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_table() {
+        let table = "| Name | Qty |\n|:-----|----:|\n| apple | 3 |\n| kiwi \\| lime | 12 |";
+        assert_eq!(
+            render(table, 80),
+            "┌─────────────┬─────┐\n\
+             │ Name        │ Qty │\n\
+             ├─────────────┼─────┤\n\
+             │ apple       │   3 │\n\
+             │ kiwi | lime │  12 │\n\
+             └─────────────┴─────┘"
+        );
+    }
+
+    #[test]
+    fn test_render_table_wraps_to_width() {
+        let table = "| Term | Meaning |\n|---|---|\n| HNSW | hierarchical navigable small world |";
+        let rendered = render(table, 30);
+        assert!(rendered.lines().all(|line| display_width(line) <= 30));
+        assert!(rendered.contains(&format!("│ HNSW │ hierarchical{} │", " ".repeat(7))));
+    }
+
+    #[test]
+    fn test_render_leaves_code_and_plain_pipes_alone() {
+        let text = "```\n| a | b |\n|---|---|\n$x^2$\n```\n| not a table\n";
+        assert_eq!(render(text, 80), text);
+    }
+
+    #[test]
+    fn test_render_math() {
+        assert_eq!(render_math("$E = mc^2$"), "E = mc²");
+        assert_eq!(
+            render_math(r"\(\frac{a+b}{2} \geq \sqrt{ab}\)"),
+            "(a+b)/2 ≥ √ab"
+        );
+        assert_eq!(render_math(r"$$\sum_{i=1}^{n} x_i$$"), "∑ᵢ₌₁ⁿ xᵢ");
+        assert_eq!(render_math(r"$\alpha^{x+y}$"), "α^(x+y)");
+        assert_eq!(render_math("It costs $5 or $10."), "It costs $5 or $10.");
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let answer = "First:\n```rust\nfn main() {}\n```\n\
+                      1. Then:\n   ````markdown\n   ```\n   nested\n   ```\n   ````\n\
+                      ```\nunterminated";
+        assert_eq!(
+            extract_code_blocks(answer),
+            vec![
+                CodeBlock {
+                    lang: Some("rust".to_string()),
+                    info: "rust".to_string(),
+                    text: "fn main() {}\n".to_string()
+                },
+                CodeBlock {
+                    lang: Some("markdown".to_string()),
+                    info: "markdown".to_string(),
+                    text: "```\nnested\n```\n".to_string()
+                },
+                CodeBlock {
+                    lang: None,
+                    info: String::new(),
+                    text: "unterminated\n".to_string()
+                },
+            ]
+        );
+        assert!(extract_code_blocks("No code here.").is_empty());
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("one two three", 7), vec!["one two", "three"]);
+        assert_eq!(wrap("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap("", 5), vec![""]);
+    }
+
+    fn wrap_stream(chunks: &[&str], width: usize) -> String {
+        let mut output = Vec::new();
+        let mut writer = WrapWriter::new(&mut output, width);
+        for chunk in chunks {
+            writer.write_str(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_wrap_writer_wraps_at_words() {
+        assert_eq!(
+            wrap_stream(&["The quick br", "own fox jumps over", " the lazy dog"], 20),
+            "The quick brown fox\njumps over the lazy\ndog"
+        );
+    }
+
+    #[test]
+    fn test_wrap_writer_hangs_list_indent() {
+        assert_eq!(
+            wrap_stream(&["- alpha beta gamma delta\n", "  1. one two three\n"], 16),
+            "- alpha beta\n  gamma delta\n  1. one two\n     three\n"
+        );
+    }
+
+    #[test]
+    fn test_wrap_writer_leaves_code_and_renders_tables() {
+        let code = "```\nlet x = 1; let y = 2;\n```\n";
+        assert_eq!(wrap_stream(&[code], 10), code);
+
+        let table = "| a | b |\n|---|---|\n| 1 | 2 |\nafter\n";
+        let chunks: Vec<String> = table.chars().map(String::from).collect();
+        let chunks: Vec<&str> = chunks.iter().map(String::as_str).collect();
+        assert_eq!(
+            wrap_stream(&chunks, 80),
+            "┌───┬───┐\n│ a │ b │\n├───┼───┤\n│ 1 │ 2 │\n└───┴───┘\nafter\n"
+        );
+    }
+
+    #[test]
+    fn test_wrap_writer_tees_unwrapped_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("answer.txt");
+        let mut output = Vec::new();
+        let mut writer =
+            WrapWriter::new(&mut output, 8).with_tee(std::fs::File::create(&path).unwrap());
+        writer.write_str("The quick br").unwrap();
+        // What was streamed so far is in the file before the answer is finished
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "The quick br");
+        writer.write_str("own fox").unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        assert_eq!(String::from_utf8(output).unwrap(), "The\nquick\nbrown\nfox");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "The quick brown fox"
+        );
+    }
+}
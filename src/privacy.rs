@@ -0,0 +1,136 @@
+//! This module keeps sensitive conversations on local backends.
+//!
+//! A template can be marked local-only, e.g. for a confidential project:
+//!
+//! ```yaml
+//! system_prompt: You help with the acquisition due diligence.
+//! local_only: true
+//! ```
+//!
+//! Questions asked with it, in `aj ask` or in interactive mode, are only sent to an `api_base`
+//! whose host is on the `local_hosts` allowlist of the config, `localhost`, `127.0.0.1`, and `::1`
+//! when it is empty. With a cloud `api_base`, aj refuses before anything is sent, not even an
+//! embedding or a rewritten search query, unless `--allow-remote` is passed.
+
+use crate::{config::AwfulJadeConfig, template::ChatTemplate};
+use std::{error::Error, fmt};
+
+/// The hosts local-only templates may send to when `local_hosts` is empty.
+pub const DEFAULT_LOCAL_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// A local-only template was about to be used with a backend that is not on the allowlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotLocal {
+    /// The `api_base` that was refused.
+    pub api_base: String,
+
+    /// The hosts that are allowed.
+    pub allowed: Vec<String>,
+}
+
+impl fmt::Display for NotLocal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The template is local-only, and {} is not on `local_hosts` ({}). Point `api_base` at a local backend, or pass --allow-remote to send it anyway.",
+            self.api_base,
+            self.allowed.join(", ")
+        )
+    }
+}
+
+impl Error for NotLocal {}
+
+/// Returns the hosts local-only templates may send to under `config`.
+pub fn local_hosts(config: &AwfulJadeConfig) -> Vec<String> {
+    if config.local_hosts.is_empty() {
+        DEFAULT_LOCAL_HOSTS
+            .iter()
+            .map(|host| host.to_string())
+            .collect()
+    } else {
+        config.local_hosts.clone()
+    }
+}
+
+/// Returns whether the host of `api_base` is one of `allowed`. An `api_base` that is not a URL
+/// is not.
+pub fn is_allowed(api_base: &str, allowed: &[String]) -> bool {
+    let Some(host) = reqwest::Url::parse(api_base)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+    else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    allowed
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+
+/// Checks that a question asked with `template` may be sent to the backend of `config`: always,
+/// unless the template is local-only, the backend is not on the allowlist, and `allow_remote` is
+/// not set.
+pub fn check(config: &AwfulJadeConfig, template: &ChatTemplate) -> Result<(), NotLocal> {
+    if !template.is_local_only() || config.allow_remote {
+        return Ok(());
+    }
+    let allowed = local_hosts(config);
+    if is_allowed(&config.api_base, &allowed) {
+        return Ok(());
+    }
+    Err(NotLocal {
+        api_base: config.api_base.clone(),
+        allowed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let local_only = ChatTemplate {
+            local_only: Some(true),
+            ..Default::default()
+        };
+        let config = |api_base: &str| AwfulJadeConfig {
+            api_base: api_base.to_string(),
+            ..Default::default()
+        };
+
+        assert!(check(&config("http://localhost:11434/v1"), &local_only).is_ok());
+        assert!(check(&config("http://[::1]:1234/v1"), &local_only).is_ok());
+        assert!(check(
+            &config("https://api.openai.com/v1"),
+            &ChatTemplate::default()
+        )
+        .is_ok());
+
+        let error = check(&config("https://api.openai.com/v1"), &local_only).unwrap_err();
+        assert_eq!(error.allowed, DEFAULT_LOCAL_HOSTS);
+        assert!(check(
+            &AwfulJadeConfig {
+                allow_remote: true,
+                ..config("https://api.openai.com/v1")
+            },
+            &local_only
+        )
+        .is_ok());
+
+        let gpu_box = AwfulJadeConfig {
+            local_hosts: vec!["gpu.lan".to_string()],
+            ..config("http://gpu.lan:8000/v1")
+        };
+        assert!(check(&gpu_box, &local_only).is_ok());
+        assert!(check(
+            &AwfulJadeConfig {
+                api_base: "http://localhost:8000/v1".to_string(),
+                ..gpu_box
+            },
+            &local_only
+        )
+        .is_err());
+    }
+}
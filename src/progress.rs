@@ -0,0 +1,108 @@
+//! This module reports progress during long-running operations.
+//!
+//! Loading the embedding model, building the vector index, and mapping a repository can take a
+//! while. Functions that do so have `_with_progress` variants taking a `ProgressSink`, which
+//! receives a `ProgressEvent` when the task starts, as it advances, and when it finishes. The `aj`
+//! binary renders these as progress bars; library callers can implement `ProgressSink` or pass a
+//! closure.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use awful_aj::{
+//!     progress::ProgressEvent,
+//!     repo::{self, WalkOptions},
+//! };
+//! use std::path::Path;
+//!
+//! # fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let report = |event: ProgressEvent| eprintln!("{:?}", event);
+//! let map = repo::load_or_build_with_progress(Path::new("."), &WalkOptions::default(), &report)?;
+//! println!("{}", map.render());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+
+/// A long-running task that reports progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    /// Downloading (on first use) and loading the sentence embeddings model.
+    LoadEmbeddingModel,
+
+    /// Building the vector store's nearest-neighbour index.
+    BuildIndex,
+
+    /// Walking a repository and extracting its symbols.
+    MapRepository,
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Task::LoadEmbeddingModel => "Loading embedding model",
+            Task::BuildIndex => "Building memory index",
+            Task::MapRepository => "Mapping repository",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// A change in the progress of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The task started. `total` is the number of steps, if known.
+    Started { task: Task, total: Option<u64> },
+
+    /// `completed` of the task's steps are done.
+    Advanced { task: Task, completed: u64 },
+
+    /// The task finished, successfully or not.
+    Finished { task: Task },
+}
+
+/// Receives progress events.
+pub trait ProgressSink: Send + Sync {
+    /// Handles a progress event.
+    fn on_event(&self, event: ProgressEvent);
+}
+
+impl<F> ProgressSink for F
+where
+    F: Fn(ProgressEvent) + Send + Sync,
+{
+    fn on_event(&self, event: ProgressEvent) {
+        self(event)
+    }
+}
+
+/// A sink that ignores every event.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn on_event(&self, _event: ProgressEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_closure_sink() {
+        let events = Mutex::new(Vec::new());
+        let sink = |event: ProgressEvent| events.lock().unwrap().push(event);
+
+        sink.on_event(ProgressEvent::Started {
+            task: Task::BuildIndex,
+            total: None,
+        });
+        sink.on_event(ProgressEvent::Finished {
+            task: Task::BuildIndex,
+        });
+
+        assert_eq!(events.lock().unwrap().len(), 2);
+        assert_eq!(Task::MapRepository.to_string(), "Mapping repository");
+    }
+}
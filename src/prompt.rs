@@ -0,0 +1,267 @@
+//! This module shows exactly what is sent to the model for a question.
+//!
+//! `assemble` puts a question together the way `api::ask_for_completion` does (the template's
+//! system prompt, recalled memories, the template's messages, and the question, adapted to the
+//! model's family and fitted to the context window) and returns the request as a
+//! `PromptSnapshot`, without sending it. Snapshots serialize to the same JSON for the same
+//! request, so they can be diffed and checked into tests; `aj debug prompt --json` prints one:
+//!
+//! ```sh
+//! aj debug prompt --json "What is a monad?"
+//! ```
+//!
+//! ```json
+//! {
+//!   "model": "gpt-4",
+//!   "stream": true,
+//!   "max_tokens": 3949,
+//!   "temperature": 0.7,
+//!   "stop": [],
+//!   "messages": [
+//!     { "role": "system", "content": "You are a helpful assistant." },
+//!     { "role": "user", "content": "What is a monad?" }
+//!   ]
+//! }
+//! ```
+
+use crate::{api, brain::Brain, config::AwfulJadeConfig, language, template::ChatTemplate};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequest, Role, Stop};
+use serde::Serialize;
+use std::error::Error;
+
+/// A request as it is sent to the model. Fields serialize in declaration order.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PromptSnapshot {
+    pub model: String,
+
+    /// Whether the response is streamed.
+    pub stream: bool,
+
+    pub max_tokens: Option<u16>,
+    pub temperature: Option<f32>,
+    pub stop: Vec<String>,
+    pub messages: Vec<SnapshotMessage>,
+}
+
+/// A message of a `PromptSnapshot`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotMessage {
+    pub role: Role,
+    pub content: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl From<&ChatCompletionRequestMessage> for SnapshotMessage {
+    fn from(message: &ChatCompletionRequestMessage) -> Self {
+        Self {
+            role: message.role.clone(),
+            content: message.content.clone().unwrap_or_default(),
+            name: message.name.clone(),
+        }
+    }
+}
+
+impl PromptSnapshot {
+    /// Describes `request`, which is streamed when `stream` is set.
+    pub fn from_request(request: &CreateChatCompletionRequest, stream: bool) -> Self {
+        let stop = match &request.stop {
+            Some(Stop::String(word)) => vec![word.clone()],
+            Some(Stop::StringArray(words)) => words.clone(),
+            None => Vec::new(),
+        };
+        Self {
+            model: request.model.clone(),
+            stream,
+            max_tokens: request.max_tokens,
+            temperature: request.temperature,
+            stop,
+            messages: request.messages.iter().map(SnapshotMessage::from).collect(),
+        }
+    }
+
+    /// Returns the snapshot as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize prompt snapshot")
+    }
+}
+
+/// Puts `question` together with `template` the way `api::ask_for_completion` would, with the
+/// memories in `brain` when there is one, and returns the request that would be sent.
+///
+/// # Returns
+///
+/// - `Ok(PromptSnapshot)`: The request.
+/// - `Err(Box<dyn Error>)`: The question does not fit in the context window (an
+///   `api::ContextOverflow`), or the request could not be built.
+pub fn assemble(
+    config: &AwfulJadeConfig,
+    template: &ChatTemplate,
+    question: &str,
+    brain: Option<&Brain<'_>>,
+) -> Result<PromptSnapshot, Box<dyn Error>> {
+    let system_prompt = language::instruct_system_prompt(
+        &template.system_prompt,
+        template.respond_in(config),
+        question,
+    );
+    let question_message = ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(question.to_string()),
+        name: None,
+        function_call: None,
+    };
+    let template_messages = api::fit_template_messages(
+        config,
+        template,
+        &config.model,
+        &system_prompt,
+        &question_message,
+    );
+    let mut messages = match brain {
+        Some(brain) => brain.build_preamble()?,
+        None => vec![api::system_message(&system_prompt)],
+    };
+    messages.extend(template_messages);
+    api::add_question(&mut messages, system_prompt, question_message);
+    api::ContextOverflow::check(&messages, &config.model, config.context_max_tokens)?;
+
+    let (request, _) = api::prepare_request(
+        config.model.clone(),
+        messages,
+        template.stop_words(config, &[]),
+        config,
+    )?;
+    Ok(PromptSnapshot::from_request(
+        &request,
+        config.should_stream(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brain::Memory, test_support};
+
+    fn config() -> AwfulJadeConfig {
+        AwfulJadeConfig {
+            max_response_tokens: Some(256),
+            temperature: Some(0.5),
+            ..test_support::config_with_budget(4096, 1024)
+        }
+    }
+
+    fn template() -> ChatTemplate {
+        ChatTemplate {
+            system_prompt: "You are a helpful assistant.".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_snapshot_without_memories() {
+        let snapshot = assemble(&config(), &template(), "What is a monad?", None).unwrap();
+        assert_eq!(
+            snapshot.to_json(),
+            r#"{
+  "model": "gpt-4",
+  "stream": false,
+  "max_tokens": 256,
+  "temperature": 0.5,
+  "stop": [],
+  "messages": [
+    {
+      "role": "system",
+      "content": "You are a helpful assistant."
+    },
+    {
+      "role": "user",
+      "content": "What is a monad?"
+    }
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn test_snapshot_with_memories() {
+        let config = config();
+        let template = template();
+        let question = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some("What is a monad?".to_string()),
+            name: None,
+            function_call: None,
+        };
+        let mut brain = Brain::new(u16::MAX, &template);
+        brain.add_memory(
+            Memory::new(Role::User, "What is a functor?".to_string()),
+            &question,
+            &config,
+        );
+        brain.add_memory(
+            Memory::new(Role::Assistant, "A type you can map over.".to_string()),
+            &question,
+            &config,
+        );
+
+        let snapshot = assemble(&config, &template, "What is a monad?", Some(&brain)).unwrap();
+        assert_eq!(
+            snapshot.to_json(),
+            r#"{
+  "model": "gpt-4",
+  "stream": false,
+  "max_tokens": 256,
+  "temperature": 0.5,
+  "stop": [],
+  "messages": [
+    {
+      "role": "system",
+      "content": "You are a helpful assistant."
+    },
+    {
+      "role": "user",
+      "content": "Below is a JSON representation of our conversation leading up to this point. Please only respond to this message with \"Ok.\":\n{\"about\":\"This JSON object is a representation of our conversation leading up to this point. This object represents your memories.\",\"memories\":[{\"role\":\"user\",\"content\":\"What is a functor?\"},{\"role\":\"assistant\",\"content\":\"A type you can map over.\"}]}"
+    },
+    {
+      "role": "assistant",
+      "content": "Ok."
+    },
+    {
+      "role": "user",
+      "content": "What is a monad?"
+    }
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn test_snapshot_for_a_family_without_system_role() {
+        let config = AwfulJadeConfig {
+            model: "mistral-7b-instruct".to_string(),
+            ..config()
+        };
+        let snapshot = assemble(&config, &template(), "What is a monad?", None).unwrap();
+        assert_eq!(
+            snapshot.to_json(),
+            r#"{
+  "model": "mistral-7b-instruct",
+  "stream": false,
+  "max_tokens": 256,
+  "temperature": 0.5,
+  "stop": [
+    "</s>",
+    "[INST]"
+  ],
+  "messages": [
+    {
+      "role": "user",
+      "content": "You are a helpful assistant.\n\nWhat is a monad?"
+    }
+  ]
+}"#
+        );
+    }
+}
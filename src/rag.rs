@@ -0,0 +1,254 @@
+//! This module searches a directory of documents by meaning, without asking the model.
+//!
+//! `aj rag query` splits the text documents in a directory into chunks, embeds them with the
+//! configured embedder, and prints the chunks closest to a question with their distances. It is
+//! meant for checking what retrieval would find, and as a standalone semantic search:
+//!
+//! ```text
+//! aj rag query --rag ~/notes "what does the auth middleware do" --top-k 8 --json
+//! ```
+//!
+//! Documents are read as text by the loader for their extension, so HTML pages and PDFs are
+//! searched by their text; see the `loader` module. `--rag` can also name a single document.
+//!
+//! Nothing is cached: every query embeds the whole directory again, so it is best pointed at a
+//! directory of notes rather than a large corpus.
+
+pub mod loader;
+
+use crate::{
+    brain::Memory,
+    digest::{self, RecentFile},
+    progress::ProgressSink,
+    vector_store::{chunk_text, VectorStore, DEFAULT_CHUNK_TOKENS},
+};
+use async_openai::types::Role;
+use serde::Serialize;
+use std::{error::Error, fmt, fs, path::Path, time::Duration};
+use tracing::{debug, warn};
+
+/// How many chunks are embedded in one request.
+const EMBEDDING_BATCH: usize = 64;
+
+/// A chunk of a document found by a query.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Hit {
+    /// The document's path, relative to the directory searched.
+    pub path: String,
+
+    /// The chunk's distance to the question; smaller is closer.
+    pub distance: f32,
+
+    /// The chunk's text.
+    pub text: String,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (distance {:.3})\n{}",
+            self.path, self.distance, self.text
+        )
+    }
+}
+
+/// A directory's documents, chunked and embedded.
+pub struct Collection {
+    vector_store: VectorStore,
+
+    /// The path of the document each chunk comes from, by the chunk's id.
+    paths: Vec<String>,
+}
+
+impl Collection {
+    /// Chunks and embeds the documents under `dir` with the embedder of `vector_store`, an empty
+    /// store. Hidden and ignored files are skipped, as are files their loader cannot read, such as
+    /// non-UTF-8 files.
+    ///
+    /// # Parameters
+    ///
+    /// - `vector_store`: An empty store to add the chunks to.
+    /// - `dir`: The directory whose documents are searched, or a single document.
+    /// - `chunk_tokens`: The most tokens in each chunk, or `DEFAULT_CHUNK_TOKENS` when `None`.
+    /// - `progress`: Where the progress of building the index is reported.
+    pub async fn load(
+        vector_store: VectorStore,
+        dir: &Path,
+        chunk_tokens: Option<usize>,
+        progress: &dyn ProgressSink,
+    ) -> Result<Self, Box<dyn Error>> {
+        let chunk_tokens = chunk_tokens.unwrap_or(DEFAULT_CHUNK_TOKENS);
+        let mut chunks = Vec::new();
+        let files = if dir.is_file() {
+            vec![RecentFile {
+                path: dir.to_path_buf(),
+                name: dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                modified: fs::metadata(dir)?.modified()?,
+            }]
+        } else {
+            digest::recent_files(dir, Duration::MAX)?
+        };
+        for file in files {
+            let loader = loader::loader_for(&file.path);
+            let content = match loader.load(&file.path) {
+                Ok(content) => content,
+                // Plain text loads fail for binary files, which are expected in most directories
+                Err(e) if loader.extensions().is_empty() => {
+                    debug!("Skipping non-text document {}: {}", file.path.display(), e);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping {}: {}", file.path.display(), e);
+                    continue;
+                }
+            };
+            for chunk in chunk_text(&content, chunk_tokens) {
+                if !chunk.is_empty() {
+                    chunks.push((file.name.clone(), chunk));
+                }
+            }
+        }
+        debug!("Embedding {} chunks from {}", chunks.len(), dir.display());
+
+        let embedder = vector_store.embedder();
+        let mut embedded = Vec::with_capacity(chunks.len());
+        for batch in chunks.chunks(EMBEDDING_BATCH) {
+            let texts: Vec<String> = batch.iter().map(|(_, text)| text.clone()).collect();
+            let vectors = embedder.embed(&texts).await?;
+            for ((path, text), vector) in batch.iter().zip(vectors) {
+                embedded.push((path.clone(), text.clone(), vector));
+            }
+        }
+        Self::from_embedded(vector_store, embedded, progress)
+    }
+
+    /// Builds a collection from chunks that are already embedded, given as `(path, text, vector)`,
+    /// in `vector_store`, an empty store whose dimension is the vectors'.
+    pub fn from_embedded(
+        mut vector_store: VectorStore,
+        chunks: Vec<(String, String, Vec<f32>)>,
+        progress: &dyn ProgressSink,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut paths = Vec::with_capacity(chunks.len());
+        for (path, text, vector) in chunks {
+            vector_store.add_vector_with_content(vector, Memory::new(Role::User, text))?;
+            paths.push(path);
+        }
+        if !paths.is_empty() {
+            vector_store.build_with_progress(progress)?;
+        }
+
+        Ok(Self {
+            vector_store,
+            paths,
+        })
+    }
+
+    /// Returns how many chunks the collection holds.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Returns whether the collection holds no chunks.
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Returns the `top_k` chunks closest to `question`, closest first.
+    pub async fn query(&self, question: &str, top_k: usize) -> Result<Vec<Hit>, Box<dyn Error>> {
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let vector = self
+            .vector_store
+            .embedder()
+            .embed(&[question.to_string()])
+            .await?
+            .pop()
+            .unwrap_or_default();
+        let neighbors = self.vector_store.search_with_scores(&vector, top_k)?;
+        Ok(neighbors
+            .into_iter()
+            .filter_map(|(id, distance)| {
+                let memory = self.vector_store.get_content_by_id(id)?;
+                Some(Hit {
+                    path: self.paths.get(id)?.clone(),
+                    distance,
+                    text: memory.content().to_string(),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Renders `hits` as a numbered list, each chunk below its document and distance.
+pub fn format_hits(hits: &[Hit]) -> String {
+    if hits.is_empty() {
+        return "No documents matched.\n".to_string();
+    }
+    hits.iter()
+        .enumerate()
+        .map(|(i, hit)| format!("[{}] {}\n\n", i + 1, hit))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{progress::NoProgress, test_support};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_query_ranks_chunks() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("auth.md"),
+            "The auth middleware checks the session token on every request.",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("garden.md"),
+            "Tomatoes need full sun and regular watering.",
+        )
+        .unwrap();
+
+        let collection =
+            Collection::load(test_support::vector_store(), dir.path(), None, &NoProgress)
+                .await
+                .unwrap();
+        assert_eq!(collection.len(), 2);
+
+        let hits = collection
+            .query("what does the auth middleware check", 1)
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].path, "auth.md");
+        assert!(format_hits(&hits).starts_with("[1] auth.md (distance "));
+    }
+
+    #[tokio::test]
+    async fn test_load_reads_a_single_html_page() {
+        let dir = tempdir().unwrap();
+        let page = dir.path().join("auth.html");
+        fs::write(
+            &page,
+            "<html><body><p>The auth middleware checks the <b>session token</b>.</p></body></html>",
+        )
+        .unwrap();
+
+        let collection = Collection::load(test_support::vector_store(), &page, None, &NoProgress)
+            .await
+            .unwrap();
+        let hits = collection.query("session token", 1).await.unwrap();
+        assert_eq!(hits[0].path, "auth.html");
+        assert_eq!(
+            hits[0].text,
+            "The auth middleware checks the session token."
+        );
+    }
+}
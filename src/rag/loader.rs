@@ -0,0 +1,293 @@
+//! This module reads documents of different formats as plain text, for chunking and embedding.
+//!
+//! The loader is picked by the file's extension:
+//!
+//! | Extension        | Loader             | Text                                              |
+//! |------------------|--------------------|---------------------------------------------------|
+//! | `.html`, `.htm`  | `HtmlLoader`       | The page without its tags, scripts, and styles    |
+//! | `.pdf`           | `PdfLoader`        | The text of its pages                             |
+//! | anything else    | `PlainTextLoader`  | The file as it is, when it is valid UTF-8         |
+//!
+//! PDF text is extracted with `pdf-extract`, so nothing needs to be installed. Encrypted PDFs, and
+//! scanned PDFs whose pages are images without text, are skipped with a `PdfError` saying so.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{
+    error::Error,
+    fmt, fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
+
+/// Comments, and the elements whose content is not shown, from their opening to their closing tag.
+static INVISIBLE: Lazy<Vec<Regex>> = Lazy::new(|| {
+    let mut invisible = vec![Regex::new(r"(?s)<!--.*?-->").unwrap()];
+    for tag in ["head", "script", "style", "noscript", "template"] {
+        invisible.push(Regex::new(&format!(r"(?is)<{0}\b[^>]*>.*?</\s*{0}\s*>", tag)).unwrap());
+    }
+    invisible
+});
+
+static BLOCK_BREAK: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)<br\s*/?>|</?(p|div|li|ul|ol|tr|table|h[1-6]|pre|blockquote|section|article|header|footer)\b[^>]*>").unwrap()
+});
+
+static TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+static ENTITY: Lazy<Regex> = Lazy::new(|| Regex::new(r"&(#x?[0-9a-fA-F]+|[a-zA-Z]+);").unwrap());
+
+static BLANK_LINES: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\s*\n\s*(\n\s*)+").unwrap());
+
+/// Reads a format of documents as plain text.
+pub trait DocumentLoader: Send + Sync {
+    /// The extensions of the files this loader reads, in lowercase and without the dot.
+    fn extensions(&self) -> &[&str];
+
+    /// Returns the text of the document at `path`.
+    fn load(&self, path: &Path) -> Result<String, Box<dyn Error>>;
+}
+
+/// Reads UTF-8 text files as they are.
+pub struct PlainTextLoader;
+
+impl DocumentLoader for PlainTextLoader {
+    fn extensions(&self) -> &[&str] {
+        &[]
+    }
+
+    fn load(&self, path: &Path) -> Result<String, Box<dyn Error>> {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+/// Reads HTML pages as their visible text.
+pub struct HtmlLoader;
+
+impl DocumentLoader for HtmlLoader {
+    fn extensions(&self) -> &[&str] {
+        &["html", "htm"]
+    }
+
+    fn load(&self, path: &Path) -> Result<String, Box<dyn Error>> {
+        Ok(html_to_text(&fs::read_to_string(path)?))
+    }
+}
+
+/// Why the text of a PDF could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PdfError {
+    /// The PDF is encrypted with a password.
+    Encrypted,
+
+    /// The PDF has no text, e.g. because it was scanned and its pages are images.
+    NoText,
+
+    /// The PDF could not be parsed.
+    Unreadable(String),
+}
+
+impl fmt::Display for PdfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdfError::Encrypted => write!(f, "the PDF is encrypted"),
+            PdfError::NoText => write!(
+                f,
+                "the PDF has no text; if it was scanned, run it through OCR first"
+            ),
+            PdfError::Unreadable(reason) => write!(f, "the PDF could not be read: {}", reason),
+        }
+    }
+}
+
+impl Error for PdfError {}
+
+/// Reads the text of PDF files.
+pub struct PdfLoader;
+
+impl DocumentLoader for PdfLoader {
+    fn extensions(&self) -> &[&str] {
+        &["pdf"]
+    }
+
+    fn load(&self, path: &Path) -> Result<String, Box<dyn Error>> {
+        Ok(pdf_to_text(&fs::read(path)?)?)
+    }
+}
+
+/// Returns the text of the PDF in `bytes`.
+pub fn pdf_to_text(bytes: &[u8]) -> Result<String, PdfError> {
+    // pdf-extract panics on some malformed fonts; one bad document must not end the whole load
+    let extracted = panic::catch_unwind(AssertUnwindSafe(|| {
+        pdf_extract::extract_text_from_mem(bytes)
+    }))
+    .map_err(|_| PdfError::Unreadable("its text could not be extracted".to_string()))?;
+    let text = match extracted {
+        Ok(text) => text,
+        Err(pdf_extract::OutputError::PdfError(pdf_extract::Error::Decryption(_))) => {
+            return Err(PdfError::Encrypted)
+        }
+        Err(e) => return Err(PdfError::Unreadable(e.to_string())),
+    };
+    if text.trim().is_empty() {
+        return Err(PdfError::NoText);
+    }
+    Ok(text)
+}
+
+/// The loaders of the formats that are not plain text.
+static LOADERS: [&dyn DocumentLoader; 2] = [&HtmlLoader, &PdfLoader];
+
+/// Returns the loader for the document at `path`, by its extension.
+pub fn loader_for(path: &Path) -> &'static dyn DocumentLoader {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    LOADERS
+        .iter()
+        .copied()
+        .find(|loader| loader.extensions().contains(&extension.as_str()))
+        .unwrap_or(&PlainTextLoader)
+}
+
+/// Returns the text of the document at `path`, read with the loader for its extension.
+pub fn load_document(path: &Path) -> Result<String, Box<dyn Error>> {
+    loader_for(path).load(path)
+}
+
+/// Returns the visible text of an HTML page: without comments, scripts, styles, and tags, with
+/// a line break for each block element and the common character references decoded.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = html.to_string();
+    for invisible in INVISIBLE.iter() {
+        text = invisible.replace_all(&text, "").into_owned();
+    }
+    let text = BLOCK_BREAK.replace_all(&text, "\n");
+    let text = TAG.replace_all(&text, "");
+    let text = ENTITY.replace_all(&text, |captures: &regex::Captures| {
+        decode_entity(&captures[1]).unwrap_or_else(|| captures[0].to_string())
+    });
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    BLANK_LINES
+        .replace_all(&lines.join("\n"), "\n\n")
+        .trim()
+        .to_string()
+}
+
+/// Decodes the character reference `&name;`, given `name`, when it is numeric or common.
+fn decode_entity(name: &str) -> Option<String> {
+    let decoded = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some(decoded.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><title>Report</title><style>p { color: red }</style></head>\
+                    <body><h1>Q3 &amp; Q4</h1><!-- draft --><p>Revenue grew&nbsp;12%.</p>\
+                    <script>track()</script><ul><li>North</li><li>South &#8212; flat</li></ul>\
+                    </body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Q3 & Q4\n\nRevenue grew 12%.\n\nNorth\n\nSouth \u{2014} flat"
+        );
+    }
+
+    /// Returns a one-page PDF showing `lines`.
+    fn pdf(lines: &[&str]) -> Vec<u8> {
+        use pdf_extract::{
+            content::{Content, Operation},
+            dictionary, Document, Object, Stream,
+        };
+
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+        let mut operations = vec![
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec!["F1".into(), 12.into()]),
+            Operation::new("Td", vec![72.into(), 720.into()]),
+        ];
+        for line in lines {
+            operations.push(Operation::new("Tj", vec![Object::string_literal(*line)]));
+            operations.push(Operation::new("Td", vec![0.into(), (-16).into()]));
+        }
+        operations.push(Operation::new("ET", vec![]));
+        let content = Content { operations }.encode().unwrap();
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_pdf_to_text() {
+        let text = pdf_to_text(&pdf(&["Quarterly report", "Revenue grew 12%."])).unwrap();
+        assert!(text.contains("Quarterly report"));
+        assert!(text.contains("Revenue grew 12%."));
+
+        assert_eq!(pdf_to_text(&pdf(&[])), Err(PdfError::NoText));
+        assert!(matches!(
+            pdf_to_text(b"not a PDF"),
+            Err(PdfError::Unreadable(_))
+        ));
+    }
+
+    #[test]
+    fn test_loader_for() {
+        assert_eq!(loader_for(Path::new("report.PDF")).extensions(), ["pdf"]);
+        assert_eq!(
+            loader_for(Path::new("docs/index.htm")).extensions(),
+            ["html", "htm"]
+        );
+        assert!(loader_for(Path::new("notes.md")).extensions().is_empty());
+        assert!(loader_for(Path::new("Makefile")).extensions().is_empty());
+    }
+}
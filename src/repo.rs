@@ -0,0 +1,912 @@
+//! This module builds a compact "map" of a source repository that can be handed to the model
+//! alongside a question about the codebase.
+//!
+//! A repository map lists every source file in the tree together with its symbols (functions,
+//! types, traits, classes, ...). Symbols are found by parsing Rust, Python, JavaScript,
+//! TypeScript, and Go files with tree-sitter, so declarations spanning several lines are listed
+//! on one, and nothing in strings or comments is mistaken for one. The members of impls, traits,
+//! modules, and classes are listed indented under them. Maps are cached in the application's
+//! config directory and rebuilt incrementally: only files whose modification time changed since
+//! the last run are parsed again.
+//!
+//! Walking respects `.gitignore` files as well as `.ajignore` files, which use the same syntax
+//! and can exclude anything that should never reach the model (generated code, fixtures,
+//! vendored dependencies, ...). Binary files are always skipped.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use awful_aj::repo::load_or_build;
+//! use std::path::Path;
+//!
+//! let map = load_or_build(Path::new("."), &Default::default()).unwrap();
+//! println!("{}", map.render());
+//! ```
+
+use crate::{
+    progress::{NoProgress, ProgressEvent, ProgressSink, Task},
+    tokens,
+};
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    error::Error,
+    fs,
+    hash::{Hash, Hasher},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+use tree_sitter::{Language, Node, Parser};
+use tree_sitter_language::LanguageFn;
+
+/// Files larger than this are listed in the map but never parsed for symbols.
+const MAX_PARSED_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Directories that are never descended into while walking a repository.
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules", "vendor", "dist", "build"];
+
+/// The name of aj's own ignore file, honoured in addition to `.gitignore`.
+pub const IGNORE_FILENAME: &str = ".ajignore";
+
+/// Files containing a NUL byte within this many leading bytes are treated as binary.
+const BINARY_SNIFF_BYTES: usize = 8000;
+
+/// Symbols longer than this are truncated when stored in the map.
+const MAX_SYMBOL_CHARS: usize = 120;
+
+/// The version of `extract_symbols`. Cached maps built by another version are rebuilt.
+const SYMBOLS_VERSION: u32 = 1;
+
+/// How the declarations of a language are found in its syntax tree.
+struct Grammar {
+    language: LanguageFn,
+
+    /// The kinds of the nodes listed as symbols.
+    declarations: &'static [&'static str],
+
+    /// The declarations whose `body` holds more declarations, listed under them.
+    containers: &'static [&'static str],
+
+    /// The nodes wrapping a declaration, with the field holding it and whether the symbol starts
+    /// at the wrapper, e.g. with `export`, rather than at the declaration, e.g. after decorators.
+    wrappers: &'static [(&'static str, &'static str, bool)],
+}
+
+const RUST: Grammar = Grammar {
+    language: tree_sitter_rust::LANGUAGE,
+    declarations: &[
+        "function_item",
+        "function_signature_item",
+        "struct_item",
+        "enum_item",
+        "union_item",
+        "trait_item",
+        "impl_item",
+        "mod_item",
+        "type_item",
+        "const_item",
+        "static_item",
+        "macro_definition",
+    ],
+    containers: &["impl_item", "trait_item", "mod_item"],
+    wrappers: &[],
+};
+
+const PYTHON: Grammar = Grammar {
+    language: tree_sitter_python::LANGUAGE,
+    declarations: &["function_definition", "class_definition"],
+    containers: &["class_definition"],
+    wrappers: &[("decorated_definition", "definition", false)],
+};
+
+/// The JavaScript and TypeScript declarations, which the TypeScript grammars extend.
+const JS_DECLARATIONS: &[&str] = &[
+    "function_declaration",
+    "generator_function_declaration",
+    "class_declaration",
+    "abstract_class_declaration",
+    "lexical_declaration",
+    "method_definition",
+    "interface_declaration",
+    "type_alias_declaration",
+    "enum_declaration",
+    "function_signature",
+];
+const JS_CONTAINERS: &[&str] = &["class_declaration", "abstract_class_declaration"];
+const JS_WRAPPERS: &[(&str, &str, bool)] = &[("export_statement", "declaration", true)];
+
+const JAVASCRIPT: Grammar = Grammar {
+    language: tree_sitter_javascript::LANGUAGE,
+    declarations: JS_DECLARATIONS,
+    containers: JS_CONTAINERS,
+    wrappers: JS_WRAPPERS,
+};
+
+const TYPESCRIPT: Grammar = Grammar {
+    language: tree_sitter_typescript::LANGUAGE_TYPESCRIPT,
+    ..JAVASCRIPT
+};
+
+const TSX: Grammar = Grammar {
+    language: tree_sitter_typescript::LANGUAGE_TSX,
+    ..JAVASCRIPT
+};
+
+const GO: Grammar = Grammar {
+    language: tree_sitter_go::LANGUAGE,
+    declarations: &[
+        "function_declaration",
+        "method_declaration",
+        "type_declaration",
+    ],
+    containers: &[],
+    wrappers: &[],
+};
+
+/// A single file in a repository map.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FileEntry {
+    /// Path of the file relative to the repository root, using `/` as separator.
+    pub path: String,
+
+    /// Modification time of the file (seconds since the Unix epoch) when it was last parsed.
+    pub modified: u64,
+
+    /// Symbols declared in the file, in source order. Members are indented under the impl, trait,
+    /// module, or class declaring them.
+    pub symbols: Vec<String>,
+}
+
+/// Glob overrides applied while walking a repository.
+///
+/// Globs are matched relative to the repository root using `.gitignore` syntax. When any
+/// `include` globs are given, only files matching one of them are mapped; `exclude` globs
+/// remove files even if they would otherwise be included.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Globs of files to include, e.g. `src/**/*.rs`.
+    pub include: Vec<String>,
+
+    /// Globs of files to exclude, e.g. `*.snap`.
+    pub exclude: Vec<String>,
+}
+
+/// A map of a repository: every source file and its symbols.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RepoMap {
+    /// The canonical path of the repository root.
+    pub root: PathBuf,
+
+    /// The files of the repository, sorted by path.
+    pub files: Vec<FileEntry>,
+
+    /// The version of the symbol extraction the map was built with.
+    #[serde(default)]
+    pub symbols_version: u32,
+}
+
+impl RepoMap {
+    /// Builds a repository map for `root`.
+    ///
+    /// Entries from `previous` are reused for every file whose modification time is unchanged,
+    /// so repeated builds of a large repository only parse the files that were edited.
+    ///
+    /// # Parameters
+    ///
+    /// - `root`: The directory to map.
+    /// - `previous`: A previously built map of the same directory, if any.
+    /// - `options`: Glob overrides deciding which files are part of the map.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the new `RepoMap`, or an error if the directory could not be read.
+    pub fn build(
+        root: &Path,
+        previous: Option<&RepoMap>,
+        options: &WalkOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::build_with_progress(root, previous, options, &NoProgress)
+    }
+
+    /// Builds a repository map like `build`, reporting each file it processes to `progress`.
+    pub fn build_with_progress(
+        root: &Path,
+        previous: Option<&RepoMap>,
+        options: &WalkOptions,
+        progress: &dyn ProgressSink,
+    ) -> Result<Self, Box<dyn Error>> {
+        let root = root.canonicalize()?;
+        let cached: HashMap<&str, &FileEntry> = previous
+            .map(|map| map.files.iter().map(|f| (f.path.as_str(), f)).collect())
+            .unwrap_or_default();
+
+        let paths = collect_files(&root, options)?;
+
+        let task = Task::MapRepository;
+        progress.on_event(ProgressEvent::Started {
+            task,
+            total: Some(paths.len() as u64),
+        });
+        let files = file_entries(&root, paths, &cached, progress);
+        progress.on_event(ProgressEvent::Finished { task });
+        let mut files = files?;
+
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Self {
+            root,
+            files,
+            symbols_version: SYMBOLS_VERSION,
+        })
+    }
+
+    /// Renders the map as a compact, indented listing of files and their symbols.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for file in &self.files {
+            output.push_str(&file.path);
+            output.push('\n');
+            for symbol in &file.symbols {
+                output.push_str("  ");
+                output.push_str(symbol);
+                output.push('\n');
+            }
+        }
+        output
+    }
+
+    /// Renders the map, dropping symbols and then whole files until it fits in `max_tokens`.
+    ///
+    /// Symbols are dropped first so that the file tree survives as long as possible; if even the
+    /// bare file list is too large, trailing files are cut off.
+    pub fn render_compressed(&self, max_tokens: usize) -> String {
+        let full = self.render();
+        if count_tokens(&full) <= max_tokens {
+            return full;
+        }
+
+        let mut output = String::new();
+        let mut used = 0;
+        for file in &self.files {
+            let line = format!("{}\n", file.path);
+            let tokens = count_tokens(&line);
+            if used + tokens > max_tokens {
+                break;
+            }
+            used += tokens;
+            output.push_str(&line);
+        }
+        output
+    }
+}
+
+/// Loads the cached map for `root`, refreshes it, and writes it back to the cache.
+///
+/// Maps are stored as YAML under `repo_maps/` in the application's config directory, keyed by
+/// a hash of the repository's canonical path.
+///
+/// # Parameters
+///
+/// - `root`: The directory to map.
+/// - `options`: Glob overrides deciding which files are part of the map.
+///
+/// # Returns
+///
+/// A result containing the up-to-date `RepoMap`, or an error if the directory could not be read
+/// or the cache could not be written.
+pub fn load_or_build(root: &Path, options: &WalkOptions) -> Result<RepoMap, Box<dyn Error>> {
+    load_or_build_with_progress(root, options, &NoProgress)
+}
+
+/// Loads and refreshes the cached map for `root` like `load_or_build`, reporting progress.
+pub fn load_or_build_with_progress(
+    root: &Path,
+    options: &WalkOptions,
+    progress: &dyn ProgressSink,
+) -> Result<RepoMap, Box<dyn Error>> {
+    let cache_path = cache_path(&root.canonicalize()?)?;
+    let previous = fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|content| serde_yaml::from_str::<RepoMap>(&content).ok())
+        .filter(|map| map.symbols_version == SYMBOLS_VERSION);
+
+    let map = RepoMap::build_with_progress(root, previous.as_ref(), options, progress)?;
+
+    if previous.as_ref() != Some(&map) {
+        debug!("Writing repo map cache: {}", cache_path.display());
+        if let Some(parent) = cache_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&cache_path, serde_yaml::to_string(&map)?)?;
+    }
+
+    Ok(map)
+}
+
+/// Describes a rendered repository map so it can be handed to the model as supplementary context.
+pub fn describe(repo_map: &str) -> String {
+    format!(
+        "A map of the repository my question is about. It lists each file followed by its top-level symbols.\n{}",
+        repo_map
+    )
+}
+
+/// Extracts the symbols from `content`, choosing a tree-sitter grammar by the file's extension.
+///
+/// The declarations at the top of the file are listed, each followed by the members of impls,
+/// traits, modules, and classes, indented by two spaces per level. Declarations inside function
+/// bodies are left out. Files with an unrecognised extension have no symbols.
+pub fn extract_symbols(path: &Path, content: &str) -> Vec<String> {
+    let grammar = match path.extension().and_then(|e| e.to_str()) {
+        Some("rs") => &RUST,
+        Some("py") => &PYTHON,
+        Some("js" | "jsx" | "mjs" | "cjs") => &JAVASCRIPT,
+        Some("ts" | "mts" | "cts") => &TYPESCRIPT,
+        Some("tsx") => &TSX,
+        Some("go") => &GO,
+        _ => return Vec::new(),
+    };
+
+    let mut parser = Parser::new();
+    let language = Language::new(grammar.language);
+    if let Err(e) = parser.set_language(&language) {
+        debug!("Failed to load the grammar for {}: {}", path.display(), e);
+        return Vec::new();
+    }
+    let Some(tree) = parser.parse(content, None) else {
+        return Vec::new();
+    };
+    let mut symbols = Vec::new();
+    collect_symbols(grammar, tree.root_node(), content, 0, &mut symbols);
+    symbols
+}
+
+/// Adds the declarations among the children of `node` to `symbols`, followed by the members of
+/// those that are containers, one level deeper.
+fn collect_symbols(
+    grammar: &Grammar,
+    node: Node,
+    content: &str,
+    depth: usize,
+    symbols: &mut Vec<String>,
+) {
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        let wrapper = grammar
+            .wrappers
+            .iter()
+            .find(|(kind, _, _)| *kind == child.kind());
+        let (start, declaration) = match wrapper {
+            Some(&(_, field, from_wrapper)) => match child.child_by_field_name(field) {
+                Some(inner) if from_wrapper => (child.start_byte(), inner),
+                Some(inner) => (inner.start_byte(), inner),
+                None => continue,
+            },
+            None => (child.start_byte(), child),
+        };
+        if !grammar.declarations.contains(&declaration.kind()) {
+            continue;
+        }
+
+        let body = declaration.child_by_field_name("body");
+        let signature = match body {
+            // Everything before the body, however many lines it spans
+            Some(body) => &content[start..body.start_byte()],
+            None => content[start..declaration.end_byte()]
+                .lines()
+                .next()
+                .unwrap_or_default(),
+        };
+        // Parameters spread over several lines are put back on one, without a trailing comma
+        let signature = signature
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace("( ", "(")
+            .replace(", )", ")")
+            .replace(" )", ")");
+        let signature = signature.trim_end_matches(['{', ':', ';']).trim_end();
+        let mut symbol = "  ".repeat(depth);
+        symbol.extend(signature.chars().take(MAX_SYMBOL_CHARS));
+        symbols.push(symbol);
+
+        if let Some(body) = body.filter(|_| grammar.containers.contains(&declaration.kind())) {
+            collect_symbols(grammar, body, content, depth + 1, symbols);
+        }
+    }
+}
+
+/// Builds the entries for `paths`, reusing cached entries for files that have not changed.
+fn file_entries(
+    root: &Path,
+    paths: Vec<PathBuf>,
+    cached: &HashMap<&str, &FileEntry>,
+    progress: &dyn ProgressSink,
+) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+    let mut files = Vec::with_capacity(paths.len());
+    for (completed, path) in paths.into_iter().enumerate() {
+        progress.on_event(ProgressEvent::Advanced {
+            task: Task::MapRepository,
+            completed: completed as u64,
+        });
+        let relative = relative_path(root, &path);
+        let metadata = fs::metadata(&path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        if let Some(entry) = cached.get(relative.as_str()) {
+            if entry.modified == modified {
+                files.push((*entry).clone());
+                continue;
+            }
+        }
+
+        debug!("Parsing symbols for {}", relative);
+        let symbols = if metadata.len() <= MAX_PARSED_FILE_BYTES {
+            fs::read_to_string(&path)
+                .map(|content| extract_symbols(&path, &content))
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        files.push(FileEntry {
+            path: relative,
+            modified,
+            symbols,
+        });
+    }
+    Ok(files)
+}
+
+fn collect_files(root: &Path, options: &WalkOptions) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut overrides = OverrideBuilder::new(root);
+    for glob in &options.include {
+        overrides.add(glob)?;
+    }
+    for glob in &options.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+
+    let walker = WalkBuilder::new(root)
+        .hidden(true)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILENAME)
+        .overrides(overrides.build()?)
+        .filter_entry(|entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            !(is_dir && SKIPPED_DIRS.contains(&entry.file_name().to_string_lossy().as_ref()))
+        })
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|t| t.is_file()) && !is_binary(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+fn is_binary(path: &Path) -> bool {
+    let mut buffer = [0u8; BINARY_SNIFF_BYTES];
+    match fs::File::open(path).and_then(|mut file| file.read(&mut buffer)) {
+        Ok(read) => buffer[..read].contains(&0),
+        Err(_) => true,
+    }
+}
+
+fn relative_path(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn cache_path(root: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut hasher = DefaultHasher::new();
+    root.hash(&mut hasher);
+    Ok(cache_dir()?.join(format!("{:016x}.yaml", hasher.finish())))
+}
+
+/// A cached repository map on disk.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    /// The path of the cache file.
+    pub path: PathBuf,
+
+    /// The repository the map belongs to, if the cache file could be parsed.
+    pub root: Option<PathBuf>,
+
+    /// The size of the cache file in bytes.
+    pub size: u64,
+
+    /// When the cache file was last written.
+    pub modified: SystemTime,
+}
+
+/// Returns the directory repository maps are cached in.
+pub fn cache_dir() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(crate::config_dir()?.join("repo_maps"))
+}
+
+/// Lists the cached repository maps, oldest first.
+pub fn list_cache() -> Result<Vec<CacheEntry>, Box<dyn Error>> {
+    list_cache_in(&cache_dir()?)
+}
+
+/// Deletes every cached repository map.
+///
+/// # Returns
+///
+/// A result containing the entries that were removed.
+pub fn clear_cache() -> Result<Vec<CacheEntry>, Box<dyn Error>> {
+    prune_cache_in(&cache_dir()?, Some(Duration::ZERO), None)
+}
+
+/// Deletes cached repository maps that are older than `older_than`, then the oldest remaining
+/// maps until the cache is no larger than `max_size` bytes.
+///
+/// # Parameters
+///
+/// - `older_than`: The maximum age of a cached map, if any.
+/// - `max_size`: The maximum total size of the cache in bytes, if any.
+///
+/// # Returns
+///
+/// A result containing the entries that were removed.
+pub fn prune_cache(
+    older_than: Option<Duration>,
+    max_size: Option<u64>,
+) -> Result<Vec<CacheEntry>, Box<dyn Error>> {
+    prune_cache_in(&cache_dir()?, older_than, max_size)
+}
+
+fn list_cache_in(dir: &Path) -> Result<Vec<CacheEntry>, Box<dyn Error>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let root = fs::read_to_string(entry.path())
+            .ok()
+            .and_then(|content| serde_yaml::from_str::<RepoMap>(&content).ok())
+            .map(|map| map.root);
+        entries.push(CacheEntry {
+            path: entry.path(),
+            root,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+
+    entries.sort_by_key(|entry| entry.modified);
+    Ok(entries)
+}
+
+fn prune_cache_in(
+    dir: &Path,
+    older_than: Option<Duration>,
+    max_size: Option<u64>,
+) -> Result<Vec<CacheEntry>, Box<dyn Error>> {
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    let mut kept = Vec::new();
+
+    for entry in list_cache_in(dir)? {
+        let age = now.duration_since(entry.modified).unwrap_or_default();
+        if older_than.is_some_and(|max_age| age >= max_age) {
+            removed.push(entry);
+        } else {
+            kept.push(entry);
+        }
+    }
+
+    if let Some(max_size) = max_size {
+        let mut total: u64 = kept.iter().map(|entry| entry.size).sum();
+        // `kept` is sorted oldest first, so the oldest maps are dropped first.
+        let mut kept_iter = kept.into_iter();
+        while total > max_size {
+            match kept_iter.next() {
+                Some(entry) => {
+                    total -= entry.size;
+                    removed.push(entry);
+                }
+                None => break,
+            }
+        }
+    }
+
+    for entry in &removed {
+        debug!("Removing cached repo map: {}", entry.path.display());
+        fs::remove_file(&entry.path)?;
+    }
+
+    Ok(removed)
+}
+
+fn count_tokens(text: &str) -> usize {
+    tokens::count(text, tokens::DEFAULT_MODEL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_extract_rust_symbols() {
+        let content = r#"
+pub struct Config {
+    pub name: String,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        todo!()
+    }
+}
+
+pub(crate) async fn load(path: &str) -> Result<(), ()> {
+    Ok(())
+}
+"#;
+        let symbols = extract_symbols(Path::new("lib.rs"), content);
+        assert_eq!(
+            symbols,
+            vec![
+                "pub struct Config",
+                "impl Config",
+                "  pub fn new() -> Self",
+                "pub(crate) async fn load(path: &str) -> Result<(), ()>",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_symbols_spanning_lines_but_not_strings() {
+        let content = r##"
+// fn commented_out() {}
+pub fn render(
+    map: &RepoMap,
+    max_tokens: usize,
+) -> String {
+    let example = r#"
+fn not_a_symbol() {}
+"#;
+    fn helper() {}
+    example.to_string()
+}
+
+mod cache {
+    pub trait Store {
+        fn load(&self) -> Option<String>;
+    }
+}
+"##;
+        let symbols = extract_symbols(Path::new("lib.rs"), content);
+        assert_eq!(
+            symbols,
+            vec![
+                "pub fn render(map: &RepoMap, max_tokens: usize) -> String",
+                "mod cache",
+                "  pub trait Store",
+                "    fn load(&self) -> Option<String>",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_symbols_of_other_languages() {
+        let python = r#"
+@dataclass
+class Config:
+    name: str
+
+    def load(self, path):
+        pass
+
+async def main():
+    """
+def not_a_symbol():
+    """
+"#;
+        assert_eq!(
+            extract_symbols(Path::new("app.py"), python),
+            vec!["class Config", "  def load(self, path)", "async def main()"]
+        );
+
+        let typescript = r#"
+export interface Options {
+  verbose: boolean;
+}
+
+export class Client {
+  async send(message: string): Promise<void> {}
+}
+
+const retries = 3;
+"#;
+        assert_eq!(
+            extract_symbols(Path::new("client.ts"), typescript),
+            vec![
+                "export interface Options",
+                "export class Client",
+                "  async send(message: string): Promise<void>",
+                "const retries = 3",
+            ]
+        );
+
+        let go = r#"
+package main
+
+type Server struct {
+	addr string
+}
+
+func (s *Server) Start() error {
+	return nil
+}
+"#;
+        assert_eq!(
+            extract_symbols(Path::new("main.go"), go),
+            vec!["type Server struct", "func (s *Server) Start() error"]
+        );
+    }
+
+    #[test]
+    fn test_extract_symbols_unknown_extension() {
+        let symbols = extract_symbols(Path::new("notes.txt"), "fn main() {}");
+        assert!(symbols.is_empty());
+    }
+
+    #[test]
+    fn test_build_skips_hidden_and_reuses_cache() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::create_dir_all(dir.path().join("target")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {\n}\n").unwrap();
+        fs::write(dir.path().join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(dir.path().join("target/out.rs"), "fn built() {}\n").unwrap();
+
+        let map = RepoMap::build(dir.path(), None, &WalkOptions::default()).unwrap();
+        assert_eq!(map.files.len(), 1);
+        assert_eq!(map.files[0].path, "src/main.rs");
+        assert_eq!(map.files[0].symbols, vec!["fn main()"]);
+
+        // Tamper with the cached symbols; an unchanged mtime means the cached entry wins.
+        let mut cached = map.clone();
+        cached.files[0].symbols = vec!["fn cached".to_string()];
+        let rebuilt = RepoMap::build(dir.path(), Some(&cached), &WalkOptions::default()).unwrap();
+        assert_eq!(rebuilt.files[0].symbols, vec!["fn cached"]);
+    }
+
+    #[test]
+    fn test_build_reports_progress() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.path().join("b.rs"), "fn b() {}\n").unwrap();
+
+        let events = Mutex::new(Vec::new());
+        let sink = |event: ProgressEvent| events.lock().unwrap().push(event);
+        RepoMap::build_with_progress(dir.path(), None, &WalkOptions::default(), &sink).unwrap();
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(
+            events.first(),
+            Some(&ProgressEvent::Started {
+                task: Task::MapRepository,
+                total: Some(2)
+            })
+        );
+        assert_eq!(
+            events.last(),
+            Some(&ProgressEvent::Finished {
+                task: Task::MapRepository
+            })
+        );
+        assert_eq!(events.len(), 4);
+    }
+
+    #[test]
+    fn test_build_respects_ignore_files_and_globs() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join("generated")).unwrap();
+        fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.path().join(IGNORE_FILENAME), "generated/\n").unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "pub fn lib() {\n}\n").unwrap();
+        fs::write(dir.path().join("src/app.py"), "def app():\n    pass\n").unwrap();
+        fs::write(dir.path().join("generated/out.rs"), "fn out() {}\n").unwrap();
+        fs::write(dir.path().join("debug.log"), "noise\n").unwrap();
+        fs::write(dir.path().join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0]).unwrap();
+
+        let paths = |options: &WalkOptions| -> Vec<String> {
+            RepoMap::build(dir.path(), None, options)
+                .unwrap()
+                .files
+                .into_iter()
+                .map(|f| f.path)
+                .collect()
+        };
+
+        assert_eq!(
+            paths(&WalkOptions::default()),
+            vec!["src/app.py", "src/lib.rs"]
+        );
+        assert_eq!(
+            paths(&WalkOptions {
+                include: vec!["*.rs".to_string()],
+                exclude: vec![],
+            }),
+            vec!["src/lib.rs"]
+        );
+        assert_eq!(
+            paths(&WalkOptions {
+                include: vec![],
+                exclude: vec!["*.py".to_string()],
+            }),
+            vec!["src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn test_prune_cache_by_age_and_size() {
+        let dir = tempdir().unwrap();
+        for (name, size) in [("a.yaml", 100), ("b.yaml", 200), ("c.yaml", 300)] {
+            fs::write(dir.path().join(name), vec![b'x'; size]).unwrap();
+            // Keep modification times distinct so the oldest-first ordering is stable.
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let removed = prune_cache_in(dir.path(), Some(Duration::from_secs(3600)), None).unwrap();
+        assert!(removed.is_empty());
+
+        let removed = prune_cache_in(dir.path(), None, Some(450)).unwrap();
+        let names: Vec<_> = removed
+            .iter()
+            .map(|e| e.path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["a.yaml", "b.yaml"]);
+        assert_eq!(list_cache_in(dir.path()).unwrap().len(), 1);
+
+        let removed = prune_cache_in(dir.path(), Some(Duration::ZERO), None).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(list_cache_in(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_render_compressed_drops_symbols_first() {
+        let map = RepoMap {
+            root: PathBuf::from("/repo"),
+            files: vec![FileEntry {
+                path: "src/lib.rs".to_string(),
+                modified: 0,
+                symbols: vec![
+                    "pub fn a_rather_long_function_name(argument: usize)".to_string();
+                    50
+                ],
+            }],
+            symbols_version: SYMBOLS_VERSION,
+        };
+        let compressed = map.render_compressed(10);
+        assert_eq!(compressed, "src/lib.rs\n");
+    }
+}
@@ -0,0 +1,33 @@
+//! This module generates the ids that tie a question to its logs and results.
+//!
+//! Every question asked through `api::ask_for_completion` or over JSON-RPC gets a random UUID v4
+//! id. Everything logged while it is answered is logged in an `ask` span carrying the id, JSON-RPC
+//! results include it as `request_id`, and `aj ask --show-sources` prints it, so the log lines of a
+//! bad answer can be found:
+//!
+//! ```text
+//! ERROR ask{request_id=3f2b8c1e-9d4a-4e7b-a1c2-5d6e7f8a9b0c}: awful_aj::api: Streaming failed: ...
+//! ```
+
+use uuid::Uuid;
+
+/// Returns a new random id in the UUID v4 format, e.g. `3f2b8c1e-9d4a-4e7b-a1c2-5d6e7f8a9b0c`.
+pub fn generate() -> String {
+    Uuid::new_v4().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let id = generate();
+        let groups: Vec<usize> = id.split('-').map(str::len).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(generate(), id);
+    }
+}
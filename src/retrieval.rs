@@ -0,0 +1,575 @@
+//! This module prepares the queries used to retrieve memories from the vector store.
+//!
+//! Questions asked mid-conversation are often poor search queries ("what about the second one?").
+//! With `rag_query_rewrite: true` in the configuration, the model first rewrites the question and
+//! the most recent turns into a standalone search query, which is what gets embedded.
+//!
+//! The rewriting prompt can be customized with a `query_rewrite` template; without one, a built-in
+//! prompt is used.
+//!
+//! With `rag_multi_query` configured, several paraphrases of the query are searched for, and their
+//! rankings are combined with reciprocal-rank fusion. This improves recall for ambiguous questions,
+//! whose best phrasing for retrieval is not known up front.
+//!
+//! By default the `DEFAULT_TOP_K` closest memories are recalled. With `rag_budget_share`, memories
+//! are instead added closest first until the conversation and memories fill that share of the
+//! context window, so short conversations get more memories and long ones get fewer.
+
+use crate::{
+    api,
+    brain::Memory,
+    config::{AwfulJadeConfig, MultiQueryConfig, ParaphraseMethod},
+    duplicates::cosine_similarity,
+    memory_kinds,
+    model_family::ModelDefaults,
+    template::{self, ChatTemplate},
+    tokens,
+    vector_store::VectorStore,
+};
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, Role};
+use std::{collections::HashMap, error::Error};
+use tracing::{debug, error};
+
+/// The name of the template used to rewrite questions into search queries.
+pub const QUERY_REWRITE_TEMPLATE: &str = "query_rewrite";
+
+/// How many of the most recent conversation messages are shown to the rewriting prompt.
+pub const RECENT_TURNS: usize = 4;
+
+/// The most tokens a rewritten query may use.
+const MAX_QUERY_TOKENS: u16 = 64;
+
+/// How many memories are recalled when `rag_budget_share` is not set.
+pub const DEFAULT_TOP_K: usize = 3;
+
+/// How many candidates are considered when memories are selected by token budget.
+pub const MAX_BUDGET_CANDIDATES: usize = 32;
+
+/// The constant that damps the influence of top ranks in reciprocal-rank fusion.
+pub const RRF_K: f32 = 60.0;
+
+/// Words left out of rule-based keyword paraphrases.
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "can", "could", "do", "does", "for", "how", "i", "in", "is", "it",
+    "me", "my", "of", "on", "or", "please", "should", "the", "to", "what", "when", "where",
+    "which", "who", "why", "with", "would", "you",
+];
+
+/// The system prompt used when no `query_rewrite` template exists.
+const DEFAULT_QUERY_REWRITE_PROMPT: &str = "You turn questions into search queries for a semantic memory store. Resolve pronouns and references using the conversation, keep the key entities and topics, and reply with only the query.";
+
+/// Returns the built-in query rewriting template.
+pub fn default_query_rewrite_template() -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: DEFAULT_QUERY_REWRITE_PROMPT.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Builds the messages asking the model to rewrite `question` into a search query.
+///
+/// # Parameters
+///
+/// - `template`: The query rewriting template.
+/// - `question`: The question being asked.
+/// - `recent`: The most recent conversation messages, oldest first.
+pub fn query_rewrite_messages(
+    template: &ChatTemplate,
+    question: &str,
+    recent: &[ChatCompletionRequestMessage],
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut prompt = String::new();
+    if !recent.is_empty() {
+        prompt.push_str("Conversation so far:\n");
+        for message in recent {
+            prompt.push_str(&format!(
+                "{}: {}\n",
+                message.role,
+                message.content.as_deref().unwrap_or_default()
+            ));
+        }
+        prompt.push('\n');
+    }
+    prompt.push_str(&format!("Question: {}\n\nSearch query:", question));
+
+    let mut messages = api::prepare_messages(template).unwrap_or_default();
+    messages.push(ChatCompletionRequestMessage {
+        role: Role::User,
+        content: Some(prompt),
+        name: None,
+        function_call: None,
+    });
+    messages
+}
+
+/// Returns the text to embed when retrieving memories for `question`.
+///
+/// When `rag_query_rewrite` is enabled the model rewrites the question using the recent turns.
+/// If rewriting fails or produces nothing, the question itself is used.
+pub async fn retrieval_query(
+    config: &AwfulJadeConfig,
+    question: &str,
+    recent: &[ChatCompletionRequestMessage],
+) -> String {
+    if !config.rag_query_rewrite {
+        return question.to_string();
+    }
+    match rewrite_query(config, question, recent).await {
+        Ok(query) if !query.is_empty() => {
+            debug!("Rewrote retrieval query: {:?} -> {:?}", question, query);
+            query
+        }
+        Ok(_) => question.to_string(),
+        Err(e) => {
+            error!("Failed to rewrite retrieval query: {}", e);
+            question.to_string()
+        }
+    }
+}
+
+/// Asks the model to rewrite `question` into a standalone search query.
+pub async fn rewrite_query(
+    config: &AwfulJadeConfig,
+    question: &str,
+    recent: &[ChatCompletionRequestMessage],
+) -> Result<String, Box<dyn Error>> {
+    let template = template::load_template(QUERY_REWRITE_TEMPLATE)
+        .await
+        .unwrap_or_else(|_| default_query_rewrite_template());
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(MAX_QUERY_TOKENS)
+        .model(config.model.clone())
+        .stop(template.stop_words(config, &[]))
+        .messages(
+            ModelDefaults::from_config(config)
+                .adapt_messages(query_rewrite_messages(&template, question, recent)),
+        )
+        .build()?;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let query = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(query.trim().trim_matches('"').trim().to_string())
+}
+
+/// Returns the last `RECENT_TURNS` messages that follow the preamble of a conversation.
+///
+/// # Parameters
+///
+/// - `messages`: The conversation, starting with the system prompt and the memory handshake.
+/// - `preamble_len`: How many messages at the start of `messages` belong to the preamble.
+pub fn recent_turns(
+    messages: &[ChatCompletionRequestMessage],
+    preamble_len: usize,
+) -> &[ChatCompletionRequestMessage] {
+    let turns = &messages[preamble_len.min(messages.len())..];
+    &turns[turns.len().saturating_sub(RECENT_TURNS)..]
+}
+
+/// Returns how many neighbours are requested from the vector store for each question.
+pub fn candidate_count(config: &AwfulJadeConfig) -> usize {
+    if config.rag_budget_share.is_some() {
+        MAX_BUDGET_CANDIDATES
+    } else {
+        DEFAULT_TOP_K
+    }
+}
+
+/// Retrieves the memories for a question asked in `conversation`, closest first.
+///
+/// Without `rag_budget_share`, this is `retrieve` with `DEFAULT_TOP_K`. Otherwise, memories are
+/// taken closest first for as long as they fit in the share of the context window that the
+/// conversation (including the question) leaves free. Either way, memories of kinds that
+/// `memory_kinds.recall` leaves out are skipped.
+pub async fn retrieve_within_budget(
+    config: &AwfulJadeConfig,
+    vector_store: &VectorStore,
+    query: &str,
+    conversation: &[ChatCompletionRequestMessage],
+) -> Result<Vec<(usize, f32)>, Box<dyn Error>> {
+    let mut candidates = retrieve(config, vector_store, query, candidate_count(config)).await?;
+    candidates.retain(|&(id, _)| {
+        vector_store
+            .get_content_by_id(id)
+            .is_some_and(|memory| memory_kinds::is_recalled(config, memory))
+    });
+    let Some(share) = config.rag_budget_share else {
+        return Ok(candidates);
+    };
+
+    let budget = ((share * config.context_max_tokens as f32) as usize)
+        .saturating_sub(tokens::count_messages(conversation, &config.model));
+    let selected = select_within_budget(&candidates, budget, |id| {
+        vector_store
+            .get_content_by_id(id)
+            .map(|memory| tokens::count(&memory.to_json().to_string(), &config.model))
+    });
+    debug!(
+        "Selected {} of {} memories within a budget of {} tokens",
+        selected.len(),
+        candidates.len(),
+        budget
+    );
+    Ok(selected)
+}
+
+/// Takes candidates in order while their combined token counts fit in `budget`.
+///
+/// Candidates whose token count is unknown (`None`) are skipped.
+pub fn select_within_budget(
+    candidates: &[(usize, f32)],
+    budget: usize,
+    token_count: impl Fn(usize) -> Option<usize>,
+) -> Vec<(usize, f32)> {
+    let mut used = 0;
+    let mut selected = Vec::new();
+    for &(id, distance) in candidates {
+        let Some(tokens) = token_count(id) else {
+            continue;
+        };
+        if used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        selected.push((id, distance));
+    }
+    selected
+}
+
+/// Retrieves the memories closest to `query`, closest first.
+///
+/// Without `rag_multi_query`, this is a single nearest-neighbour search. Otherwise the query and
+/// its paraphrases are each searched, and the rankings are fused with `reciprocal_rank_fusion`.
+///
+/// # Returns
+///
+/// Up to `top_k` `(id, distance)` pairs. For fused results, the distance is the smallest distance
+/// any of the queries had to the memory.
+pub async fn retrieve(
+    config: &AwfulJadeConfig,
+    vector_store: &VectorStore,
+    query: &str,
+    top_k: usize,
+) -> Result<Vec<(usize, f32)>, Box<dyn Error>> {
+    let queries = match &config.rag_multi_query {
+        Some(multi_query) if multi_query.count > 0 => {
+            let mut queries = vec![query.to_string()];
+            queries.extend(paraphrase(config, multi_query, query).await);
+            queries
+        }
+        _ => vec![query.to_string()],
+    };
+
+    let mut rankings = Vec::with_capacity(queries.len());
+    for query in &queries {
+        let vector = vector_store.embed_text_to_vector(query).await?;
+        rankings.push(vector_store.search_with_scores(&vector, top_k)?);
+    }
+    if rankings.len() == 1 {
+        return Ok(rankings.remove(0));
+    }
+
+    debug!("Fusing retrieval results for queries: {:?}", queries);
+    let mut distances: HashMap<usize, f32> = HashMap::new();
+    for &(id, distance) in rankings.iter().flatten() {
+        let best = distances.entry(id).or_insert(distance);
+        *best = best.min(distance);
+    }
+    Ok(reciprocal_rank_fusion(&rankings)
+        .into_iter()
+        .take(top_k)
+        .map(|(id, _score)| (id, distances[&id]))
+        .collect())
+}
+
+/// Scores how important `memories` are to a conversation with `system_prompt`, for
+/// `EvictionPolicy::Importance`: the cosine similarity of each memory's embedding to the system
+/// prompt's. The system prompt and the memories are embedded in one call.
+///
+/// # Returns
+///
+/// A `(content, similarity)` pair for each memory, for `Brain::add_importance`.
+pub async fn importance(
+    vector_store: &VectorStore,
+    system_prompt: &str,
+    memories: &[Memory],
+) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
+    if memories.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut texts = vec![system_prompt.to_string()];
+    texts.extend(memories.iter().map(|memory| memory.content().to_string()));
+    let vectors = vector_store.embedder().embed(&texts).await?;
+    let (prompt, vectors) = vectors
+        .split_first()
+        .ok_or("The embedder returned no vectors")?;
+    Ok(memories
+        .iter()
+        .zip(vectors)
+        .map(|(memory, vector)| {
+            (
+                memory.content().to_string(),
+                cosine_similarity(prompt, vector),
+            )
+        })
+        .collect())
+}
+
+/// Combines several rankings of `(id, distance)` pairs, each closest first.
+///
+/// Each id scores `1 / (RRF_K + rank)` in every ranking it appears in, with ranks starting at 1.
+///
+/// # Returns
+///
+/// Every id with its fused score, highest score first. Ties keep the order ids were first seen in.
+pub fn reciprocal_rank_fusion(rankings: &[Vec<(usize, f32)>]) -> Vec<(usize, f32)> {
+    let mut fused: Vec<(usize, f32)> = Vec::new();
+    for ranking in rankings {
+        for (rank, &(id, _distance)) in ranking.iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            match fused.iter_mut().find(|(fused_id, _)| *fused_id == id) {
+                Some((_, total)) => *total += score,
+                None => fused.push((id, score)),
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused
+}
+
+/// Generates up to `multi_query.count` paraphrases of `query`, excluding the query itself.
+async fn paraphrase(
+    config: &AwfulJadeConfig,
+    multi_query: &MultiQueryConfig,
+    query: &str,
+) -> Vec<String> {
+    let paraphrases = match multi_query.method {
+        ParaphraseMethod::Rules => rule_based_paraphrases(query),
+        ParaphraseMethod::Llm => match llm_paraphrases(config, query, multi_query.count).await {
+            Ok(paraphrases) => paraphrases,
+            Err(e) => {
+                error!(
+                    "Failed to generate paraphrases, falling back to rules: {}",
+                    e
+                );
+                rule_based_paraphrases(query)
+            }
+        },
+    };
+
+    let mut unique: Vec<String> = Vec::new();
+    for paraphrase in paraphrases {
+        let paraphrase = paraphrase.trim().to_string();
+        if !paraphrase.is_empty()
+            && !paraphrase.eq_ignore_ascii_case(query.trim())
+            && !unique.contains(&paraphrase)
+        {
+            unique.push(paraphrase);
+        }
+    }
+    unique.truncate(multi_query.count);
+    unique
+}
+
+/// Paraphrases a query without a model: its keywords, and each of its sentences on their own.
+pub fn rule_based_paraphrases(query: &str) -> Vec<String> {
+    let keywords: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        .filter(|word| !word.is_empty())
+        .filter(|word| !STOP_WORDS.contains(&word.to_lowercase().as_str()))
+        .map(|word| word.to_string())
+        .collect();
+
+    let mut paraphrases = vec![keywords.join(" ")];
+    let sentences = VectorStore::tokenize_sentences(query);
+    if sentences.len() > 1 {
+        paraphrases.extend(sentences);
+    }
+    paraphrases
+}
+
+/// Asks the model for `count` alternative phrasings of `query`, one per line.
+async fn llm_paraphrases(
+    config: &AwfulJadeConfig,
+    query: &str,
+    count: usize,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let messages = vec![
+        ChatCompletionRequestMessage {
+            role: Role::System,
+            content: Some("You write alternative search queries for a semantic memory store. Reply with one query per line and nothing else.".to_string()),
+            name: None,
+            function_call: None,
+        },
+        ChatCompletionRequestMessage {
+            role: Role::User,
+            content: Some(format!(
+                "Write {} different search queries for: {}",
+                count, query
+            )),
+            name: None,
+            function_call: None,
+        },
+    ];
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(MAX_QUERY_TOKENS * count as u16)
+        .model(config.model.clone())
+        .stop(config.stop_words.clone())
+        .messages(ModelDefaults::from_config(config).adapt_messages(messages))
+        .build()?;
+
+    let response = api::create_client(config)?.chat().create(request).await?;
+    let content = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .unwrap_or_default();
+
+    Ok(content
+        .lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || c == '.' || c == '-' || c == '*'
+                })
+                .trim()
+                .trim_matches('"')
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::message;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query_rewrite_messages() {
+        let recent = vec![
+            message(Role::User, "What crates parse YAML?"),
+            message(Role::Assistant, "serde_yaml is the usual choice."),
+        ];
+        let messages = query_rewrite_messages(
+            &default_query_rewrite_template(),
+            "Is it maintained?",
+            &recent,
+        );
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(
+            messages[1].content.as_deref(),
+            Some("Conversation so far:\nuser: What crates parse YAML?\nassistant: serde_yaml is the usual choice.\n\nQuestion: Is it maintained?\n\nSearch query:")
+        );
+    }
+
+    #[test]
+    fn test_recent_turns() {
+        let messages: Vec<_> = (0..9)
+            .map(|i| message(Role::User, &i.to_string()))
+            .collect();
+        let recent = recent_turns(&messages, 3);
+        let contents: Vec<_> = recent
+            .iter()
+            .map(|m| m.content.as_deref().unwrap())
+            .collect();
+        assert_eq!(contents, vec!["5", "6", "7", "8"]);
+        assert!(recent_turns(&messages[..2], 3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retrieval_query() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "chatcmpl-1234567890",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "mock_model",
+                    "choices": [
+                        {
+                            "message": { "role": "assistant", "content": "\"serde_yaml maintenance status\"" },
+                            "finish_reason": "stop",
+                            "index": 0
+                        }
+                    ]
+                }));
+        });
+
+        let mut config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            model: "mock_model".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            retrieval_query(&config, "Is it maintained?", &[]).await,
+            "Is it maintained?"
+        );
+        mock.assert_hits(0);
+
+        config.rag_query_rewrite = true;
+        assert_eq!(
+            retrieval_query(&config, "Is it maintained?", &[]).await,
+            "serde_yaml maintenance status"
+        );
+        mock.assert();
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let rankings = vec![
+            vec![(1, 0.1), (2, 0.2), (3, 0.3)],
+            vec![(2, 0.15), (4, 0.25), (1, 0.4)],
+        ];
+        let fused: Vec<usize> = reciprocal_rank_fusion(&rankings)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        // 2 ranks 2nd and 1st, beating 1, which ranks 1st and 3rd.
+        assert_eq!(fused, vec![2, 1, 4, 3]);
+    }
+
+    #[test]
+    fn test_rule_based_paraphrases() {
+        assert_eq!(
+            rule_based_paraphrases("How do I read a file in Rust?"),
+            vec!["read file Rust".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_select_within_budget() {
+        let candidates = vec![(1, 0.1), (2, 0.2), (3, 0.3), (4, 0.4)];
+        let tokens = |id: usize| match id {
+            2 => None,
+            id => Some(id * 10),
+        };
+
+        let ids = |selected: Vec<(usize, f32)>| -> Vec<usize> {
+            selected.into_iter().map(|(id, _)| id).collect()
+        };
+        assert_eq!(
+            ids(select_within_budget(&candidates, 45, tokens)),
+            vec![1, 3]
+        );
+        assert_eq!(
+            ids(select_within_budget(&candidates, 80, tokens)),
+            vec![1, 3, 4]
+        );
+        assert!(select_within_budget(&candidates, 5, tokens).is_empty());
+    }
+}
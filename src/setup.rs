@@ -0,0 +1,190 @@
+//! This module finds the backends `aj init` can set aj up with.
+//!
+//! Local OpenAI-compatible servers are detected by probing their usual ports on localhost:
+//!
+//! | Backend   | `api_base`                  |
+//! |-----------|-----------------------------|
+//! | Ollama    | `http://localhost:11434/v1` |
+//! | LM Studio | `http://localhost:1234/v1`  |
+//!
+//! A backend is detected when its `/models` endpoint answers, and the models it lists are offered
+//! to pick from. Once the configuration is written, `check_connectivity` makes sure its backend
+//! answers and serves its model.
+//!
+//! `aj models` lists the models the configured backend serves, marking the configured one:
+//!
+//! ```text
+//!   llama3:8b
+//! * qwen2.5:7b
+//! ```
+
+use crate::config::AwfulJadeConfig;
+use serde::Deserialize;
+use std::{error::Error, time::Duration};
+use tracing::debug;
+
+/// The local backends that are probed, by name and port.
+pub const KNOWN_BACKENDS: [(&str, u16); 2] = [("Ollama", 11434), ("LM Studio", 1234)];
+
+/// How long a probed backend has to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long the configured backend has to answer when checking connectivity.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A backend that answered, with the models it serves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectedBackend {
+    pub name: &'static str,
+    pub api_base: String,
+    pub models: Vec<String>,
+}
+
+/// The part of a `/models` response aj reads. Backends disagree on the other fields of a model.
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// Returns the `api_base` of a local backend listening on `port`.
+pub fn local_api_base(port: u16) -> String {
+    format!("http://localhost:{}/v1", port)
+}
+
+/// Returns the ids of the models the backend at `api_base` serves, waiting at most `timeout`.
+pub async fn list_models(
+    api_base: &str,
+    api_key: &str,
+    timeout: Duration,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let url = format!("{}/models", api_base.trim_end_matches('/'));
+    let body = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(&url)
+        .bearer_auth(api_key)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let list: ModelList = serde_json::from_str(&body)?;
+    Ok(list.data.into_iter().map(|model| model.id).collect())
+}
+
+/// Probes the `KNOWN_BACKENDS`, and returns the ones that answered.
+pub async fn detect_backends() -> Vec<DetectedBackend> {
+    let mut detected = Vec::new();
+    for (name, port) in KNOWN_BACKENDS {
+        let api_base = local_api_base(port);
+        match list_models(&api_base, "", PROBE_TIMEOUT).await {
+            Ok(models) => detected.push(DetectedBackend {
+                name,
+                api_base,
+                models,
+            }),
+            Err(e) => debug!("No {} at {}: {}", name, api_base, e),
+        }
+    }
+    detected
+}
+
+/// Returns the ids of the models the backend of `config` serves.
+pub async fn served_models(config: &AwfulJadeConfig) -> Result<Vec<String>, Box<dyn Error>> {
+    list_models(&config.api_base, &config.api_key, CHECK_TIMEOUT)
+        .await
+        .map_err(|e| format!("Failed to reach {}: {}", config.api_base, e).into())
+}
+
+/// Returns an error saying that the backend of `config`, serving `models`, does not serve its
+/// model, or `Ok(())` when it does.
+pub fn check_model(config: &AwfulJadeConfig, models: &[String]) -> Result<(), Box<dyn Error>> {
+    if !models.contains(&config.model) {
+        return Err(format!(
+            "{} does not serve the model `{}`; it serves: {}",
+            config.api_base,
+            config.model,
+            models.join(", ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Renders `models` one per line, marking `configured` with a `*`.
+pub fn format_models(models: &[String], configured: &str) -> String {
+    models
+        .iter()
+        .map(|model| {
+            let marker = if model == configured { '*' } else { ' ' };
+            format!("{} {}", marker, model)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Checks that the backend of `config` answers and serves its model.
+///
+/// # Returns
+///
+/// - `Ok(())`: The backend lists the model.
+/// - `Err(Box<dyn Error>)`: The backend could not be reached, or does not list the model.
+pub async fn check_connectivity(config: &AwfulJadeConfig) -> Result<(), Box<dyn Error>> {
+    check_model(config, &served_models(config).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_check_connectivity() {
+        let server = MockServer::start();
+        // LM Studio leaves out `created`, which async-openai's `Model` requires.
+        server.mock(|when, then| {
+            when.method(GET).path("/v1/models");
+            then.status(200).json_body(json!({
+                "object": "list",
+                "data": [
+                    { "id": "llama3:8b", "object": "model", "owned_by": "library" },
+                    { "id": "qwen2.5:7b", "object": "model", "owned_by": "library" },
+                ]
+            }));
+        });
+        let config = AwfulJadeConfig {
+            api_base: server.url("/v1"),
+            model: "qwen2.5:7b".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            list_models(&config.api_base, "", PROBE_TIMEOUT)
+                .await
+                .unwrap(),
+            vec!["llama3:8b", "qwen2.5:7b"]
+        );
+        assert!(check_connectivity(&config).await.is_ok());
+
+        let config = AwfulJadeConfig {
+            model: "mistral:7b".to_string(),
+            ..config
+        };
+        let error = check_connectivity(&config).await.unwrap_err().to_string();
+        assert!(error.ends_with("it serves: llama3:8b, qwen2.5:7b"));
+    }
+
+    #[test]
+    fn test_format_models() {
+        let models = vec!["llama3:8b".to_string(), "qwen2.5:7b".to_string()];
+        assert_eq!(
+            format_models(&models, "qwen2.5:7b"),
+            "  llama3:8b\n* qwen2.5:7b"
+        );
+    }
+}
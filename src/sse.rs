@@ -0,0 +1,166 @@
+//! This module relays an answer to a single client as server-sent events.
+//!
+//! `aj ask --sse-port 9999` waits for one HTTP request on `127.0.0.1:9999`, whatever its path,
+//! streams the answer to it instead of printing it, and closes the connection. Each piece of the
+//! answer is a `data` event, and the stream ends with a `done` event, or an `error` event when the
+//! question failed:
+//!
+//! ```text
+//! data: {"content":"A monad is"}
+//!
+//! data: {"content":" a monoid in the category of endofunctors."}
+//!
+//! event: done
+//! data: {"request_id":"3f2b8c1e-...","finish_reason":"stop"}
+//! ```
+//!
+//! Any origin may connect, so a local web page can listen with
+//! `new EventSource("http://127.0.0.1:9999/")`.
+
+use crate::api::Completion;
+use serde_json::{json, Value as JsonValue};
+use std::{error::Error, io};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+/// The response headers starting the event stream.
+const HEADERS: &str = "HTTP/1.1 200 OK\r\n\
+                       Content-Type: text/event-stream\r\n\
+                       Cache-Control: no-cache\r\n\
+                       Connection: close\r\n\
+                       Access-Control-Allow-Origin: *\r\n\r\n";
+
+/// The most bytes of the client's request that are read before the stream starts.
+const MAX_REQUEST_BYTES: usize = 16 * 1024;
+
+/// Formats a server-sent event named `name`, or an unnamed `message` event, carrying `data`.
+/// Compact JSON has no line breaks, so `data` always fits on the one `data:` line.
+pub fn event(name: Option<&str>, data: &JsonValue) -> String {
+    match name {
+        Some(name) => format!("event: {}\ndata: {}\n\n", name, data),
+        None => format!("data: {}\n\n", data),
+    }
+}
+
+/// An event stream to one client. Events are written in the background, so `send` never waits
+/// for the network.
+pub struct SseRelay {
+    sender: UnboundedSender<String>,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+impl SseRelay {
+    /// Waits for a client on `127.0.0.1:port` and starts the event stream.
+    pub async fn accept(port: u16) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        eprintln!("Waiting for a client on http://{}/", listener.local_addr()?);
+        Self::accept_from(&listener).await
+    }
+
+    /// Waits for a client on `listener` and starts the event stream.
+    pub async fn accept_from(listener: &TcpListener) -> Result<Self, Box<dyn Error>> {
+        let (mut stream, peer) = listener.accept().await?;
+        debug!("Relaying the answer to {}", peer);
+        read_request(&mut stream).await?;
+        stream.write_all(HEADERS.as_bytes()).await?;
+
+        let (sender, mut receiver) = unbounded_channel::<String>();
+        let writer = tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                stream.write_all(event.as_bytes()).await?;
+                stream.flush().await?;
+            }
+            stream.shutdown().await
+        });
+        Ok(Self { sender, writer })
+    }
+
+    /// Sends a piece of the answer.
+    pub fn send(&self, content: &str) {
+        // A client that went away is reported when the relay is closed
+        let _ = self
+            .sender
+            .send(event(None, &json!({ "content": content })));
+    }
+
+    /// Ends the stream with a `done` event describing `completion`.
+    pub async fn finish(self, completion: &Completion) -> Result<(), Box<dyn Error>> {
+        let done = json!({
+            "request_id": completion.request_id,
+            "finish_reason": completion.finish_reason,
+        });
+        self.close(event(Some("done"), &done)).await
+    }
+
+    /// Ends the stream with an `error` event carrying the message of `error`.
+    pub async fn fail(self, error: &dyn Error) -> Result<(), Box<dyn Error>> {
+        let message = json!({ "message": error.to_string() });
+        self.close(event(Some("error"), &message)).await
+    }
+
+    /// Sends `last`, then waits for every event to be written and closes the connection.
+    async fn close(self, last: String) -> Result<(), Box<dyn Error>> {
+        let Self { sender, writer } = self;
+        let _ = sender.send(last);
+        drop(sender);
+        Ok(writer.await??)
+    }
+}
+
+/// Reads the client's request up to the blank line ending its headers. The request itself is not
+/// looked at: every request gets the answer.
+async fn read_request(stream: &mut TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_BYTES
+    {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_relay_streams_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+
+        let relay = SseRelay::accept_from(&listener).await.unwrap();
+        relay.send("Hello,\n");
+        relay.send("world.");
+        let error: Box<dyn Error> = "The backend went away".into();
+        relay.fail(error.as_ref()).await.unwrap();
+
+        let response = client.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/event-stream\r\n"));
+        assert!(response.ends_with(
+            "\r\n\r\ndata: {\"content\":\"Hello,\\n\"}\n\n\
+             data: {\"content\":\"world.\"}\n\n\
+             event: error\ndata: {\"message\":\"The backend went away\"}\n\n"
+        ));
+    }
+}
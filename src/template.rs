@@ -8,16 +8,27 @@
 //! Loading a chat template from a file:
 //!
 //! ```no_run
-//! use awful_jade::template::{ChatTemplate, load_template};
+//! use awful_aj::template::{ChatTemplate, load_template};
 //!
+//! # async fn example() {
 //! let template_name = "example";
 //! let template: ChatTemplate = load_template(template_name).await.unwrap();
 //! println!("{:?}", template);
+//! # }
 //! ```
 
-use async_openai::types::ChatCompletionRequestMessage;
+use crate::{
+    brain::EvictionPolicy, config::AwfulJadeConfig, model_family::ModelDefaults,
+    postprocess::OutputFilter, tokens,
+};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
 use tracing::debug;
 
 /// Represents a chat template.
@@ -28,13 +39,318 @@ use tracing::debug;
 /// ## Fields
 /// - `system_prompt`: A `String` that defines the assistant's behavior.
 /// - `messages`: A `Vec<ChatCompletionRequestMessage>` that contains the messages constituting the conversation.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// - `acknowledgment`, `memory_framing`, `context_framing`, `preamble_role`: Optional overrides for the
+///   handshake messages that introduce memories and supplementary context to the model.
+/// - `postprocess`: Filters applied to the model's output.
+/// - `use_memory`, `use_rag`: Whether memories and supplementary context are sent at all,
+///   whatever the command line asks for.
+/// - `knowledge`: Reference documents searched for every question asked with the template.
+/// - `prompts`: The variables the template is rendered with, and the questions that ask for them.
+/// - `local_only`: Whether questions asked with the template may only be sent to local backends.
+/// - `eviction_policy`: Which memories are evicted first, overriding the configuration's.
+/// - `optional_messages`: The messages marked `optional: true` in the template file, such as
+///   few-shot examples, which are left out when the context window is tight.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ChatTemplate {
     /// The system prompt that guides the assistant's behavior.
     pub system_prompt: String,
 
     /// A list of messages that are part of the chat template.
     pub messages: Vec<ChatCompletionRequestMessage>,
+
+    /// The fake assistant reply that follows each handshake message. Defaults to `Ok.`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledgment: Option<String>,
+
+    /// The text introducing the serialized memories. `{acknowledgment}` is replaced with the acknowledgment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_framing: Option<String>,
+
+    /// The text introducing supplementary context such as repository maps or editor selections.
+    /// `{acknowledgment}` is replaced with the acknowledgment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_framing: Option<String>,
+
+    /// The language responses are written in (`auto`, a language code, or a name). Overrides the configuration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub respond_in: Option<String>,
+
+    /// The role that sends the handshake messages. Defaults to `user`; some models handle `system` better.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preamble_role: Option<Role>,
+
+    /// Filters applied to the model's output before it is returned or kept in the conversation,
+    /// e.g. `trim_code_fences`, `extract_json`, or `regex:'<pattern>'`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub postprocess: Vec<String>,
+
+    /// Stop sequences for this template's prompt format, added to the configuration's.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_words: Vec<String>,
+
+    /// Whether questions asked with this template recall and archive memories. Defaults to
+    /// `true`; strict extractors set it to `false` so memories can't leak into their output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_memory: Option<bool>,
+
+    /// Whether supplementary context, such as a repository map or editor context, is sent with
+    /// questions asked with this template. Defaults to `true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_rag: Option<bool>,
+
+    /// Markdown files in the `knowledge` directory whose excerpts closest to each question are
+    /// appended to the system prompt; see the `knowledge` module.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub knowledge: Vec<String>,
+
+    /// Variables written `{{name}}` in the system prompt and messages, asked for when they are not
+    /// given with `--var`; see `render`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prompts: Vec<TemplatePrompt>,
+
+    /// Whether questions asked with this template may only be sent to an `api_base` on the
+    /// config's `local_hosts`; see the `privacy` module. Defaults to `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_only: Option<bool>,
+
+    /// Which memories the brain evicts first, overriding the config's `eviction_policy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eviction_policy: Option<EvictionPolicy>,
+
+    /// The indices in `messages` of the messages marked `optional: true`; see `messages_within`.
+    /// Set by `parse_template`, as the flag is not part of a message.
+    #[serde(skip)]
+    pub optional_messages: Vec<usize>,
+}
+
+/// A variable of a template, and the question asked for its value.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TemplatePrompt {
+    /// The name of the variable, written `{{var}}` in the template.
+    pub var: String,
+
+    /// The question asked for the variable's value, e.g. `Which language?`.
+    pub question: String,
+}
+
+/// The flags of a template file's messages, read next to the messages themselves.
+#[derive(Deserialize)]
+struct MessageFlags {
+    #[serde(default)]
+    optional: bool,
+}
+
+#[derive(Deserialize)]
+struct TemplateFlags {
+    #[serde(default)]
+    messages: Vec<MessageFlags>,
+}
+
+/// The most stop sequences the OpenAI API accepts in one request.
+pub const MAX_STOP_WORDS: usize = 4;
+
+/// The acknowledgment used when a template does not define one.
+pub const DEFAULT_ACKNOWLEDGMENT: &str = "Ok.";
+
+/// The memory framing used when a template does not define one.
+pub const DEFAULT_MEMORY_FRAMING: &str = "Below is a JSON representation of our conversation leading up to this point. Please only respond to this message with \"{acknowledgment}\":\n";
+
+/// The context framing used when a template does not define one.
+pub const DEFAULT_CONTEXT_FRAMING: &str = "Below is supplementary documentation for my next questions. Please only respond to this message with \"{acknowledgment}\":\n";
+
+impl ChatTemplate {
+    /// Returns the response language, preferring the template's setting over the configuration's.
+    pub fn respond_in<'a>(&'a self, config: &'a AwfulJadeConfig) -> Option<&'a str> {
+        self.respond_in.as_deref().or(config.respond_in.as_deref())
+    }
+
+    /// Returns the eviction policy of the brain, preferring the template's setting over the
+    /// configuration's.
+    pub fn eviction_policy(&self, config: &AwfulJadeConfig) -> EvictionPolicy {
+        self.eviction_policy.unwrap_or(config.eviction_policy)
+    }
+
+    /// Returns the stop words for a request: `extra` (e.g. from `--stop`), then the template's, then
+    /// the configuration's, then those of the model's family, without duplicates and capped at
+    /// `MAX_STOP_WORDS`.
+    pub fn stop_words(&self, config: &AwfulJadeConfig, extra: &[String]) -> Vec<String> {
+        let family_stop_words = ModelDefaults::from_config(config).stop_words;
+        let mut stop_words: Vec<String> = Vec::new();
+        for word in extra
+            .iter()
+            .chain(&self.stop_words)
+            .chain(&config.stop_words)
+            .chain(&family_stop_words)
+        {
+            if !word.is_empty() && !stop_words.contains(word) {
+                stop_words.push(word.clone());
+            }
+        }
+        if stop_words.len() > MAX_STOP_WORDS {
+            debug!(
+                "Dropping stop words beyond the first {}: {:?}",
+                MAX_STOP_WORDS,
+                &stop_words[MAX_STOP_WORDS..]
+            );
+            stop_words.truncate(MAX_STOP_WORDS);
+        }
+        stop_words
+    }
+
+    /// Returns whether memories are recalled and archived with this template, defaulting to `true`.
+    pub fn uses_memory(&self) -> bool {
+        self.use_memory.unwrap_or(true)
+    }
+
+    /// Returns whether questions asked with this template may only be sent to local backends,
+    /// defaulting to `false`.
+    pub fn is_local_only(&self) -> bool {
+        self.local_only.unwrap_or(false)
+    }
+
+    /// Returns whether supplementary context is sent with this template, defaulting to `true`.
+    pub fn uses_rag(&self) -> bool {
+        self.use_rag.unwrap_or(true)
+    }
+
+    /// Returns the template's messages, leaving out optional messages, the last one first, until
+    /// they take no more than `max_tokens` for `model`. Messages that are not optional are always
+    /// kept, even when they alone take more.
+    pub fn messages_within(
+        &self,
+        max_tokens: usize,
+        model: &str,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let mut kept = vec![true; self.messages.len()];
+        let mut droppable = self
+            .optional_messages
+            .iter()
+            .rev()
+            .filter(|&&index| index < self.messages.len());
+        loop {
+            let messages: Vec<ChatCompletionRequestMessage> = self
+                .messages
+                .iter()
+                .zip(&kept)
+                .filter(|(_, kept)| **kept)
+                .map(|(message, _)| message.clone())
+                .collect();
+            if tokens::count_messages(&messages, model) <= max_tokens {
+                return messages;
+            }
+            match droppable.next() {
+                Some(&index) => {
+                    debug!("Leaving out optional template message {}", index);
+                    kept[index] = false;
+                }
+                None => return messages,
+            }
+        }
+    }
+
+    /// Puts `messages` in front of the template's messages, e.g. a handshake carrying context,
+    /// keeping the optional messages marked.
+    pub fn prepend_messages(&mut self, messages: Vec<ChatCompletionRequestMessage>) {
+        let count = messages.len();
+        self.messages.splice(0..0, messages);
+        for index in &mut self.optional_messages {
+            *index += count;
+        }
+    }
+
+    /// Parses the template's output filters.
+    pub fn output_filters(&self) -> Result<Vec<OutputFilter>, Box<dyn Error>> {
+        self.postprocess
+            .iter()
+            .map(|filter| OutputFilter::parse(filter))
+            .collect()
+    }
+
+    /// Returns the prompts of the variables missing from `vars`.
+    pub fn missing_vars(&self, vars: &BTreeMap<String, String>) -> Vec<&TemplatePrompt> {
+        self.prompts
+            .iter()
+            .filter(|prompt| !vars.contains_key(&prompt.var))
+            .collect()
+    }
+
+    /// Returns the template with each `{{name}}` in its system prompt and messages replaced by the
+    /// value of `name` in `vars`.
+    ///
+    /// ## Returns
+    /// - `Ok(ChatTemplate)`: The rendered template.
+    /// - `Err(Box<dyn Error>)`: A variable in `prompts` has no value in `vars`.
+    pub fn render(&self, vars: &BTreeMap<String, String>) -> Result<ChatTemplate, Box<dyn Error>> {
+        if let Some(prompt) = self.missing_vars(vars).first() {
+            return Err(format!(
+                "The template asks for `{}` ({}); pass it with `--var {}=<value>`",
+                prompt.var, prompt.question, prompt.var
+            )
+            .into());
+        }
+        let substitute = |text: &str| {
+            vars.iter().fold(text.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{{{}}}}}", name), value)
+            })
+        };
+        let mut rendered = self.clone();
+        rendered.system_prompt = substitute(&self.system_prompt);
+        for message in &mut rendered.messages {
+            if let Some(content) = &message.content {
+                message.content = Some(substitute(content));
+            }
+        }
+        Ok(rendered)
+    }
+
+    /// Returns the acknowledgment the assistant gives to handshake messages.
+    pub fn acknowledgment(&self) -> &str {
+        self.acknowledgment
+            .as_deref()
+            .unwrap_or(DEFAULT_ACKNOWLEDGMENT)
+    }
+
+    /// Returns the text introducing the serialized memories, with the acknowledgment filled in.
+    pub fn memory_framing(&self) -> String {
+        self.memory_framing
+            .as_deref()
+            .unwrap_or(DEFAULT_MEMORY_FRAMING)
+            .replace("{acknowledgment}", self.acknowledgment())
+    }
+
+    /// Returns the text introducing supplementary context, with the acknowledgment filled in.
+    pub fn context_framing(&self) -> String {
+        self.context_framing
+            .as_deref()
+            .unwrap_or(DEFAULT_CONTEXT_FRAMING)
+            .replace("{acknowledgment}", self.acknowledgment())
+    }
+
+    /// Builds a handshake: a message containing `framing` followed by `content`, and the
+    /// assistant's acknowledgment of it.
+    ///
+    /// ## Parameters
+    /// - `framing`: The text introducing the content, usually from `memory_framing` or `context_framing`.
+    /// - `content`: The content being handed to the model.
+    ///
+    /// ## Returns
+    /// - `Vec<ChatCompletionRequestMessage>`: The handshake and acknowledgment messages.
+    pub fn handshake(&self, framing: &str, content: &str) -> Vec<ChatCompletionRequestMessage> {
+        vec![
+            ChatCompletionRequestMessage {
+                role: self.preamble_role.clone().unwrap_or(Role::User),
+                content: Some(format!("{}{}", framing, content)),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionRequestMessage {
+                role: Role::Assistant,
+                content: Some(self.acknowledgment().to_string()),
+                name: None,
+                function_call: None,
+            },
+        ]
+    }
 }
 
 /// Loads a chat template from a file.
@@ -52,8 +368,7 @@ pub struct ChatTemplate {
 /// ## Examples
 ///
 /// ```no_run
-/// use awful_jade::template::load_template;
-/// use tokio;
+/// use awful_aj::template::load_template;
 ///
 /// #[tokio::main]
 /// async fn main() {
@@ -65,31 +380,64 @@ pub struct ChatTemplate {
 /// }
 /// ```
 pub async fn load_template(name: &str) -> Result<ChatTemplate, Box<dyn Error>> {
-    let path = format!("templates/{}.yaml", name);
-    let config_dir = crate::config_dir()?.join(&path);
+    load_template_from(&templates_dir()?, name).await
+}
+
+/// Loads the template named `name` from `templates_dir`, like `load_template`.
+pub async fn load_template_from(
+    templates_dir: &Path,
+    name: &str,
+) -> Result<ChatTemplate, Box<dyn Error>> {
+    let path = templates_dir.join(format!("{}.yaml", name));
 
-    debug!("Loading template: {}", config_dir.display());
+    debug!("Loading template: {}", path.display());
 
-    let content = fs::read_to_string(path)?;
-    let template: ChatTemplate = serde_yaml::from_str(&content)?;
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read template {}: {}", path.display(), e))?;
+    parse_template(&content)
+}
+
+/// Returns the templates directory, `templates` in the config directory, where `aj init` writes
+/// the starter templates and `aj template install` installs them.
+pub fn templates_dir() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(crate::config_dir()?.join("templates"))
+}
+
+/// Parses a chat template from YAML, noting which of its messages are marked `optional: true`.
+pub fn parse_template(content: &str) -> Result<ChatTemplate, Box<dyn Error>> {
+    let mut template: ChatTemplate = serde_yaml::from_str(content)?;
+    let flags: TemplateFlags = serde_yaml::from_str(content)?;
+    template.optional_messages = flags
+        .messages
+        .iter()
+        .enumerate()
+        .filter(|(_, flags)| flags.optional)
+        .map(|(index, _)| index)
+        .collect();
 
     Ok(template)
 }
 
+/// Parses a `--var` argument, `name=value`, into the variable's name and value.
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("`{}` is not of the form name=value", arg)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{io::Write, path::Path};
-    use tempfile::NamedTempFile;
+    use tempfile::tempdir;
     use tokio;
 
     #[tokio::test]
     async fn test_load_template_valid_file() {
-        // Ensure the templates directory exists
-        let templates_dir = Path::new("templates");
-        if !templates_dir.exists() {
-            fs::create_dir(templates_dir).expect("Failed to create templates directory");
-        }
+        let dir = tempdir().unwrap();
+        let templates_dir = dir.path();
 
         // Create a temporary file within the templates directory
         let file_content = r#"
@@ -104,10 +452,7 @@ mod tests {
         fs::write(&file_path, file_content).expect("Unable to write to temporary file");
 
         // Attempt to load the template
-        let template = load_template(file_name).await;
-
-        // Clean up the temporary file
-        fs::remove_file(file_path).expect("Unable to delete temporary file");
+        let template = load_template_from(templates_dir, file_name).await;
 
         assert!(template.is_ok(), "Failed to load valid template");
     }
@@ -124,13 +469,166 @@ mod tests {
     #[tokio::test]
     async fn test_load_template_invalid_format() {
         // Create a temporary file with an invalid template format.
-        let mut temp_file = NamedTempFile::new().unwrap();
-        writeln!(temp_file, r#"invalid: template: format"#).unwrap();
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("invalid.yaml"),
+            "invalid: template: format\n",
+        )
+        .unwrap();
 
         // Try to load the template from the temporary file.
-        let template = load_template(temp_file.path().to_str().unwrap()).await;
+        let template = load_template_from(dir.path(), "invalid").await;
 
         // Assert that an error occurred due to the invalid format.
         assert!(template.is_err());
     }
+
+    #[test]
+    fn test_handshake_defaults() {
+        let template = ChatTemplate::default();
+        let messages = template.handshake(&template.memory_framing(), "{}");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, Role::User);
+        assert!(messages[0]
+            .content
+            .as_ref()
+            .unwrap()
+            .contains("respond to this message with \"Ok.\""));
+        assert_eq!(messages[1].content.as_deref(), Some("Ok."));
+    }
+
+    #[test]
+    fn test_handshake_overrides() {
+        let template: ChatTemplate = serde_yaml::from_str(
+            r#"
+system_prompt: "You are a helpful assistant."
+messages: []
+acknowledgment: "Understood."
+memory_framing: "Memories follow, reply {acknowledgment}\n"
+preamble_role: system
+"#,
+        )
+        .unwrap();
+        let messages = template.handshake(&template.memory_framing(), "[]");
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(
+            messages[0].content.as_deref(),
+            Some("Memories follow, reply Understood.\n[]")
+        );
+        assert_eq!(messages[1].content.as_deref(), Some("Understood."));
+    }
+
+    #[test]
+    fn test_memory_and_rag_toggles() {
+        let template = ChatTemplate::default();
+        assert!(template.uses_memory() && template.uses_rag());
+
+        let template: ChatTemplate = serde_yaml::from_str(
+            "system_prompt: Extract JSON.\nmessages: []\nuse_memory: false\nuse_rag: false\n",
+        )
+        .unwrap();
+        assert!(!template.uses_memory());
+        assert!(!template.uses_rag());
+    }
+
+    #[test]
+    fn test_optional_messages_are_dropped_last_first() {
+        let mut template = parse_template(
+            r#"
+system_prompt: "Translate to French."
+messages:
+  - role: user
+    content: "Good morning, how are you today?"
+    optional: true
+  - role: assistant
+    content: "Bonjour, comment allez-vous aujourd'hui ?"
+    optional: true
+  - role: user
+    content: "Where is the train station, please?"
+    optional: true
+  - role: assistant
+    content: "Où est la gare, s'il vous plaît ?"
+    optional: true
+  - role: user
+    content: "Answer with the translation only."
+"#,
+        )
+        .unwrap();
+        assert_eq!(template.optional_messages, vec![0, 1, 2, 3]);
+
+        let count =
+            |messages: &[ChatCompletionRequestMessage]| tokens::count_messages(messages, "gpt-4");
+        let all = count(&template.messages);
+        assert_eq!(template.messages_within(all, "gpt-4"), template.messages);
+
+        let kept = template.messages_within(all - 1, "gpt-4");
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[3], template.messages[4]);
+
+        let kept = template.messages_within(0, "gpt-4");
+        assert_eq!(kept, vec![template.messages[4].clone()]);
+
+        template.prepend_messages(template.handshake("Context:\n", "none"));
+        assert_eq!(template.optional_messages, vec![2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_stop_words_merge() {
+        let strings = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        let config = AwfulJadeConfig {
+            stop_words: strings(&["<|im_end|>", "\n<|im_start|>"]),
+            ..Default::default()
+        };
+        let template = ChatTemplate {
+            stop_words: strings(&["</s>", "<|im_end|>"]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            template.stop_words(&config, &strings(&["###"])),
+            strings(&["###", "</s>", "<|im_end|>", "\n<|im_start|>"])
+        );
+        assert_eq!(
+            template
+                .stop_words(&config, &strings(&["a", "b", "c"]))
+                .len(),
+            MAX_STOP_WORDS
+        );
+    }
+
+    #[test]
+    fn test_render_prompts() {
+        let template = parse_template(
+            r#"
+system_prompt: "You write {{language}} code."
+messages:
+  - role: user
+    content: "Write hello world in {{language}}."
+prompts:
+  - var: language
+    question: Which language?
+"#,
+        )
+        .unwrap();
+        let mut vars = BTreeMap::new();
+        assert_eq!(template.missing_vars(&vars), vec![&template.prompts[0]]);
+        assert!(template
+            .render(&vars)
+            .unwrap_err()
+            .to_string()
+            .contains("--var language=<value>"));
+
+        vars.insert("language".to_string(), "Rust".to_string());
+        let rendered = template.render(&vars).unwrap();
+        assert_eq!(rendered.system_prompt, "You write Rust code.");
+        assert_eq!(
+            rendered.messages[0].content.as_deref(),
+            Some("Write hello world in Rust.")
+        );
+        assert_eq!(
+            parse_var("language=Rust"),
+            Ok(("language".to_string(), "Rust".to_string()))
+        );
+        assert!(parse_var("Rust").is_err());
+    }
 }
@@ -0,0 +1,509 @@
+//! This module installs chat templates from remote sources.
+//!
+//! `aj template install <source>` downloads a template, checks that it parses as a `ChatTemplate`,
+//! and writes it into the templates directory. Sources are either plain `http(s)://` URLs or
+//! `github:owner/repo/path/to/template.yaml`, optionally pinned with `@ref` (a branch, tag, or commit).
+//!
+//! Every install is recorded in `templates.lock` next to the templates, with the source, the
+//! resolved URL, and a checksum of the content, so a set of templates can be reproduced later.
+//! GitHub sources without a `@ref` are pinned to the commit their default branch points at when
+//! they are installed, so the lockfile never records a moving `HEAD`.
+//! Reinstalling a template that changed upstream shows a line diff of the update.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use awful_aj::template_install::{install, TemplateSource};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let templates_dir = awful_aj::config_dir()?.join("templates");
+//! let source = TemplateSource::parse("github:graves/awful_aj/templates/simple_question.yaml")?;
+//! let installed = install(&templates_dir, &source, None).await?;
+//! println!("Installed {}", installed.path.display());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::template::ChatTemplate;
+use crate::word_diff::DIFF_TIMEOUT;
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the lockfile in the templates directory.
+pub const LOCKFILE_NAME: &str = "templates.lock";
+
+/// The GitHub API that unpinned GitHub sources are resolved against.
+const GITHUB_API: &str = "https://api.github.com";
+
+/// Where a template is downloaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateSource {
+    /// A plain `http://` or `https://` URL.
+    Url(String),
+
+    /// A file in a GitHub repository.
+    GitHub {
+        owner: String,
+        repo: String,
+        path: String,
+        /// The branch, tag, or commit to fetch. Defaults to the repository's default branch.
+        reference: Option<String>,
+    },
+}
+
+impl TemplateSource {
+    /// Parses a source given on the command line.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(TemplateSource)`: The parsed source.
+    /// - `Err(&'static str)`: The source is neither a URL nor a `github:` path.
+    pub fn parse(source: &str) -> Result<Self, &'static str> {
+        let source = source.trim();
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(TemplateSource::Url(source.to_string()));
+        }
+
+        let path = source
+            .strip_prefix("github:")
+            .ok_or("Template sources must be http(s) URLs or github:owner/repo/path.yaml")?;
+        let (path, reference) = match path.rsplit_once('@') {
+            Some((path, reference)) if !reference.is_empty() => (path, Some(reference.to_string())),
+            _ => (path, None),
+        };
+        let mut parts = path.splitn(3, '/');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(owner), Some(repo), Some(path))
+                if !owner.is_empty() && !repo.is_empty() && !path.is_empty() =>
+            {
+                Ok(TemplateSource::GitHub {
+                    owner: owner.to_string(),
+                    repo: repo.to_string(),
+                    path: path.to_string(),
+                    reference,
+                })
+            }
+            _ => Err("GitHub template sources look like github:owner/repo/path.yaml"),
+        }
+    }
+
+    /// Returns the URL the template's content is downloaded from.
+    pub fn url(&self) -> String {
+        match self {
+            TemplateSource::Url(url) => url.clone(),
+            TemplateSource::GitHub {
+                owner,
+                repo,
+                path,
+                reference,
+            } => format!(
+                "https://raw.githubusercontent.com/{}/{}/{}/{}",
+                owner,
+                repo,
+                reference.as_deref().unwrap_or("HEAD"),
+                path
+            ),
+        }
+    }
+
+    /// Whether the source always downloads the same content: a URL, or a GitHub path with a `@ref`.
+    ///
+    /// Branches and tags are taken at their word; only a missing reference counts as unpinned.
+    pub fn is_pinned(&self) -> bool {
+        !matches!(
+            self,
+            TemplateSource::GitHub {
+                reference: None,
+                ..
+            }
+        )
+    }
+
+    /// Pins a GitHub source without a `@ref` to the commit its default branch points at.
+    ///
+    /// Other sources are returned unchanged.
+    pub async fn resolve(&self) -> Result<TemplateSource, Box<dyn Error>> {
+        self.resolve_from(GITHUB_API).await
+    }
+
+    async fn resolve_from(&self, api: &str) -> Result<TemplateSource, Box<dyn Error>> {
+        let TemplateSource::GitHub {
+            owner,
+            repo,
+            path,
+            reference: None,
+        } = self
+        else {
+            return Ok(self.clone());
+        };
+
+        let sha = reqwest::Client::new()
+            .get(format!("{}/repos/{}/{}/commits/HEAD", api, owner, repo))
+            .header("Accept", "application/vnd.github.sha")
+            .header("User-Agent", "aj")
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| {
+                format!(
+                    "Unable to resolve the default branch of {}/{}: {}",
+                    owner, repo, e
+                )
+            })?
+            .text()
+            .await?;
+        let sha = sha.trim();
+        if sha.len() != 40 || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("GitHub returned `{}`, which is not a commit SHA", sha).into());
+        }
+
+        Ok(TemplateSource::GitHub {
+            owner: owner.clone(),
+            repo: repo.clone(),
+            path: path.clone(),
+            reference: Some(sha.to_string()),
+        })
+    }
+
+    /// Returns the template name implied by the source: its file name without the extension.
+    pub fn default_name(&self) -> Option<String> {
+        let url = self.url();
+        let file_name = url.split(['?', '#']).next()?.rsplit('/').next()?;
+        let name = file_name
+            .strip_suffix(".yaml")
+            .or_else(|| file_name.strip_suffix(".yml"))
+            .unwrap_or(file_name);
+        (!name.is_empty()).then(|| name.to_string())
+    }
+}
+
+impl fmt::Display for TemplateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateSource::Url(url) => write!(f, "{}", url),
+            TemplateSource::GitHub {
+                owner,
+                repo,
+                path,
+                reference,
+            } => {
+                write!(f, "github:{}/{}/{}", owner, repo, path)?;
+                if let Some(reference) = reference {
+                    write!(f, "@{}", reference)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A template recorded in the lockfile.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LockEntry {
+    /// The source the template was installed from, as given on the command line.
+    pub source: String,
+
+    /// The URL the content was downloaded from.
+    pub url: String,
+
+    /// A checksum of the installed content.
+    pub checksum: String,
+
+    /// When the template was installed, in seconds since the Unix epoch.
+    pub installed_at: u64,
+}
+
+/// The installed templates, keyed by template name.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Lockfile {
+    pub templates: BTreeMap<String, LockEntry>,
+}
+
+impl Lockfile {
+    /// Loads the lockfile from the templates directory, or an empty one if there is none.
+    pub fn load(templates_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = templates_dir.join(LOCKFILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_yaml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Writes the lockfile to the templates directory.
+    pub fn save(&self, templates_dir: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(
+            templates_dir.join(LOCKFILE_NAME),
+            serde_yaml::to_string(self)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// One line of a diff between two versions of a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Unchanged(String),
+    Added(String),
+    Removed(String),
+}
+
+impl fmt::Display for DiffLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffLine::Unchanged(line) => write!(f, "  {}", line),
+            DiffLine::Added(line) => write!(f, "+ {}", line),
+            DiffLine::Removed(line) => write!(f, "- {}", line),
+        }
+    }
+}
+
+/// The result of installing a template.
+#[derive(Debug)]
+pub struct Installed {
+    /// The name the template can be loaded by.
+    pub name: String,
+
+    /// Where the template was written.
+    pub path: PathBuf,
+
+    /// The changes from the previously installed version, if the template was already installed.
+    pub diff: Option<Vec<DiffLine>>,
+}
+
+impl Installed {
+    /// Whether the install replaced a different version of the template.
+    pub fn changed(&self) -> bool {
+        self.diff.as_ref().is_some_and(|diff| {
+            diff.iter()
+                .any(|line| !matches!(line, DiffLine::Unchanged(_)))
+        })
+    }
+}
+
+/// Downloads, validates, and installs a template.
+///
+/// # Parameters
+///
+/// - `templates_dir`: The directory templates are installed into.
+/// - `source`: Where to download the template from. Unpinned GitHub sources are pinned first.
+/// - `name`: The name to install the template as. Defaults to the source's file name.
+///
+/// # Returns
+///
+/// What was installed, including a diff against the previous version if there was one.
+pub async fn install(
+    templates_dir: &Path,
+    source: &TemplateSource,
+    name: Option<String>,
+) -> Result<Installed, Box<dyn Error>> {
+    let source = source.resolve().await?;
+    let content = reqwest::get(&source.url())
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    install_content(templates_dir, &source, name, &content)
+}
+
+/// Validates and installs already-downloaded template content.
+///
+/// The source must be pinned (see `TemplateSource::is_pinned`), since it is recorded in the
+/// lockfile; `TemplateSource::resolve` pins GitHub sources.
+pub fn install_content(
+    templates_dir: &Path,
+    source: &TemplateSource,
+    name: Option<String>,
+    content: &str,
+) -> Result<Installed, Box<dyn Error>> {
+    if !source.is_pinned() {
+        return Err(format!(
+            "{} is not pinned to a commit and can't be recorded in {}",
+            source, LOCKFILE_NAME
+        )
+        .into());
+    }
+    let name = name
+        .or_else(|| source.default_name())
+        .ok_or("Unable to determine a template name; pass --name")?;
+    if name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("Invalid template name `{}`", name).into());
+    }
+    serde_yaml::from_str::<ChatTemplate>(content)
+        .map_err(|e| format!("{} is not a valid template: {}", source, e))?;
+
+    fs::create_dir_all(templates_dir)?;
+    let path = templates_dir.join(format!("{}.yaml", name));
+    let diff = match fs::read_to_string(&path) {
+        Ok(previous) => Some(diff_lines(&previous, content)),
+        Err(_) => None,
+    };
+    fs::write(&path, content)?;
+
+    let mut lockfile = Lockfile::load(templates_dir)?;
+    lockfile.templates.insert(
+        name.clone(),
+        LockEntry {
+            source: source.to_string(),
+            url: source.url(),
+            checksum: checksum(content),
+            installed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        },
+    );
+    lockfile.save(templates_dir)?;
+
+    Ok(Installed { name, path, diff })
+}
+
+/// Computes a line diff from `old` to `new`.
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_lines(old, new)
+        .iter_all_changes()
+        .map(|change| {
+            let line = change.value().trim_end_matches(['\n', '\r']).to_string();
+            match change.tag() {
+                ChangeTag::Equal => DiffLine::Unchanged(line),
+                ChangeTag::Insert => DiffLine::Added(line),
+                ChangeTag::Delete => DiffLine::Removed(line),
+            }
+        })
+        .collect()
+}
+
+/// A stable 64-bit FNV-1a checksum, rendered as hex.
+fn checksum(content: &str) -> String {
+    let hash = content.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("fnv1a64:{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const TEMPLATE: &str = "system_prompt: \"You are a helpful assistant.\"\nmessages: []\n";
+
+    #[test]
+    fn test_parse_source() {
+        let source =
+            TemplateSource::parse("github:graves/awful_aj/templates/code.yaml@v1").unwrap();
+        assert_eq!(
+            source.url(),
+            "https://raw.githubusercontent.com/graves/awful_aj/v1/templates/code.yaml"
+        );
+        assert_eq!(source.default_name().as_deref(), Some("code"));
+        assert_eq!(
+            source.to_string(),
+            "github:graves/awful_aj/templates/code.yaml@v1"
+        );
+
+        let source = TemplateSource::parse("https://example.com/t/reviewer.yml?raw=1").unwrap();
+        assert_eq!(source.default_name().as_deref(), Some("reviewer"));
+
+        assert!(TemplateSource::parse("github:graves/awful_aj").is_err());
+        assert!(TemplateSource::parse("ftp://example.com/t.yaml").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_installed_templates_load() {
+        let dir = tempdir().unwrap();
+        let source = TemplateSource::parse("https://example.com/t/reviewer.yaml").unwrap();
+        let installed = install_content(dir.path(), &source, None, TEMPLATE).unwrap();
+
+        let template = crate::template::load_template_from(dir.path(), &installed.name)
+            .await
+            .unwrap();
+        assert_eq!(template.system_prompt, "You are a helpful assistant.");
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let diff = diff_lines("a\nb\nc\n", "a\nc\nd\n");
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Unchanged("a".to_string()),
+                DiffLine::Removed("b".to_string()),
+                DiffLine::Unchanged("c".to_string()),
+                DiffLine::Added("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_install_content_records_lockfile_and_diff() {
+        let dir = tempdir().unwrap();
+        let source = TemplateSource::parse("https://example.com/helper.yaml").unwrap();
+
+        let installed = install_content(dir.path(), &source, None, TEMPLATE).unwrap();
+        assert_eq!(installed.name, "helper");
+        assert!(installed.diff.is_none());
+
+        let updated = TEMPLATE.replace("helpful", "terse");
+        let installed = install_content(dir.path(), &source, None, &updated).unwrap();
+        assert!(installed.changed());
+        assert_eq!(fs::read_to_string(&installed.path).unwrap(), updated);
+
+        let lockfile = Lockfile::load(dir.path()).unwrap();
+        let entry = &lockfile.templates["helper"];
+        assert_eq!(entry.url, "https://example.com/helper.yaml");
+        assert_eq!(entry.checksum, checksum(&updated));
+    }
+
+    #[test]
+    fn test_install_content_rejects_invalid_template() {
+        let dir = tempdir().unwrap();
+        let source = TemplateSource::parse("https://example.com/broken.yaml").unwrap();
+
+        assert!(install_content(dir.path(), &source, None, "not: [a template").is_err());
+        assert!(!dir.path().join("broken.yaml").exists());
+    }
+
+    #[tokio::test]
+    async fn test_unpinned_github_sources_are_locked_to_a_commit() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::GET)
+                .path("/repos/graves/awful_aj/commits/HEAD")
+                .header("Accept", "application/vnd.github.sha");
+            then.status(200).body(sha);
+        });
+
+        let dir = tempdir().unwrap();
+        let source = TemplateSource::parse("github:graves/awful_aj/templates/code.yaml").unwrap();
+        assert!(!source.is_pinned());
+        assert!(install_content(dir.path(), &source, None, TEMPLATE).is_err());
+
+        let pinned = source.resolve_from(&server.base_url()).await.unwrap();
+        assert!(pinned.is_pinned());
+        install_content(dir.path(), &pinned, None, TEMPLATE).unwrap();
+
+        let lockfile = Lockfile::load(dir.path()).unwrap();
+        let entry = &lockfile.templates["code"];
+        assert_eq!(
+            entry.source,
+            format!("github:graves/awful_aj/templates/code.yaml@{}", sha)
+        );
+        assert_eq!(
+            entry.url,
+            format!(
+                "https://raw.githubusercontent.com/graves/awful_aj/{}/templates/code.yaml",
+                sha
+            )
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! This module helps tests exercise memories without a model or a backend.
+//!
+//! It is compiled for the crate's own tests and, with the `test_support` feature, for the tests
+//! of crates that depend on awful_aj:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! awful_aj = { version = "*", features = ["test_support"] }
+//! ```
+//!
+//! Its configurations have tiny context windows, so a few exchanges are enough to eject messages
+//! into memory, and its vector stores embed text with `Embedder::Hash`, so nothing is downloaded
+//! and the same text always gets the same vector. A `TestSession` plays a conversation without
+//! sending it anywhere: it ejects and archives messages exactly as a request would.
+//!
+//! ```
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use awful_aj::test_support::{self, TestSession};
+//!
+//! let mut session = TestSession::new(test_support::config(), test_support::template());
+//! for turn in 0..8 {
+//!     let question = format!("What happened on day {}?", turn);
+//!     session.exchange(&question, "Nothing much; it rained all day.").await?;
+//! }
+//! assert!(!session.vector_store.is_empty());
+//! assert!(!session.recall("day 0").await?.is_empty());
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "rag")]
+use crate::{
+    api,
+    brain::{Brain, Memory},
+    embeddings::Embedder,
+    retrieval,
+    vector_store::VectorStore,
+};
+use crate::{config::AwfulJadeConfig, template::ChatTemplate};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+#[cfg(feature = "rag")]
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The dimension of the vectors of test vector stores.
+#[cfg(feature = "rag")]
+pub const TEST_DIMENSION: usize = 64;
+
+/// The model test configurations count tokens for.
+pub const TEST_MODEL: &str = "gpt-4";
+
+/// Returns a configuration with a 256 token context window, of which 64 tokens are kept for the
+/// answer, pointed at an address nothing listens on.
+pub fn config() -> AwfulJadeConfig {
+    config_with_budget(256, 64)
+}
+
+/// Returns a configuration like `config`, with the given context window and tokens kept for the
+/// answer.
+pub fn config_with_budget(
+    context_max_tokens: u16,
+    assistant_minimum_context_tokens: u16,
+) -> AwfulJadeConfig {
+    AwfulJadeConfig {
+        api_key: "test_api_key".to_string(),
+        api_base: "http://127.0.0.1:9".to_string(),
+        model: TEST_MODEL.to_string(),
+        context_max_tokens,
+        assistant_minimum_context_tokens,
+        stop_words: Vec::new(),
+        should_stream: Some(false),
+        ..Default::default()
+    }
+}
+
+/// Returns a configuration for tests against a mock backend, with an 8192 token context window
+/// of which 2048 tokens are kept for the answer. Point `api_base` at the mock server.
+pub fn mock_config() -> AwfulJadeConfig {
+    AwfulJadeConfig {
+        api_key: "mock_api_key".to_string(),
+        api_base: "http://mock.api.base".to_string(),
+        model: "mock_model".to_string(),
+        context_max_tokens: 8192,
+        assistant_minimum_context_tokens: 2048,
+        ..Default::default()
+    }
+}
+
+/// Returns a template with a short system prompt and no messages.
+pub fn template() -> ChatTemplate {
+    template_with_prompt("You are a test assistant.")
+}
+
+/// Returns a template with `system_prompt` and no messages.
+pub fn template_with_prompt(system_prompt: &str) -> ChatTemplate {
+    ChatTemplate {
+        system_prompt: system_prompt.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Returns an empty vector store that embeds with `Embedder::Hash`.
+#[cfg(feature = "rag")]
+pub fn vector_store() -> VectorStore {
+    VectorStore::with_embedder(
+        TEST_DIMENSION,
+        Embedder::Hash {
+            dimension: TEST_DIMENSION,
+        },
+    )
+}
+
+/// Returns a message from `role` with `content`.
+pub fn message(role: Role, content: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage {
+        role,
+        content: Some(content.to_string()),
+        name: None,
+        function_call: None,
+    }
+}
+
+/// A clock for code that is given the current time, which only moves when it is told to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FakeClock {
+    now: SystemTime,
+}
+
+impl Default for FakeClock {
+    /// Starts the clock at 2023-11-14 22:13:20 UTC.
+    fn default() -> Self {
+        Self::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+    }
+}
+
+impl FakeClock {
+    /// Starts the clock at `now`.
+    pub fn at(now: SystemTime) -> Self {
+        Self { now }
+    }
+
+    /// Returns the clock's time.
+    pub fn now(&self) -> SystemTime {
+        self.now
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+}
+
+/// A conversation played in memory, with its archived memories.
+///
+/// The conversation starts with the template's preamble, and each exchange is fitted into the
+/// context window the way a request is: the oldest exchanges are ejected into `vector_store`.
+#[cfg(feature = "rag")]
+pub struct TestSession {
+    pub config: AwfulJadeConfig,
+    pub template: ChatTemplate,
+    pub vector_store: VectorStore,
+
+    /// The conversation as it would be sent next, starting with the preamble.
+    pub messages: Vec<ChatCompletionRequestMessage>,
+
+    /// How many of `messages` belong to the preamble.
+    preamble_len: usize,
+}
+
+#[cfg(feature = "rag")]
+impl TestSession {
+    /// Starts a conversation with an empty memory.
+    pub fn new(config: AwfulJadeConfig, template: ChatTemplate) -> Self {
+        let vector_store = vector_store();
+        Self::with_vector_store(config, template, vector_store)
+    }
+
+    /// Starts a conversation with the memories in `vector_store`.
+    pub fn with_vector_store(
+        config: AwfulJadeConfig,
+        template: ChatTemplate,
+        vector_store: VectorStore,
+    ) -> Self {
+        let brain = Brain::new(crate::brain::max_brain_tokens(&config), &template);
+        let messages = brain
+            .build_preamble()
+            .expect("an empty brain always serializes");
+        Self {
+            config,
+            template,
+            vector_store,
+            preamble_len: messages.len(),
+            messages,
+        }
+    }
+
+    /// Asks `question` and records `answer` as the assistant's reply, ejecting and archiving the
+    /// oldest exchanges first if the question does not leave room for an answer.
+    pub async fn exchange(&mut self, question: &str, answer: &str) -> Result<(), Box<dyn Error>> {
+        self.messages.push(message(Role::User, question));
+        api::eject_to_fit(
+            &mut self.messages,
+            &self.config.model,
+            &self.config,
+            Some(&mut self.vector_store),
+        )
+        .await?;
+        self.messages.push(message(Role::Assistant, answer));
+        Ok(())
+    }
+
+    /// Returns the memories that would be recalled into the preamble for `question`, most
+    /// relevant first.
+    pub async fn recall(&self, question: &str) -> Result<Vec<Memory>, Box<dyn Error>> {
+        if self.vector_store.is_empty() {
+            return Ok(Vec::new());
+        }
+        let recent = retrieval::recent_turns(&self.messages, self.preamble_len);
+        let query = retrieval::retrieval_query(&self.config, question, recent).await;
+        let mut conversation = self.messages[self.preamble_len..].to_vec();
+        conversation.push(message(Role::User, question));
+        let neighbors = retrieval::retrieve_within_budget(
+            &self.config,
+            &self.vector_store,
+            &query,
+            &conversation,
+        )
+        .await?;
+        Ok(neighbors
+            .into_iter()
+            .filter_map(|(id, _distance)| self.vector_store.get_content_by_id(id).cloned())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "rag")]
+    #[tokio::test]
+    async fn test_session_ejects_into_memory() {
+        let mut session = TestSession::new(config(), template());
+        let preamble_len = session.messages.len();
+        session
+            .exchange(
+                "Where did we park the car?",
+                "On level three, near the elevator.",
+            )
+            .await
+            .unwrap();
+        assert!(session.vector_store.is_empty());
+
+        for day in ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday"] {
+            let question = format!("What is for lunch on {}?", day);
+            session
+                .exchange(&question, "Soup and a sandwich, as every week.")
+                .await
+                .unwrap();
+        }
+        assert!(!session.vector_store.is_empty());
+        assert!(session.messages.len() > preamble_len);
+
+        let recalled = session.recall("Where is the car parked?").await.unwrap();
+        assert_eq!(
+            recalled.first().map(Memory::content),
+            Some("Where did we park the car?")
+        );
+    }
+
+    #[test]
+    fn test_fake_clock() {
+        let mut clock = FakeClock::default();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now().duration_since(start).unwrap().as_secs(), 90);
+    }
+}
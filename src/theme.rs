@@ -0,0 +1,216 @@
+//! This module defines the colors and spinner used for terminal output.
+//!
+//! A theme starts from one of the built-in themes (`dark`, the default, or `light`) and can
+//! override any of its parts in the `theme:` section of `config.yaml`:
+//!
+//! ```yaml
+//! theme:
+//!   name: light
+//!   assistant: "#268bd2"
+//!   spinner: "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏"
+//! ```
+//!
+//! Colors are either names (`blue`, `dark_green`, `grey`, ...) or `#rrggbb` hex codes.
+
+use crate::config::AwfulJadeConfig;
+use clap::ValueEnum;
+use crossterm::style::{style, Color, StyledContent, Stylize};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt::Display};
+
+/// The spinner frames used when a theme does not set its own.
+const DEFAULT_SPINNER: &str = "⠁⠂⠄⡀⢀⠠⠐⠈ ";
+
+/// A built-in theme, selected with `--theme` or the `name` of the `theme:` config section.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinTheme {
+    /// Bright colors for dark terminal backgrounds.
+    #[default]
+    Dark,
+
+    /// Darker colors for light terminal backgrounds.
+    Light,
+}
+
+/// The resolved colors and spinner for terminal output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    /// The color of the user's input.
+    pub user: Color,
+
+    /// The color of the assistant's responses.
+    pub assistant: Color,
+
+    /// The color of messages from aj itself, such as the conversation header.
+    pub system: Color,
+
+    /// The frames of the spinner shown while waiting on slow tasks.
+    pub spinner: String,
+}
+
+impl Theme {
+    /// Returns a built-in theme.
+    pub fn builtin(theme: BuiltinTheme) -> Self {
+        match theme {
+            BuiltinTheme::Dark => Self {
+                user: Color::Green,
+                assistant: Color::Blue,
+                system: Color::Yellow,
+                spinner: DEFAULT_SPINNER.to_string(),
+            },
+            BuiltinTheme::Light => Self {
+                user: Color::DarkGreen,
+                assistant: Color::DarkBlue,
+                system: Color::DarkMagenta,
+                spinner: DEFAULT_SPINNER.to_string(),
+            },
+        }
+    }
+
+    /// Resolves the theme configured in `config`, applying its overrides to the built-in theme it
+    /// names.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Theme)`: The resolved theme; the `dark` theme when none is configured.
+    /// - `Err(Box<dyn Error>)`: A color could not be parsed, or the spinner has fewer than two frames.
+    pub fn from_config(config: &AwfulJadeConfig) -> Result<Self, Box<dyn Error>> {
+        let Some(theme_config) = &config.theme else {
+            return Ok(Self::builtin(BuiltinTheme::default()));
+        };
+
+        let mut theme = Self::builtin(theme_config.name.unwrap_or_default());
+        if let Some(color) = &theme_config.user {
+            theme.user = parse_color(color)?;
+        }
+        if let Some(color) = &theme_config.assistant {
+            theme.assistant = parse_color(color)?;
+        }
+        if let Some(color) = &theme_config.system {
+            theme.system = parse_color(color)?;
+        }
+        if let Some(spinner) = &theme_config.spinner {
+            if spinner.chars().count() < 2 {
+                return Err("A theme's spinner needs at least two frames".into());
+            }
+            theme.spinner = spinner.clone();
+        }
+        Ok(theme)
+    }
+
+    /// Styles `text` as a message from aj itself.
+    pub fn system<D: Display>(&self, text: D) -> StyledContent<D> {
+        style(text).with(self.system)
+    }
+
+    /// Styles `text` as a dimmed hint from aj itself, such as suggested follow-up questions.
+    pub fn hint<D: Display>(&self, text: D) -> StyledContent<D> {
+        style(text).with(self.system).dim()
+    }
+}
+
+/// Parses a color name such as `blue` or `dark_green`, or a `#rrggbb` hex code.
+///
+/// # Returns
+///
+/// - `Ok(Color)`: The parsed color.
+/// - `Err(String)`: A description of why the value could not be parsed.
+pub fn parse_color(value: &str) -> Result<Color, String> {
+    let invalid = || {
+        format!(
+            "Invalid color `{}`, expected a name such as `blue` or a hex code such as `#268bd2`",
+            value
+        )
+    };
+
+    if let Some(hex) = value.trim().strip_prefix('#') {
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        return Ok(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+
+    let color = match value
+        .trim()
+        .to_lowercase()
+        .replace(['-', ' '], "_")
+        .as_str()
+    {
+        "reset" => Color::Reset,
+        "black" => Color::Black,
+        "dark_grey" | "dark_gray" => Color::DarkGrey,
+        "red" => Color::Red,
+        "dark_red" => Color::DarkRed,
+        "green" => Color::Green,
+        "dark_green" => Color::DarkGreen,
+        "yellow" => Color::Yellow,
+        "dark_yellow" => Color::DarkYellow,
+        "blue" => Color::Blue,
+        "dark_blue" => Color::DarkBlue,
+        "magenta" => Color::Magenta,
+        "dark_magenta" => Color::DarkMagenta,
+        "cyan" => Color::Cyan,
+        "dark_cyan" => Color::DarkCyan,
+        "white" => Color::White,
+        "grey" | "gray" => Color::Grey,
+        _ => return Err(invalid()),
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ThemeConfig;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("blue"), Ok(Color::Blue));
+        assert_eq!(parse_color("Dark-Green"), Ok(Color::DarkGreen));
+        assert_eq!(
+            parse_color("#268bd2"),
+            Ok(Color::Rgb {
+                r: 0x26,
+                g: 0x8b,
+                b: 0xd2
+            })
+        );
+        assert!(parse_color("#268bd").is_err());
+        assert!(parse_color("chartreuse").is_err());
+    }
+
+    #[test]
+    fn test_from_config_overrides_builtin() {
+        assert_eq!(
+            Theme::from_config(&AwfulJadeConfig::default()).unwrap(),
+            Theme::builtin(BuiltinTheme::Dark)
+        );
+
+        let config = AwfulJadeConfig {
+            theme: Some(ThemeConfig {
+                name: Some(BuiltinTheme::Light),
+                assistant: Some("red".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let theme = Theme::from_config(&config).unwrap();
+        assert_eq!(theme.assistant, Color::Red);
+        assert_eq!(theme.user, Color::DarkGreen);
+
+        let config = AwfulJadeConfig {
+            theme: Some(ThemeConfig {
+                spinner: Some("*".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(Theme::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! This module measures how fast responses arrive.
+//!
+//! With `show_timings: true` in the config, or `--timings` on the command line, a status line is
+//! printed to stderr after each response in `aj ask` and interactive mode:
+//!
+//! ```text
+//! [timings] first token 0.42s · 38.1 tokens/s · 5.30s total
+//! ```
+//!
+//! The time to the first token is measured for streamed responses only; a fetched response
+//! arrives all at once. Throughput counts the tokens generated after the first one arrived, so it
+//! reflects the model's generation speed rather than the time it spent reading the prompt.
+
+use std::{fmt, time::Duration};
+
+/// How long a response took, and how many tokens it had.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    /// How long after the request was sent the first token arrived, for streamed responses.
+    pub time_to_first_token: Option<Duration>,
+
+    /// How long after the request was sent the response was complete.
+    pub total: Duration,
+
+    /// How many tokens the response had.
+    pub tokens: usize,
+}
+
+impl Timings {
+    /// Returns how many tokens were generated per second, or `None` if the response took no
+    /// measurable time.
+    ///
+    /// For streamed responses, the time before the first token is left out.
+    #[must_use]
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let generating = self
+            .total
+            .saturating_sub(self.time_to_first_token.unwrap_or_default())
+            .as_secs_f64();
+        (generating > 0.0 && self.tokens > 0).then(|| self.tokens as f64 / generating)
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(first) = self.time_to_first_token {
+            write!(f, "first token {:.2}s · ", first.as_secs_f64())?;
+        }
+        if let Some(rate) = self.tokens_per_second() {
+            write!(f, "{:.1} tokens/s · ", rate)?;
+        }
+        write!(f, "{:.2}s total", self.total.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings_display() {
+        let streamed = Timings {
+            time_to_first_token: Some(Duration::from_millis(500)),
+            total: Duration::from_millis(2500),
+            tokens: 50,
+        };
+        assert_eq!(streamed.tokens_per_second(), Some(25.0));
+        assert_eq!(
+            streamed.to_string(),
+            "first token 0.50s · 25.0 tokens/s · 2.50s total"
+        );
+
+        let fetched = Timings {
+            time_to_first_token: None,
+            total: Duration::from_secs(4),
+            tokens: 0,
+        };
+        assert_eq!(fetched.tokens_per_second(), None);
+        assert_eq!(fetched.to_string(), "4.00s total");
+    }
+}
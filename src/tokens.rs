@@ -0,0 +1,151 @@
+//! This module counts tokens the same way aj does when it budgets prompts.
+//!
+//! Counts use the tokenizer of the given model when tiktoken knows it, and `cl100k_base` otherwise,
+//! which is a reasonable approximation for the local models aj is usually pointed at. Message
+//! counts include the per-message overhead of the gpt-4 chat format.
+//!
+//! # Example
+//!
+//! ```
+//! use awful_aj::tokens;
+//!
+//! assert!(tokens::count("How many tokens is this?", "gpt-4") > 0);
+//! ```
+
+use crate::config::AwfulJadeConfig;
+use async_openai::types::ChatCompletionRequestMessage;
+use once_cell::sync::Lazy;
+use tiktoken_rs::{
+    tokenizer::{get_tokenizer, Tokenizer},
+    CoreBPE,
+};
+
+/// The model whose tokenizer is used when no model is known, e.g. for repository maps.
+pub const DEFAULT_MODEL: &str = "gpt-4";
+
+static CL100K_BASE: Lazy<CoreBPE> = Lazy::new(|| tiktoken_rs::cl100k_base().unwrap());
+static P50K_BASE: Lazy<CoreBPE> = Lazy::new(|| tiktoken_rs::p50k_base().unwrap());
+static P50K_EDIT: Lazy<CoreBPE> = Lazy::new(|| tiktoken_rs::p50k_edit().unwrap());
+static R50K_BASE: Lazy<CoreBPE> = Lazy::new(|| tiktoken_rs::r50k_base().unwrap());
+
+/// Tokens added to every message by the chat format.
+const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Tokens added when a message has a name.
+const TOKENS_PER_NAME: usize = 1;
+
+/// Tokens priming every reply.
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Returns the tokenizer for a model, falling back to `cl100k_base` for unknown models.
+fn bpe(model: &str) -> &'static CoreBPE {
+    match get_tokenizer(model) {
+        Some(Tokenizer::P50kBase) => &P50K_BASE,
+        Some(Tokenizer::P50kEdit) => &P50K_EDIT,
+        Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => &R50K_BASE,
+        _ => &CL100K_BASE,
+    }
+}
+
+/// Counts the tokens in `text` for `model`.
+pub fn count(text: &str, model: &str) -> usize {
+    bpe(model).encode_with_special_tokens(text).len()
+}
+
+/// Counts the tokens a list of chat messages takes up in a request to `model`.
+pub fn count_messages(messages: &[ChatCompletionRequestMessage], model: &str) -> usize {
+    let bpe = bpe(model);
+    let count = |text: &str| bpe.encode_with_special_tokens(text).len();
+
+    messages
+        .iter()
+        .map(|message| {
+            TOKENS_PER_MESSAGE
+                + count(&message.role.to_string())
+                + message.content.as_deref().map_or(0, count)
+                + message
+                    .name
+                    .as_deref()
+                    .map_or(0, |name| count(name) + TOKENS_PER_NAME)
+        })
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+/// Returns how many tokens are left for the response in a context window of `context_max_tokens`.
+pub fn remaining(
+    messages: &[ChatCompletionRequestMessage],
+    model: &str,
+    context_max_tokens: u16,
+) -> usize {
+    (context_max_tokens as usize).saturating_sub(count_messages(messages, model))
+}
+
+/// Returns the `max_tokens` to request for a response to `messages`: the tokens left in the
+/// context window, capped at `max_response_tokens` when it is configured.
+///
+/// Requesting the whole context size makes many servers reject the request, since the prompt and
+/// the response together would not fit.
+pub fn response_limit(
+    messages: &[ChatCompletionRequestMessage],
+    model: &str,
+    config: &AwfulJadeConfig,
+) -> u16 {
+    let remaining = remaining(messages, model, config.context_max_tokens) as u16;
+    match config.max_response_tokens {
+        Some(cap) => remaining.min(cap),
+        None => remaining,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::message;
+    use async_openai::types::Role;
+
+    #[test]
+    fn test_count_matches_tiktoken() {
+        let messages = vec![
+            message(Role::System, "You are a helpful assistant."),
+            message(Role::User, "What is the meaning of life?"),
+        ];
+        let expected =
+            tiktoken_rs::async_openai::num_tokens_from_messages("gpt-4", &messages).unwrap();
+
+        assert_eq!(count_messages(&messages, "gpt-4"), expected);
+        assert_eq!(count("hello world", "gpt-4"), 2);
+    }
+
+    #[test]
+    fn test_unknown_models_fall_back_to_cl100k() {
+        let text = "Local models still need budgeting.";
+        assert_eq!(count(text, "mistral-7b-openorca"), count(text, "gpt-4"));
+    }
+
+    #[test]
+    fn test_remaining() {
+        let messages = vec![message(Role::User, "Hi")];
+        let used = count_messages(&messages, DEFAULT_MODEL);
+        assert_eq!(remaining(&messages, DEFAULT_MODEL, 100), 100 - used);
+        assert_eq!(remaining(&messages, DEFAULT_MODEL, 1), 0);
+    }
+
+    #[test]
+    fn test_response_limit() {
+        let messages = vec![message(Role::User, "Hi")];
+        let mut config = AwfulJadeConfig {
+            model: DEFAULT_MODEL.to_string(),
+            context_max_tokens: 100,
+            ..Default::default()
+        };
+        let left = remaining(&messages, DEFAULT_MODEL, 100) as u16;
+        assert_eq!(response_limit(&messages, DEFAULT_MODEL, &config), left);
+
+        config.max_response_tokens = Some(10);
+        assert_eq!(response_limit(&messages, DEFAULT_MODEL, &config), 10);
+
+        config.max_response_tokens = Some(1000);
+        assert_eq!(response_limit(&messages, DEFAULT_MODEL, &config), left);
+    }
+}
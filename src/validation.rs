@@ -0,0 +1,397 @@
+//! This module checks a configuration for values that parse but make no sense.
+//!
+//! A context window of 0 tokens, an assistant minimum as large as the whole window, or an
+//! `api_base` that is not a URL all load without complaint, and only fail later in confusing ways.
+//! `load_config` runs `validate` on every configuration it loads: errors stop aj with a list of
+//! what to fix, and warnings are printed to stderr. `aj config validate` prints every diagnostic
+//! without stopping at the first error.
+//!
+//! ```text
+//! error: context_max_tokens: must be greater than 0
+//!   fix: set it to the model's context window, e.g. `context_max_tokens: 8192`
+//! ```
+
+use crate::config::{self, AwfulJadeConfig};
+use std::{error::Error, fmt};
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The configuration cannot work as written.
+    Error,
+
+    /// The configuration works, but probably not as intended.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem with one field of a configuration, and how to fix it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+
+    /// The field the problem is in, as it is written in `config.yaml`.
+    pub field: &'static str,
+
+    /// What is wrong.
+    pub message: String,
+
+    /// What to change.
+    pub suggestion: String,
+}
+
+impl Diagnostic {
+    fn error(
+        field: &'static str,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: Severity::Error,
+            field,
+            message: message.into(),
+            suggestion: suggestion.into(),
+        }
+    }
+
+    fn warning(
+        field: &'static str,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(field, message, suggestion)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: {}\n  fix: {}",
+            self.severity, self.field, self.message, self.suggestion
+        )
+    }
+}
+
+/// The errors found in a configuration, returned by `load_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidConfig(pub Vec<Diagnostic>);
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The configuration has {} error(s):", self.0.len())?;
+        for diagnostic in &self.0 {
+            write!(f, "\n{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for InvalidConfig {}
+
+/// Checks `config`, returning its errors and warnings in the order of its fields.
+pub fn validate(config: &AwfulJadeConfig) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    match reqwest::Url::parse(&config.api_base) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {
+            if url.path().trim_end_matches('/').ends_with("/chat/completions") {
+                diagnostics.push(Diagnostic::warning(
+                    "api_base",
+                    "ends with `/chat/completions`, which aj adds itself",
+                    format!(
+                        "remove the endpoint, e.g. `api_base: {}`",
+                        config
+                            .api_base
+                            .trim_end_matches('/')
+                            .trim_end_matches("/chat/completions")
+                    ),
+                ));
+            }
+        }
+        Ok(url) => diagnostics.push(Diagnostic::error(
+            "api_base",
+            format!("has the scheme `{}`; only http and https work", url.scheme()),
+            "use the backend's http(s) address, e.g. `api_base: http://localhost:5001/v1`",
+        )),
+        Err(e) => diagnostics.push(Diagnostic::error(
+            "api_base",
+            format!("`{}` is not a URL ({})", config.api_base, e),
+            "use the backend's address including the scheme, e.g. `api_base: http://localhost:5001/v1`",
+        )),
+    }
+
+    if config.model.trim().is_empty() {
+        diagnostics.push(Diagnostic::error(
+            "model",
+            "is empty",
+            "set it to the name of a model the backend serves",
+        ));
+    }
+
+    if config.context_max_tokens == 0 {
+        diagnostics.push(Diagnostic::error(
+            "context_max_tokens",
+            "must be greater than 0",
+            "set it to the model's context window, e.g. `context_max_tokens: 8192`",
+        ));
+    } else if config.assistant_minimum_context_tokens >= config.context_max_tokens {
+        diagnostics.push(Diagnostic::error(
+            "assistant_minimum_context_tokens",
+            format!(
+                "is {}, which leaves no room for the prompt in a context window of {}",
+                config.assistant_minimum_context_tokens, config.context_max_tokens
+            ),
+            format!(
+                "keep it well below context_max_tokens, e.g. `assistant_minimum_context_tokens: {}`",
+                config.context_max_tokens / 4
+            ),
+        ));
+    }
+
+    match config.max_response_tokens {
+        Some(0) => diagnostics.push(Diagnostic::error(
+            "max_response_tokens",
+            "must be greater than 0",
+            "remove it to allow the rest of the context window, or set a positive limit",
+        )),
+        Some(tokens) if tokens > config.context_max_tokens => {
+            diagnostics.push(Diagnostic::warning(
+                "max_response_tokens",
+                format!(
+                    "is {}, more than the whole context window of {}",
+                    tokens, config.context_max_tokens
+                ),
+                "lower it, or remove it to allow the rest of the context window",
+            ))
+        }
+        _ => {}
+    }
+
+    if let Some(temperature) = config.temperature {
+        if !(0.0..=2.0).contains(&temperature) {
+            diagnostics.push(Diagnostic::error(
+                "temperature",
+                format!("is {}, outside the range of 0 to 2", temperature),
+                "use a temperature from 0 (deterministic) to 2, e.g. `temperature: 0.7`",
+            ));
+        }
+    }
+
+    if config.max_response_seconds == Some(0) {
+        diagnostics.push(Diagnostic::error(
+            "max_response_seconds",
+            "must be greater than 0",
+            "remove it to let responses take as long as they need, or set a positive limit",
+        ));
+    }
+
+    for (field, value) in [
+        ("cache_max_age", &config.cache_max_age),
+        ("notify_after", &config.notify_after),
+    ] {
+        if let Some(Err(e)) = value.as_deref().map(config::parse_duration) {
+            diagnostics.push(Diagnostic::error(
+                field,
+                e,
+                format!("use a duration such as `{}: 30s`, `15m`, or `12h`", field),
+            ));
+        }
+    }
+    if let Some(Err(e)) = config.cache_max_size.as_deref().map(config::parse_size) {
+        diagnostics.push(Diagnostic::error(
+            "cache_max_size",
+            e,
+            "use a size such as `cache_max_size: 500M` or `2G`",
+        ));
+    }
+
+    if let Some(share) = config.rag_budget_share {
+        if !(share > 0.0 && share < 1.0) {
+            diagnostics.push(Diagnostic::error(
+                "rag_budget_share",
+                format!("is {}, but must be a share of the context window", share),
+                "use a number between 0 and 1, e.g. `rag_budget_share: 0.3`",
+            ));
+        }
+    }
+
+    if config.memory_chunk_tokens == Some(0) {
+        diagnostics.push(Diagnostic::error(
+            "memory_chunk_tokens",
+            "must be greater than 0",
+            "remove it to use 256 tokens per chunk",
+        ));
+    }
+
+    if let Some(threshold) = config.duplicate_question_threshold {
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            diagnostics.push(Diagnostic::error(
+                "duplicate_question_threshold",
+                format!(
+                    "is {}, but must be a similarity above 0 and at most 1",
+                    threshold
+                ),
+                "use a high similarity, e.g. `duplicate_question_threshold: 0.95`",
+            ));
+        }
+    }
+
+    if let Some(consolidation) = &config.memory_consolidation {
+        if consolidation.every == 0 {
+            diagnostics.push(Diagnostic::error(
+                "memory_consolidation.every",
+                "must be greater than 0",
+                "remove it to consolidate every 32 memories",
+            ));
+        }
+        if !(consolidation.similarity > 0.0 && consolidation.similarity <= 1.0) {
+            diagnostics.push(Diagnostic::error(
+                "memory_consolidation.similarity",
+                format!(
+                    "is {}, but must be a similarity above 0 and at most 1",
+                    consolidation.similarity
+                ),
+                "remove it to merge memories that are at least 0.85 similar",
+            ));
+        }
+    }
+
+    if let Some(guardrails) = &config.guardrails {
+        if guardrails.max_question_tokens == Some(0) {
+            diagnostics.push(Diagnostic::error(
+                "guardrails.max_question_tokens",
+                "must be greater than 0",
+                "remove it to allow questions of any length, or set a positive limit",
+            ));
+        }
+        for pattern in &guardrails.banned_patterns {
+            if let Err(e) = regex::Regex::new(pattern) {
+                diagnostics.push(Diagnostic::error(
+                    "guardrails.banned_patterns",
+                    format!("`{}` is not a regular expression ({})", pattern, e),
+                    "escape special characters, e.g. `\\(` for a literal parenthesis",
+                ));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Validates `config`, printing its warnings to stderr.
+///
+/// # Returns
+///
+/// - `Ok(())`: The configuration has no errors.
+/// - `Err(InvalidConfig)`: The configuration's errors.
+pub fn check(config: &AwfulJadeConfig) -> Result<(), InvalidConfig> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = validate(config)
+        .into_iter()
+        .partition(|diagnostic| diagnostic.severity == Severity::Error);
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidConfig(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConsolidationConfig, GuardrailsConfig};
+
+    fn valid_config() -> AwfulJadeConfig {
+        AwfulJadeConfig {
+            api_base: "http://localhost:5001/v1".to_string(),
+            model: "mistral-7b".to_string(),
+            context_max_tokens: 8192,
+            assistant_minimum_context_tokens: 2048,
+            ..Default::default()
+        }
+    }
+
+    fn fields(diagnostics: &[Diagnostic]) -> Vec<(&'static str, Severity)> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.field, diagnostic.severity))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config_has_no_diagnostics() {
+        assert_eq!(validate(&valid_config()), Vec::new());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let config = AwfulJadeConfig {
+            api_base: "localhost:5001".to_string(),
+            context_max_tokens: 2048,
+            assistant_minimum_context_tokens: 4096,
+            max_response_seconds: Some(0),
+            notify_after: Some("soon".to_string()),
+            rag_budget_share: Some(30.0),
+            memory_consolidation: Some(ConsolidationConfig {
+                every: 0,
+                ..Default::default()
+            }),
+            guardrails: Some(GuardrailsConfig {
+                banned_patterns: vec!["(unclosed".to_string()],
+                ..Default::default()
+            }),
+            ..valid_config()
+        };
+        assert_eq!(
+            fields(&validate(&config)),
+            vec![
+                ("api_base", Severity::Error),
+                ("assistant_minimum_context_tokens", Severity::Error),
+                ("max_response_seconds", Severity::Error),
+                ("notify_after", Severity::Error),
+                ("rag_budget_share", Severity::Error),
+                ("memory_consolidation.every", Severity::Error),
+                ("guardrails.banned_patterns", Severity::Error),
+            ]
+        );
+
+        let error = check(&config).unwrap_err().to_string();
+        assert!(error.starts_with("The configuration has 7 error(s):"));
+        assert!(error.contains("fix: keep it well below context_max_tokens"));
+    }
+
+    #[test]
+    fn test_warnings_do_not_fail_the_check() {
+        let config = AwfulJadeConfig {
+            api_base: "http://localhost:5001/v1/chat/completions".to_string(),
+            max_response_tokens: Some(10_000),
+            ..valid_config()
+        };
+        let diagnostics = validate(&config);
+        assert_eq!(
+            fields(&diagnostics),
+            vec![
+                ("api_base", Severity::Warning),
+                ("max_response_tokens", Severity::Warning),
+            ]
+        );
+        assert!(diagnostics[0]
+            .suggestion
+            .contains("`api_base: http://localhost:5001/v1`"));
+        assert!(check(&config).is_ok());
+    }
+}
@@ -1,45 +1,151 @@
-use async_openai::types::ChatCompletionRequestMessage;
 use hora::core::ann_index::ANNIndex;
 use hora::core::metrics::Metric;
 use hora::index::hnsw_idx::HNSWIndex;
 use hora::index::hnsw_params::HNSWParams;
 use regex::Regex;
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
-};
+#[cfg(feature = "embeddings")]
+use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModel;
 use std::collections::HashMap;
-use tiktoken_rs::async_openai::get_chat_completion_max_tokens;
+#[cfg(feature = "embeddings")]
+use std::sync::{Arc, Mutex};
 
-use crate::config::AwfulJadeConfig;
-use crate::Memory;
+use crate::brain::Memory;
+use crate::config::{AwfulJadeConfig, MemoryEmbedding};
+#[cfg(feature = "embeddings")]
+use crate::embeddings;
+use crate::embeddings::{Embedder, DEFAULT_API_MODEL};
+use crate::progress::{NoProgress, ProgressEvent, ProgressSink, Task};
+use crate::tokens;
+
+/// The most tokens in a chunk of a long memory when `memory_chunk_tokens` is not set.
+pub const DEFAULT_CHUNK_TOKENS: usize = 256;
+
+/// How many more neighbours are searched for when memories are chunked, since several of them
+/// may be chunks of the same memory.
+const CHUNK_SEARCH_FACTOR: usize = 4;
 
 pub struct VectorStore {
     index: HNSWIndex<f32, usize>,
     dimension: usize,
-    model: SentenceEmbeddingsModel,
+    embedder: Embedder,
     current_id: usize,
     id_to_memory: HashMap<usize, Memory>, // Added to hold the content mapping
+
+    /// The most tokens in each embedded chunk of a memory, or `None` to embed only the first
+    /// sentence of each memory.
+    chunk_tokens: Option<usize>,
+
+    /// The memory each chunk after a memory's first belongs to. A memory's id is the id of its
+    /// first chunk.
+    chunk_to_memory: HashMap<usize, usize>,
 }
 
 impl VectorStore {
+    #[cfg(feature = "embeddings")]
     pub async fn new(dimension: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_progress(dimension, &NoProgress).await
+    }
+
+    /// Creates a vector store, reporting the progress of loading the embedding model to `progress`.
+    #[cfg(feature = "embeddings")]
+    pub async fn with_progress(
+        dimension: usize,
+        progress: &dyn ProgressSink,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = embeddings::load_embedding_model(progress).await?;
+        Ok(Self::with_model(dimension, model))
+    }
+
+    /// Creates a vector store with the embedder configured by `embedding_provider`, reporting the
+    /// progress of loading it to `progress`. The store's dimension is the embedder's.
+    pub async fn from_config(
+        config: &AwfulJadeConfig,
+        progress: &dyn ProgressSink,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let embedder = Embedder::from_config(config, progress).await?;
+        let dimension = embedder.dimension().await?;
+        let chunk_tokens = match config.memory_embedding {
+            MemoryEmbedding::FirstSentence => None,
+            MemoryEmbedding::Chunks => {
+                Some(config.memory_chunk_tokens.unwrap_or(DEFAULT_CHUNK_TOKENS))
+            }
+        };
+        Ok(Self::with_embedder(dimension, embedder).with_chunk_tokens(chunk_tokens))
+    }
+
+    /// Creates a vector store that embeds text with an already loaded model.
+    ///
+    /// Stores created from the same `Arc` share one copy of the model's weights, so a memory store
+    /// and a RAG store don't each pay for loading it.
+    #[cfg(feature = "embeddings")]
+    pub fn with_model(dimension: usize, model: Arc<Mutex<SentenceEmbeddingsModel>>) -> Self {
+        Self::with_embedder(dimension, Embedder::Local(model))
+    }
+
+    /// Creates a vector store that embeds text with `embedder`.
+    pub fn with_embedder(dimension: usize, embedder: Embedder) -> Self {
         let params = HNSWParams::default();
         let index = HNSWIndex::new(dimension, &params);
 
-        let model = tokio::task::spawn_blocking(|| {
-            SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
-                .create_model()
-        })
-        .await?
-        .unwrap();
-
-        Ok(Self {
+        Self {
             index,
             dimension,
-            model,
+            embedder,
             current_id: 0,
             id_to_memory: HashMap::new(), // Initialize the HashMap here
-        })
+            chunk_tokens: None,
+            chunk_to_memory: HashMap::new(),
+        }
+    }
+
+    /// Makes `add_memory` embed memories in chunks of up to `chunk_tokens` tokens, or only their
+    /// first sentence when it is `None`.
+    pub fn with_chunk_tokens(mut self, chunk_tokens: Option<usize>) -> Self {
+        self.chunk_tokens = chunk_tokens;
+        self
+    }
+
+    /// Returns the most tokens in each embedded chunk of a memory, if memories are chunked.
+    pub fn chunk_tokens(&self) -> Option<usize> {
+        self.chunk_tokens
+    }
+
+    /// Returns the store's embedder, for creating other stores that share it.
+    pub fn embedder(&self) -> Embedder {
+        self.embedder.clone()
+    }
+
+    /// Embeds `memory` and adds it to the store, returning its id.
+    ///
+    /// Without chunking, the memory is represented by its first sentence. With chunking, it is
+    /// split into chunks of up to `chunk_tokens` tokens, and each chunk is added with the memory's
+    /// id as its parent, so every part of a long answer can be found. Searches return each memory
+    /// once, however many of its chunks matched.
+    pub async fn add_memory(
+        &mut self,
+        memory: Memory,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let Some(chunk_tokens) = self.chunk_tokens else {
+            let vector = self.embed_text_to_vector(memory.content()).await?;
+            return Ok(self.add_vector_with_content(vector, memory)?);
+        };
+
+        let chunks = chunk_text(memory.content(), chunk_tokens);
+        let vectors = self.embedder.embed(&chunks).await?;
+        let mut vectors = vectors.into_iter();
+        let first = vectors.next().ok_or("The memory has no text to embed.")?;
+        let id = self.add_vector_with_content(first, memory)?;
+        for vector in vectors {
+            if vector.len() != self.dimension {
+                return Err("Vector dimension does not match the index dimension.".into());
+            }
+            self.index
+                .add(&vector, self.current_id)
+                .map_err(|_| "Failed to add vector to the index.")?;
+            self.chunk_to_memory.insert(self.current_id, id);
+            self.current_id += 1;
+        }
+        Ok(id)
     }
 
     pub fn add_vector_with_content(
@@ -64,28 +170,123 @@ impl VectorStore {
     }
 
     pub fn get_content_by_id(&self, id: usize) -> Option<&Memory> {
-        self.id_to_memory.get(&id)
+        self.id_to_memory.get(&self.memory_id(id))
+    }
+
+    /// Returns the id of the memory a vector belongs to: its own, unless it is a later chunk of a
+    /// chunked memory.
+    fn memory_id(&self, id: usize) -> usize {
+        self.chunk_to_memory.get(&id).copied().unwrap_or(id)
+    }
+
+    /// Returns the id of the memory added after the one with id `id`, if there is one.
+    ///
+    /// Memories added together, such as a question and its answer, are found this way.
+    pub fn next_memory_id(&self, id: usize) -> Option<usize> {
+        (id + 1..self.current_id).find(|next| !self.chunk_to_memory.contains_key(next))
+    }
+
+    /// Returns the store's memories with their ids, oldest first.
+    pub fn memories(&self) -> Vec<(usize, &Memory)> {
+        let mut memories: Vec<_> = self.id_to_memory.iter().map(|(id, m)| (*id, m)).collect();
+        memories.sort_by_key(|(id, _)| *id);
+        memories
+    }
+
+    /// Returns the dimension of the store's vectors.
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Returns the number of memories in the store.
+    pub fn len(&self) -> usize {
+        self.id_to_memory.len()
+    }
+
+    /// Returns whether the store has no memories.
+    pub fn is_empty(&self) -> bool {
+        self.id_to_memory.is_empty()
     }
 
     pub fn build(&mut self) -> Result<(), &'static str> {
-        self.index
+        self.build_with_progress(&NoProgress)
+    }
+
+    /// Builds the index, reporting its progress to `progress`.
+    pub fn build_with_progress(&mut self, progress: &dyn ProgressSink) -> Result<(), &'static str> {
+        let task = Task::BuildIndex;
+        progress.on_event(ProgressEvent::Started { task, total: None });
+        let result = self
+            .index
             .build(Metric::Euclidean)
-            .map_err(|_| "Failed to build the index.")
+            .map_err(|_| "Failed to build the index.");
+        progress.on_event(ProgressEvent::Finished { task });
+        result
     }
 
     pub fn search(&self, vector: &[f32], top_k: usize) -> Result<Vec<usize>, &'static str> {
+        Ok(self
+            .search_with_scores(vector, top_k)?
+            .into_iter()
+            .map(|(id, _distance)| id)
+            .collect())
+    }
+
+    /// Returns the ids of the `top_k` memories closest to `vector`, with their distances, closest
+    /// first. A chunked memory is returned once, at the distance of its closest chunk.
+    pub fn search_with_scores(
+        &self,
+        vector: &[f32],
+        top_k: usize,
+    ) -> Result<Vec<(usize, f32)>, &'static str> {
         if vector.len() != self.dimension {
             return Err("Query vector dimension does not match the index dimension.");
         }
-        Ok(self.index.search(vector, top_k))
+        let candidates = if self.chunk_to_memory.is_empty() {
+            top_k
+        } else {
+            top_k * CHUNK_SEARCH_FACTOR
+        };
+        let mut neighbors: Vec<(usize, f32)> = Vec::with_capacity(top_k);
+        for (node, distance) in self.index.search_nodes(vector, candidates) {
+            let Some(id) = *node.idx() else {
+                continue;
+            };
+            let id = self.memory_id(id);
+            if neighbors.len() < top_k && !neighbors.iter().any(|(seen, _)| *seen == id) {
+                neighbors.push((id, distance));
+            }
+        }
+        Ok(neighbors)
+    }
+
+    /// Returns the `top_k` memories closest to `text`, with their distances, closest first.
+    ///
+    /// `text` is embedded the way memories are, so only its first sentence counts; see
+    /// `embed_text_to_vector`.
+    pub async fn search_memories(
+        &self,
+        text: &str,
+        top_k: usize,
+    ) -> Result<Vec<(Memory, f32)>, Box<dyn std::error::Error>> {
+        let vector = self.embed_text_to_vector(text).await?;
+        Ok(self
+            .search_with_scores(&vector, top_k)?
+            .into_iter()
+            .filter_map(|(id, distance)| Some((self.get_content_by_id(id)?.clone(), distance)))
+            .collect())
     }
 
-    pub fn embed_text_to_vector(&self, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-        // Put your text into an array (you can add more sentences if needed)
-        let sentences: Vec<String> = Self::tokenize_sentences(text);
+    pub async fn embed_text_to_vector(
+        &self,
+        text: &str,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        // The text is represented by its first sentence, so only that one is embedded
+        let mut sentences: Vec<String> = Self::tokenize_sentences(text);
+        sentences.truncate(1);
 
         // Generate embeddings
-        let embeddings = self.model.encode(&sentences)?;
+        let embeddings = self.embedder.embed(&sentences).await?;
 
         // Since it returns a 2D vector, we need to flatten it or select the first element
         // as each sentence corresponds to an embedding vector in the output
@@ -119,22 +320,165 @@ impl VectorStore {
 
         sentences
     }
+}
 
-    pub fn count_tokens(
-        messages: &Vec<ChatCompletionRequestMessage>,
-        config: &AwfulJadeConfig,
-    ) -> u16 {
-        let tokens_left = get_chat_completion_max_tokens("gpt-4", &messages).unwrap() as u16;
-        config.context_max_tokens - tokens_left
+/// Splits `text` into chunks of up to `max_tokens` tokens, breaking between words.
+///
+/// A single word longer than `max_tokens` becomes a chunk of its own. Text without words yields
+/// one empty chunk, so every memory has at least one vector.
+pub fn chunk_text(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for word in text.split_whitespace() {
+        let candidate = if chunk.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", chunk, word)
+        };
+        if !chunk.is_empty() && tokens::count(&candidate, DEFAULT_API_MODEL) > max_tokens {
+            chunks.push(std::mem::replace(&mut chunk, word.to_string()));
+        } else {
+            chunk = candidate;
+        }
     }
+    chunks.push(chunk);
+    chunks
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_openai::types::Role;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    fn embeddings(vectors: &[[f32; 3]]) -> serde_json::Value {
+        json!({
+            "object": "list",
+            "model": "mock_embedding_model",
+            "data": vectors.iter().enumerate().map(|(index, vector)| json!({
+                "object": "embedding",
+                "index": index,
+                "embedding": vector,
+            })).collect::<Vec<_>>(),
+            "usage": {"prompt_tokens": 1, "total_tokens": 1}
+        })
+    }
+
+    #[test]
+    fn test_chunk_text() {
+        let text = "one two three four five six seven eight nine ten";
+        let chunks = chunk_text(text, 4);
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| tokens::count(chunk, DEFAULT_API_MODEL) <= 4));
+        assert_eq!(chunks.join(" "), text);
+        assert_eq!(chunk_text("", 4), vec![String::new()]);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_memories() -> Result<(), Box<dyn std::error::Error>> {
+        let long = "Tokio is an async runtime. It schedules tasks on a pool of threads. \
+                    The tail of this answer mentions the blocking pool.";
+        let chunks = chunk_text(long, 8);
+        assert!(chunks.len() > 2);
+
+        // Only the last chunk of the long memory is close to the query.
+        let mut vectors = vec![[0.0, 0.0, 1.0]; chunks.len()];
+        *vectors.last_mut().unwrap() = [1.0, 0.0, 0.0];
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST)
+                .path("/embeddings")
+                .json_body_partial(json!({ "input": chunks }).to_string());
+            then.status(200).json_body(embeddings(&vectors));
+        });
+        for (text, vector) in [
+            ("Cats sleep a lot.", [0.5, 0.5, 0.0]),
+            ("What is the blocking pool?", [1.0, 0.0, 0.0]),
+        ] {
+            server.mock(|when, then| {
+                when.method(POST)
+                    .path("/embeddings")
+                    .json_body_partial(json!({ "input": [text] }).to_string());
+                then.status(200).json_body(embeddings(&[vector]));
+            });
+        }
+
+        let config = AwfulJadeConfig {
+            api_key: "mock_api_key".to_string(),
+            api_base: server.url(""),
+            ..Default::default()
+        };
+        let embedder = Embedder::Api {
+            client: crate::api::create_client(&config)?,
+            model: "mock_embedding_model".to_string(),
+        };
+        let mut store = VectorStore::with_embedder(3, embedder).with_chunk_tokens(Some(8));
+        let long_id = store
+            .add_memory(Memory::new(Role::Assistant, long.to_string()))
+            .await?;
+        let short_id = store
+            .add_memory(Memory::new(Role::User, "Cats sleep a lot.".to_string()))
+            .await?;
+        store.build()?;
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.next_memory_id(long_id), Some(short_id));
+        let query = store
+            .embed_text_to_vector("What is the blocking pool?")
+            .await?;
+        let neighbors = store.search(&query, 2)?;
+        assert_eq!(neighbors, vec![long_id, short_id]);
+        assert_eq!(
+            store.get_content_by_id(neighbors[0]).map(Memory::content),
+            Some(long)
+        );
+
+        let memories = store
+            .search_memories("What is the blocking pool?", 1)
+            .await?;
+        assert_eq!(memories.len(), 1);
+        assert_eq!(memories[0].0.content(), long);
+        assert_eq!(memories[0].1, 0.0);
+
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_vector_store() -> Result<(), Box<dyn std::error::Error>> {
+        let mut store = crate::test_support::vector_store();
+
+        let sentences = vec![
+            "Rust is pretty cool.",
+            "I love programming.",
+            "Coding is my passion.",
+            "I enjoy writing code.",
+            "Software development is fascinating.",
+        ];
+        for sentence in &sentences {
+            let vector = store.embed_text_to_vector(sentence).await?;
+            store.add_vector_with_content(vector, Memory::new(Role::User, sentence.to_string()))?;
+        }
+        store.build()?;
+
+        let query_vector = store.embed_text_to_vector("My passion is coding").await?;
+        let neighbors = store.search(&query_vector, 1)?;
+        assert_eq!(neighbors, vec![2]);
+
+        Ok(())
+    }
+}
+
+/// Tests that load the local embedding model.
+#[cfg(all(test, feature = "embeddings"))]
+mod local_tests {
+    use super::*;
+    use async_openai::types::Role;
+
+    #[tokio::test]
+    async fn test_local_vector_store() -> Result<(), Box<dyn std::error::Error>> {
         let mut store: VectorStore = VectorStore::new(384).await?;
 
         let sentences = vec![
@@ -146,15 +490,14 @@ mod tests {
         ];
 
         for sentence in &sentences {
-            let vector = store.embed_text_to_vector(sentence)?;
-            store.add_vector_with_content(vector, sentence.clone().to_string())?;
-            // Fixed this line
+            let vector = store.embed_text_to_vector(sentence).await?;
+            store.add_vector_with_content(vector, Memory::new(Role::User, sentence.to_string()))?;
         }
 
         store.build()?;
 
         let query_sentence = "Programming is love.";
-        let query_vector = store.embed_text_to_vector(query_sentence)?;
+        let query_vector = store.embed_text_to_vector(query_sentence).await?;
 
         let neighbors = store.search(&query_vector, 1)?;
 
@@ -162,4 +505,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_stores_share_model() -> Result<(), Box<dyn std::error::Error>> {
+        let model = embeddings::load_embedding_model(&NoProgress).await?;
+        let memories = VectorStore::with_model(384, Arc::clone(&model));
+        let documents = VectorStore::with_embedder(384, memories.embedder());
+
+        match (&memories.embedder, &documents.embedder) {
+            (Embedder::Local(a), Embedder::Local(b)) => assert!(Arc::ptr_eq(a, b)),
+            _ => panic!("Expected both stores to use the local model"),
+        }
+        assert_eq!(Arc::strong_count(&model), 3);
+        assert_eq!(
+            memories
+                .embed_text_to_vector("Rust is pretty cool.")
+                .await?,
+            documents
+                .embed_text_to_vector("Rust is pretty cool.")
+                .await?
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,143 @@
+//! This module compares two answers word by word.
+//!
+//! When a question is asked again after a clarification, the new answer often differs from the
+//! old one in a few words. `/diff` in interactive mode shows just those words: removed words are
+//! struck through in red and added words are green, or, without colors, they are marked the way
+//! `git diff --word-diff` marks them, `[-removed-]{+added+}`.
+
+#[cfg(feature = "tty")]
+use crossterm::style::Stylize;
+use similar::{ChangeTag, TextDiff};
+use std::time::Duration;
+
+/// How long a diff may search for the smallest set of changes before settling for a coarser one.
+pub(crate) const DIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A run of text that is in one or both answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WordChange {
+    Unchanged(String),
+    Added(String),
+    Removed(String),
+}
+
+impl WordChange {
+    /// Appends `text` to the run if it is of the same kind as `other`, returning whether it was.
+    fn extend(&mut self, other: &WordChange) -> bool {
+        match (self, other) {
+            (Self::Unchanged(run), Self::Unchanged(text))
+            | (Self::Added(run), Self::Added(text))
+            | (Self::Removed(run), Self::Removed(text)) => {
+                run.push_str(text);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns the changes that turn `old` into `new`, word by word.
+///
+/// Whitespace is compared like words, so joining the unchanged and removed runs gives back `old`,
+/// and joining the unchanged and added runs gives back `new`. The comparison gives up looking for
+/// the smallest diff after `DIFF_TIMEOUT`, so long answers are still compared quickly.
+pub fn diff(old: &str, new: &str) -> Vec<WordChange> {
+    let words = TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_words(old, new);
+
+    let mut changes: Vec<WordChange> = Vec::new();
+    for change in words.iter_all_changes() {
+        let text = change.value().to_string();
+        let change = match change.tag() {
+            ChangeTag::Equal => WordChange::Unchanged(text),
+            ChangeTag::Insert => WordChange::Added(text),
+            ChangeTag::Delete => WordChange::Removed(text),
+        };
+        // Neighbouring changes of the same kind are joined, so each run is marked once.
+        if !changes.last_mut().is_some_and(|last| last.extend(&change)) {
+            changes.push(change);
+        }
+    }
+    changes
+}
+
+/// Whether `changes` contain any addition or removal.
+pub fn has_changes(changes: &[WordChange]) -> bool {
+    changes
+        .iter()
+        .any(|change| !matches!(change, WordChange::Unchanged(_)))
+}
+
+/// Renders `changes` as text, marking removed runs `[-like this-]` and added runs `{+like this+}`.
+pub fn render_plain(changes: &[WordChange]) -> String {
+    changes
+        .iter()
+        .map(|change| match change {
+            WordChange::Unchanged(text) => text.clone(),
+            WordChange::Added(text) => format!("{{+{}+}}", text),
+            WordChange::Removed(text) => format!("[-{}-]", text),
+        })
+        .collect()
+}
+
+/// Renders `changes` for a terminal: removed runs struck through in red, added runs in green.
+#[cfg(feature = "tty")]
+pub fn render_colored(changes: &[WordChange]) -> String {
+    changes
+        .iter()
+        .map(|change| match change {
+            WordChange::Unchanged(text) => text.clone(),
+            WordChange::Added(text) => text.as_str().green().to_string(),
+            WordChange::Removed(text) => text.as_str().red().crossed_out().to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_words() {
+        let old = "Use `fs::read` to read the file.";
+        let new = "Use `fs::read_to_string` to read the whole file.";
+        let changes = diff(old, new);
+        assert_eq!(
+            render_plain(&changes),
+            "Use [-`fs::read`-]{+`fs::read_to_string`+} to read the {+whole +}file."
+        );
+        assert!(has_changes(&changes));
+        assert!(!has_changes(&diff(old, old)));
+    }
+
+    #[test]
+    fn test_diff_round_trips() {
+        let old = "one two\nthree  four";
+        let new = "one 2\nthree four five";
+        let changes = diff(old, new);
+        let side = |keep_added: bool| -> String {
+            changes
+                .iter()
+                .filter_map(|change| match change {
+                    WordChange::Unchanged(text) => Some(text.as_str()),
+                    WordChange::Added(text) if keep_added => Some(text.as_str()),
+                    WordChange::Removed(text) if !keep_added => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(side(false), old);
+        assert_eq!(side(true), new);
+    }
+
+    #[test]
+    fn test_diff_long_answers_quickly() {
+        let old: String = (0..20_000).map(|i| format!("{} ", i % 97)).collect();
+        let new: String = (0..20_000).map(|i| format!("{} ", i % 89)).collect();
+        let started = std::time::Instant::now();
+        let changes = diff(&old, &new);
+        assert!(started.elapsed() < DIFF_TIMEOUT * 10);
+        assert!(has_changes(&changes));
+    }
+}